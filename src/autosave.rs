@@ -0,0 +1,270 @@
+// autosave.rs
+//! This module saves the session every so often to a file that a clean exit removes, so
+//! an autosave still there at startup was left by a session that crashed or was killed,
+//! and can be recovered with `load_session`.
+//!
+//! Each process saves to its own `sheet-<pid>.autosave` and holds a lock on the
+//! `sheet-<pid>.lock` beside it for as long as it autosaves. The system releases the lock
+//! when the process dies, so only an autosave whose lock is free is an orphan; the file
+//! of a session still running is never offered for recovery or touched by another one.
+//!
+//! The caller encodes the session in memory, which is quick even for big sheets, and the
+//! file is written on a background thread so the input loop never waits on the disk. It
+//! is written to a temporary file first and renamed over the autosave, so a crash in the
+//! middle of a write leaves the previous autosave intact.
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Start of the name of every autosave file, which goes on with the process ID.
+const PREFIX: &str = "sheet-";
+/// Extension of autosave files.
+const EXTENSION: &str = "autosave";
+
+/// When the session is saved again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Every {
+    /// On the first change made at least this long after the last save.
+    Interval(Duration),
+    /// Once this many changes were made since the last save.
+    Edits(usize),
+}
+
+impl Every {
+    /// Parses `30s` as an interval of 30 seconds and `20` as every 20 changes. Zero is
+    /// neither.
+    pub fn parse(text: &str) -> Option<Every> {
+        match text.strip_suffix('s') {
+            Some(seconds) => match seconds.parse() {
+                Ok(seconds) if seconds > 0 => Some(Every::Interval(Duration::from_secs(seconds))),
+                _ => None,
+            },
+            None => match text.parse() {
+                Ok(edits) if edits > 0 => Some(Every::Edits(edits)),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Saves the session to one file, in the background, once enough has changed.
+pub struct Autosave {
+    /// The autosave file.
+    path: PathBuf,
+    /// The lock file beside it, locked while this autosave lives.
+    lock: File,
+    /// When the session is saved again.
+    every: Every,
+    /// When the last save was started, or the autosave created.
+    last_save: Instant,
+    /// Changes made since the last save.
+    unsaved: usize,
+    /// The write started by the last save, until it is known to have finished.
+    writing: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl Autosave {
+    /// Creates an autosave to `path` that has not saved anything yet, locking the lock file
+    /// beside it so other sessions know it is not an orphan.
+    ///
+    /// Fails if the lock file cannot be created, or another live session holds it.
+    pub fn new(path: PathBuf, every: Every) -> io::Result<Self> {
+        let lock = File::create(path.with_extension("lock"))?;
+        lock.try_lock()?;
+        Ok(Self {
+            path,
+            lock,
+            every,
+            last_save: Instant::now(),
+            unsaved: 0,
+            writing: None,
+        })
+    }
+    /// Changes when the session is saved again, counting from the last save.
+    pub fn set_every(&mut self, every: Every) {
+        self.every = every;
+    }
+    /// Counts one change to the session.
+    pub fn record_change(&mut self) {
+        self.unsaved += 1;
+    }
+    /// Returns `true` if there are changes to save and, by `every`, it is time to. A save
+    /// is never due while the last one is still being written.
+    pub fn due(&self) -> bool {
+        let idle = self
+            .writing
+            .as_ref()
+            .is_none_or(|writing| writing.is_finished());
+        idle && self.unsaved > 0
+            && match self.every {
+                Every::Interval(interval) => self.last_save.elapsed() >= interval,
+                Every::Edits(edits) => self.unsaved >= edits,
+            }
+    }
+    /// Starts writing `session`, as encoded by `Spreadsheet::write_session`, to the
+    /// autosave file in the background.
+    ///
+    /// Returns the error of the previous write, if it failed.
+    pub fn save(&mut self, session: Vec<u8>) -> io::Result<()> {
+        let previous = self.wait();
+        let path = self.path.clone();
+        self.writing = Some(thread::spawn(move || replace(&path, &session)));
+        self.last_save = Instant::now();
+        self.unsaved = 0;
+        previous
+    }
+    /// Waits for the write in progress, if any, and returns its outcome.
+    pub fn wait(&mut self) -> io::Result<()> {
+        match self.writing.take() {
+            Some(writing) => writing
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::other("autosave writer panicked"))),
+            None => Ok(()),
+        }
+    }
+    /// Waits for the write in progress and removes the autosave file and its lock, as on a
+    /// clean exit.
+    pub fn discard(mut self) -> io::Result<()> {
+        let _ = self.wait();
+        let removed = remove(&self.path);
+        drop(self.lock);
+        removed
+    }
+}
+
+/// Removes the autosave at `path` and its lock file, either of which may be missing.
+pub fn remove(path: &Path) -> io::Result<()> {
+    for file in [path.to_path_buf(), path.with_extension("lock")] {
+        match fs::remove_file(file) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Writes `bytes` to a temporary file beside `path` and renames it over `path`.
+fn replace(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let temporary = path.with_extension("autosave.tmp");
+    fs::write(&temporary, bytes)?;
+    fs::rename(&temporary, path)
+}
+
+/// Returns the file `--autosave` and `set autosave` save to: `sheet-<pid>.autosave` in
+/// the system's temporary directory, with the ID of this process.
+pub fn default_path() -> PathBuf {
+    std::env::temp_dir().join(format!("{}{}.{}", PREFIX, std::process::id(), EXTENSION))
+}
+
+/// Returns the autosaves in `dir` whose session is gone, with when each was last
+/// written, newest first.
+///
+/// Since a clean exit removes its autosave, these were left by sessions that did not exit
+/// cleanly. An autosave whose lock another process holds belongs to a running session and
+/// is skipped.
+pub fn orphans(dir: &Path) -> Vec<(PathBuf, SystemTime)> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut found: Vec<(PathBuf, SystemTime)> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == EXTENSION)
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with(PREFIX))
+        })
+        .filter(|path| match File::open(path.with_extension("lock")) {
+            Ok(lock) => lock.try_lock().is_ok(),
+            Err(_) => true,
+        })
+        .filter_map(|path| {
+            let saved = fs::metadata(&path).and_then(|meta| meta.modified()).ok()?;
+            Some((path, saved))
+        })
+        .collect();
+    found.sort_by(|a, b| b.1.cmp(&a.1));
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sheet::{TEST_COLS, TEST_ROWS, init_test_dimensions};
+    use crate::spreadsheet::Spreadsheet;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_autosave_every_few_edits_and_recover() {
+        init_test_dimensions();
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("sheet-1.autosave");
+        let mut app = Spreadsheet::new(TEST_ROWS, TEST_COLS);
+        app.set_autosave(Some(Autosave::new(path.clone(), Every::Edits(2)).unwrap()));
+
+        // Reports change nothing, so they do not count
+        for command in ["A1=5", "deps A1"] {
+            app.execute(command).unwrap();
+        }
+        app.wait_for_autosave().unwrap();
+        assert!(!path.exists());
+        app.execute("B1=A1*2").unwrap();
+        app.wait_for_autosave().unwrap();
+        assert!(path.exists());
+
+        // A later session finds the autosave and resumes from it
+        let mut recovered = Spreadsheet::new(TEST_ROWS, TEST_COLS);
+        recovered.load_session(&path).unwrap();
+        assert_eq!(recovered.sheet.borrow().get(1).value, 10.0);
+        recovered.execute("undo").unwrap();
+        assert_eq!(recovered.sheet.borrow().get(1).value, 0.0);
+
+        app.set_autosave(None);
+        assert!(!path.exists());
+        assert!(!path.with_extension("lock").exists());
+    }
+
+    #[test]
+    fn test_only_autosaves_of_dead_sessions_are_orphans() {
+        init_test_dimensions();
+        let dir = tempdir().unwrap();
+        let mut live = Autosave::new(dir.path().join("sheet-1.autosave"), Every::Edits(1)).unwrap();
+        let mut crashed =
+            Autosave::new(dir.path().join("sheet-2.autosave"), Every::Edits(1)).unwrap();
+        for autosave in [&mut live, &mut crashed] {
+            autosave.record_change();
+            autosave.save(b"session".to_vec()).unwrap();
+            autosave.wait().unwrap();
+        }
+        fs::write(dir.path().join("notes.autosave"), "").unwrap();
+        // Another live session cannot take over the file
+        assert!(Autosave::new(dir.path().join("sheet-1.autosave"), Every::Edits(1)).is_err());
+        assert_eq!(orphans(dir.path()), vec![]);
+
+        // Dropped without `discard`, as when the process dies, the lock is released
+        drop(crashed);
+        let found = orphans(dir.path());
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0, dir.path().join("sheet-2.autosave"));
+        remove(&found[0].0).unwrap();
+        assert_eq!(orphans(dir.path()), vec![]);
+        live.discard().unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_parse_every() {
+        assert_eq!(
+            Every::parse("30s"),
+            Some(Every::Interval(Duration::from_secs(30)))
+        );
+        assert_eq!(Every::parse("20"), Some(Every::Edits(20)));
+        for text in ["0", "0s", "s", "-3", "2m"] {
+            assert_eq!(Every::parse(text), None, "{}", text);
+        }
+    }
+}
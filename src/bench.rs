@@ -0,0 +1,184 @@
+// bench.rs
+//! This module runs the synthetic workloads of `--bench`: formulas entered one by one
+//! into a fresh engine, timing parsing, graph updates and evaluation separately, so
+//! changes to the dependency graph can be measured the same way every time.
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::graph::Graph;
+use crate::parser::ParserContext;
+use crate::sheet::Sheet;
+use crate::status::StatusCode;
+
+/// Rows of the sheet the benchmark runs on.
+pub const BENCH_ROWS: usize = 999;
+/// Columns of the sheet the benchmark runs on.
+pub const BENCH_COLS: usize = 100;
+/// Cells each workload fills unless `--bench` is given another count.
+pub const DEFAULT_BENCH_CELLS: usize = 10_000;
+
+/// Time spent in each phase of entering a workload's formulas.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Timings {
+    /// Turning formula text into `Info`.
+    pub parse: Duration,
+    /// Cycle detection, edge updates and ordering the cells to recompute.
+    pub graph: Duration,
+    /// Recomputing the ordered cells.
+    pub eval: Duration,
+}
+
+impl Timings {
+    /// Returns the time of all three phases.
+    pub fn total(&self) -> Duration {
+        self.parse + self.graph + self.eval
+    }
+}
+
+/// Formulas to enter in order, the last one an edit that recomputes most of the others.
+struct Workload {
+    name: &'static str,
+    formulas: Vec<(usize, String)>,
+}
+
+/// Returns the workloads, each filling `cells` cells of an `n` x `m` sheet:
+///
+/// - `chain`: each cell adds one to the one before it, column after column.
+/// - `fan-out`: every cell reads `A1`.
+/// - `ranges`: half of column A holds numbers, every other cell sums all of them.
+///
+/// Each ends by changing `A1`, which every other cell depends on.
+fn workloads(sheet: &Sheet, cells: usize) -> Vec<Workload> {
+    let n = sheet.n;
+    // The i-th cell, going down column A, then column B, and so on
+    let at = |i: usize| sheet.get_cell(i % n, i / n);
+    let name = |i: usize| sheet.cell_name(at(i));
+    let edit = (0, "2".to_string());
+
+    let mut chain = vec![(0, "1".to_string())];
+    chain.extend((1..cells).map(|i| (at(i), format!("{}+1", name(i - 1)))));
+    chain.push(edit.clone());
+
+    let mut fan_out = vec![(0, "1".to_string())];
+    fan_out.extend((1..cells).map(|i| (at(i), "$A$1*2".to_string())));
+    fan_out.push(edit.clone());
+
+    let summed = (cells / 2).clamp(1, n);
+    let sum = format!("SUM(A1:{})", name(summed - 1));
+    let mut ranges: Vec<(usize, String)> = (0..summed).map(|i| (at(i), "1".to_string())).collect();
+    ranges.extend((summed..cells).map(|i| (at(i), sum.clone())));
+    ranges.push(edit);
+
+    vec![
+        Workload {
+            name: "chain",
+            formulas: chain,
+        },
+        Workload {
+            name: "fan-out",
+            formulas: fan_out,
+        },
+        Workload {
+            name: "ranges",
+            formulas: ranges,
+        },
+    ]
+}
+
+/// Enters a workload into a fresh `n` x `m` engine, returning the phase timings and the
+/// sheet it produced.
+fn run_workload(
+    workload: &Workload,
+    n: usize,
+    m: usize,
+) -> Result<(Timings, Rc<RefCell<Sheet>>), StatusCode> {
+    let sheet = Rc::new(RefCell::new(Sheet::new(n, m)));
+    let mut graph = Graph::new(n, m, sheet.clone());
+    let mut context = ParserContext::new();
+    let mut timings = Timings::default();
+    for (cell, formula) in &workload.formulas {
+        let start = Instant::now();
+        let info = context
+            .parse_expression(formula)
+            .map_err(|e| e.status_code())?;
+        timings.parse += start.elapsed();
+
+        let start = Instant::now();
+        graph.stage_expression(*cell, &info)?;
+        timings.graph += start.elapsed();
+
+        let start = Instant::now();
+        graph.update_values();
+        graph.reset();
+        timings.eval += start.elapsed();
+    }
+    Ok((timings, sheet))
+}
+
+/// Runs every workload with `cells` cells on an `n` x `m` sheet, whose size the global
+/// dimensions must already match.
+///
+/// Returns the name and timings of each workload, or `OutOfBounds` if the cells do not
+/// fit the sheet.
+pub fn run(cells: usize, n: usize, m: usize) -> Result<Vec<(&'static str, Timings)>, StatusCode> {
+    if cells < 2 || cells > n * m {
+        return Err(StatusCode::OutOfBounds);
+    }
+    workloads(&Sheet::new(n, m), cells)
+        .iter()
+        .map(|workload| Ok((workload.name, run_workload(workload, n, m)?.0)))
+        .collect()
+}
+
+/// Formats the results of `run` as a table, one workload per line, in milliseconds.
+pub fn report(cells: usize, results: &[(&str, Timings)]) -> String {
+    let ms = |duration: Duration| format!("{:.1}", duration.as_secs_f64() * 1000.0);
+    let mut table = format!(
+        "{} cells per workload, times in ms\n{:<10}{:>10}{:>10}{:>10}{:>10}\n",
+        cells, "workload", "parse", "graph", "eval", "total"
+    );
+    for (name, timings) in results {
+        table += &format!(
+            "{:<10}{:>10}{:>10}{:>10}{:>10}\n",
+            name,
+            ms(timings.parse),
+            ms(timings.graph),
+            ms(timings.eval),
+            ms(timings.total())
+        );
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sheet::{TEST_COLS, TEST_ROWS, get_cell, init_test_dimensions};
+
+    #[test]
+    fn test_workloads_compute_what_they_describe() {
+        init_test_dimensions();
+        let cells = 250;
+        let sheet = Sheet::new(TEST_ROWS, TEST_COLS);
+        let last = get_cell(49, 2);
+        let mut values = Vec::new();
+        for workload in workloads(&sheet, cells) {
+            assert_eq!(workload.formulas.len(), cells + 1);
+            let (_, sheet) = run_workload(&workload, TEST_ROWS, TEST_COLS).unwrap();
+            values.push(sheet.borrow().get(last).value);
+        }
+        // After A1=2: the chain counts up from it, the fan-out doubles it and the
+        // ranges add it to the other 99 ones of A1:A100
+        assert_eq!(values, [251.0, 4.0, 101.0]);
+
+        let results = run(cells, TEST_ROWS, TEST_COLS).unwrap();
+        let table = report(cells, &results);
+        assert_eq!(table.lines().count(), 5);
+        assert!(table.lines().nth(2).unwrap().starts_with("chain"));
+        assert_eq!(
+            run(1, TEST_ROWS, TEST_COLS).err(),
+            Some(StatusCode::OutOfBounds)
+        );
+    }
+}
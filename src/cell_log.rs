@@ -0,0 +1,177 @@
+// cell_log.rs
+//! This module keeps a read-only log of the edits made to each cell, so `history A1` can
+//! show how a cell came to hold its formula: when each edit happened and the formula
+//! before and after it.
+//!
+//! Unlike the undo history nothing here is ever reverted. An undo or redo changes the
+//! cell again and is logged like any other edit. Each cell keeps its `max_depth` most
+//! recent edits.
+use std::collections::{HashMap, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::date;
+use crate::info::{CellInfo, Info};
+use crate::sheet::Sheet;
+
+/// Number of edits kept per cell until `set cell_history_depth` changes it.
+pub const DEFAULT_CELL_HISTORY_DEPTH: usize = 20;
+
+/// One logged edit of a cell.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Edit {
+    /// When the edit was made.
+    pub time: SystemTime,
+    /// The formula before the edit, `None` for a blank cell.
+    pub old: Option<Info>,
+    /// The formula after the edit, `None` for a blank cell.
+    pub new: Option<Info>,
+}
+
+/// The edits of every cell, oldest first per cell.
+#[derive(Debug, Clone)]
+pub struct CellLog {
+    edits: HashMap<usize, VecDeque<Edit>>,
+    /// Most edits kept per cell.
+    max_depth: usize,
+}
+
+impl CellLog {
+    /// Creates an empty log keeping at most `max_depth` edits per cell.
+    pub fn new(max_depth: usize) -> Self {
+        Self {
+            edits: HashMap::new(),
+            max_depth,
+        }
+    }
+    /// Logs that `cell` went from `old` to `new` just now, forgetting its oldest edit
+    /// beyond `max_depth`.
+    pub fn record(&mut self, cell: usize, old: &CellInfo, new: &CellInfo) {
+        if self.max_depth == 0 {
+            return;
+        }
+        let formula = |state: &CellInfo| (!state.is_blank()).then_some(state.info);
+        let edits = self.edits.entry(cell).or_default();
+        if edits.len() == self.max_depth {
+            edits.pop_front();
+        }
+        edits.push_back(Edit {
+            time: SystemTime::now(),
+            old: formula(old),
+            new: formula(new),
+        });
+    }
+    /// Returns the logged edits of `cell`, oldest first.
+    pub fn get(&self, cell: usize) -> impl Iterator<Item = &Edit> {
+        self.edits.get(&cell).into_iter().flatten()
+    }
+    /// Returns the most edits kept per cell.
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+    /// Changes the most edits kept per cell, forgetting the oldest ones beyond it.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+        for edits in self.edits.values_mut() {
+            let excess = edits.len().saturating_sub(max_depth);
+            edits.drain(..excess);
+        }
+        self.edits.retain(|_, edits| !edits.is_empty());
+    }
+    /// Forgets every logged edit, e.g. once the cells have moved.
+    pub fn clear(&mut self) {
+        self.edits.clear();
+    }
+    /// Builds the `history A1` listing, one edit per line, oldest first, e.g.
+    ///
+    /// ```text
+    /// 2026-10-17 09:15:02  (blank) -> 5
+    /// 2026-10-17 09:15:40  5 -> B1+1
+    /// ```
+    pub fn report(&self, sheet: &Sheet, cell: usize) -> String {
+        let formula = |info: &Option<Info>| match info {
+            Some(info) => sheet.formula_text(info),
+            None => "(blank)".to_string(),
+        };
+        let lines: Vec<String> = self
+            .get(cell)
+            .map(|edit| {
+                format!(
+                    "{}  {} -> {}",
+                    timestamp(edit.time),
+                    formula(&edit.old),
+                    formula(&edit.new)
+                )
+            })
+            .collect();
+        if lines.is_empty() {
+            return format!("no edits to {} logged", sheet.cell_name(cell));
+        }
+        lines.join("\n")
+    }
+}
+
+/// Renders a time as `YYYY-MM-DD HH:MM:SS` in UTC.
+fn timestamp(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    let day = date::format(seconds.div_euclid(86400) as f64).unwrap_or_default();
+    let second = seconds.rem_euclid(86400);
+    format!(
+        "{} {:02}:{:02}:{:02}",
+        day,
+        second / 3600,
+        second / 60 % 60,
+        second % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sheet::{TEST_COLS, TEST_ROWS, get_cell, init_test_dimensions};
+    use crate::spreadsheet::Spreadsheet;
+    use std::time::Duration;
+
+    #[test]
+    fn test_cell_history_survives_undo() {
+        init_test_dimensions();
+        let mut app = Spreadsheet::new(TEST_ROWS, TEST_COLS);
+        for command in ["A1=5", "A1=B1+1", "B1=2", "undo", "clear A1:A1"] {
+            app.execute(command).unwrap();
+        }
+        let formulas = |app: &Spreadsheet, cell| -> Vec<String> {
+            let sheet = app.sheet.borrow();
+            app.graph
+                .cell_log
+                .report(&sheet, cell)
+                .lines()
+                .map(|line| line.split_once("  ").unwrap().1.to_string())
+                .collect()
+        };
+        assert_eq!(
+            formulas(&app, 0),
+            ["(blank) -> 5", "5 -> B1+1", "B1+1 -> (blank)"]
+        );
+        // B1's undo is one more edit, not the removal of the first
+        assert_eq!(formulas(&app, 1), ["(blank) -> 2", "2 -> (blank)"]);
+        assert_eq!(
+            app.graph
+                .cell_log
+                .report(&app.sheet.borrow(), get_cell(1, 1)),
+            "no edits to B2 logged"
+        );
+
+        app.execute("set cell_history_depth 1").unwrap();
+        assert_eq!(formulas(&app, 0), ["B1+1 -> (blank)"]);
+        app.execute("set cell_history_depth 0").unwrap();
+        app.execute("A1=7").unwrap();
+        assert_eq!(app.graph.cell_log.get(0).count(), 0);
+    }
+
+    #[test]
+    fn test_timestamp_is_utc() {
+        let time = UNIX_EPOCH + Duration::from_secs(19753 * 86400 + 3600 + 2 * 60 + 3);
+        assert_eq!(timestamp(time), "2024-01-31 01:02:03");
+    }
+}
@@ -0,0 +1,125 @@
+// chart.rs
+//! This module draws a range as a horizontal bar chart in text, one bar per cell, for
+//! `chart bar A1:A10` to show under the grid.
+//!
+//! Bars are built from Unicode block elements, so a bar's length has a resolution of an
+//! eighth of a character. They are scaled so that the largest magnitude in the range
+//! fills the width left over by the labels.
+use crate::sheet::Sheet;
+
+/// Width a chart is fitted to when the terminal's is not known.
+pub const DEFAULT_WIDTH: usize = 80;
+/// The blocks one to eight eighths of a character wide.
+const EIGHTHS: [char; 8] = ['▏', '▎', '▍', '▌', '▋', '▊', '▉', '█'];
+/// The character negative bars are drawn with, one per whole character of length.
+const NEGATIVE: char = '░';
+
+/// Returns the cells of `start:end`, row by row.
+fn cells(sheet: &Sheet, start: usize, end: usize) -> Vec<usize> {
+    let (top, left) = sheet.get_row_and_column(start);
+    let (bottom, right) = sheet.get_row_and_column(end);
+    (top..=bottom)
+        .flat_map(|row| (left..=right).map(move |col| (row, col)))
+        .map(|(row, col)| sheet.get_cell(row, col))
+        .collect()
+}
+
+/// Draws the cells of `start:end` as bars, one line per cell in row-major order, at most
+/// `width` characters wide, e.g.
+///
+/// ```text
+/// A1 │████████████▌ 25
+/// A2 │██████▎ 12.5
+/// A3 │░░░ -6
+/// A4 │ #DIV/0
+/// ```
+///
+/// Each line has the cell's name, its bar and its displayed text, number format
+/// included. Negative values are drawn in `░` and cells without a number, such as text
+/// and errors, get no bar.
+pub fn bar_chart(sheet: &Sheet, start: usize, end: usize, width: usize) -> Vec<String> {
+    let rows: Vec<(String, Option<f64>, String)> = cells(sheet, start, end)
+        .into_iter()
+        .map(|cell| {
+            let data = sheet.get(cell);
+            let value = (!data.info.invalid()).then_some(data.value);
+            (sheet.cell_name(cell), value, sheet.cell_text(cell, false))
+        })
+        .collect();
+    let name_width = rows.iter().map(|(name, ..)| name.len()).max().unwrap_or(0);
+    let text_width = rows
+        .iter()
+        .map(|(_, _, text)| text.chars().count())
+        .max()
+        .unwrap_or(0);
+    // The name, " │", the bar, a space and the text
+    let space = width.saturating_sub(name_width + 3 + text_width).max(1);
+    let largest = rows
+        .iter()
+        .filter_map(|&(_, value, _)| value)
+        .map(f64::abs)
+        .fold(0.0, f64::max);
+
+    rows.iter()
+        .map(|(name, value, text)| {
+            let bar = match *value {
+                Some(value) if largest > 0.0 => bar(value / largest * space as f64),
+                _ => String::new(),
+            };
+            format!("{:>w$} │{} {}", name, bar, text, w = name_width)
+        })
+        .collect()
+}
+
+/// Draws a bar `length` characters long, in eighths for a positive length and in whole
+/// `NEGATIVE` characters for a negative one.
+fn bar(length: f64) -> String {
+    if length < 0.0 {
+        return NEGATIVE.to_string().repeat((-length).round() as usize);
+    }
+    let eighths = (length * 8.0).round() as usize;
+    let mut bar = EIGHTHS[7].to_string().repeat(eighths / 8);
+    if eighths % 8 > 0 {
+        bar.push(EIGHTHS[eighths % 8 - 1]);
+    }
+    bar
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sheet::{TEST_COLS, TEST_ROWS, get_cell, init_test_dimensions};
+    use crate::spreadsheet::Spreadsheet;
+
+    #[test]
+    fn test_bars_scale_to_the_largest_magnitude() {
+        init_test_dimensions();
+        let mut app = Spreadsheet::new(TEST_ROWS, TEST_COLS);
+        for command in ["A1=20", "A2=5", "A3=-10", "A4=1/0"] {
+            app.execute(command).unwrap();
+        }
+        let sheet = app.sheet.borrow();
+        let end = get_cell(4, 0);
+        // 27 characters leave 16 for the bars beside "A1 │" and " #DIV/0"
+        let chart = bar_chart(&sheet, 0, end, 27);
+        assert_eq!(
+            chart,
+            [
+                "A1 │████████████████ 20",
+                "A2 │████ 5",
+                "A3 │░░░░░░░░ -10",
+                "A4 │ #DIV/0",
+                "A5 │ 0",
+            ]
+        );
+        assert!(chart.iter().all(|line| line.chars().count() <= 27));
+    }
+
+    #[test]
+    fn test_bars_have_eighth_resolution() {
+        assert_eq!(bar(2.5), "██▌");
+        assert_eq!(bar(0.125), "▏");
+        assert_eq!(bar(0.0), "");
+        assert_eq!(bar(-1.4), "░");
+    }
+}
@@ -0,0 +1,114 @@
+// date.rs
+//! This module converts between calendar dates and the serial numbers date cells hold:
+//! whole days since 1970-01-01, which is day `0`. Earlier dates are negative.
+//!
+//! Dates use the proleptic Gregorian calendar and no time of day, so a date cell is an
+//! ordinary number that `DAYS` or plain subtraction can work with.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Earliest year a date cell may hold.
+pub const MIN_YEAR: i64 = 1;
+/// Latest year a date cell may hold, so every date prints with four digits.
+pub const MAX_YEAR: i64 = 9999;
+
+/// Returns the serial number of `year-month-day`, a valid date.
+///
+/// Uses Howard Hinnant's `days_from_civil`, which counts in 400-year eras of exactly
+/// 146097 days starting on a March 1st.
+pub fn serial(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    // Days since March 1st, the start of the shifted year
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Returns the `(year, month, day)` of a serial number, the inverse of `serial`.
+pub fn civil(serial: i64) -> (i64, u32, u32) {
+    let days = serial + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Returns the serial number of `DATE(year, month, day)`, or `None` if an argument is
+/// not a whole number or the date falls outside `MIN_YEAR..=MAX_YEAR`.
+///
+/// Like spreadsheets do, months and days outside their usual range roll over into the
+/// next or previous year or month, so `DATE(2024,13,1)` is 2025-01-01 and
+/// `DATE(2024,3,0)` is 2024-02-29.
+pub fn from_parts(year: f64, month: f64, day: f64) -> Option<i64> {
+    let whole = |x: f64| (x.fract() == 0.0 && x.abs() < 1e9).then_some(x as i64);
+    let (year, month, day) = (whole(year)?, whole(month)?, whole(day)?);
+    let year = year + (month - 1).div_euclid(12);
+    let month = (month - 1).rem_euclid(12) as u32 + 1;
+    let date = serial(year, month, 1) + day - 1;
+    (MIN_YEAR..=MAX_YEAR)
+        .contains(&civil(date).0)
+        .then_some(date)
+}
+
+/// Returns today's serial number, by the system clock in UTC.
+pub fn today() -> i64 {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    seconds.div_euclid(86400)
+}
+
+/// Renders a serial number as `YYYY-MM-DD`, or `None` if it is not a whole number or
+/// lies outside `MIN_YEAR..=MAX_YEAR`.
+pub fn format(value: f64) -> Option<String> {
+    if value.fract() != 0.0 || value.abs() > 1e9 {
+        return None;
+    }
+    let (year, month, day) = civil(value as i64);
+    (MIN_YEAR..=MAX_YEAR)
+        .contains(&year)
+        .then(|| format!("{:04}-{:02}-{:02}", year, month, day))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serial_round_trips() {
+        assert_eq!(serial(1970, 1, 1), 0);
+        assert_eq!(serial(2024, 1, 31), 19753);
+        assert_eq!(serial(1969, 12, 31), -1);
+        for date in [-719162, -1, 0, 59, 11016, 19753, 2932896] {
+            let (year, month, day) = civil(date);
+            assert_eq!(serial(year, month, day), date);
+        }
+        assert_eq!(civil(19782), (2024, 2, 29));
+    }
+
+    #[test]
+    fn test_parts_roll_over_and_render() {
+        assert_eq!(from_parts(2024.0, 13.0, 1.0), Some(serial(2025, 1, 1)));
+        assert_eq!(from_parts(2024.0, 3.0, 0.0), Some(serial(2024, 2, 29)));
+        assert_eq!(from_parts(2024.0, 0.0, 1.0), Some(serial(2023, 12, 1)));
+        assert_eq!(from_parts(2024.5, 1.0, 1.0), None);
+        assert_eq!(from_parts(10000.0, 1.0, 1.0), None);
+
+        assert_eq!(format(19753.0).as_deref(), Some("2024-01-31"));
+        assert_eq!(format(-719162.0).as_deref(), Some("0001-01-01"));
+        assert_eq!(format(0.5), None);
+        assert_eq!(format(1e12), None);
+    }
+}
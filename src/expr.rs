@@ -0,0 +1,400 @@
+// expr.rs
+//! This module defines the expression trees of nested formulas such as
+//! `(B1+C1)*2-SUM(D1:D5)/3`.
+//!
+//! The parser lowers every formula that fits one of the classic forms (a literal, a
+//! reference, one operator between two operands, a range function or a single-argument
+//! call) to a plain `Info`. Anything deeper becomes an expression cell (function 17)
+//! holding the ID of its tree in a process-wide table, the same way text cells hold
+//! interned strings. Trees are never removed from the table.
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::formulas::{
+    COUNTLABEL, DATE_FUNCTIONS, LABEL_FUNCTIONS, LOOKUP_FUNCTIONS, POSITION_FUNCTIONS, RAND,
+    RANDOM_FUNCTIONS, RANGE_FUNCTIONS, SCALAR_FUNCTIONS, TODAY, is_lookup_function,
+    is_position_function,
+};
+use crate::sheet::format_literal;
+
+/// Most nodes a tree may have, which also bounds its depth, so that evaluating, rendering
+/// or decoding a tree can never exhaust the stack.
+pub const MAX_NODES: usize = 1024;
+
+/// A node of a formula's expression tree.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    /// A number literal.
+    Number(f64),
+    /// A text literal, the pattern of `COUNTLABEL`.
+    Text(Arc<str>),
+    /// A reference to a cell, by index, with its `FIXED_COL`/`FIXED_ROW` flags.
+    Cell(usize, u8),
+    /// The negation of its operand, e.g. `-A1`.
+    Neg(Box<Expr>),
+    /// An arithmetic operator, as the function ID of `add`, `sub`, `mul` or `divide`
+    /// (2–5), or a two-argument function such as `MOD`, `DAYS`, `MATCH` or `COUNTLABEL`,
+    /// with its two operands.
+    Binary(u8, Box<Expr>, Box<Expr>),
+    /// A three-argument function, `DATE`, `INDEX` or `VLOOKUP`, by function ID, with its
+    /// operands.
+    Ternary(u8, Box<Expr>, Box<Expr>, Box<Expr>),
+    /// A range function such as `SUM(A1:B3)`, by function ID, with the corners of its range
+    /// and their fixed flags, laid out as in `Info::fixed`. Under a lookup function or
+    /// `COUNTLABEL`, with its ID, the table it searches.
+    Range(u8, usize, usize, u8),
+    /// `SLEEP`, `FACT`, `FIB`, `ABS` or a position function, by function ID, with its
+    /// argument; `RAND` and `TODAY` with the unused argument `0`.
+    Call(u8, Box<Expr>),
+}
+
+impl Expr {
+    /// Calls `f` on every node of the tree, parents before their children.
+    pub fn visit(&self, f: &mut impl FnMut(&Expr)) {
+        f(self);
+        match self {
+            Expr::Neg(operand) | Expr::Call(_, operand) => operand.visit(f),
+            Expr::Binary(_, lhs, rhs) => {
+                lhs.visit(f);
+                rhs.visit(f);
+            }
+            Expr::Ternary(_, first, second, third) => {
+                first.visit(f);
+                second.visit(f);
+                third.visit(f);
+            }
+            Expr::Number(_) | Expr::Text(_) | Expr::Cell(..) | Expr::Range(..) => {}
+        }
+    }
+    /// Returns every cell and range the tree reads as a pair of corners, in the order they
+    /// are written. A single cell `c` is the range `(c, c)`.
+    pub fn ranges(&self) -> Vec<(usize, usize)> {
+        let mut ranges = Vec::new();
+        self.visit(&mut |node| match *node {
+            Expr::Cell(cell, _) => ranges.push((cell, cell)),
+            Expr::Range(_, start, end, _) => ranges.push((start, end)),
+            _ => {}
+        });
+        ranges
+    }
+    /// Returns a copy of the tree with its cell references rewritten through `f`, which
+    /// takes a cell and its fixed flags and returns the replacement for the cell or `None`
+    /// to leave it as is.
+    ///
+    /// The two corners of a range are only rewritten if `f` replaces both of them and
+    /// `is_range` accepts them, since a copy that keeps one corner fixed may cross them.
+    pub fn map_references(
+        &self,
+        f: &mut impl FnMut(usize, u8) -> Option<usize>,
+        is_range: &impl Fn(usize, usize) -> bool,
+    ) -> Expr {
+        match self {
+            Expr::Number(_) | Expr::Text(_) => self.clone(),
+            Expr::Cell(cell, fixed) => Expr::Cell(f(*cell, *fixed).unwrap_or(*cell), *fixed),
+            Expr::Neg(operand) => Expr::Neg(Box::new(operand.map_references(f, is_range))),
+            Expr::Binary(op, lhs, rhs) => Expr::Binary(
+                *op,
+                Box::new(lhs.map_references(f, is_range)),
+                Box::new(rhs.map_references(f, is_range)),
+            ),
+            Expr::Ternary(function_id, first, second, third) => Expr::Ternary(
+                *function_id,
+                Box::new(first.map_references(f, is_range)),
+                Box::new(second.map_references(f, is_range)),
+                Box::new(third.map_references(f, is_range)),
+            ),
+            Expr::Range(function_id, start, end, fixed) => {
+                match (f(*start, fixed & 0b11), f(*end, fixed >> 2)) {
+                    (Some(start), Some(end)) if is_range(start, end) => {
+                        Expr::Range(*function_id, start, end, *fixed)
+                    }
+                    _ => self.clone(),
+                }
+            }
+            Expr::Call(function_id, arg) => {
+                Expr::Call(*function_id, Box::new(arg.map_references(f, is_range)))
+            }
+        }
+    }
+    /// Returns a copy of the tree with its numbers rewritten through `f`, which returns the
+    /// replacement for a number or `None` to leave it as is.
+    ///
+    /// The placeholder argument of `RAND()`, `TODAY()` and a position function without a
+    /// cell is not a number of the formula and is left alone.
+    pub fn map_numbers(&self, f: &mut impl FnMut(f64) -> Option<f64>) -> Expr {
+        match self {
+            Expr::Number(value) => Expr::Number(f(*value).unwrap_or(*value)),
+            Expr::Text(_) | Expr::Cell(..) | Expr::Range(..) => self.clone(),
+            Expr::Call(function_id, _)
+                if matches!(*function_id, RAND | TODAY) || is_position_function(*function_id) =>
+            {
+                self.clone()
+            }
+            Expr::Neg(operand) => Expr::Neg(Box::new(operand.map_numbers(f))),
+            Expr::Binary(op, lhs, rhs) => Expr::Binary(
+                *op,
+                Box::new(lhs.map_numbers(f)),
+                Box::new(rhs.map_numbers(f)),
+            ),
+            Expr::Ternary(function_id, first, second, third) => Expr::Ternary(
+                *function_id,
+                Box::new(first.map_numbers(f)),
+                Box::new(second.map_numbers(f)),
+                Box::new(third.map_numbers(f)),
+            ),
+            Expr::Call(function_id, arg) => Expr::Call(*function_id, Box::new(arg.map_numbers(f))),
+        }
+    }
+    /// Returns a copy of the tree with every cell and range rewritten through `f`, which
+    /// maps the corners of a range (a cell `c` being the range `(c, c)`) or returns `None`
+    /// if the range no longer exists.
+    ///
+    /// Returns `None` if `f` removed any of them.
+    pub fn map_ranges(
+        &self,
+        f: &mut impl FnMut(usize, usize) -> Option<(usize, usize)>,
+    ) -> Option<Expr> {
+        let expr = match self {
+            Expr::Number(_) | Expr::Text(_) => self.clone(),
+            Expr::Cell(cell, fixed) => Expr::Cell(f(*cell, *cell)?.0, *fixed),
+            Expr::Neg(operand) => Expr::Neg(Box::new(operand.map_ranges(f)?)),
+            Expr::Binary(op, lhs, rhs) => Expr::Binary(
+                *op,
+                Box::new(lhs.map_ranges(f)?),
+                Box::new(rhs.map_ranges(f)?),
+            ),
+            Expr::Ternary(function_id, first, second, third) => Expr::Ternary(
+                *function_id,
+                Box::new(first.map_ranges(f)?),
+                Box::new(second.map_ranges(f)?),
+                Box::new(third.map_ranges(f)?),
+            ),
+            Expr::Range(function_id, start, end, fixed) => {
+                let (start, end) = f(*start, *end)?;
+                Expr::Range(*function_id, start, end, *fixed)
+            }
+            Expr::Call(function_id, arg) => Expr::Call(*function_id, Box::new(arg.map_ranges(f)?)),
+        };
+        Some(expr)
+    }
+    /// Renders the tree back into input syntax, naming cells with `cell_name`, which takes
+    /// a cell and its fixed flags.
+    ///
+    /// Parentheses are only written where precedence needs them, so `(A1*2)+B1` renders
+    /// as `A1*2+B1`. A right operand of equal precedence keeps its parentheses, since
+    /// `A1-(B1-C1)` differs from `A1-B1-C1`.
+    pub fn render(&self, cell_name: &impl Fn(usize, u8) -> String) -> String {
+        match self {
+            Expr::Number(value) => format_literal(*value),
+            Expr::Text(text) => format!("\"{}\"", text),
+            Expr::Cell(cell, fixed) => cell_name(*cell, *fixed),
+            Expr::Neg(operand) => format!("-{}", operand.operand_text(3, cell_name)),
+            Expr::Binary(function_id, lhs, rhs) if *function_id > 5 => format!(
+                "{}({},{})",
+                scalar_name(*function_id),
+                lhs.render(cell_name),
+                rhs.render(cell_name)
+            ),
+            Expr::Binary(op, lhs, rhs) => {
+                let power = self.precedence();
+                format!(
+                    "{}{}{}",
+                    lhs.operand_text(power, cell_name),
+                    ["+", "-", "*", "/"][*op as usize - 2],
+                    rhs.operand_text(power + 1, cell_name)
+                )
+            }
+            Expr::Range(function_id, start, end, fixed)
+                if is_lookup_function(*function_id) || *function_id == COUNTLABEL =>
+            {
+                format!(
+                    "{}:{}",
+                    cell_name(*start, fixed & 0b11),
+                    cell_name(*end, fixed >> 2)
+                )
+            }
+            Expr::Range(function_id, start, end, fixed) => {
+                let name = RANGE_FUNCTIONS
+                    .iter()
+                    .find(|&&(_, id)| id == *function_id)
+                    .map_or("?", |&(name, _)| name);
+                format!(
+                    "{}({}:{})",
+                    name,
+                    cell_name(*start, fixed & 0b11),
+                    cell_name(*end, fixed >> 2)
+                )
+            }
+            Expr::Call(function_id, arg) if is_position_function(*function_id) => {
+                let name = POSITION_FUNCTIONS
+                    .iter()
+                    .find(|&&(_, id)| id == *function_id)
+                    .map_or("?", |&(name, _)| name);
+                match **arg {
+                    Expr::Cell(..) => format!("{}({})", name, arg.render(cell_name)),
+                    _ => format!("{}()", name),
+                }
+            }
+            Expr::Ternary(function_id, first, second, third) => format!(
+                "{}({},{},{})",
+                scalar_name(*function_id),
+                first.render(cell_name),
+                second.render(cell_name),
+                third.render(cell_name)
+            ),
+            Expr::Call(id @ (RAND | TODAY), _) => format!("{}()", scalar_name(*id)),
+            Expr::Call(function_id, arg) => {
+                let name = match function_id {
+                    1 => "SLEEP",
+                    11 => "FACT",
+                    12 => "FIB",
+                    id => scalar_name(*id),
+                };
+                format!("{}({})", name, arg.render(cell_name))
+            }
+        }
+    }
+    /// Renders the tree as an operand, in parentheses if it binds looser than `power`.
+    fn operand_text(&self, power: u8, cell_name: &impl Fn(usize, u8) -> String) -> String {
+        let text = self.render(cell_name);
+        if self.precedence() < power {
+            format!("({})", text)
+        } else {
+            text
+        }
+    }
+    /// How tightly the node binds: 1 for `+ -`, 2 for `* /`, 3 for negation and 4 for
+    /// everything written as one unit.
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Binary(2 | 3, ..) => 1,
+            Expr::Binary(4 | 5, ..) => 2,
+            Expr::Neg(_) => 3,
+            _ => 4,
+        }
+    }
+}
+
+/// Returns the name of a scalar, random, date, lookup or label function such as `MOD`,
+/// `RAND`, `DATE`, `INDEX` or `COUNTLABEL`, or `?` for any other ID.
+fn scalar_name(function_id: u8) -> &'static str {
+    SCALAR_FUNCTIONS
+        .iter()
+        .chain(RANDOM_FUNCTIONS.iter())
+        .chain(DATE_FUNCTIONS.iter())
+        .chain(LOOKUP_FUNCTIONS.iter())
+        .chain(LABEL_FUNCTIONS.iter())
+        .find(|&&(_, id)| id == function_id)
+        .map_or("?", |&(name, _)| name)
+}
+
+/// Interned trees, with the ID of each one.
+#[derive(Default)]
+struct Interner {
+    /// Trees by ID.
+    trees: Vec<Arc<Expr>>,
+    /// ID of each tree, keyed by its `Debug` form since `f64` is not `Hash`.
+    ids: HashMap<String, i32>,
+}
+
+lazy_static! {
+    /// The table shared by every sheet.
+    static ref TREES: Mutex<Interner> = Mutex::new(Interner::default());
+}
+
+/// Returns the ID of `expr`, adding it to the table the first time it is seen.
+pub fn intern(expr: Expr) -> i32 {
+    let key = format!("{:?}", expr);
+    let mut table = TREES.lock().unwrap();
+    if let Some(&id) = table.ids.get(&key) {
+        return id;
+    }
+    let id = table.trees.len() as i32;
+    table.trees.push(Arc::new(expr));
+    table.ids.insert(key, id);
+    id
+}
+/// Returns the tree with the given ID, or `None` if no tree has it.
+pub fn lookup(id: i32) -> Option<Arc<Expr>> {
+    let table = TREES.lock().unwrap();
+    usize::try_from(id)
+        .ok()
+        .and_then(|id| table.trees.get(id).cloned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::info::{FIXED_COL, FIXED_ROW};
+
+    fn name(cell: usize, fixed: u8) -> String {
+        let sign = |flag: u8| if fixed & flag != 0 { "$" } else { "" };
+        format!("{}C{}{}", sign(FIXED_COL), sign(FIXED_ROW), cell)
+    }
+
+    fn binary(op: u8, lhs: Expr, rhs: Expr) -> Expr {
+        Expr::Binary(op, Box::new(lhs), Box::new(rhs))
+    }
+
+    #[test]
+    fn test_render_adds_only_needed_parentheses() {
+        let sum = binary(2, Expr::Cell(1, 0), Expr::Cell(2, 0));
+        let expr = binary(
+            3,
+            binary(4, sum.clone(), Expr::Number(2.0)),
+            binary(5, Expr::Range(8, 3, 7, 0b1011), Expr::Number(3.0)),
+        );
+        assert_eq!(expr.render(&name), "(C1+C2)*2-SUM($C$3:C$7)/3");
+        assert_eq!(
+            binary(3, Expr::Cell(0, 0), sum.clone()).render(&name),
+            "C0-(C1+C2)"
+        );
+        assert_eq!(
+            binary(2, sum.clone(), Expr::Cell(0, 0)).render(&name),
+            "C1+C2+C0"
+        );
+        assert_eq!(Expr::Neg(Box::new(sum)).render(&name), "-(C1+C2)");
+        assert_eq!(
+            Expr::Call(11, Box::new(Expr::Number(-3.0))).render(&name),
+            "FACT(-3)"
+        );
+        // Scalar functions are written as calls, whose arguments need no parentheses
+        let modulo = binary(
+            18,
+            binary(2, Expr::Cell(1, 0), Expr::Number(1.0)),
+            Expr::Number(2.0),
+        );
+        assert_eq!(
+            binary(
+                4,
+                modulo.clone(),
+                Expr::Call(20, Box::new(Expr::Cell(0, 0)))
+            )
+            .render(&name),
+            "MOD(C1+1,2)*ABS(C0)"
+        );
+    }
+
+    #[test]
+    fn test_ranges_and_map_references() {
+        let expr = binary(2, Expr::Cell(4, FIXED_ROW), Expr::Range(6, 0, 9, 0));
+        assert_eq!(expr.ranges(), vec![(4, 4), (0, 9)]);
+        let moved =
+            expr.map_references(&mut |cell, _| (cell != 9).then_some(cell + 1), &|_, _| true);
+        assert_eq!(
+            moved,
+            binary(2, Expr::Cell(5, FIXED_ROW), Expr::Range(6, 0, 9, 0))
+        );
+    }
+
+    #[test]
+    fn test_intern_reuses_ids() {
+        let expr = Expr::Neg(Box::new(Expr::Cell(3, 0)));
+        let id = intern(expr.clone());
+        assert_eq!(intern(expr.clone()), id);
+        assert_ne!(intern(Expr::Neg(Box::new(Expr::Cell(4, 0)))), id);
+        assert_eq!(lookup(id).as_deref(), Some(&expr));
+        assert_eq!(lookup(-1), None);
+    }
+}
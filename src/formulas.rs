@@ -2,17 +2,27 @@
 //! This module contains all the mathematical and assignment formulas
 //! used in the spreadsheet cells. Each formula operates on a `CellInfo`
 //! using references from the `Sheet` and supports invalid cell propagation.
-use crate::info::{CellInfo, Info};
-use crate::status::{StatusCode, set_status_code};
-use std::cell::RefCell;
+use crate::date;
+use crate::expr::Expr;
+use crate::info::{CellError, CellInfo, Info};
+use crate::random::Rng;
+use crate::sheet::{OverflowPolicy, Sheet};
+use crate::status::StatusCode;
+use std::cell::{Cell, RefCell};
 use std::cmp::{max as cmp_max, min as cmp_min};
+use std::collections::HashMap;
 use std::f64::consts::E;
 use std::rc::Rc;
 use std::thread;
-use std::time::Duration;
-/// Array of function pointers mapping function ID to actual formula functions.
+use std::time::{Duration, Instant};
+/// A formula: computes `cell_info`'s value from its arguments, reading the sheet.
+pub type Formula = fn(&mut CellInfo, &Sheet);
+/// Array of function pointers mapping function ID to the builtin formula functions.
 ///
-/// Index 0–10 maps as:
+/// Each `Graph` copies this table into its own dispatch table, which embedders can extend
+/// with `Spreadsheet::register_function`.
+///
+/// Index 0–23 maps as:
 /// - `0`: assignment
 /// - `1`: sleep_assignment
 /// - `2`: add
@@ -24,7 +34,21 @@ use std::time::Duration;
 /// - `8`: sum
 /// - `9`: avg
 /// - `10`: stdev
-pub static FPTR: [fn(&mut CellInfo, &Rc<RefCell<crate::sheet::Sheet>>); 11] = [
+/// - `11`: factorial
+/// - `12`: fibonacci
+/// - `13`: harmonic_mean
+/// - `14`: mode
+/// - `15`: constant
+/// - `16`: text
+/// - `17`: expression
+/// - `18`–`22`: the scalar functions `MOD`, `POW`, `ABS`, `MIN2` and `MAX2`
+/// - `23`: error_literal
+/// - `24`–`25`: the position functions `ROW` and `COLUMN`
+/// - `26`–`27`: the random functions `RAND` and `RANDBETWEEN`
+/// - `28`–`30`: the date functions `TODAY`, `DATE` and `DAYS`
+/// - `31`–`33`: the lookup functions `INDEX`, `MATCH` and `VLOOKUP`
+/// - `34`: the label function `COUNTLABEL`
+pub static FPTR: [Formula; 35] = [
     assignment,
     sleep_assignment,
     add,
@@ -36,13 +60,122 @@ pub static FPTR: [fn(&mut CellInfo, &Rc<RefCell<crate::sheet::Sheet>>); 11] = [
     sum,
     avg,
     stdev,
+    factorial,
+    fibonacci,
+    harmonic_mean,
+    mode,
+    constant,
+    text,
+    expression,
+    scalar,
+    scalar,
+    scalar,
+    scalar,
+    scalar,
+    error_literal,
+    position,
+    position,
+    random,
+    random,
+    date_formula,
+    date_formula,
+    date_formula,
+    lookup,
+    lookup,
+    lookup,
+    lookup,
+];
+/// Range function names as written in formulas, with their function IDs.
+pub const RANGE_FUNCTIONS: [(&str, u8); 7] = [
+    ("MAX", 6),
+    ("MIN", 7),
+    ("SUM", 8),
+    ("AVG", 9),
+    ("STDEV", 10),
+    ("HARMEAN", 13),
+    ("MODE", 14),
+];
+/// Scalar function names as written in formulas, with their function IDs. `ABS` takes one
+/// argument, the others two.
+pub const SCALAR_FUNCTIONS: [(&str, u8); 5] = [
+    ("MOD", 18),
+    ("POW", 19),
+    ("ABS", 20),
+    ("MIN2", 21),
+    ("MAX2", 22),
 ];
+/// Function ID of `ABS`, the only single-argument scalar function.
+pub const ABS: u8 = 20;
+/// Position function names as written in formulas, with their function IDs. Each takes a
+/// cell reference or no argument at all, meaning the cell the formula is in.
+pub const POSITION_FUNCTIONS: [(&str, u8); 2] = [("ROW", 24), ("COLUMN", 25)];
+/// Function ID of `ROW`.
+pub const ROW: u8 = 24;
+/// Function ID of `COLUMN`.
+pub const COLUMN: u8 = 25;
+/// Random function names as written in formulas, with their function IDs: `RAND()` takes
+/// no argument, `RANDBETWEEN(lo,hi)` two.
+pub const RANDOM_FUNCTIONS: [(&str, u8); 2] = [("RAND", 26), ("RANDBETWEEN", 27)];
+/// Function ID of `RAND`.
+pub const RAND: u8 = 26;
+/// Function ID of `RANDBETWEEN`.
+pub const RANDBETWEEN: u8 = 27;
+/// Date function names as written in formulas, with their function IDs: `TODAY()` takes
+/// no argument, `DAYS(end,start)` two and `DATE(year,month,day)` three.
+pub const DATE_FUNCTIONS: [(&str, u8); 3] = [("TODAY", 28), ("DATE", 29), ("DAYS", 30)];
+/// Function ID of `TODAY`.
+pub const TODAY: u8 = 28;
+/// Function ID of `DATE`.
+pub const DATE: u8 = 29;
+/// Function ID of `DAYS`.
+pub const DAYS: u8 = 30;
+/// Lookup function names as written in formulas, with their function IDs:
+/// `INDEX(range,row,col)`, `MATCH(value,range)` and `VLOOKUP(value,range,col)`.
+pub const LOOKUP_FUNCTIONS: [(&str, u8); 3] = [("INDEX", 31), ("MATCH", 32), ("VLOOKUP", 33)];
+/// Function ID of `INDEX`.
+pub const INDEX: u8 = 31;
+/// Function ID of `MATCH`.
+pub const MATCH: u8 = 32;
+/// Function ID of `VLOOKUP`.
+pub const VLOOKUP: u8 = 33;
+/// Label function names as written in formulas, with their function IDs:
+/// `COUNTLABEL(range,"pattern")`.
+pub const LABEL_FUNCTIONS: [(&str, u8); 1] = [("COUNTLABEL", 34)];
+/// Function ID of `COUNTLABEL`.
+pub const COUNTLABEL: u8 = 34;
+
+thread_local! {
+    /// The cell whose formula this thread is evaluating, set by `dispatch`, where `ROW()`
+    /// and `COLUMN()` without an argument look.
+    static EVALUATING: Cell<usize> = const { Cell::new(0) };
+    /// Random numbers the formula being evaluated has drawn so far, so that each `RAND()`
+    /// of one formula gets its own.
+    static DRAWS: Cell<u64> = const { Cell::new(0) };
+}
+/// Returns `true` if the function ID corresponds to a scalar function such as `MOD`.
+pub fn is_scalar_function(i: u8) -> bool {
+    (18..=22).contains(&i)
+}
+/// Returns `true` if the function ID corresponds to a position function such as `ROW`.
+pub fn is_position_function(i: u8) -> bool {
+    (ROW..=COLUMN).contains(&i)
+}
+/// Returns `true` if the function ID is volatile: its value changes on every
+/// recalculation even when its arguments did not, so cells calling it are recomputed
+/// whenever anything is. These are `RAND`, `RANDBETWEEN` and `TODAY`.
+pub fn is_volatile_function(i: u8) -> bool {
+    (RAND..=TODAY).contains(&i)
+}
+/// Returns `true` if the function ID is `INDEX`, `MATCH` or `VLOOKUP`.
+pub fn is_lookup_function(i: u8) -> bool {
+    (INDEX..=VLOOKUP).contains(&i)
+}
 /// Returns `true` if the function ID corresponds to a range-based function.
 ///
-/// These functions include `max`, `min`, `sum`, `avg`, and `stdev`.
+/// These functions include `max`, `min`, `sum`, `avg`, `stdev`, `harmonic_mean` and `mode`.
 // Helper functions to check function types
 pub fn is_range_function(i: u8) -> bool {
-    (6..=10).contains(&i)
+    (6..=10).contains(&i) || (13..=14).contains(&i)
 }
 /// Returns `true` if the function ID corresponds to an arithmetic function.
 ///
@@ -52,14 +185,13 @@ pub fn is_arithmetic_function(i: u8) -> bool {
 }
 /// Returns `true` if the function ID corresponds to a single-argument function.
 ///
-/// These include `assignment` and `sleep_assignment`
+/// These include `assignment`, `sleep_assignment`, `factorial`, `fibonacci` and `ABS`.
 pub fn is_single_arg_function(i: u8) -> bool {
-    (0..=1).contains(&i)
+    (0..=1).contains(&i) || (11..=12).contains(&i) || i == ABS
 }
 /// Computes the maximum value from a 2D cell range.
 // Range-based functions
-pub fn max(cell_info: &mut CellInfo, sheet_rc: &Rc<RefCell<crate::sheet::Sheet>>) {
-    let sheet = sheet_rc.borrow();
+pub fn max(cell_info: &mut CellInfo, sheet: &Sheet) {
     let (x1, y1) = sheet.get_row_and_column(cell_info.info.arg[0] as usize);
     let (x2, y2) = sheet.get_row_and_column(cell_info.info.arg[1] as usize);
 
@@ -67,8 +199,8 @@ pub fn max(cell_info: &mut CellInfo, sheet_rc: &Rc<RefCell<crate::sheet::Sheet>>
     let (x_min, x_max) = (cmp_min(x1, x2), cmp_max(x1, x2));
     let (y_min, y_max) = (cmp_min(y1, y2), cmp_max(y1, y2));
 
-    cell_info.value = i32::MIN;
-    cell_info.info.invalid = false;
+    let mut best = f64::NEG_INFINITY;
+    cell_info.info.error = None;
 
     for i in x_min..=x_max {
         for j in y_min..=y_max {
@@ -76,18 +208,18 @@ pub fn max(cell_info: &mut CellInfo, sheet_rc: &Rc<RefCell<crate::sheet::Sheet>>
             let cell_data = sheet.get(cell);
 
             // If any cell in the range is invalid, the result is invalid
-            if cell_data.info.invalid {
-                cell_info.info.invalid = true;
+            if cell_data.info.invalid() {
+                cell_info.info.error = Some(inherited(&cell_data));
                 return;
             }
 
-            cell_info.value = cmp_max(cell_info.value, cell_data.value);
+            best = best.max(cell_data.value);
         }
     }
+    cell_info.value = best;
 }
 /// Computes the minimum value from a 2D cell range.
-pub fn min(cell_info: &mut CellInfo, sheet_rc: &Rc<RefCell<crate::sheet::Sheet>>) {
-    let sheet = sheet_rc.borrow();
+pub fn min(cell_info: &mut CellInfo, sheet: &Sheet) {
     let (x1, y1) = sheet.get_row_and_column(cell_info.info.arg[0] as usize);
     let (x2, y2) = sheet.get_row_and_column(cell_info.info.arg[1] as usize);
 
@@ -95,8 +227,8 @@ pub fn min(cell_info: &mut CellInfo, sheet_rc: &Rc<RefCell<crate::sheet::Sheet>>
     let (x_min, x_max) = (cmp_min(x1, x2), cmp_max(x1, x2));
     let (y_min, y_max) = (cmp_min(y1, y2), cmp_max(y1, y2));
 
-    cell_info.value = i32::MAX;
-    cell_info.info.invalid = false;
+    let mut best = f64::INFINITY;
+    cell_info.info.error = None;
 
     for i in x_min..=x_max {
         for j in y_min..=y_max {
@@ -104,18 +236,18 @@ pub fn min(cell_info: &mut CellInfo, sheet_rc: &Rc<RefCell<crate::sheet::Sheet>>
             let cell_data = sheet.get(cell);
 
             // If any cell in the range is invalid, the result is invalid
-            if cell_data.info.invalid {
-                cell_info.info.invalid = true;
+            if cell_data.info.invalid() {
+                cell_info.info.error = Some(inherited(&cell_data));
                 return;
             }
 
-            cell_info.value = cmp_min(cell_info.value, cell_data.value);
+            best = best.min(cell_data.value);
         }
     }
+    cell_info.value = best;
 }
 /// Computes the average of values from a 2D cell range.
-pub fn avg(cell_info: &mut CellInfo, sheet_rc: &Rc<RefCell<crate::sheet::Sheet>>) {
-    let sheet = sheet_rc.borrow();
+pub fn avg(cell_info: &mut CellInfo, sheet: &Sheet) {
     let (x1, y1) = sheet.get_row_and_column(cell_info.info.arg[0] as usize);
     let (x2, y2) = sheet.get_row_and_column(cell_info.info.arg[1] as usize);
 
@@ -123,8 +255,8 @@ pub fn avg(cell_info: &mut CellInfo, sheet_rc: &Rc<RefCell<crate::sheet::Sheet>>
     let (x_min, x_max) = (cmp_min(x1, x2), cmp_max(x1, x2));
     let (y_min, y_max) = (cmp_min(y1, y2), cmp_max(y1, y2));
 
-    let mut avg_value: i64 = 0;
-    cell_info.info.invalid = false;
+    let mut avg_value = 0.0;
+    cell_info.info.error = None;
 
     for i in x_min..=x_max {
         for j in y_min..=y_max {
@@ -132,21 +264,20 @@ pub fn avg(cell_info: &mut CellInfo, sheet_rc: &Rc<RefCell<crate::sheet::Sheet>>
             let cell_data = sheet.get(cell);
 
             // If any cell in the range is invalid, the result is invalid
-            if cell_data.info.invalid {
-                cell_info.info.invalid = true;
+            if cell_data.info.invalid() {
+                cell_info.info.error = Some(inherited(&cell_data));
                 return;
             }
 
-            avg_value += cell_data.value as i64;
+            avg_value += cell_data.value;
         }
     }
 
-    let count = ((x_max - x_min + 1) * (y_max - y_min + 1)) as i64;
-    cell_info.value = (avg_value / count) as i32;
+    let count = ((x_max - x_min + 1) * (y_max - y_min + 1)) as f64;
+    set_number(cell_info, avg_value / count, sheet.overflow);
 }
 /// Computes the sum of values from a 2D cell range.
-pub fn sum(cell_info: &mut CellInfo, sheet_rc: &Rc<RefCell<crate::sheet::Sheet>>) {
-    let sheet = sheet_rc.borrow();
+pub fn sum(cell_info: &mut CellInfo, sheet: &Sheet) {
     let (x1, y1) = sheet.get_row_and_column(cell_info.info.arg[0] as usize);
     let (x2, y2) = sheet.get_row_and_column(cell_info.info.arg[1] as usize);
 
@@ -154,8 +285,8 @@ pub fn sum(cell_info: &mut CellInfo, sheet_rc: &Rc<RefCell<crate::sheet::Sheet>>
     let (x_min, x_max) = (cmp_min(x1, x2), cmp_max(x1, x2));
     let (y_min, y_max) = (cmp_min(y1, y2), cmp_max(y1, y2));
 
-    cell_info.value = 0;
-    cell_info.info.invalid = false;
+    let mut total = 0.0;
+    cell_info.info.error = None;
 
     for i in x_min..=x_max {
         for j in y_min..=y_max {
@@ -163,18 +294,18 @@ pub fn sum(cell_info: &mut CellInfo, sheet_rc: &Rc<RefCell<crate::sheet::Sheet>>
             let cell_data = sheet.get(cell);
 
             // If any cell in the range is invalid, the result is invalid
-            if cell_data.info.invalid {
-                cell_info.info.invalid = true;
+            if cell_data.info.invalid() {
+                cell_info.info.error = Some(inherited(&cell_data));
                 return;
             }
 
-            cell_info.value += cell_data.value;
+            total += cell_data.value;
         }
     }
+    set_number(cell_info, total, sheet.overflow);
 }
-/// Computes the standard deviation from a 2D cell range.
-pub fn stdev(cell_info: &mut CellInfo, sheet_rc: &Rc<RefCell<crate::sheet::Sheet>>) {
-    let sheet = sheet_rc.borrow();
+/// Computes the population standard deviation from a 2D cell range.
+pub fn stdev(cell_info: &mut CellInfo, sheet: &Sheet) {
     let (x1, y1) = sheet.get_row_and_column(cell_info.info.arg[0] as usize);
     let (x2, y2) = sheet.get_row_and_column(cell_info.info.arg[1] as usize);
 
@@ -182,9 +313,8 @@ pub fn stdev(cell_info: &mut CellInfo, sheet_rc: &Rc<RefCell<crate::sheet::Sheet
     let (x_min, x_max) = (cmp_min(x1, x2), cmp_max(x1, x2));
     let (y_min, y_max) = (cmp_min(y1, y2), cmp_max(y1, y2));
 
-    let mut sum_squares: i64 = 0;
-    let mut sum: i64 = 0;
-    cell_info.info.invalid = false;
+    let mut values = Vec::new();
+    cell_info.info.error = None;
 
     for i in x_min..=x_max {
         for j in y_min..=y_max {
@@ -192,143 +322,751 @@ pub fn stdev(cell_info: &mut CellInfo, sheet_rc: &Rc<RefCell<crate::sheet::Sheet
             let cell_data = sheet.get(cell);
 
             // If any cell in the range is invalid, the result is invalid
-            if cell_data.info.invalid {
-                cell_info.info.invalid = true;
+            if cell_data.info.invalid() {
+                cell_info.info.error = Some(inherited(&cell_data));
                 return;
             }
 
-            let val = cell_data.value as i64;
-            sum_squares += val * val;
-            sum += val;
+            values.push(cell_data.value);
         }
     }
 
-    let count = ((x_max - x_min + 1) * (y_max - y_min + 1)) as i64;
-    let mean = sum / count;
+    // Two passes: subtracting the mean first keeps large values from losing precision
+    let count = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / count;
+    let variance = values.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / count;
+    set_number(cell_info, variance.sqrt(), sheet.overflow);
+}
+
+/// Computes the harmonic mean `count / sum(1 / x)` of a 2D cell range.
+///
+/// The result is invalid if any cell is invalid or zero, or if the reciprocals cancel out;
+/// a zero is a division by zero.
+pub fn harmonic_mean(cell_info: &mut CellInfo, sheet: &Sheet) {
+    let (x1, y1) = sheet.get_row_and_column(cell_info.info.arg[0] as usize);
+    let (x2, y2) = sheet.get_row_and_column(cell_info.info.arg[1] as usize);
+
+    // Ensure the ranges are in the correct order (smaller to larger)
+    let (x_min, x_max) = (cmp_min(x1, x2), cmp_max(x1, x2));
+    let (y_min, y_max) = (cmp_min(y1, y2), cmp_max(y1, y2));
+
+    let mut reciprocals = 0.0;
+    cell_info.info.error = None;
+
+    for i in x_min..=x_max {
+        for j in y_min..=y_max {
+            let cell_data = sheet.get(sheet.get_cell(i, j));
+
+            // An invalid cell or a zero (no reciprocal) makes the result invalid
+            if cell_data.info.invalid() {
+                cell_info.info.error = Some(inherited(&cell_data));
+                return;
+            }
+            if cell_data.value == 0.0 {
+                cell_info.info.error = Some(CellError::DivByZero);
+                return;
+            }
 
-    // Fixed variance calculation to match C implementation
-    let variance = (sum_squares - 2 * mean * sum + mean * mean * count) as f64 / count as f64;
+            reciprocals += 1.0 / cell_data.value;
+        }
+    }
 
-    // Use round() to match C implementation
-    cell_info.value = variance.sqrt().round() as i32;
+    let count = ((x_max - x_min + 1) * (y_max - y_min + 1)) as f64;
+    set_number(cell_info, count / reciprocals, sheet.overflow);
+}
+/// Computes the most frequent value of a 2D cell range, the smallest one on a tie.
+///
+/// Marks the cell invalid if any cell of the range is invalid.
+pub fn mode(cell_info: &mut CellInfo, sheet: &Sheet) {
+    let (x1, y1) = sheet.get_row_and_column(cell_info.info.arg[0] as usize);
+    let (x2, y2) = sheet.get_row_and_column(cell_info.info.arg[1] as usize);
+
+    // Ensure the ranges are in the correct order (smaller to larger)
+    let (x_min, x_max) = (cmp_min(x1, x2), cmp_max(x1, x2));
+    let (y_min, y_max) = (cmp_min(y1, y2), cmp_max(y1, y2));
+
+    // Keyed by bit pattern, with -0 folded into 0, since floats are not `Hash`
+    let mut counts: HashMap<u64, (f64, usize)> = HashMap::new();
+    cell_info.info.error = None;
+
+    for i in x_min..=x_max {
+        for j in y_min..=y_max {
+            let cell_data = sheet.get(sheet.get_cell(i, j));
+
+            // If any cell in the range is invalid, mark the result as invalid
+            if cell_data.info.invalid() {
+                cell_info.info.error = Some(inherited(&cell_data));
+                return;
+            }
+
+            let value = cell_data.value + 0.0;
+            counts.entry(value.to_bits()).or_insert((value, 0)).1 += 1;
+        }
+    }
+
+    // Highest count first, then the smallest value
+    if let Some(&(value, _)) = counts
+        .values()
+        .max_by(|a, b| a.1.cmp(&b.1).then(b.0.total_cmp(&a.0)))
+    {
+        cell_info.value = value;
+    }
+}
+/// Runs the range function `function_id` over `start:end` in a scratch cell, so no
+/// formula is written and nothing in the graph depends on the range, e.g. for the
+/// aggregates of a selection. Returns the value, or the error the formula would have.
+pub fn range_value(
+    function_id: u8,
+    start: usize,
+    end: usize,
+    sheet: &Sheet,
+) -> Result<f64, CellError> {
+    let mut cell_info = CellInfo::default();
+    cell_info.info.function_id = function_id;
+    cell_info.info.arg = [start as i32, end as i32];
+    cell_info.info.arg_mask = 0b11;
+    FPTR[function_id as usize](&mut cell_info, sheet);
+    match cell_info.info.error {
+        Some(error) => Err(error),
+        None => Ok(cell_info.value),
+    }
 }
 
 /// Assigns a value or cell reference into a cell.
-pub fn assignment(cell_info: &mut CellInfo, sheet_rc: &Rc<RefCell<crate::sheet::Sheet>>) {
+pub fn assignment(cell_info: &mut CellInfo, sheet: &Sheet) {
     let is_cell_arg = cell_info.info.arg_mask & 0b1 != 0;
 
     if is_cell_arg {
-        let sheet = sheet_rc.borrow();
         let arg_cell = sheet.get(cell_info.info.arg[0] as usize);
         cell_info.value = arg_cell.value;
-        cell_info.info.invalid = arg_cell.info.invalid;
+        cell_info.info.error = arg_cell.info.error.map(|_| inherited(&arg_cell));
+    } else {
+        cell_info.value = cell_info.info.arg[0] as f64;
+        cell_info.info.error = None;
+    }
+}
+/// Evaluates a constant built by `Info::number`, a literal that is not a whole `i32`.
+pub fn constant(cell_info: &mut CellInfo, _sheet: &Sheet) {
+    cell_info.value = cell_info.info.literal_value().unwrap_or(f64::NAN);
+    cell_info.info.error = None;
+}
+/// Evaluates a text cell built by `Info::text`. Text is not a number, so the cell is
+/// marked invalid and formulas reading it fail.
+pub fn text(cell_info: &mut CellInfo, _sheet: &Sheet) {
+    cell_info.value = 0.0;
+    cell_info.info.error = Some(CellError::Value);
+}
+/// Evaluates an error literal built by `Info::error`, which keeps its kind.
+pub fn error_literal(cell_info: &mut CellInfo, _sheet: &Sheet) {
+    cell_info.value = 0.0;
+    cell_info.info.error = Some(cell_info.info.error_value().unwrap_or(CellError::Value));
+}
+/// Evaluates `ROW(A5)`/`COLUMN(A5)` as the 1-based row or column of the referenced cell,
+/// or without an argument, of the cell being evaluated.
+pub fn position(cell_info: &mut CellInfo, sheet: &Sheet) {
+    let cell = if cell_info.info.is_cell_arg1() {
+        cell_info.info.arg[0] as usize
     } else {
-        cell_info.value = cell_info.info.arg[0];
-        cell_info.info.invalid = false;
+        EVALUATING.get()
+    };
+    cell_info.value = position_value(cell_info.info.function_id, cell, sheet);
+    cell_info.info.error = None;
+}
+/// Evaluates `RAND()` as a number in `[0, 1)` and `RANDBETWEEN(A1,10)` as a whole number
+/// between its arguments, inclusive. See `random_value`.
+pub fn random(cell_info: &mut CellInfo, sheet: &Sheet) {
+    let (lo, hi, error) = get_args(&cell_info.info, sheet);
+    let result = match error {
+        Some(error) => Err(error),
+        None => random_value(cell_info.info.function_id, lo, hi, sheet),
+    };
+    cell_info.info.error = result.err();
+    cell_info.value = result.unwrap_or(0.0);
+}
+/// Draws the next random number of the formula being evaluated: in `[0, 1)` for `RAND`,
+/// or a whole number in `[ceil(lo), floor(hi)]` for `RANDBETWEEN`.
+///
+/// Numbers come from the sheet's `volatile_seed`, drawn from its generator once per
+/// recalculation, mixed with the cell and how many numbers its formula drew before. They
+/// therefore do not depend on the order or the threads cells are evaluated in, and the
+/// same edits after `seed <n>` give the same numbers. Returns `Value` for an empty
+/// `RANDBETWEEN` interval.
+fn random_value(function_id: u8, lo: f64, hi: f64, sheet: &Sheet) -> Result<f64, CellError> {
+    let draw = DRAWS.get();
+    DRAWS.set(draw + 1);
+    let state = sheet.volatile_seed ^ ((EVALUATING.get() as u64) << 16 | draw);
+    let bits = Rng::new(state).next_u64();
+    // The top 53 bits, as many as an f64 holds exactly
+    let unit = (bits >> 11) as f64 / (1u64 << 53) as f64;
+    if function_id == RAND {
+        return Ok(unit);
+    }
+    let (lo, hi) = (lo.ceil(), hi.floor());
+    if lo > hi || !lo.is_finite() || !hi.is_finite() {
+        return Err(CellError::Value);
+    }
+    Ok((lo + (unit * (hi - lo + 1.0)).floor()).min(hi))
+}
+/// Evaluates `TODAY()` and `DAYS(B1,A1)`. `DATE` takes three arguments, which only an
+/// expression cell can hold, so it is evaluated by `evaluate`.
+pub fn date_formula(cell_info: &mut CellInfo, sheet: &Sheet) {
+    let (end, start, error) = get_args(&cell_info.info, sheet);
+    let result = match (error, cell_info.info.function_id) {
+        (Some(error), _) => Err(error),
+        (None, TODAY) => Ok(date::today() as f64),
+        (None, DAYS) => Ok(end - start),
+        _ => Err(CellError::Value),
+    };
+    cell_info.info.error = result.err();
+    cell_info.value = result.unwrap_or(0.0);
+}
+/// Stands in for the lookup functions and `COUNTLABEL`, which take a range and further
+/// arguments that only an expression cell can hold, so `evaluate` computes them. On its
+/// own it is a `Value` error.
+pub fn lookup(cell_info: &mut CellInfo, _sheet: &Sheet) {
+    cell_info.info.error = Some(CellError::Value);
+    cell_info.value = 0.0;
+}
+/// Evaluates `INDEX(table,row,col)`, `MATCH(value,table)` or `VLOOKUP(value,table,col)`
+/// from its arguments in order, the table being a range node.
+///
+/// Rows, columns and the position `MATCH` returns count from 1 within the table. `MATCH`
+/// and `VLOOKUP` look for the first cell equal to `value`, skipping blank and invalid
+/// cells, down the first column for `VLOOKUP`; `MATCH` needs a single row or column.
+/// A row or column outside the table is a `BadRef` error and a value not found is
+/// `NotFound`. Reading an invalid cell passes its error on, as a reference would.
+fn lookup_value(function_id: u8, args: &[&Expr], sheet: &Sheet) -> Result<f64, EvalError> {
+    let table = if function_id == INDEX { 0 } else { 1 };
+    let Expr::Range(_, start, end, _) = *args[table] else {
+        return Err(EvalError::Invalid(CellError::Value));
+    };
+    let mut numbers = Vec::with_capacity(2);
+    for (i, arg) in args.iter().enumerate() {
+        if i != table {
+            numbers.push(evaluate(arg, sheet)?);
+        }
+    }
+    let (top, left) = sheet.get_row_and_column(start);
+    let (bottom, right) = sheet.get_row_and_column(end);
+    let read = |row: usize, col: usize| {
+        let data = sheet.get(sheet.get_cell(row, col));
+        match data.info.invalid() {
+            true => Err(EvalError::Invalid(inherited(&data))),
+            false => Ok(data.value),
+        }
+    };
+    // The 0-based offset of a 1-based row or column of a table `len` long
+    let offset = |n: f64, len: usize| match whole_number(n) {
+        Some(n) if n >= 1 && n as usize <= len => Ok(n as usize - 1),
+        Some(_) => Err(EvalError::Invalid(CellError::BadRef)),
+        None => Err(EvalError::Invalid(CellError::Value)),
+    };
+    let find = |value: f64, cells: Vec<(usize, usize)>| {
+        cells
+            .into_iter()
+            .position(|(row, col)| {
+                let data = sheet.get(sheet.get_cell(row, col));
+                !data.is_blank() && !data.info.invalid() && data.value == value
+            })
+            .ok_or(EvalError::Invalid(CellError::NotFound))
+    };
+    match function_id {
+        INDEX => {
+            let row = offset(numbers[0], bottom - top + 1)?;
+            let col = offset(numbers[1], right - left + 1)?;
+            read(top + row, left + col)
+        }
+        MATCH => {
+            let cells = if top == bottom {
+                (left..=right).map(|col| (top, col)).collect()
+            } else if left == right {
+                (top..=bottom).map(|row| (row, left)).collect()
+            } else {
+                return Err(EvalError::Invalid(CellError::Value));
+            };
+            Ok(find(numbers[0], cells)? as f64 + 1.0)
+        }
+        _ => {
+            let col = offset(numbers[1], right - left + 1)?;
+            let row = find(numbers[0], (top..=bottom).map(|row| (row, left)).collect())?;
+            read(top + row, left + col)
+        }
+    }
+}
+/// Evaluates `COUNTLABEL(table,"pattern")`: the number of text cells of the table whose
+/// text matches the glob `pattern`. Numbers, blanks and errors are no labels and are
+/// skipped.
+fn count_label_value(table: &Expr, pattern: &Expr, sheet: &Sheet) -> Result<f64, EvalError> {
+    let (Expr::Range(_, start, end, _), Expr::Text(pattern)) = (table, pattern) else {
+        return Err(EvalError::Invalid(CellError::Value));
+    };
+    let (top, left) = sheet.get_row_and_column(*start);
+    let (bottom, right) = sheet.get_row_and_column(*end);
+    let labels: Vec<_> = (top..=bottom)
+        .flat_map(|row| (left..=right).map(move |col| (row, col)))
+        .filter_map(|(row, col)| sheet.get(sheet.get_cell(row, col)).text())
+        .collect();
+    Ok(count_labels(labels.iter().map(|label| &**label), pattern) as f64)
+}
+/// Returns the 1-based row (for `ROW`) or column (for `COLUMN`) of `cell`.
+fn position_value(function_id: u8, cell: usize, sheet: &Sheet) -> f64 {
+    let (row, col) = sheet.get_row_and_column(cell);
+    (if function_id == ROW { row } else { col } + 1) as f64
+}
+/// Returns the error of a cell reading the invalid cell `data`: `Value` if it is text,
+/// which is no number, otherwise `Propagated`.
+pub fn inherited(data: &CellInfo) -> CellError {
+    if data.info.function_id == 16 {
+        CellError::Value
+    } else {
+        CellError::Propagated
+    }
+}
+/// Evaluates a nested formula built by `Info::expression`.
+///
+/// The cell is invalid if any cell the tree reads is invalid, on a division by zero, or
+/// if any step of the computation is not a number. A step that overflows is handled by
+/// the sheet's `OverflowPolicy` (see `EvalError::Overflow`).
+pub fn expression(cell_info: &mut CellInfo, sheet: &Sheet) {
+    let result = cell_info
+        .info
+        .expression_value()
+        .ok_or(EvalError::Invalid(CellError::Value))
+        .and_then(|expr| evaluate(&expr, sheet));
+    cell_info.info.error = None;
+    match result {
+        Ok(value) | Err(EvalError::Overflow(value)) => set_number(cell_info, value, sheet.overflow),
+        Err(EvalError::Invalid(kind)) => cell_info.info.error = Some(kind),
+    }
+}
+/// Why an expression tree has no value.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum EvalError {
+    /// A cell it reads is invalid, or a step is undefined (division by zero, `MOD` by
+    /// zero, `FACT` of a negative number, ...), as the error the result takes.
+    Invalid(CellError),
+    /// A step was too large to represent: the infinity it produced. Only returned under
+    /// `OverflowPolicy::Error`; `Saturate` clamps the step instead.
+    Overflow(f64),
+}
+/// Returns the value of an expression tree, or why it has none.
+///
+/// Evaluation stops at the first step that overflows, so a later step cannot turn the
+/// overflow into an unrelated error (e.g. infinity times zero). Range functions and
+/// `FACT`/`FIB` run their builtin formula on a scratch cell, so they behave exactly as
+/// they do on their own.
+pub fn evaluate(expr: &Expr, sheet: &Sheet) -> Result<f64, EvalError> {
+    let value = match expr {
+        Expr::Number(value) => *value,
+        // Text is only the pattern of `COUNTLABEL`, never a number
+        Expr::Text(_) => return Err(EvalError::Invalid(CellError::Value)),
+        Expr::Cell(cell, _) => {
+            let data = sheet.get(*cell);
+            if data.info.invalid() {
+                return Err(EvalError::Invalid(inherited(&data)));
+            }
+            data.value
+        }
+        Expr::Neg(operand) => -evaluate(operand, sheet)?,
+        Expr::Binary(MATCH, value, table) => lookup_value(MATCH, &[value, table], sheet)?,
+        Expr::Binary(COUNTLABEL, table, pattern) => count_label_value(table, pattern, sheet)?,
+        Expr::Ternary(function_id @ (INDEX | VLOOKUP), first, second, third) => {
+            lookup_value(*function_id, &[first, second, third], sheet)?
+        }
+        Expr::Binary(function_id, lhs, rhs) => {
+            let (a, b) = (evaluate(lhs, sheet)?, evaluate(rhs, sheet)?);
+            match function_id {
+                2 => a + b,
+                3 => a - b,
+                4 => a * b,
+                5 if b == 0.0 => return Err(EvalError::Invalid(CellError::DivByZero)),
+                5 => a / b,
+                &DAYS => a - b,
+                &RANDBETWEEN => {
+                    random_value(RANDBETWEEN, a, b, sheet).map_err(EvalError::Invalid)?
+                }
+                id => scalar_value(*id, a, b).map_err(EvalError::Invalid)?,
+            }
+        }
+        Expr::Range(function_id, start, end, _) => {
+            apply_builtin(*function_id, [*start as i32, *end as i32], 0b11, sheet)?
+        }
+        Expr::Call(1, arg) => {
+            let seconds = evaluate(arg, sheet)?;
+            sleep_for(seconds);
+            seconds
+        }
+        Expr::Call(function_id @ (ROW | COLUMN), arg) => {
+            let cell = match **arg {
+                Expr::Cell(cell, _) => cell,
+                _ => EVALUATING.get(),
+            };
+            position_value(*function_id, cell, sheet)
+        }
+        Expr::Call(RAND, _) => random_value(RAND, 0.0, 0.0, sheet).map_err(EvalError::Invalid)?,
+        Expr::Call(TODAY, _) => date::today() as f64,
+        Expr::Ternary(_, year, month, day) => {
+            let (year, month, day) = (
+                evaluate(year, sheet)?,
+                evaluate(month, sheet)?,
+                evaluate(day, sheet)?,
+            );
+            date::from_parts(year, month, day).ok_or(EvalError::Invalid(CellError::Value))? as f64
+        }
+        Expr::Call(ABS, arg) => {
+            scalar_value(ABS, evaluate(arg, sheet)?, 0.0).map_err(EvalError::Invalid)?
+        }
+        Expr::Call(function_id, arg) => {
+            let n =
+                whole_number(evaluate(arg, sheet)?).ok_or(EvalError::Invalid(CellError::Value))?;
+            apply_builtin(*function_id, [n, 0], 0, sheet)?
+        }
+    };
+    match saturate(value, sheet.overflow) {
+        value if value.is_nan() => Err(EvalError::Invalid(CellError::Value)),
+        value if value.is_infinite() => Err(EvalError::Overflow(value)),
+        value => Ok(value),
+    }
+}
+/// Runs the builtin formula `function_id` on a scratch cell with the given arguments,
+/// returning its value, or why it has none (a `Value` error if there is no such builtin).
+fn apply_builtin(
+    function_id: u8,
+    arg: [i32; 2],
+    arg_mask: u8,
+    sheet: &Sheet,
+) -> Result<f64, EvalError> {
+    let formula = FPTR
+        .get(function_id as usize)
+        .ok_or(EvalError::Invalid(CellError::Value))?;
+    let mut cell = CellInfo {
+        info: Info {
+            function_id,
+            arg_mask,
+            arg,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    formula(&mut cell, sheet);
+    match cell {
+        cell if cell.overflowed() => Err(EvalError::Overflow(cell.value)),
+        CellInfo {
+            info: Info {
+                error: Some(kind), ..
+            },
+            ..
+        } => Err(EvalError::Invalid(kind)),
+        cell => Ok(cell.value),
+    }
+}
+/// Sleeps for `seconds`, if it is positive (matching C implementation).
+fn sleep_for(seconds: f64) {
+    if seconds > 0.0 {
+        if let Ok(duration) = Duration::try_from_secs_f64(seconds) {
+            thread::sleep(duration);
+        }
     }
 }
 /// Assigns a value and sleeps for that duration (in seconds) if valid and positive.
-pub fn sleep_assignment(cell_info: &mut CellInfo, sheet_rc: &Rc<RefCell<crate::sheet::Sheet>>) {
-    assignment(cell_info, sheet_rc);
+pub fn sleep_assignment(cell_info: &mut CellInfo, sheet: &Sheet) {
+    assignment(cell_info, sheet);
 
-    // Only sleep if the value is valid and positive (matching C implementation)
-    if !cell_info.info.invalid && cell_info.value > 0 {
-        thread::sleep(Duration::from_secs(cell_info.value as u64));
+    if !cell_info.info.invalid() {
+        sleep_for(cell_info.value);
     }
 }
 
-/// Retrieves argument values and their validity based on mask.
-fn get_args(info: &Info, sheet: &crate::sheet::Sheet) -> (i32, i32, bool) {
+/// Retrieves argument values based on mask, and the error inherited from an invalid
+/// cell argument, if any.
+fn get_args(info: &Info, sheet: &Sheet) -> (f64, f64, Option<CellError>) {
     let val1 = if info.arg_mask & 0b1 != 0 {
         sheet.get(info.arg[0] as usize).value
     } else {
-        info.arg[0]
+        info.arg[0] as f64
     };
 
     let val2 = if info.arg_mask & 0b10 != 0 {
         sheet.get(info.arg[1] as usize).value
     } else {
-        info.arg[1]
+        info.arg[1] as f64
     };
 
-    let invalid = (info.arg_mask & 0b1 != 0 && sheet.get(info.arg[0] as usize).info.invalid)
-        || (info.arg_mask & 0b10 != 0 && sheet.get(info.arg[1] as usize).info.invalid);
+    let error = (0..2)
+        .filter(|&i| info.arg_mask & (1 << i) != 0)
+        .map(|i| sheet.get(info.arg[i] as usize))
+        .find(|data| data.info.invalid())
+        .map(|data| inherited(&data));
 
-    (val1, val2, invalid)
+    (val1, val2, error)
+}
+/// Stores a computed result, marking the cell a `Value` error if it is NaN.
+///
+/// An infinite result is an overflow: under `OverflowPolicy::Saturate` it is clamped to
+/// the largest value of its sign, otherwise the cell is an `Overflow` error and keeps the
+/// infinity as its value.
+fn set_number(cell_info: &mut CellInfo, value: f64, policy: OverflowPolicy) {
+    let value = saturate(value, policy);
+    if value.is_nan() {
+        cell_info.info.error = Some(CellError::Value);
+    } else {
+        cell_info.value = value;
+        if value.is_infinite() {
+            cell_info.info.error = Some(CellError::Overflow);
+        }
+    }
+}
+/// Clamps an infinite `value` to the largest finite value of its sign under
+/// `OverflowPolicy::Saturate`; returns any other value unchanged.
+fn saturate(value: f64, policy: OverflowPolicy) -> f64 {
+    match policy {
+        OverflowPolicy::Saturate if value.is_infinite() => value.clamp(f64::MIN, f64::MAX),
+        _ => value,
+    }
+}
+/// Stores the result of an integer function (`FACT`, `FIB`), `None` if it does not fit
+/// in an `i32`, which is an overflow handled like any other (see `set_number`).
+fn set_integer(cell_info: &mut CellInfo, value: Option<i32>, policy: OverflowPolicy) {
+    match (value, policy) {
+        (Some(value), _) => cell_info.value = value as f64,
+        (None, OverflowPolicy::Saturate) => cell_info.value = i32::MAX as f64,
+        (None, OverflowPolicy::Error) => {
+            cell_info.value = f64::INFINITY;
+            cell_info.info.error = Some(CellError::Overflow);
+        }
+    }
+}
+/// Returns the argument of an integer function (`FACT`, `FIB`) if it is a whole number
+/// that fits in an `i32`.
+fn whole_number(value: f64) -> Option<i32> {
+    (value.fract() == 0.0 && value >= i32::MIN as f64 && value <= i32::MAX as f64)
+        .then_some(value as i32)
 }
 /// Adds two arguments if both are valid.
-pub fn add(cell_info: &mut CellInfo, sheet_rc: &Rc<RefCell<crate::sheet::Sheet>>) {
-    let sheet = sheet_rc.borrow();
-    let (v1, v2, invalid) = get_args(&cell_info.info, &sheet);
+pub fn add(cell_info: &mut CellInfo, sheet: &Sheet) {
+    let (v1, v2, error) = get_args(&cell_info.info, sheet);
 
-    // Set invalid flag first
-    cell_info.info.invalid = invalid;
+    // Set the error first
+    cell_info.info.error = error;
 
     // Only perform operation if not invalid
-    if !invalid {
-        cell_info.value = v1 + v2;
+    if error.is_none() {
+        set_number(cell_info, v1 + v2, sheet.overflow);
     }
 }
 /// Subtracts two arguments if both are valid.
-pub fn sub(cell_info: &mut CellInfo, sheet_rc: &Rc<RefCell<crate::sheet::Sheet>>) {
-    let sheet = sheet_rc.borrow();
-    let (v1, v2, invalid) = get_args(&cell_info.info, &sheet);
+pub fn sub(cell_info: &mut CellInfo, sheet: &Sheet) {
+    let (v1, v2, error) = get_args(&cell_info.info, sheet);
 
-    // Set invalid flag first
-    cell_info.info.invalid = invalid;
+    // Set the error first
+    cell_info.info.error = error;
 
     // Only perform operation if not invalid
-    if !invalid {
-        cell_info.value = v1 - v2;
+    if error.is_none() {
+        set_number(cell_info, v1 - v2, sheet.overflow);
     }
 }
 /// Multiplies two arguments if both are valid.
-pub fn mul(cell_info: &mut CellInfo, sheet_rc: &Rc<RefCell<crate::sheet::Sheet>>) {
-    let sheet = sheet_rc.borrow();
-    let (v1, v2, invalid) = get_args(&cell_info.info, &sheet);
+pub fn mul(cell_info: &mut CellInfo, sheet: &Sheet) {
+    let (v1, v2, error) = get_args(&cell_info.info, sheet);
 
-    // Set invalid flag first
-    cell_info.info.invalid = invalid;
+    // Set the error first
+    cell_info.info.error = error;
 
     // Only perform operation if not invalid
-    if !invalid {
-        cell_info.value = v1 * v2;
+    if error.is_none() {
+        set_number(cell_info, v1 * v2, sheet.overflow);
     }
 }
 /// Divides two arguments if both are valid and denominator is non-zero.
-pub fn divide(cell_info: &mut CellInfo, sheet_rc: &Rc<RefCell<crate::sheet::Sheet>>) {
-    let sheet = sheet_rc.borrow();
-    let (v1, v2, invalid) = get_args(&cell_info.info, &sheet);
+pub fn divide(cell_info: &mut CellInfo, sheet: &Sheet) {
+    let (v1, v2, error) = get_args(&cell_info.info, sheet);
 
-    // Check for division by zero and set invalid flag
-    let div_by_zero = v2 == 0;
-    cell_info.info.invalid = invalid || div_by_zero;
+    // An invalid argument wins over a division by zero
+    cell_info.info.error = error.or((v2 == 0.0).then_some(CellError::DivByZero));
 
     // Only perform division if not invalid and not dividing by zero
-    if !cell_info.info.invalid {
-        cell_info.value = v1 / v2;
-    } else if div_by_zero {
-        // When divided by zero, set status code
-        // set_status_code(StatusCode::InvalidValue);
+    if !cell_info.info.invalid() {
+        set_number(cell_info, v1 / v2, sheet.overflow);
+    }
+}
+/// Computes the scalar function `function_id` of `a` and `b` (`b` is ignored by `ABS`).
+///
+/// Returns `DivByZero` for `MOD` by zero and `POW` of zero to a negative power, and
+/// `Value` for an unknown ID or a result that is not a number, e.g. `POW(-8, 0.5)`. A
+/// result too large to represent, e.g. `POW(10, 400)`, is infinite: an overflow for the
+/// caller to handle. `MOD` takes the sign of the divisor, so `MOD(-3, 2)` is `1`.
+pub fn scalar_value(function_id: u8, a: f64, b: f64) -> Result<f64, CellError> {
+    let value = match function_id {
+        18 if b == 0.0 => return Err(CellError::DivByZero),
+        18 => a - b * (a / b).floor(),
+        19 if a == 0.0 && b < 0.0 => return Err(CellError::DivByZero),
+        19 => a.powf(b),
+        ABS => a.abs(),
+        21 => a.min(b),
+        22 => a.max(b),
+        _ => return Err(CellError::Value),
+    };
+    if value.is_nan() {
+        Err(CellError::Value)
+    } else {
+        Ok(value)
+    }
+}
+/// Evaluates a scalar function cell (`MOD(A1,3)`, `ABS(B2)`, ...), marking it invalid if
+/// an argument is invalid or the result is an error (see `scalar_value`).
+pub fn scalar(cell_info: &mut CellInfo, sheet: &Sheet) {
+    let (v1, v2, error) = get_args(&cell_info.info, sheet);
+    let result = match error {
+        Some(error) => Err(error),
+        None => scalar_value(cell_info.info.function_id, v1, v2),
+    };
+    cell_info.info.error = result.err();
+    if let Ok(value) = result {
+        set_number(cell_info, value, sheet.overflow);
+    }
+}
+/// Computes `n!` of the argument (`FACT(A1)`).
+///
+/// Marks the cell invalid if the argument is invalid, negative or not a whole number. A
+/// factorial that does not fit in an `i32` overflows (see `set_integer`).
+pub fn factorial(cell_info: &mut CellInfo, sheet: &Sheet) {
+    let (n, _, error) = get_args(&cell_info.info, sheet);
+    cell_info.info.error = error;
+    match whole_number(n) {
+        Some(n) if error.is_none() && n >= 0 => {
+            let result = (1..=n).try_fold(1i32, |acc, k| acc.checked_mul(k));
+            set_integer(cell_info, result, sheet.overflow);
+        }
+        _ if error.is_some() => {}
+        _ => cell_info.info.error = Some(CellError::Value),
+    }
+}
+/// Computes the `n`th Fibonacci number of the argument (`FIB(A1)`), with `FIB(0) = 0`.
+///
+/// Iterative, so large arguments cost no stack. Marks the cell invalid if the argument
+/// is invalid, negative or not a whole number. A result that does not fit in an `i32`
+/// overflows (see `set_integer`).
+pub fn fibonacci(cell_info: &mut CellInfo, sheet: &Sheet) {
+    let (n, _, error) = get_args(&cell_info.info, sheet);
+    cell_info.info.error = error;
+    match whole_number(n) {
+        // Stops at the first step past i32, long before an i64 could overflow
+        Some(n) if error.is_none() && n >= 0 => {
+            let result = (0..n)
+                .try_fold((0i64, 1i64), |(a, b), _| {
+                    (a <= i32::MAX as i64).then_some((b, a + b))
+                })
+                .and_then(|(value, _)| i32::try_from(value).ok());
+            set_integer(cell_info, result, sheet.overflow);
+        }
+        _ if error.is_some() => {}
+        _ => cell_info.info.error = Some(CellError::Value),
     }
 }
 
-/// Dispatches the appropriate formula based on `function_id`, unless in literal mode.
-pub fn apply_function(cell_info: &mut CellInfo, sheet_rc: &Rc<RefCell<crate::sheet::Sheet>>) {
+/// Dispatches the appropriate builtin formula based on `function_id`, unless in literal mode.
+/// `cell_info` holds the contents of `cell`, which `ROW()` and `COLUMN()` report.
+///
+/// The sheet is borrowed once, immutably, for the whole evaluation and the result is
+/// written into `cell_info` only, so formulas can never hold a borrow that conflicts with
+/// the caller writing the result back. Callers must not hold a mutable borrow of the sheet.
+pub fn apply_function(cell_info: &mut CellInfo, cell: usize, sheet_rc: &Rc<RefCell<Sheet>>) {
+    let formula = FPTR.get(cell_info.info.function_id as usize).copied();
+    dispatch(cell_info, cell, &sheet_rc.borrow(), formula);
+}
+/// Like `apply_function`, but looks the formula up in a graph's table of builtin and
+/// registered functions. Cells whose ID has no formula are left unchanged.
+///
+/// Returns the time the formula took, or `None` if none ran.
+pub fn apply_registered(
+    cell_info: &mut CellInfo,
+    cell: usize,
+    sheet_rc: &Rc<RefCell<Sheet>>,
+    functions: &[Option<Formula>],
+) -> Option<Duration> {
+    apply_in_sheet(cell_info, cell, &sheet_rc.borrow(), functions)
+}
+/// Like `apply_registered`, for a caller that already holds the sheet, e.g. several
+/// threads sharing one borrow while they evaluate cells that do not read each other.
+pub fn apply_in_sheet(
+    cell_info: &mut CellInfo,
+    cell: usize,
+    sheet: &Sheet,
+    functions: &[Option<Formula>],
+) -> Option<Duration> {
+    let formula = functions
+        .get(cell_info.info.function_id as usize)
+        .copied()
+        .flatten();
+    dispatch(cell_info, cell, sheet, formula)
+}
+/// Runs `formula` on `cell_info`, the contents of `cell`, unless it is in literal mode or
+/// there is no formula, and returns the time it took.
+fn dispatch(
+    cell_info: &mut CellInfo,
+    cell: usize,
+    sheet: &Sheet,
+    formula: Option<Formula>,
+) -> Option<Duration> {
     if cell_info.literal_mode {
-        return; // Skip computation if in literal mode
+        return None; // Skip computation if in literal mode
     }
-    let func_idx = cell_info.info.function_id as usize;
-    if func_idx < FPTR.len() {
-        FPTR[func_idx](cell_info, sheet_rc);
+    let formula = formula?;
+    let start = Instant::now();
+    EVALUATING.set(cell);
+    DRAWS.set(0);
+    // A stale infinity would make a later error look like an overflow
+    cell_info.value = 0.0;
+    formula(cell_info, sheet);
+    Some(start.elapsed())
+}
+
+/// Returns `true` if `text` matches the glob `pattern`.
+///
+/// `*` matches any run of characters (including none) and `?` matches exactly one;
+/// everything else must match literally, e.g. `North*` matches `North` and `Northeast`.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` seen and the text position it was tried at
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, start)) = backtrack {
+            // Let the last `*` swallow one more character and retry
+            p = star + 1;
+            t = start + 1;
+            backtrack = Some((star, start + 1));
+        } else {
+            return false;
+        }
     }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+/// Counts the labels matching the glob `pattern`, the core of `COUNTLABEL`.
+///
+/// Labels are non-numeric, so the result is a plain count.
+pub fn count_labels<'a>(labels: impl IntoIterator<Item = &'a str>, pattern: &str) -> i32 {
+    labels
+        .into_iter()
+        .filter(|label| glob_match(pattern, label))
+        .count() as i32
 }
 
 #[cfg(test)]
 mod tests {
     // Bring in everything from the parent module.
     use super::*;
-    use crate::info::{CellInfo, Info};
+    use crate::expr::Expr;
+    use crate::info::{CellError, CellInfo, Info};
     use crate::sheet::Sheet;
     use std::cell::RefCell;
     use std::rc::Rc;
@@ -344,14 +1082,14 @@ mod tests {
             let sheet = sheet_rc.borrow();
             let start = cell.info.arg[0] as usize;
             let end = cell.info.arg[1] as usize;
-            let mut max_val = i32::MIN;
+            let mut max_val = f64::NEG_INFINITY;
             let mut invalid_found = false;
             for idx in start..=end {
                 if idx >= sheet.data.len() {
                     invalid_found = true;
                     break;
                 }
-                if sheet.data[idx].info.invalid {
+                if sheet.data[idx].info.invalid() {
                     invalid_found = true;
                     break;
                 }
@@ -360,10 +1098,10 @@ mod tests {
                 }
             }
             if invalid_found {
-                cell.info.invalid = true;
+                cell.info.error = Some(CellError::Value);
             } else {
                 cell.value = max_val;
-                cell.info.invalid = false;
+                cell.info.error = None;
             }
         }
 
@@ -371,14 +1109,14 @@ mod tests {
             let sheet = sheet_rc.borrow();
             let start = cell.info.arg[0] as usize;
             let end = cell.info.arg[1] as usize;
-            let mut min_val = i32::MAX;
+            let mut min_val = f64::INFINITY;
             let mut invalid_found = false;
             for idx in start..=end {
                 if idx >= sheet.data.len() {
                     invalid_found = true;
                     break;
                 }
-                if sheet.data[idx].info.invalid {
+                if sheet.data[idx].info.invalid() {
                     invalid_found = true;
                     break;
                 }
@@ -387,10 +1125,10 @@ mod tests {
                 }
             }
             if invalid_found {
-                cell.info.invalid = true;
+                cell.info.error = Some(CellError::Value);
             } else {
                 cell.value = min_val;
-                cell.info.invalid = false;
+                cell.info.error = None;
             }
         }
 
@@ -398,24 +1136,24 @@ mod tests {
             let sheet = sheet_rc.borrow();
             let start = cell.info.arg[0] as usize;
             let end = cell.info.arg[1] as usize;
-            let mut total = 0;
+            let mut total = 0.0;
             let mut invalid_found = false;
             for idx in start..=end {
                 if idx >= sheet.data.len() {
                     invalid_found = true;
                     break;
                 }
-                if sheet.data[idx].info.invalid {
+                if sheet.data[idx].info.invalid() {
                     invalid_found = true;
                     break;
                 }
                 total += sheet.data[idx].value;
             }
             if invalid_found {
-                cell.info.invalid = true;
+                cell.info.error = Some(CellError::Value);
             } else {
                 cell.value = total;
-                cell.info.invalid = false;
+                cell.info.error = None;
             }
         }
 
@@ -424,10 +1162,10 @@ mod tests {
             let idx1 = cell.info.arg[0] as usize;
             let idx2 = cell.info.arg[1] as usize;
             if idx1 >= sheet.data.len() || idx2 >= sheet.data.len() {
-                cell.info.invalid = true;
+                cell.info.error = Some(CellError::Value);
             } else {
                 cell.value = sheet.data[idx1].value + sheet.data[idx2].value;
-                cell.info.invalid = false;
+                cell.info.error = None;
             }
         }
 
@@ -436,30 +1174,30 @@ mod tests {
             let idx1 = cell.info.arg[0] as usize;
             let idx2 = cell.info.arg[1] as usize;
             if idx1 >= sheet.data.len() || idx2 >= sheet.data.len() {
-                cell.info.invalid = true;
+                cell.info.error = Some(CellError::Value);
             } else {
                 let denominator = sheet.data[idx2].value;
-                if denominator == 0 {
-                    cell.info.invalid = true;
+                if denominator == 0.0 {
+                    cell.info.error = Some(CellError::Value);
                 } else {
                     cell.value = sheet.data[idx1].value / denominator;
-                    cell.info.invalid = false;
+                    cell.info.error = None;
                 }
             }
         }
 
         pub fn assignment(cell: &mut CellInfo, sheet_rc: &Rc<RefCell<Sheet>>) {
             if cell.info.arg_mask == 0 {
-                cell.value = cell.info.arg[0];
-                cell.info.invalid = false;
+                cell.value = cell.info.arg[0] as f64;
+                cell.info.error = None;
             } else {
                 let sheet = sheet_rc.borrow();
                 let idx = cell.info.arg[0] as usize;
                 if idx >= sheet.data.len() {
-                    cell.info.invalid = true;
+                    cell.info.error = Some(CellError::Value);
                 } else {
                     cell.value = sheet.data[idx].value;
-                    cell.info.invalid = sheet.data[idx].info.invalid;
+                    cell.info.error = sheet.data[idx].info.error;
                 }
             }
         }
@@ -468,7 +1206,7 @@ mod tests {
             let sheet = sheet_rc.borrow();
             let start = cell.info.arg[0] as usize;
             let end = cell.info.arg[1] as usize;
-            let mut total = 0;
+            let mut total = 0.0;
             let mut count = 0;
             let mut invalid_found = false;
             for idx in start..=end {
@@ -476,7 +1214,7 @@ mod tests {
                     invalid_found = true;
                     break;
                 }
-                if sheet.data[idx].info.invalid {
+                if sheet.data[idx].info.invalid() {
                     invalid_found = true;
                     break;
                 }
@@ -484,10 +1222,10 @@ mod tests {
                 count += 1;
             }
             if invalid_found || count == 0 {
-                cell.info.invalid = true;
+                cell.info.error = Some(CellError::Value);
             } else {
-                cell.value = total / (count as i32);
-                cell.info.invalid = false;
+                cell.value = total / (count as f64);
+                cell.info.error = None;
             }
         }
 
@@ -502,29 +1240,29 @@ mod tests {
                     invalid_found = true;
                     break;
                 }
-                if sheet.data[idx].info.invalid {
+                if sheet.data[idx].info.invalid() {
                     invalid_found = true;
                     break;
                 }
-                values.push(sheet.data[idx].value as f64);
+                values.push(sheet.data[idx].value);
             }
             if invalid_found || values.is_empty() {
-                cell.info.invalid = true;
+                cell.info.error = Some(CellError::Value);
             } else {
                 let mean: f64 = values.iter().sum::<f64>() / (values.len() as f64);
                 let variance: f64 =
                     values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (values.len() as f64);
-                cell.value = variance.sqrt().round() as i32;
-                cell.info.invalid = false;
+                cell.value = variance.sqrt();
+                cell.info.error = None;
             }
         }
 
         pub fn sleep_assignment(cell: &mut CellInfo, _sheet_rc: &Rc<RefCell<Sheet>>) {
             if cell.info.arg[0] >= 0 {
-                cell.value = cell.info.arg[0];
-                cell.info.invalid = false;
+                cell.value = cell.info.arg[0] as f64;
+                cell.info.error = None;
             } else {
-                cell.info.invalid = true;
+                cell.info.error = Some(CellError::Value);
             }
         }
 
@@ -542,7 +1280,7 @@ mod tests {
                 8 => avg(cell, sheet_rc),
                 9 => sum(cell, sheet_rc),
                 10 => stdev(cell, sheet_rc),
-                _ => cell.info.invalid = true,
+                _ => cell.info.error = Some(CellError::Value),
             }
         }
 
@@ -571,7 +1309,7 @@ mod tests {
                 for j in 0..5 {
                     let cell = sheet_mut.get_cell(i, j);
                     sheet_mut.data[cell] = CellInfo {
-                        value: (i * 5 + j) as i32,
+                        value: (i * 5 + j) as f64,
                         info: Info::default(),
                         literal_mode: false,
                     };
@@ -579,7 +1317,7 @@ mod tests {
             }
             // For testing, mark cell (2,2) as invalid so that MIN detects it.
             let cell_index = sheet_mut.get_cell(2, 2);
-            sheet_mut.data[cell_index].info.invalid = true;
+            sheet_mut.data[cell_index].info.error = Some(CellError::Value);
         }
 
         rc_sheet
@@ -604,7 +1342,7 @@ mod tests {
                     for j in 0..5 {
                         let cell_idx = sheet.get_cell(i, j);
                         sheet.data[cell_idx] = CellInfo {
-                            value: (i * 5 + j) as i32,
+                            value: (i * 5 + j) as f64,
                             info: Info::default(),
                             literal_mode: false,
                         };
@@ -613,10 +1351,10 @@ mod tests {
 
                 // Set up specific test cases
                 let invalid_cell = sheet.get_cell(2, 2);
-                sheet.data[invalid_cell].info.invalid = true;
+                sheet.data[invalid_cell].info.error = Some(CellError::Value);
 
                 let zero_cell = sheet.get_cell(4, 4);
-                sheet.data[zero_cell].value = 0;
+                sheet.data[zero_cell].value = 0.0;
             } // Mutable borrow automatically drops here.
 
             rc_sheet
@@ -630,26 +1368,26 @@ mod tests {
             // Addition
             cell.info.function_id = 2;
             cell.info.arg = [0, 1]; // 0 + 1
-            apply_function(&mut cell, &sheet);
-            assert_eq!(cell.value, 1);
+            apply_function(&mut cell, 0, &sheet);
+            assert_eq!(cell.value, 1.0);
 
             // Subtraction
             cell.info.function_id = 3;
             cell.info.arg = [5, 2]; // 5 - 2
-            apply_function(&mut cell, &sheet);
-            assert_eq!(cell.value, 3);
+            apply_function(&mut cell, 0, &sheet);
+            assert_eq!(cell.value, 3.0);
 
             // Multiplication
             cell.info.function_id = 4;
             cell.info.arg = [3, 4]; // 3 * 4
-            apply_function(&mut cell, &sheet);
-            assert_eq!(cell.value, 12);
+            apply_function(&mut cell, 0, &sheet);
+            assert_eq!(cell.value, 12.0);
 
             // Division
             cell.info.function_id = 5;
             cell.info.arg = [10, 2]; // 10 / 2
-            apply_function(&mut cell, &sheet);
-            assert_eq!(cell.value, 5);
+            apply_function(&mut cell, 0, &sheet);
+            assert_eq!(cell.value, 5.0);
         }
 
         #[test]
@@ -660,16 +1398,16 @@ mod tests {
             // Invalid cell reference
             cell.info.function_id = 2;
             cell.info.arg = [100, 1]; // Invalid cell
-            apply_function(&mut cell, &sheet);
-            cell.info.invalid = true;
-            assert!(cell.info.invalid);
+            apply_function(&mut cell, 0, &sheet);
+            cell.info.error = Some(CellError::Value);
+            assert!(cell.info.invalid());
 
             // Division by zero
             cell.info.function_id = 5;
             cell.info.arg = [5, 24]; // 5 / 0 (cell 24 is zero)
-            apply_function(&mut cell, &sheet);
-            cell.info.invalid = true;
-            assert!(cell.info.invalid);
+            apply_function(&mut cell, 0, &sheet);
+            cell.info.error = Some(CellError::Value);
+            assert!(cell.info.invalid());
         }
 
         #[test]
@@ -680,32 +1418,32 @@ mod tests {
             // MAX of first row (0-4)
             cell.info.function_id = 6;
             cell.info.arg = [0, 4];
-            apply_function(&mut cell, &sheet);
-            assert_eq!(cell.value, 4);
+            apply_function(&mut cell, 0, &sheet);
+            assert_eq!(cell.value, 4.0);
 
             // MIN with invalid cell
             cell.info.function_id = 7;
             cell.info.arg = [0, 12]; // Includes invalid cell
-            apply_function(&mut cell, &sheet);
-            assert!(cell.info.invalid);
+            apply_function(&mut cell, 0, &sheet);
+            assert!(cell.info.invalid());
 
             // SUM of 2x2 area
             cell.info.function_id = 8;
             cell.info.arg = [0, 6]; // Cells 0-1-5-6
-            apply_function(&mut cell, &sheet);
-            assert_eq!(cell.value, 0 + 1 + 5 + 6);
+            apply_function(&mut cell, 0, &sheet);
+            assert_eq!(cell.value, (0 + 1 + 5 + 6) as f64);
 
             // AVG of single cell
             cell.info.function_id = 9;
             cell.info.arg = [3, 3];
-            apply_function(&mut cell, &sheet);
-            assert_eq!(cell.value, 3);
+            apply_function(&mut cell, 0, &sheet);
+            assert_eq!(cell.value, 3.0);
 
             // STDEV of perfect square
             cell.info.function_id = 10;
             cell.info.arg = [0, 3]; // 0,1,2,3
-            apply_function(&mut cell, &sheet);
-            assert_eq!(cell.value, 1);
+            apply_function(&mut cell, 0, &sheet);
+            assert!((cell.value - 1.25f64.sqrt()).abs() < 1e-9);
         }
 
         #[test]
@@ -716,15 +1454,15 @@ mod tests {
             // Direct value assignment
             cell.info.function_id = 0;
             cell.info.arg = [42, 0];
-            apply_function(&mut cell, &sheet);
-            assert_eq!(cell.value, 42);
+            apply_function(&mut cell, 0, &sheet);
+            assert_eq!(cell.value, 42.0);
 
             // Cell reference assignment
             cell.info.function_id = 0;
             cell.info.arg_mask = 0b1;
             cell.info.arg = [12, 0]; // Cell 12 has value 12
-            apply_function(&mut cell, &sheet);
-            assert_eq!(cell.value, 12);
+            apply_function(&mut cell, 0, &sheet);
+            assert_eq!(cell.value, 12.0);
         }
 
         #[test]
@@ -735,17 +1473,17 @@ mod tests {
             // Valid sleep
             cell.info.function_id = 1;
             cell.info.arg = [1, 0];
-            apply_function(&mut cell, &sheet);
-            assert!(!cell.info.invalid);
+            apply_function(&mut cell, 0, &sheet);
+            assert!(!cell.info.invalid());
 
             // Invalid sleep
             cell.info.function_id = 1;
             cell.info.arg = [-1, 0];
-            apply_function(&mut cell, &sheet);
+            apply_function(&mut cell, 0, &sheet);
             if cell.info.arg[0] < 0 {
-                cell.info.invalid = true;
+                cell.info.error = Some(CellError::Value);
             }
-            assert!(cell.info.invalid);
+            assert!(cell.info.invalid());
         }
 
         #[test]
@@ -774,8 +1512,8 @@ mod tests {
             };
 
             cell.info.function_id = 2;
-            apply_function(&mut cell, &sheet);
-            assert_eq!(cell.value, 0); // Value shouldn't change
+            apply_function(&mut cell, 0, &sheet);
+            assert_eq!(cell.value, 0.0); // Value shouldn't change
         }
 
         #[test]
@@ -786,15 +1524,15 @@ mod tests {
             // Empty range
             cell.info.function_id = 6;
             cell.info.arg = [5, 0]; // Invalid range
-            apply_function(&mut cell, &sheet);
-            cell.info.invalid = true;
-            assert!(cell.info.invalid);
+            apply_function(&mut cell, 0, &sheet);
+            cell.info.error = Some(CellError::Value);
+            assert!(cell.info.invalid());
 
             // Single cell stdev
             cell.info.function_id = 10;
             cell.info.arg = [0, 0];
-            apply_function(&mut cell, &sheet);
-            assert_eq!(cell.value, 0);
+            apply_function(&mut cell, 0, &sheet);
+            assert_eq!(cell.value, 0.0);
         }
 
         #[test]
@@ -806,9 +1544,213 @@ mod tests {
             for func_id in 0..=10 {
                 cell.info.function_id = func_id;
                 cell.info.arg = [0, 1];
-                apply_function(&mut cell, &sheet);
-                assert_ne!(cell.info.invalid, true);
+                apply_function(&mut cell, 0, &sheet);
+                assert_ne!(cell.info.invalid(), true);
             }
         }
     }
+
+    #[test]
+    fn test_factorial_and_fibonacci() {
+        let mut sheet = Sheet::new(2, 2);
+        let eval = |sheet: &Sheet, function_id: u8, arg: i32, arg_mask: u8| {
+            let mut cell = CellInfo::default();
+            cell.info.function_id = function_id;
+            cell.info.arg[0] = arg;
+            cell.info.arg_mask = arg_mask;
+            FPTR[function_id as usize](&mut cell, sheet);
+            (!cell.info.invalid()).then_some(cell.value)
+        };
+        assert_eq!(eval(&sheet, 11, 5, 0), Some(120.0));
+        assert_eq!(eval(&sheet, 11, 0, 0), Some(1.0));
+        assert_eq!(eval(&sheet, 11, 12, 0), Some(479_001_600.0));
+        assert_eq!(eval(&sheet, 11, 13, 0), None); // overflow
+        assert_eq!(eval(&sheet, 11, -1, 0), None);
+
+        assert_eq!(eval(&sheet, 12, 10, 0), Some(55.0));
+        assert_eq!(eval(&sheet, 12, 0, 0), Some(0.0));
+        assert_eq!(eval(&sheet, 12, 46, 0), Some(1_836_311_903.0));
+        assert_eq!(eval(&sheet, 12, 47, 0), None); // overflow
+        assert_eq!(eval(&sheet, 12, -3, 0), None);
+
+        // Cell arguments are read, and their errors propagate
+        sheet.data[1].value = 6.0;
+        assert_eq!(eval(&sheet, 11, 1, 0b1), Some(720.0));
+        sheet.data[1].value = 2.5;
+        assert_eq!(eval(&sheet, 11, 1, 0b1), None); // not a whole number
+        sheet.data[1].info.error = Some(CellError::Value);
+        assert_eq!(eval(&sheet, 12, 1, 0b1), None);
+    }
+
+    #[test]
+    fn test_harmonic_mean() {
+        let mut sheet = Sheet::new(3, 2);
+        for (i, v) in [1.0, 2.0, 4.0].into_iter().enumerate() {
+            let cell = sheet.get_cell(i, 0);
+            sheet.data[cell].value = v;
+        }
+        let column_end = sheet.get_cell(2, 0);
+        let eval = |sheet: &Sheet, start: usize, end: usize| {
+            let mut cell = CellInfo::default();
+            cell.info.function_id = 13;
+            cell.info.arg = [start as i32, end as i32];
+            cell.info.arg_mask = 0b11;
+            FPTR[13](&mut cell, sheet);
+            (!cell.info.invalid()).then_some(cell.value)
+        };
+        // 3 / (1 + 1/2 + 1/4) = 12/7
+        assert_eq!(eval(&sheet, 0, column_end), Some(12.0 / 7.0));
+        assert_eq!(eval(&sheet, 1, 1), None); // B1 is 0
+        assert_eq!(eval(&sheet, 0, 0), Some(1.0));
+
+        // 1 and -1 have reciprocals summing to zero
+        sheet.data[2].value = -1.0;
+        sheet.data[0].value = 1.0;
+        assert_eq!(eval(&sheet, 0, 2), None);
+
+        sheet.data[column_end].info.error = Some(CellError::Value);
+        assert_eq!(eval(&sheet, 0, column_end), None);
+        assert!(is_range_function(13));
+    }
+
+    #[test]
+    fn test_mode() {
+        let mut sheet = Sheet::new(5, 1);
+        for (i, v) in [3.0, 7.0, 3.0, 1.0, 7.0].into_iter().enumerate() {
+            sheet.data[i].value = v;
+        }
+        let eval = |sheet: &Sheet, start: usize, end: usize| {
+            let mut cell = CellInfo::default();
+            cell.info.function_id = 14;
+            cell.info.arg = [start as i32, end as i32];
+            cell.info.arg_mask = 0b11;
+            FPTR[14](&mut cell, sheet);
+            (!cell.info.invalid()).then_some(cell.value)
+        };
+        assert_eq!(eval(&sheet, 0, 3), Some(3.0));
+        // 3 and 7 both appear twice
+        assert_eq!(eval(&sheet, 0, 4), Some(3.0));
+        // Every value appears once
+        assert_eq!(eval(&sheet, 1, 3), Some(1.0));
+        sheet.data[4].value = 1.0;
+        assert_eq!(eval(&sheet, 0, 4), Some(1.0));
+
+        sheet.data[2].info.error = Some(CellError::Value);
+        assert_eq!(eval(&sheet, 0, 4), None);
+        assert!(is_range_function(14));
+    }
+
+    #[test]
+    fn test_fractional_results() {
+        let mut sheet = Sheet::new(4, 1);
+        for (i, v) in [1.0, 2.0, 2.0, 3.5].into_iter().enumerate() {
+            sheet.data[i].value = v;
+        }
+        let eval = |sheet: &Sheet, function_id: u8, arg: [i32; 2]| {
+            let mut cell = CellInfo::default();
+            cell.info.function_id = function_id;
+            cell.info.arg = arg;
+            cell.info.arg_mask = 0b11;
+            FPTR[function_id as usize](&mut cell, sheet);
+            (!cell.info.invalid()).then_some(cell.value)
+        };
+        // AVG and STDEV no longer truncate
+        assert_eq!(eval(&sheet, 9, [0, 2]), Some(5.0 / 3.0));
+        assert_eq!(eval(&sheet, 10, [0, 1]), Some(0.5));
+        assert_eq!(eval(&sheet, 8, [0, 3]), Some(8.5));
+        // Division keeps the remainder
+        assert_eq!(eval(&sheet, 5, [0, 1]), Some(0.5));
+        assert_eq!(eval(&sheet, 5, [3, 0]), Some(3.5));
+        assert_eq!(eval(&sheet, 4, [3, 3]), Some(12.25));
+
+        // A result too large for f64 is an error
+        sheet.data[0].value = f64::MAX;
+        assert_eq!(eval(&sheet, 4, [0, 0]), None);
+
+        // Constants hold the exact value they were built from
+        let mut cell = CellInfo {
+            info: Info::number(-0.125),
+            ..Default::default()
+        };
+        FPTR[cell.info.function_id as usize](&mut cell, &sheet);
+        assert_eq!(cell.value, -0.125);
+        assert_eq!(cell.info.literal_value(), Some(-0.125));
+    }
+
+    #[test]
+    fn test_scalar_functions() {
+        let mut sheet = Sheet::new(2, 1);
+        sheet.data[0].value = -7.0;
+        sheet.data[1].info.error = Some(CellError::Value);
+        let eval = |sheet: &Sheet, function_id: u8, arg: [i32; 2], arg_mask: u8| {
+            let mut cell = CellInfo::default();
+            cell.info.function_id = function_id;
+            cell.info.arg = arg;
+            cell.info.arg_mask = arg_mask;
+            FPTR[function_id as usize](&mut cell, sheet);
+            (!cell.info.invalid()).then_some(cell.value)
+        };
+        // MOD takes the sign of the divisor
+        assert_eq!(eval(&sheet, 18, [0, 3], 0b1), Some(2.0));
+        assert_eq!(eval(&sheet, 18, [7, -3], 0), Some(-2.0));
+        assert_eq!(eval(&sheet, 18, [7, 0], 0), None);
+        assert_eq!(eval(&sheet, 19, [2, 10], 0), Some(1024.0));
+        assert_eq!(eval(&sheet, 19, [2, -1], 0), Some(0.5));
+        assert_eq!(eval(&sheet, ABS, [0, 0], 0b1), Some(7.0));
+        assert_eq!(eval(&sheet, 21, [0, 3], 0b1), Some(-7.0));
+        assert_eq!(eval(&sheet, 22, [0, 3], 0b1), Some(3.0));
+        assert_eq!(eval(&sheet, 22, [0, 1], 0b10), None);
+
+        // Results that are not finite numbers are errors, never a panic
+        assert_eq!(eval(&sheet, 19, [10, 400], 0), None);
+        assert_eq!(eval(&sheet, 19, [0, -1], 0), None);
+        assert_eq!(scalar_value(19, -8.0, 0.5), Err(CellError::Value));
+        assert_eq!(scalar_value(18, 5.0, 0.0), Err(CellError::DivByZero));
+        assert_eq!(scalar_value(ABS, i32::MIN as f64, 0.0), Ok(2147483648.0));
+    }
+
+    #[test]
+    fn test_overflow_policy() {
+        let mut sheet = Sheet::new(1, 2);
+        sheet.data[0].value = f64::MAX;
+        let eval = |sheet: &Sheet, function_id: u8, arg: [i32; 2], arg_mask: u8| {
+            let mut cell = CellInfo::default();
+            cell.info.function_id = function_id;
+            cell.info.arg = arg;
+            cell.info.arg_mask = arg_mask;
+            FPTR[function_id as usize](&mut cell, sheet);
+            cell
+        };
+        // Under the default policy an overflow is an error, told apart from other errors
+        let sum = eval(&sheet, 2, [0, 0], 0b11);
+        assert!(sum.info.invalid() && sum.overflowed());
+        assert!(eval(&sheet, 11, [13, 0], 0).overflowed());
+        assert!(eval(&sheet, 12, [47, 0], 0).overflowed());
+        let div = eval(&sheet, 5, [1, 0], 0);
+        assert!(div.info.invalid() && !div.overflowed());
+        let pole = eval(&sheet, 19, [0, -1], 0);
+        assert!(pole.info.invalid() && !pole.overflowed());
+
+        sheet.overflow = OverflowPolicy::Saturate;
+        let sum = eval(&sheet, 2, [0, 0], 0b11);
+        assert!(!sum.info.invalid());
+        assert_eq!(sum.value, f64::MAX);
+        assert_eq!(eval(&sheet, 3, [1, 0], 0b10).value, f64::MIN + 1.0);
+        assert_eq!(eval(&sheet, 11, [13, 0], 0).value, i32::MAX as f64);
+        assert_eq!(eval(&sheet, 19, [10, 400], 0).value, f64::MAX);
+        // Saturating does not hide errors that are not overflows
+        assert!(eval(&sheet, 5, [1, 0], 0).info.invalid());
+    }
+
+    #[test]
+    fn test_count_labels_glob() {
+        let labels = ["North", "Northeast", "South", "north"];
+        assert_eq!(count_labels(labels, "South"), 1);
+        assert_eq!(count_labels(labels, "North*"), 2);
+        assert_eq!(count_labels(labels, "*th"), 3);
+        assert_eq!(count_labels(labels, "?orth"), 2);
+        assert_eq!(count_labels(labels, "West*"), 0);
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("N?", "N"));
+    }
 }
@@ -1,12 +1,18 @@
 // graph.rs
 //! Dependency graph module for formula computation in a spreadsheet-like system.
 use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, BinaryHeap, HashMap, VecDeque};
 use std::rc::Rc;
+use std::time::Duration;
+use std::{panic, thread};
 
-use crate::formulas::{apply_function, is_range_function};
+use crate::cell_log::{CellLog, DEFAULT_CELL_HISTORY_DEPTH};
+use crate::formulas::{FPTR, Formula, apply_in_sheet, apply_registered};
 use crate::info::{CellInfo, Info};
-use crate::list::{ListMemPool, Node, erase_list, push_front};
+use crate::profile::Profile;
 use crate::status::StatusCode;
+use crate::trace::{CellChange, Trace};
 /// Enum representing the visit status of a node during DFS traversal.
 #[derive(PartialEq, Eq, Clone, Copy)]
 pub enum VisitStatus {
@@ -18,73 +24,308 @@ pub enum VisitStatus {
     Visited = 2,
 }
 
-/// Struct representing an adjacency list node in the graph.
-#[derive(Debug, Clone)]
+/// Smallest level of the recompute order that `update_values` splits across threads;
+/// spawning costs more than evaluating fewer cells serially.
+pub const PARALLEL_MIN_CELLS: usize = 64;
+
+/// Order in which topologically independent cells are recomputed.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum RecalcOrder {
+    /// Keep the order produced by the DFS, which follows adjacency-list insertion order.
+    #[default]
+    Dfs,
+    /// Recompute independent cells row by row (A1, B1, ..., A2, ...).
+    RowMajor,
+    /// Recompute independent cells column by column (A1, A2, ..., B1, ...).
+    ColMajor,
+}
+
+/// When edits recompute the cells that depend on them.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum CalcMode {
+    /// Every edit recomputes its dependents right away.
+    #[default]
+    Auto,
+    /// Edits only compute the cells written; dependents wait for `recalc`.
+    Manual,
+}
+
+/// Why `Graph::update_expression` rejected a formula.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpdateError {
+    /// The formula would make the cell depend on itself. The path starts and ends at the
+    /// edited cell and each cell refers to the next, e.g. `[A1, C1, B1, A1]` for `A1=C1+1`
+    /// when `C1=B1` and `B1=A1`.
+    Cycle(Vec<usize>),
+    /// The cell is protected by `lock`.
+    Locked(usize),
+}
+
+impl UpdateError {
+    /// Maps an update error to the status code shown to the user.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            UpdateError::Cycle(_) => StatusCode::CyclicDep,
+            UpdateError::Locked(_) => StatusCode::CellLocked,
+        }
+    }
+}
+
+impl From<UpdateError> for StatusCode {
+    fn from(error: UpdateError) -> Self {
+        error.status_code()
+    }
+}
+
+/// The cells whose formulas name one cell on its own (not as part of a larger range).
+///
+/// Edges are stored oldest first and walked newest first, so a traversal lists a cell's
+/// dependents most recent first.
+#[derive(Debug, Clone, Default)]
 pub struct AdjList {
-    /// Pointer to the head of the linked list of adjacent nodes.
-    pub head: Option<Rc<RefCell<Node>>>,
-    /// Pointer used for traversal during DFS.
-    pub ptr: Option<Rc<RefCell<Node>>>,
+    /// Indices of the dependent cells, one entry per reference (a formula reading the
+    /// cell twice has two entries).
+    pub cells: Vec<u32>,
+}
+
+impl AdjList {
+    /// Adds an edge to `cell`, walked first from now on.
+    pub fn push(&mut self, cell: u32) {
+        self.cells.push(cell);
+    }
+    /// Removes the most recent edge to `cell`, returning `false` if there is none.
+    pub fn erase(&mut self, cell: u32) -> bool {
+        match self.cells.iter().rposition(|&c| c == cell) {
+            Some(i) => {
+                self.cells.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+    /// Returns the dependents, most recent first.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.cells.iter().rev().map(|&c| c as usize)
+    }
+}
+
+/// A range stored in `RangeIndex` under one of its lines: the span it covers along that
+/// line, inclusive, and the cell that reads it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Interval {
+    first: u32,
+    last: u32,
+    dependent: u32,
+}
+
+/// The ranges of more than one cell that formulas read, without an edge per cell.
+///
+/// A range is stored once per line along its shorter side: one at least as wide as it is
+/// tall goes into the list of each of its rows as the columns it spans, a taller one into
+/// the list of each of its columns as the rows it spans. `SUM(A1:Z1000)` thus takes 26
+/// entries rather than 26,000 edges, and the ranges covering a cell are found by scanning
+/// the lists of its row and its column.
+#[derive(Debug, Clone, Default)]
+pub struct RangeIndex {
+    /// Ranges stored by row, each spanning columns.
+    rows: Vec<Vec<Interval>>,
+    /// Ranges stored by column, each spanning rows.
+    cols: Vec<Vec<Interval>>,
+}
+
+impl RangeIndex {
+    /// Creates an empty index for an `n` x `m` sheet.
+    pub fn new(n: usize, m: usize) -> Self {
+        Self {
+            rows: vec![Vec::new(); n],
+            cols: vec![Vec::new(); m],
+        }
+    }
+    /// Records that `dependent` reads the range with corners `start` and `end`, given as
+    /// `(row, column)`.
+    pub fn insert(&mut self, start: (usize, usize), end: (usize, usize), dependent: usize) {
+        let (lines, interval) = self.place(start, end, dependent);
+        for line in lines {
+            line.push(interval);
+        }
+    }
+    /// Removes one record of `dependent` reading the range, the inverse of `insert`.
+    pub fn remove(&mut self, start: (usize, usize), end: (usize, usize), dependent: usize) {
+        let (lines, interval) = self.place(start, end, dependent);
+        for line in lines {
+            if let Some(i) = line.iter().rposition(|&e| e == interval) {
+                line.remove(i);
+            }
+        }
+    }
+    /// Returns the lists a range is stored in and its entry in each of them.
+    fn place(
+        &mut self,
+        (top, left): (usize, usize),
+        (bottom, right): (usize, usize),
+        dependent: usize,
+    ) -> (&mut [Vec<Interval>], Interval) {
+        let (lines, first, last) = if right - left >= bottom - top {
+            (&mut self.rows[top..=bottom], left, right)
+        } else {
+            (&mut self.cols[left..=right], top, bottom)
+        };
+        let interval = Interval {
+            first: first as u32,
+            last: last as u32,
+            dependent: dependent as u32,
+        };
+        (lines, interval)
+    }
+    /// Returns the cells reading a range that covers `cell`, most recent first within its
+    /// row and then within its column.
+    pub fn dependents(&self, cell: usize) -> impl Iterator<Item = usize> + '_ {
+        let (row, col) = (cell / self.cols.len(), cell % self.cols.len());
+        covering(&self.rows[row], col).chain(covering(&self.cols[col], row))
+    }
+    /// Returns the number of stored entries, a measure of the index's size.
+    pub fn len(&self) -> usize {
+        self.rows.iter().chain(&self.cols).map(Vec::len).sum()
+    }
+    /// Returns `true` if no range is stored.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+/// Returns the cells reading the intervals of `line` that span `at`, most recent first.
+fn covering(line: &[Interval], at: usize) -> impl Iterator<Item = usize> + '_ {
+    line.iter()
+        .rev()
+        .filter(move |e| (e.first as usize..=e.last as usize).contains(&at))
+        .map(|e| e.dependent as usize)
 }
 // Graph structure to hold state
 /// Represents the dependency graph of the spreadsheet.
 pub struct Graph {
-    /// Adjacency list of the graph.
+    /// Adjacency list of the graph: the edges of references to single cells.
     pub adj_list: Vec<AdjList>,
+    /// The edges of references to larger ranges.
+    pub ranges: RangeIndex,
+    /// Dependents each cell on the DFS stack has yet to walk, the next one last.
+    walks: HashMap<usize, Vec<usize>>,
     /// Stack used for DFS traversal.
     pub stack: Vec<i32>,
     /// Pointer to current position in topological order.
     pub order_ptr: usize,
     /// Pointer to the top of the DFS stack.
     pub stack_ptr: usize,
-    /// Memory pool for reusing list nodes.
-    pub mem_pool: Rc<RefCell<ListMemPool>>,
     /// Reference to the spreadsheet data.
     pub sheet: Rc<RefCell<crate::sheet::Sheet>>,
+    /// Tie-breaking order among independent cells during recomputation.
+    pub recalc_order: RecalcOrder,
+    /// Formula for each function ID: the builtins, then any registered functions.
+    pub functions: Vec<Option<Formula>>,
+    /// When set, `update_expression` appends the state of every cell it is about to
+    /// overwrite, so a bulk command can be undone as one step.
+    pub journal: Option<Vec<(usize, CellInfo)>>,
+    /// Threads `update_values` spreads each level of the recompute order over
+    /// (`set threads`); 1 evaluates serially.
+    pub threads: usize,
+    /// Where each recomputation pass is reported while `trace on` is active.
+    pub trace: Option<Trace>,
+    /// Callbacks told which cells each recomputation pass changed (`Spreadsheet::subscribe`).
+    pub subscribers: Vec<Box<dyn FnMut(CellChange)>>,
+    /// Cells overwritten by an edit that is about to be recomputed, as they were before,
+    /// kept while a trace or subscriber watches the passes.
+    overwritten: HashMap<usize, CellInfo>,
+    /// Whether edits recompute their dependents (`set_calc`).
+    pub calc: CalcMode,
+    /// Set when a manual-mode edit left dependents to recompute, until `recompute_all`.
+    pub stale: bool,
+    /// How often each cell was recomputed and the time its formula took (`profile top`).
+    pub profile: Profile,
+    /// The formula each edit gave each cell, and when (`history A1`).
+    pub cell_log: CellLog,
+    /// Cells whose formula calls a volatile function such as `RAND`, recomputed with
+    /// their dependents on every recalculation.
+    pub volatile: BTreeSet<usize>,
+    /// Writes `update_expression` holds back between `begin_batch` and `commit_batch`, in
+    /// the order they were made, one per cell.
+    batch: Option<Vec<(usize, Info)>>,
 }
 
 impl Graph {
     // Initialize graph data structures
     /// Creates a new graph for a spreadsheet with given dimensions.
-    pub fn new(
-        n: usize,
-        m: usize,
-        sheet: Rc<RefCell<crate::sheet::Sheet>>,
-        mem_pool: Rc<RefCell<ListMemPool>>,
-    ) -> Self {
+    pub fn new(n: usize, m: usize, sheet: Rc<RefCell<crate::sheet::Sheet>>) -> Self {
         let total_cells = n * m;
         Self {
-            adj_list: vec![
-                AdjList {
-                    head: None,
-                    ptr: None,
-                };
-                total_cells
-            ],
+            adj_list: vec![AdjList::default(); total_cells],
+            ranges: RangeIndex::new(n, m),
+            walks: HashMap::new(),
             stack: vec![0; total_cells],
             order_ptr: total_cells,
             stack_ptr: 0,
-            mem_pool,
             sheet,
+            recalc_order: RecalcOrder::default(),
+            functions: FPTR.iter().copied().map(Some).collect(),
+            journal: None,
+            threads: 1,
+            trace: None,
+            subscribers: Vec::new(),
+            overwritten: HashMap::new(),
+            calc: CalcMode::default(),
+            stale: false,
+            profile: Profile::new(total_cells),
+            cell_log: CellLog::new(DEFAULT_CELL_HISTORY_DEPTH),
+            volatile: BTreeSet::new(),
+            batch: None,
+        }
+    }
+    /// Installs `formula` as the function with ID `id`.
+    ///
+    /// Returns `InvalidValue` if the ID is already taken, so builtins cannot be replaced.
+    pub fn register_function(&mut self, id: u8, formula: Formula) -> Result<(), StatusCode> {
+        let id = id as usize;
+        if self.functions.get(id).is_some_and(Option::is_some) {
+            return Err(StatusCode::InvalidValue);
+        }
+        if id >= self.functions.len() {
+            self.functions.resize(id + 1, None);
         }
+        self.functions[id] = Some(formula);
+        Ok(())
     }
     /// Checks if a given cell is a dependency of a formula in another cell.
     // Check if a cell is in the dependency of a formula
     pub fn in_dependency(&self, cell: i32, info: &CellInfo) -> bool {
         let sheet_borrow = self.sheet.borrow();
+        let (row, col) = sheet_borrow.get_row_and_column(cell as usize);
+
+        // Check if cell is in one of the ranges (or is one of the cells) the formula reads
+        info.info.references().into_iter().any(|(start, end)| {
+            let (x1, y1) = sheet_borrow.get_row_and_column(start);
+            let (x2, y2) = sheet_borrow.get_row_and_column(end);
+            (x1..=x2).contains(&row) && (y1..=y2).contains(&col)
+        })
+    }
+    /// Returns every cell that would recompute if `cell` changed, i.e. the transitive
+    /// closure of its dependents, sorted by cell index.
+    ///
+    /// Walks the adjacency lists breadth-first with a visited set, so shared dependents are
+    /// reported once. `cell` itself is only included if it depends on itself.
+    pub fn transitive_dependents(&self, cell: usize) -> Vec<usize> {
+        let mut visited = vec![false; self.adj_list.len()];
+        let mut queue = VecDeque::from([cell]);
+        let mut result = Vec::new();
 
-        if is_range_function(info.info.function_id) {
-            // Check if cell is in the given range
-            let col = sheet_borrow.get_column(cell as usize);
-            return cell >= info.info.arg[0]
-                && cell <= info.info.arg[1]
-                && col >= sheet_borrow.get_column(info.info.arg[0] as usize)
-                && col <= sheet_borrow.get_column(info.info.arg[1] as usize);
+        while let Some(u) = queue.pop_front() {
+            for v in self.direct_dependents(u) {
+                if !visited[v] {
+                    visited[v] = true;
+                    result.push(v);
+                    queue.push_back(v);
+                }
+            }
         }
 
-        // Check if cell is one of the direct arguments
-        (self.is_cell_arg1(info.info.arg_mask) && info.info.arg[0] == cell)
-            || (self.is_cell_arg2(info.info.arg_mask) && info.info.arg[1] == cell)
+        result.sort_unstable();
+        result
     }
     /// Checks if argument 1 is a reference to a cell.
     // Helper functions to check if arguments are cells
@@ -95,38 +336,39 @@ impl Graph {
     pub fn is_cell_arg2(&self, arg_mask: u8) -> bool {
         arg_mask & 0b10 != 0
     }
-    /// Generalized function to modify the dependency graph using a passed-in function
+    /// Adds (`add`) or removes the edges from everything `info` reads to `cell`.
+    ///
+    /// A single cell gets an edge in its adjacency list, a larger range one record in
+    /// `ranges`, however many cells it covers.
     // Helper function to modify the graph by adding or removing dependencies
-    pub fn modify_graph<F>(&mut self, cell: i32, info: &CellInfo, func: F)
-    where
-        F: Fn(&mut Option<Rc<RefCell<Node>>>, i32, &mut Rc<RefCell<ListMemPool>>),
-    {
+    pub fn modify_graph(&mut self, cell: i32, info: &CellInfo, add: bool) {
         let sheet_borrow = self.sheet.borrow();
+        if info.info.is_volatile() {
+            if add {
+                self.volatile.insert(cell as usize);
+            } else {
+                self.volatile.remove(&(cell as usize));
+            }
+        }
 
-        if is_range_function(info.info.function_id) {
-            // Handle range function dependency
-            let (x1, y1) = sheet_borrow.get_row_and_column(info.info.arg[0] as usize);
-            let (x2, y2) = sheet_borrow.get_row_and_column(info.info.arg[1] as usize);
-
-            for i in x1..=x2 {
-                for j in y1..=y2 {
-                    let x = sheet_borrow.get_cell(i, j);
-                    func(&mut self.adj_list[x].head, cell, &mut self.mem_pool);
-                    self.adj_list[x].ptr = self.adj_list[x].head.clone(); // Reset pointer
+        // A cell argument is a range of one cell
+        for (start, end) in info.info.references() {
+            if start == end {
+                let list = &mut self.adj_list[start];
+                if add {
+                    list.push(cell as u32);
+                } else {
+                    list.erase(cell as u32);
                 }
+                continue;
             }
-        } else {
-            // Handle direct cell arguments
-            if self.is_cell_arg1(info.info.arg_mask) {
-                let arg_idx = info.info.arg[0] as usize;
-                func(&mut self.adj_list[arg_idx].head, cell, &mut self.mem_pool);
-                self.adj_list[arg_idx].ptr = self.adj_list[arg_idx].head.clone();
-            }
-
-            if self.is_cell_arg2(info.info.arg_mask) {
-                let arg_idx = info.info.arg[1] as usize;
-                func(&mut self.adj_list[arg_idx].head, cell, &mut self.mem_pool);
-                self.adj_list[arg_idx].ptr = self.adj_list[arg_idx].head.clone();
+            let (x1, y1) = sheet_borrow.get_row_and_column(start);
+            let (x2, y2) = sheet_borrow.get_row_and_column(end);
+            let corners = ((x1.min(x2), y1.min(y2)), (x1.max(x2), y1.max(y2)));
+            if add {
+                self.ranges.insert(corners.0, corners.1, cell as usize);
+            } else {
+                self.ranges.remove(corners.0, corners.1, cell as usize);
             }
         }
     }
@@ -137,30 +379,19 @@ impl Graph {
         let cell_info = sheet_borrow.data[cell as usize].clone();
         drop(sheet_borrow); // Release the borrow before calling modify_graph
 
-        // self.modify_graph(cell, &cell_info, |head, value, mem_pool| {
-        //     erase_list(head, value);
-        // });
-
-        self.modify_graph(cell, &cell_info, |head, value, mem_pool| {
-            let mut pool = mem_pool.borrow_mut();
-            erase_list(head, value, &mut pool);
-        });
+        self.modify_graph(cell, &cell_info, false);
     }
     /// Adds a new expression's dependencies into the graph.
     // Add new expression dependencies
     pub fn add_expression(&mut self, cell: i32, new_info: &CellInfo) {
-        // self.modify_graph(cell, new_info, |head, value, mem_pool| {
-        //     push_front(head, value);
-        // });
-
-        self.modify_graph(cell, new_info, |head, value, mem_pool| {
-            let mut pool = mem_pool.borrow_mut();
-            push_front(head, value, &mut pool);
-        });
+        self.modify_graph(cell, new_info, true);
     }
     /// Performs a non-recursive DFS to detect cycles and build topological order.
+    ///
+    /// Returns the cycle as `Err(path)` (see `UpdateError::Cycle`) if `new_info` would
+    /// close one. Either way `reset` must be called afterwards.
     // Perform iterative DFS to detect cycles and build topological order
-    pub fn iterative_dfs(&mut self, cell: i32, new_info: &CellInfo) -> bool {
+    pub fn iterative_dfs(&mut self, cell: i32, new_info: &CellInfo) -> Result<(), Vec<usize>> {
         {
             let mut sheet_borrow = self.sheet.borrow_mut();
             // Mark initial cell and push to stack
@@ -174,17 +405,18 @@ impl Graph {
             let u = self.stack[self.stack_ptr - 1]; // Top of stack
 
             if self.in_dependency(u, new_info) {
-                // Found a cycle
-                return false;
+                // Found a cycle: the new formula reads u, which follows from cell
+                return Err(self.cycle_path(0));
             }
 
             // Check if there are unvisited dependencies
-            if let Some(ref ptr_node) = self.adj_list[u as usize].ptr {
-                let v = ptr_node.borrow().data;
-
-                // Move to next dependency for future iteration
-                let next = ptr_node.borrow().next.clone();
-                self.adj_list[u as usize].ptr = next;
+            if !self.walks.contains_key(&(u as usize)) {
+                let mut walk = self.direct_dependents(u as usize);
+                walk.reverse();
+                self.walks.insert(u as usize, walk);
+            }
+            if let Some(v) = self.walks.get_mut(&(u as usize)).and_then(Vec::pop) {
+                let v = v as i32;
 
                 // Check the status of the destination node
                 let v_status = {
@@ -194,7 +426,11 @@ impl Graph {
 
                 if v_status == VisitStatus::InStack as u8 {
                     // Cycle detected
-                    return false;
+                    let start = self.stack[..self.stack_ptr]
+                        .iter()
+                        .position(|&c| c == v)
+                        .unwrap_or(0);
+                    return Err(self.cycle_path(start));
                 }
 
                 if v_status == VisitStatus::NotVisited as u8 {
@@ -222,7 +458,77 @@ impl Graph {
             self.stack_ptr -= 1;
         }
 
-        true // No cycles found
+        self.apply_recalc_order();
+        Ok(()) // No cycles found
+    }
+    /// Returns the cycle closed from the top of the DFS stack back to `stack[start]`.
+    ///
+    /// Each stack entry depends on the one below it, so reading the stack downwards gives
+    /// the references in the order the formulas name them.
+    fn cycle_path(&self, start: usize) -> Vec<usize> {
+        let top = &self.stack[start..self.stack_ptr];
+        std::iter::once(top[0])
+            .chain(top.iter().rev().copied())
+            .map(|c| c as usize)
+            .collect()
+    }
+    /// Returns the cells of the current topological order, in recompute order.
+    pub fn recompute_order(&self) -> &[i32] {
+        &self.stack[self.order_ptr..self.adj_list.len()]
+    }
+    /// Re-sorts the topological order so that, among cells that do not depend on each
+    /// other, the one earliest in `recalc_order` is recomputed first.
+    ///
+    /// This is Kahn's algorithm restricted to the cells in the current order, with a
+    /// min-heap keyed by spatial position choosing among the ready cells.
+    fn apply_recalc_order(&mut self) {
+        let m = self.sheet.borrow().m;
+        let key = match self.recalc_order {
+            RecalcOrder::Dfs => return,
+            RecalcOrder::RowMajor => |cell: usize, m: usize| (cell / m, cell % m),
+            RecalcOrder::ColMajor => |cell: usize, m: usize| (cell % m, cell / m),
+        };
+        let n_cells = self.adj_list.len();
+        let order: Vec<usize> = self.stack[self.order_ptr..n_cells]
+            .iter()
+            .map(|&c| c as usize)
+            .collect();
+
+        let mut indegree: HashMap<usize, usize> = order.iter().map(|&c| (c, 0)).collect();
+        for &u in &order {
+            for v in self.direct_dependents(u) {
+                if let Some(d) = indegree.get_mut(&v) {
+                    *d += 1;
+                }
+            }
+        }
+
+        let mut ready: BinaryHeap<Reverse<((usize, usize), usize)>> = order
+            .iter()
+            .filter(|c| indegree[c] == 0)
+            .map(|&c| Reverse((key(c, m), c)))
+            .collect();
+        let mut i = self.order_ptr;
+        while let Some(Reverse((_, u))) = ready.pop() {
+            self.stack[i] = u as i32;
+            i += 1;
+            for v in self.direct_dependents(u) {
+                if let Some(d) = indegree.get_mut(&v) {
+                    *d -= 1;
+                    if *d == 0 {
+                        ready.push(Reverse((key(v, m), v)));
+                    }
+                }
+            }
+        }
+    }
+    /// Returns the cells whose formulas reference `cell` directly: those naming it on its
+    /// own, most recent first, then those reading a range that covers it.
+    pub fn direct_dependents(&self, cell: usize) -> Vec<usize> {
+        self.adj_list[cell]
+            .iter()
+            .chain(self.ranges.dependents(cell))
+            .collect()
     }
     /// Resets visit statuses and graph traversal pointers.
     // Reset all visit statuses after traversal
@@ -238,8 +544,6 @@ impl Graph {
             let mut sheet_borrow = self.sheet.borrow_mut();
             sheet_borrow.data[node_idx].info.visit = VisitStatus::NotVisited as u8;
             drop(sheet_borrow);
-
-            self.adj_list[node_idx].ptr = self.adj_list[node_idx].head.clone();
         }
 
         // Reset nodes in topological order
@@ -248,54 +552,310 @@ impl Graph {
             let mut sheet_borrow = self.sheet.borrow_mut();
             sheet_borrow.data[node_idx].info.visit = VisitStatus::NotVisited as u8;
             drop(sheet_borrow);
-
-            self.adj_list[node_idx].ptr = self.adj_list[node_idx].head.clone();
         }
 
+        self.walks.clear();
         self.stack_ptr = 0;
         self.order_ptr = n_cells;
     }
     /// Recomputes values for all cells in topological order.
+    ///
+    /// With more than one thread, the order is split into levels of cells that do not
+    /// read each other and each large level is evaluated concurrently (see `update_level`).
+    ///
+    /// The volatile cells and their dependents join the order first, so every
+    /// recalculation draws new random numbers.
     // Update values in topological order
     pub fn update_values(&mut self) {
+        self.stage_volatile();
         let n_cells = {
             let sheet_borrow = self.sheet.borrow();
             sheet_borrow.n * sheet_borrow.m
         };
 
+        if self.threads > 1 {
+            let order: Vec<usize> = self.stack[self.order_ptr..n_cells]
+                .iter()
+                .map(|&c| c as usize)
+                .collect();
+            let levels = self.levels(&order);
+            let before = self.trace_before(&levels.concat());
+            for level in levels {
+                self.update_level(&level);
+            }
+            self.trace_pass(before);
+            return;
+        }
+
+        let traced: Vec<usize> = match self.watched() {
+            true => self.stack[self.order_ptr..n_cells]
+                .iter()
+                .map(|&c| c as usize)
+                .collect(),
+            false => Vec::new(),
+        };
+        let before = self.trace_before(&traced);
         for i in self.order_ptr..n_cells {
             let cell_idx = self.stack[i] as usize;
-            let mut sheet_borrow = self.sheet.borrow_mut();
-            let mut cell_info = sheet_borrow.data[cell_idx].clone();
-            drop(sheet_borrow);
+            // Work on a copy so that no borrow is held while the formula reads the sheet
+            let mut cell_info = self.sheet.borrow().data[cell_idx];
 
             // Only compute if not in literal mode
             if !cell_info.literal_mode {
-                apply_function(&mut cell_info, &self.sheet);
+                let elapsed =
+                    apply_registered(&mut cell_info, cell_idx, &self.sheet, &self.functions);
+                self.profile.record(cell_idx, elapsed);
             }
 
-            let mut sheet_borrow = self.sheet.borrow_mut();
-            sheet_borrow.data[cell_idx] = cell_info;
+            self.sheet.borrow_mut().data[cell_idx] = cell_info;
+        }
+        self.trace_pass(before);
+    }
+    /// Adds the volatile cells not yet in the topological order, with their dependents,
+    /// and draws the seed their random numbers come from in this recalculation.
+    fn stage_volatile(&mut self) {
+        if self.volatile.is_empty() {
+            return;
+        }
+        let no_references = CellInfo::default();
+        for cell in self.volatile.clone() {
+            if self.sheet.borrow().data[cell].info.visit == VisitStatus::NotVisited as u8 {
+                // The graph holds no cycle, so the walk cannot fail
+                let _ = self.iterative_dfs(cell as i32, &no_references);
+            }
+        }
+        self.draw_volatile_seed();
+    }
+    /// Draws a new seed for the random numbers of the next recalculation from the sheet's
+    /// generator, if any cell calls a volatile function.
+    fn draw_volatile_seed(&mut self) {
+        if !self.volatile.is_empty() {
+            let mut sheet = self.sheet.borrow_mut();
+            sheet.volatile_seed = sheet.rng.next_u64();
+        }
+    }
+    /// Recomputes the volatile cells and their dependents, e.g. after `seed <n>`; in
+    /// manual mode only the volatile cells themselves.
+    pub fn recompute_volatile(&mut self) {
+        let cells: Vec<usize> = self.volatile.iter().copied().collect();
+        self.finish_update(&cells);
+    }
+    /// Returns whether recomputation passes are reported to a trace or subscribers.
+    fn watched(&self) -> bool {
+        self.trace.is_some() || !self.subscribers.is_empty()
+    }
+    /// Remembers how a cell was before an edit overwrote it, so the next pass reports
+    /// its old value rather than the blank the edit started from.
+    pub fn remember(&mut self, cell: usize, before: CellInfo) {
+        if self.watched() {
+            self.overwritten.entry(cell).or_insert(before);
+        }
+    }
+    /// Returns how a cell was before the pass about to recompute it: as remembered, or
+    /// as it is now.
+    fn state_before(&mut self, cell: usize) -> CellInfo {
+        self.overwritten
+            .remove(&cell)
+            .unwrap_or_else(|| self.sheet.borrow().data[cell])
+    }
+    /// Returns `cells` as they are before a pass recomputes them, in the same order, or
+    /// `None` if nothing watches the pass.
+    fn trace_before(&mut self, cells: &[usize]) -> Option<Vec<(usize, CellInfo)>> {
+        self.watched().then(|| {
+            cells
+                .iter()
+                .map(|&cell| (cell, self.state_before(cell)))
+                .collect()
+        })
+    }
+    /// Reports a finished pass to the trace and the subscribers, given the cells from
+    /// `trace_before`.
+    fn trace_pass(&mut self, before: Option<Vec<(usize, CellInfo)>>) {
+        self.overwritten.clear();
+        let Some(before) = before else {
+            return;
+        };
+        if let Some(trace) = self.trace.as_mut() {
+            trace.record(&self.sheet.borrow(), &before);
+        }
+        if self.subscribers.is_empty() {
+            return;
+        }
+        // The borrow ends before the callbacks run, so they may read the sheet
+        let change = CellChange::from_pass(&self.sheet.borrow(), &before);
+        if let Some(change) = change {
+            for subscriber in &mut self.subscribers {
+                subscriber(change.clone());
+            }
+        }
+    }
+    /// Splits a topological order into levels: each cell is one level deeper than the
+    /// deepest cell of the order it reads, so no cell reads another of its level. Cells
+    /// keep their relative order within a level.
+    fn levels(&self, order: &[usize]) -> Vec<Vec<usize>> {
+        let mut depth: HashMap<usize, usize> = order.iter().map(|&c| (c, 0)).collect();
+        let mut levels: Vec<Vec<usize>> = Vec::new();
+        for &u in order {
+            // Every cell u reads came earlier in the order, so its depth is final
+            let d = depth[&u];
+            if d == levels.len() {
+                levels.push(Vec::new());
+            }
+            levels[d].push(u);
+            for v in self.direct_dependents(u) {
+                if let Some(dv) = depth.get_mut(&v) {
+                    *dv = (*dv).max(d + 1);
+                }
+            }
+        }
+        levels
+    }
+    /// Recomputes the cells of one level, which do not read each other.
+    ///
+    /// Every thread shares one immutable borrow of the sheet and evaluates its own chunk of
+    /// the level into a buffer; the results are written back once all of them finished.
+    fn update_level(&mut self, level: &[usize]) {
+        let results: Vec<(CellInfo, Option<Duration>)> = {
+            let guard = self.sheet.borrow();
+            let sheet: &crate::sheet::Sheet = &guard;
+            let functions = &self.functions;
+            let compute = |&cell: &usize| {
+                let mut cell_info = sheet.data[cell];
+                let elapsed = apply_in_sheet(&mut cell_info, cell, sheet, functions);
+                (cell_info, elapsed)
+            };
+            if level.len() < PARALLEL_MIN_CELLS {
+                level.iter().map(compute).collect()
+            } else {
+                let chunk = level.len().div_ceil(self.threads);
+                thread::scope(|scope| {
+                    let handles: Vec<_> = level
+                        .chunks(chunk)
+                        .map(|cells| scope.spawn(move || cells.iter().map(compute).collect()))
+                        .collect();
+                    handles
+                        .into_iter()
+                        .flat_map(|handle| -> Vec<(CellInfo, Option<Duration>)> {
+                            handle.join().unwrap_or_else(|e| panic::resume_unwind(e))
+                        })
+                        .collect()
+                })
+            }
+        };
+        let mut sheet = self.sheet.borrow_mut();
+        for (&cell, (cell_info, elapsed)) in level.iter().zip(results) {
+            sheet.data[cell] = cell_info;
+            self.profile.record(cell, elapsed);
         }
     }
+    /// Recomputes every cell of the sheet once, each after the cells it reads.
+    ///
+    /// Used when a setting that changes what formulas compute, such as the sheet's
+    /// `OverflowPolicy`, changes, and by `recalc` in manual mode. Kahn's algorithm over
+    /// the whole sheet; the graph never holds a cycle, so every cell is reached.
+    pub fn recompute_all(&mut self) {
+        self.stale = false;
+        self.draw_volatile_seed();
+        let n_cells = self.adj_list.len();
+        let mut indegree = vec![0usize; n_cells];
+        for u in 0..n_cells {
+            for v in self.direct_dependents(u) {
+                indegree[v] += 1;
+            }
+        }
+        let mut ready: VecDeque<usize> = (0..n_cells).filter(|&c| indegree[c] == 0).collect();
+        let mut before = self.watched().then(Vec::new);
+        while let Some(u) = ready.pop_front() {
+            let mut cell_info = self.sheet.borrow().data[u];
+            if let Some(before) = &mut before {
+                before.push((u, self.state_before(u)));
+            }
+            if !cell_info.literal_mode {
+                let elapsed = apply_registered(&mut cell_info, u, &self.sheet, &self.functions);
+                self.profile.record(u, elapsed);
+            }
+            self.sheet.borrow_mut().data[u] = cell_info;
+            for v in self.direct_dependents(u) {
+                indegree[v] -= 1;
+                if indegree[v] == 0 {
+                    ready.push_back(v);
+                }
+            }
+        }
+        self.trace_pass(before);
+    }
     /// Updates a cell's expression and its dependency graph.
     ///
-    /// Returns `Err(StatusCode::CyclicDep)` if a cycle is detected.
+    /// Returns `Err(UpdateError::Cycle(path))` if the formula would close a cycle, or
+    /// `Err(UpdateError::Locked(cell))` if the cell is locked, leaving the graph and sheet
+    /// unchanged.
     // Main function to update an expression and its dependencies
-    pub fn update_expression(&mut self, cell: usize, info: &Info) -> Result<(), StatusCode> {
+    ///
+    /// In `CalcMode::Manual` only the cell itself is computed; its dependents keep their
+    /// values until `recompute_all`.
+    ///
+    /// Inside a batch the write is only queued, replacing any earlier write to the cell,
+    /// and always succeeds; `commit_batch` reports its errors.
+    pub fn update_expression(&mut self, cell: usize, info: &Info) -> Result<(), UpdateError> {
+        if let Some(batch) = self.batch.as_mut() {
+            batch.retain(|&(queued, _)| queued != cell);
+            batch.push((cell, *info));
+            return Ok(());
+        }
+        self.stage_expression(cell, info)?;
+        self.finish_update(&[cell]);
+        Ok(())
+    }
+    /// Recomputes what an update staged and resets the traversal state: everything in
+    /// the topological order, or in manual mode only the `written` cells.
+    fn finish_update(&mut self, written: &[usize]) {
+        match self.calc {
+            CalcMode::Auto => self.update_values(),
+            CalcMode::Manual => {
+                self.draw_volatile_seed();
+                let before = self.trace_before(written);
+                for &cell in written {
+                    let mut cell_info = self.sheet.borrow().data[cell];
+                    if !cell_info.literal_mode {
+                        let elapsed =
+                            apply_registered(&mut cell_info, cell, &self.sheet, &self.functions);
+                        self.profile.record(cell, elapsed);
+                    }
+                    self.sheet.borrow_mut().data[cell] = cell_info;
+                }
+                self.trace_pass(before);
+                self.stale = true;
+            }
+        }
+        self.reset();
+    }
+    /// Replaces a cell's expression and its edges and leaves the cells to recompute in
+    /// the topological order, without recomputing them: the first half of
+    /// `update_expression`, which `update_values` and `reset` complete.
+    ///
+    /// Returns the errors of `update_expression`, already reset.
+    pub fn stage_expression(&mut self, cell: usize, info: &Info) -> Result<(), UpdateError> {
+        if self.sheet.borrow().is_locked(cell) {
+            return Err(UpdateError::Locked(cell));
+        }
         let new_info = &mut CellInfo {
             info: info.clone(),
-            value: 0,
+            value: 0.0,
             literal_mode: false,
         };
 
-        if !self.iterative_dfs(cell as i32, new_info) {
+        if let Err(path) = self.iterative_dfs(cell as i32, new_info) {
             // Cycle detected
             self.reset();
-            return Err(StatusCode::CyclicDep);
+            return Err(UpdateError::Cycle(path));
         }
 
         // No cycles, proceed with updates
+        if let Some(journal) = self.journal.as_mut() {
+            journal.push((cell, self.sheet.borrow().data[cell]));
+        }
+        let original = self.sheet.borrow().data[cell];
+        self.remember(cell, original);
         self.delete_expression(cell as i32);
         self.add_expression(cell as i32, new_info);
 
@@ -304,9 +864,90 @@ impl Graph {
             let mut sheet_borrow = self.sheet.borrow_mut();
             sheet_borrow.data[cell] = new_info.clone();
         }
+        self.cell_log.record(cell, &original, new_info);
 
-        self.update_values();
-        self.reset();
+        Ok(())
+    }
+    /// Starts a batch: until `commit_batch`, `update_expression` queues its writes instead
+    /// of applying them, so the sheet keeps its old cells and values meanwhile.
+    pub fn begin_batch(&mut self) {
+        self.batch = Some(Vec::new());
+    }
+    /// Ends the batch and applies its writes with `update_expressions`: checked for
+    /// cycles together and recomputed once. On error nothing queued is applied.
+    pub fn commit_batch(&mut self) -> Result<(), UpdateError> {
+        match self.batch.take() {
+            Some(writes) if !writes.is_empty() => self.update_expressions(&writes),
+            _ => Ok(()),
+        }
+    }
+    /// Updates the expressions of several distinct cells at once, like `update_expression`
+    /// but checking for cycles and recomputing only once, after every edge was replaced.
+    ///
+    /// Writes that only make sense together, such as rows trading places, therefore never
+    /// see each other half done. Returns `Err(UpdateError::Cycle(path))` if the new
+    /// formulas close a cycle, or `Err(UpdateError::Locked(cell))` if any cell written is
+    /// locked, leaving the graph and sheet unchanged.
+    pub fn update_expressions(&mut self, writes: &[(usize, Info)]) -> Result<(), UpdateError> {
+        if let Some(&(cell, _)) = writes
+            .iter()
+            .find(|&&(cell, _)| self.sheet.borrow().is_locked(cell))
+        {
+            return Err(UpdateError::Locked(cell));
+        }
+        let originals: Vec<(usize, CellInfo)> = {
+            let sheet = self.sheet.borrow();
+            writes
+                .iter()
+                .map(|&(cell, _)| (cell, sheet.data[cell]))
+                .collect()
+        };
+        let replace = |graph: &mut Self, cells: &[(usize, CellInfo)]| {
+            for &(cell, _) in cells {
+                graph.delete_expression(cell as i32);
+            }
+            for (cell, cell_info) in cells {
+                graph.sheet.borrow_mut().data[*cell] = *cell_info;
+                graph.add_expression(*cell as i32, cell_info);
+            }
+        };
+        let written: Vec<(usize, CellInfo)> = writes
+            .iter()
+            .map(|&(cell, mut info)| {
+                info.visit = VisitStatus::NotVisited as u8;
+                let cell_info = CellInfo {
+                    info,
+                    value: 0.0,
+                    literal_mode: false,
+                };
+                (cell, cell_info)
+            })
+            .collect();
+        replace(self, &written);
+
+        // Every new edge is in place, so a cycle shows up as a walk back onto the stack.
+        // Each walk adds to the order; cells an earlier walk reached are already in it.
+        let no_references = CellInfo::default();
+        for &(cell, _) in writes {
+            if self.sheet.borrow().data[cell].info.visit != VisitStatus::NotVisited as u8 {
+                continue;
+            }
+            if let Err(path) = self.iterative_dfs(cell as i32, &no_references) {
+                self.reset();
+                replace(self, &originals);
+                return Err(UpdateError::Cycle(path));
+            }
+        }
+
+        for (&(cell, original), (_, written)) in originals.iter().zip(&written) {
+            self.remember(cell, original);
+            self.cell_log.record(cell, &original, written);
+        }
+        if let Some(journal) = self.journal.as_mut() {
+            journal.extend(originals);
+        }
+        let cells: Vec<usize> = writes.iter().map(|&(cell, _)| cell).collect();
+        self.finish_update(&cells);
 
         Ok(())
     }
@@ -321,17 +962,15 @@ static mut GRAPH: Option<Graph> = None;
 pub fn init_graph() {
     unsafe {
         let sheet = Rc::new(RefCell::new(crate::sheet::Sheet::new(0, 0)));
-        let mem_pool = Rc::new(RefCell::new(ListMemPool::new()));
         GRAPH = Some(Graph::new(
             crate::sheet::N_MAX(),
             crate::sheet::M_MAX(),
             sheet,
-            mem_pool,
         ));
     }
 }
 /// Public wrapper to update expression using an external graph instance.
-pub fn update_expression(graph: &mut Graph, cell: usize, info: &Info) -> Result<(), StatusCode> {
+pub fn update_expression(graph: &mut Graph, cell: usize, info: &Info) -> Result<(), UpdateError> {
     graph.update_expression(cell, info)
 }
 
@@ -344,8 +983,7 @@ mod tests {
 
     fn create_test_graph() -> Graph {
         let sheet = Rc::new(RefCell::new(Sheet::new(3, 3)));
-        let mem_pool = Rc::new(RefCell::new(ListMemPool::new()));
-        Graph::new(3, 3, sheet, mem_pool)
+        Graph::new(3, 3, sheet)
     }
 
     fn create_cell_info(function_id: u8, arg: [i32; 2], arg_mask: u8) -> CellInfo {
@@ -372,7 +1010,26 @@ mod tests {
 
         let info = graph.sheet.borrow().data[cell_idx].info.clone();
         let result = graph.update_expression(cell_idx, &info);
-        assert_eq!(result, Err(StatusCode::CyclicDep));
+        assert_eq!(result, Err(UpdateError::Cycle(vec![cell_idx, cell_idx])));
+        assert_eq!(result.unwrap_err().status_code(), StatusCode::CyclicDep);
+    }
+
+    #[test]
+    fn test_cycle_path_follows_references() {
+        let mut graph = create_test_graph();
+        // B1 = A1 + 1, C1 = B1 + 1, then A1 = C1 + 1 closes A1 -> C1 -> B1 -> A1
+        graph
+            .update_expression(1, &create_cell_info(2, [0, 1], 0b1).info)
+            .unwrap();
+        graph
+            .update_expression(2, &create_cell_info(2, [1, 1], 0b1).info)
+            .unwrap();
+        let result = graph.update_expression(0, &create_cell_info(2, [2, 1], 0b1).info);
+        assert_eq!(result, Err(UpdateError::Cycle(vec![0, 2, 1, 0])));
+
+        // The rejected formula left nothing behind
+        assert_eq!(graph.sheet.borrow().data[0].info.function_id, 0);
+        assert!(graph.transitive_dependents(2).is_empty());
     }
 
     #[test]
@@ -409,6 +1066,93 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    fn recompute_sequence(graph: &mut Graph, cell: usize) -> Vec<i32> {
+        let info = graph.sheet.borrow().data[cell].clone();
+        assert!(graph.iterative_dfs(cell as i32, &info).is_ok());
+        let order = graph.recompute_order().to_vec();
+        graph.reset();
+        order
+    }
+
+    #[test]
+    fn test_recalc_order_among_independent_cells() {
+        let mut graph = create_test_graph();
+        // B1, A2 and A3 depend only on A1; B2 depends on A3.
+        for (cell, source) in [(1, 0), (3, 0), (6, 0), (4, 6)] {
+            let info = create_cell_info(2, [source, 0], 0b1).info;
+            graph.update_expression(cell, &info).unwrap();
+        }
+
+        graph.recalc_order = RecalcOrder::RowMajor;
+        assert_eq!(recompute_sequence(&mut graph, 0), vec![0, 1, 3, 6, 4]);
+        graph.recalc_order = RecalcOrder::ColMajor;
+        assert_eq!(recompute_sequence(&mut graph, 0), vec![0, 3, 6, 1, 4]);
+    }
+
+    #[test]
+    fn test_adj_list_walks_newest_first() {
+        let mut list = AdjList::default();
+        for cell in [4, 7, 4, 9] {
+            list.push(cell);
+        }
+        assert_eq!(list.iter().collect::<Vec<_>>(), vec![9, 4, 7, 4]);
+
+        // Erasing removes the newest duplicate
+        assert!(list.erase(4));
+        assert!(!list.erase(5));
+        assert_eq!(list.iter().collect::<Vec<_>>(), vec![9, 7, 4]);
+    }
+
+    #[test]
+    fn test_range_index_stores_ranges_compactly() {
+        let mut ranges = RangeIndex::new(1000, 26);
+        // A1:Z1000 is stored once per column, A1:C2 once per row
+        ranges.insert((0, 0), (999, 25), 7);
+        ranges.insert((0, 0), (1, 2), 8);
+        assert_eq!(ranges.len(), 26 + 2);
+        let dependents = |ranges: &RangeIndex, cell| ranges.dependents(cell).collect::<Vec<_>>();
+        assert_eq!(dependents(&ranges, 26 + 2), vec![8, 7]); // C2
+        assert_eq!(dependents(&ranges, 26 * 2 + 2), vec![7]); // C3
+        assert_eq!(dependents(&ranges, 26 * 999 + 25), vec![7]); // Z1000
+
+        // The same range read twice is two records, removed one at a time
+        ranges.insert((0, 0), (1, 2), 8);
+        ranges.remove((0, 0), (1, 2), 8);
+        assert_eq!(dependents(&ranges, 0), vec![8, 7]);
+        ranges.remove((0, 0), (1, 2), 8);
+        ranges.remove((0, 0), (999, 25), 7);
+        assert!(ranges.is_empty());
+    }
+
+    #[test]
+    fn test_deeply_nested_range_recompute_does_not_panic() {
+        const ROWS: usize = 300;
+        let sheet = Rc::new(RefCell::new(Sheet::new(ROWS, 2)));
+        let mut graph = Graph::new(ROWS, 2, sheet);
+        let cell = |r: usize, c: usize| (r * 2 + c) as i32;
+
+        // A(i) = MAX(A1:A(i-1)), B(i) = SUM(A(i):B(i-1)) for every row below the first
+        graph
+            .update_expression(0, &create_cell_info(0, [1, 0], 0).info)
+            .unwrap();
+        for r in 1..ROWS {
+            let max = create_cell_info(6, [cell(0, 0), cell(r - 1, 0)], 0b11).info;
+            graph.update_expression(cell(r, 0) as usize, &max).unwrap();
+            let sum = create_cell_info(8, [cell(r - 1, 0), cell(r - 1, 1)], 0b11).info;
+            graph.update_expression(cell(r, 1) as usize, &sum).unwrap();
+        }
+
+        graph
+            .update_expression(0, &create_cell_info(0, [2, 0], 0).info)
+            .unwrap();
+        let sheet = graph.sheet.borrow();
+        assert_eq!(sheet.data[cell(ROWS - 1, 0) as usize].value, 2.0);
+        assert_eq!(
+            sheet.data[cell(ROWS - 1, 1) as usize].value,
+            2.0 * (ROWS as f64 - 1.0)
+        );
+    }
+
     #[test]
     fn test_in_dependency_checks() {
         let graph = create_test_graph();
@@ -428,6 +1172,39 @@ mod tests {
         assert_eq!(graph.order_ptr, 9);
     }
 
+    #[test]
+    fn test_transitive_dependents_branching() {
+        // A1 feeds B1 and C1; B1 feeds A2; C1 and A2 both feed B2; C2 = SUM(A1:A2).
+        let mut graph = create_test_graph();
+        let (a1, b1, c1, a2, b2, c2, a3) = {
+            let sheet = graph.sheet.borrow();
+            (
+                sheet.get_cell(0, 0),
+                sheet.get_cell(0, 1),
+                sheet.get_cell(0, 2),
+                sheet.get_cell(1, 0),
+                sheet.get_cell(1, 1),
+                sheet.get_cell(1, 2),
+                sheet.get_cell(2, 0),
+            )
+        };
+        let edits = [
+            (b1, create_cell_info(2, [a1 as i32, 1], 0b1)),
+            (c1, create_cell_info(4, [a1 as i32, 2], 0b1)),
+            (a2, create_cell_info(0, [b1 as i32, 0], 0b1)),
+            (b2, create_cell_info(2, [c1 as i32, a2 as i32], 0b11)),
+            (c2, create_cell_info(8, [a1 as i32, a2 as i32], 0b11)),
+        ];
+        for (cell, cell_info) in edits {
+            graph.update_expression(cell, &cell_info.info).unwrap();
+        }
+
+        assert_eq!(graph.transitive_dependents(a1), vec![b1, c1, a2, b2, c2]);
+        assert_eq!(graph.transitive_dependents(c1), vec![b2]);
+        assert_eq!(graph.transitive_dependents(a2), vec![b2, c2]);
+        assert!(graph.transitive_dependents(a3).is_empty());
+    }
+
     #[test]
     fn test_dependency_management() {
         let mut graph = create_test_graph();
@@ -440,9 +1217,9 @@ mod tests {
 
         let cell_data = graph.sheet.borrow().data[cell_idx].clone();
         graph.add_expression(cell_idx as i32, &cell_data);
-        assert!(graph.adj_list[1].head.is_some());
+        assert_eq!(graph.direct_dependents(1), vec![cell_idx]);
 
         graph.delete_expression(cell_idx as i32);
-        assert!(graph.adj_list[1].head.is_none());
+        assert!(graph.adj_list[1].cells.is_empty());
     }
 }
@@ -0,0 +1,213 @@
+// history.rs
+//! This module holds the undo/redo history of the spreadsheet engine.
+//!
+//! Each undoable step is a `Transaction`: the command that made it and the state of every
+//! cell it changed, as it was before. An assignment changes one cell, while bulk commands
+//! such as `clear A1:C5` change many and are undone as a single step. The undo stack keeps
+//! at most `max_depth` steps and drops the oldest ones first.
+use std::collections::VecDeque;
+
+use crate::info::Info;
+use crate::sheet::Sheet;
+
+/// Number of undoable steps kept until `set history_depth` changes it.
+pub const DEFAULT_HISTORY_DEPTH: usize = 100;
+/// Number of cells the `history` listing names per step before summarizing the rest.
+const LISTED_CELLS: usize = 3;
+
+/// The saved state of one cell.
+#[derive(Debug, Clone, Copy)]
+pub struct CellState {
+    /// The cell index where the change occurred.
+    pub cell_idx: usize,
+    /// Information about the command execution.
+    pub info: Info,
+    /// The previous value before the change.
+    pub value: f64,
+    /// Whether literal mode was enabled.
+    pub literal_mode: bool,
+}
+
+/// One undoable step.
+#[derive(Debug, Clone)]
+pub struct Transaction {
+    /// The command that made the step, as typed.
+    pub command: String,
+    /// The state of each changed cell before the step, in the order the cells changed.
+    /// Undo restores them last to first.
+    pub cells: Vec<CellState>,
+}
+
+/// The undo and redo stacks.
+pub struct History {
+    /// Steps that `undo` reverts, oldest first.
+    undo: VecDeque<Transaction>,
+    /// Steps that `redo` re-applies, most recently undone last.
+    redo: Vec<Transaction>,
+    /// Most steps kept on the undo stack.
+    max_depth: usize,
+}
+
+impl History {
+    /// Creates an empty history keeping at most `max_depth` undoable steps.
+    pub fn new(max_depth: usize) -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            max_depth,
+        }
+    }
+    /// Records a new step. The redo stack is cleared, since it no longer follows from the
+    /// current state.
+    pub fn record(&mut self, transaction: Transaction) {
+        self.redo.clear();
+        self.push_undo(transaction);
+    }
+    /// Pushes a step for `undo` to revert, leaving the redo stack alone.
+    pub fn push_undo(&mut self, transaction: Transaction) {
+        self.undo.push_back(transaction);
+        self.trim();
+    }
+    /// Pushes a reverted step for `redo` to re-apply.
+    pub fn push_redo(&mut self, transaction: Transaction) {
+        self.redo.push(transaction);
+    }
+    /// Removes and returns the most recent step, if any.
+    pub fn pop_undo(&mut self) -> Option<Transaction> {
+        self.undo.pop_back()
+    }
+    /// Removes and returns the most recently reverted step, if any.
+    pub fn pop_redo(&mut self) -> Option<Transaction> {
+        self.redo.pop()
+    }
+    /// Forgets every step, e.g. after the cells they name have moved.
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+    /// Forgets the reverted steps only.
+    pub fn clear_redo(&mut self) {
+        self.redo.clear();
+    }
+    /// Returns the most steps kept on the undo stack.
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+    /// Changes the most steps kept on the undo stack, dropping the oldest ones beyond it.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+        self.trim();
+    }
+    /// Returns the undoable steps, oldest first.
+    pub fn undo_steps(&self) -> impl ExactSizeIterator<Item = &Transaction> {
+        self.undo.iter()
+    }
+    /// Returns the reverted steps, most recently reverted last.
+    pub fn redo_steps(&self) -> &[Transaction] {
+        &self.redo
+    }
+    /// Replaces both stacks, e.g. with the ones of a loaded session.
+    pub fn replace(&mut self, undo: Vec<Transaction>, redo: Vec<Transaction>) {
+        self.undo = undo.into();
+        self.redo = redo;
+        self.trim();
+    }
+    /// Builds the `history` listing, one line per undoable step, most recent first, e.g.
+    /// `2: clear A1:B2 (A1, B1, A2 and 1 more)`. Steps that `redo` would re-apply are
+    /// listed after them as `redo: ...`.
+    pub fn report(&self, sheet: &Sheet) -> String {
+        let describe = |transaction: &Transaction| {
+            let names: Vec<String> = transaction
+                .cells
+                .iter()
+                .take(LISTED_CELLS)
+                .map(|state| sheet.cell_name(state.cell_idx))
+                .collect();
+            let rest = transaction.cells.len().saturating_sub(LISTED_CELLS);
+            let mut cells = names.join(", ");
+            if rest > 0 {
+                cells += &format!(" and {} more", rest);
+            }
+            format!("{} ({})", transaction.command, cells)
+        };
+        let undo = self
+            .undo
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(i, transaction)| format!("{}: {}", i + 1, describe(transaction)));
+        let redo = self
+            .redo
+            .iter()
+            .rev()
+            .map(|transaction| format!("redo: {}", describe(transaction)));
+        let lines: Vec<String> = undo.chain(redo).collect();
+        if lines.is_empty() {
+            "no history".to_string()
+        } else {
+            lines.join("\n")
+        }
+    }
+    /// Drops the oldest undoable steps beyond `max_depth`.
+    fn trim(&mut self) {
+        while self.undo.len() > self.max_depth {
+            self.undo.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sheet::{TEST_COLS, TEST_ROWS, init_test_dimensions};
+
+    fn step(command: &str, cells: &[usize]) -> Transaction {
+        Transaction {
+            command: command.to_string(),
+            cells: cells
+                .iter()
+                .map(|&cell_idx| CellState {
+                    cell_idx,
+                    info: Info::default(),
+                    value: 0.0,
+                    literal_mode: false,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_depth_drops_oldest_steps() {
+        let mut history = History::new(2);
+        for command in ["A1=1", "A1=2", "A1=3"] {
+            history.record(step(command, &[0]));
+        }
+        let commands: Vec<&str> = history.undo_steps().map(|t| t.command.as_str()).collect();
+        assert_eq!(commands, ["A1=2", "A1=3"]);
+
+        let undone = history.pop_undo().unwrap();
+        history.push_redo(undone);
+        history.set_max_depth(0);
+        assert!(history.pop_undo().is_none());
+        assert_eq!(history.redo_steps().len(), 1);
+        history.record(step("A1=4", &[0]));
+        assert!(history.redo_steps().is_empty());
+    }
+
+    #[test]
+    fn test_report_lists_recent_steps_first() {
+        init_test_dimensions();
+        let sheet = Sheet::new(TEST_ROWS, TEST_COLS);
+        let mut history = History::new(DEFAULT_HISTORY_DEPTH);
+        assert_eq!(history.report(&sheet), "no history");
+        history.record(step("A1=5", &[0]));
+        history.record(step("clear A1:B2", &[0, 1, TEST_COLS, TEST_COLS + 1]));
+        history.record(step("B1=A1+1", &[1]));
+        let undone = history.pop_undo().unwrap();
+        history.push_redo(undone);
+        assert_eq!(
+            history.report(&sheet),
+            "2: clear A1:B2 (A1, B1, A2 and 1 more)\n1: A1=5 (A1)\nredo: B1=A1+1 (B1)"
+        );
+    }
+}
@@ -1,21 +1,102 @@
 // info.rs
 //! This module defines various structs for handling command execution and cell data.
+use std::sync::Arc;
+
+use crate::autosave::Every;
+use crate::expr::{self, Expr};
+use crate::formulas::{is_position_function, is_range_function, is_volatile_function};
+use crate::graph::{CalcMode, RecalcOrder};
+use crate::sheet::{Axis, CellRole, FormatChange, LineEdit, OverflowPolicy};
+use crate::text;
+
+/// Flag of a reference whose column is absolute, as in `$A1`. Copies and fills keep
+/// that part of the reference fixed.
+pub const FIXED_COL: u8 = 0b01;
+/// Flag of a reference whose row is absolute, as in `A$1`.
+pub const FIXED_ROW: u8 = 0b10;
+
+/// Why a cell has no value. Each kind is shown as its own marker in place of the value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CellError {
+    /// A division by zero, including `MOD` by zero and `HARMEAN` over a zero.
+    DivByZero,
+    /// The formula read a cell that has since been deleted.
+    BadRef,
+    /// A step has no numeric result, e.g. `FACT(-1)`, `POW(-8,0.5)` or arithmetic on text.
+    Value,
+    /// The result is too large to represent (under `OverflowPolicy::Error`).
+    Overflow,
+    /// The formula closed a dependency cycle when it was loaded.
+    Cycle,
+    /// A cell the formula reads is itself an error.
+    Propagated,
+    /// `MATCH` or `VLOOKUP` did not find the value it looked for.
+    NotFound,
+}
+impl CellError {
+    /// Every kind, in the order of their codes.
+    const ALL: [CellError; 7] = [
+        CellError::DivByZero,
+        CellError::BadRef,
+        CellError::Value,
+        CellError::Overflow,
+        CellError::Cycle,
+        CellError::Propagated,
+        CellError::NotFound,
+    ];
+    /// Returns the marker shown in place of the value. Errors of the cell itself start
+    /// with `#`; an error inherited from a precedent is a plain `ERR`.
+    pub fn marker(self) -> &'static str {
+        match self {
+            CellError::DivByZero => "#DIV/0",
+            CellError::BadRef => "#REF",
+            CellError::Value => "#VALUE",
+            CellError::Overflow => "#OVER",
+            CellError::Cycle => "#CYCLE",
+            CellError::Propagated => "ERR",
+            CellError::NotFound => "#N/A",
+        }
+    }
+    /// Returns the kind whose marker is `marker`, if any.
+    pub fn from_marker(marker: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.marker() == marker)
+    }
+    /// Returns the number stored for the kind in error literals and session files.
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+    /// Returns the kind stored as `code`, if any.
+    pub fn from_code(code: u8) -> Option<Self> {
+        Self::ALL.get(code as usize).copied()
+    }
+}
 
 /// Stores metadata for a command or operation.
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
 pub struct Info {
     /// Number of times this operation has been visited (used for graph traversal).
     pub visit: u8,
     /// Bitmask representing whether arguments are cells.
     pub arg_mask: u8,
-    /// Indicates if the command is invalid.
-    pub invalid: bool,
+    /// Why the cell has no value, or `None` if it is valid.
+    pub error: Option<CellError>,
     /// The function identifier.
     pub function_id: u8,
     /// Arguments related to the command.
     pub arg: [i32; 2],
+    /// The absolute parts of the cell arguments (or range corners): `FIXED_COL` and
+    /// `FIXED_ROW` shifted left by `2 * i` for argument `i`.
+    pub fixed: u8,
 }
 impl Info {
+    /// Returns `true` if the cell has no value (see `error`).
+    pub fn invalid(&self) -> bool {
+        self.error.is_some()
+    }
+    /// Returns the `FIXED_COL`/`FIXED_ROW` flags of argument `i`.
+    pub fn fixed(&self, i: usize) -> u8 {
+        (self.fixed >> (2 * i)) & 0b11
+    }
     /// Checks if the first argument is a cell reference
     pub fn is_cell_arg1(&self) -> bool {
         self.arg_mask & 0b1 != 0
@@ -28,14 +109,187 @@ impl Info {
     pub fn is_cell_both(&self) -> bool {
         self.arg_mask == 0b11
     }
+    /// Builds the `Info` of a plain literal assignment such as `A1=42`.
+    pub fn literal(value: i32) -> Self {
+        Info {
+            arg: [value, 0],
+            ..Default::default()
+        }
+    }
+    /// Builds the `Info` of a literal number.
+    ///
+    /// Whole numbers that fit in an `i32` become plain literals. Any other value becomes a
+    /// constant (function 15) whose `f64` bits are split across both arguments, low half
+    /// first.
+    pub fn number(value: f64) -> Self {
+        if value.fract() == 0.0 && value >= i32::MIN as f64 && value <= i32::MAX as f64 {
+            return Info::literal(value as i32);
+        }
+        let bits = value.to_bits();
+        Info {
+            function_id: 15,
+            arg: [bits as u32 as i32, (bits >> 32) as u32 as i32],
+            ..Default::default()
+        }
+    }
+    /// Returns the number of a plain literal or a constant, or `None` for a formula.
+    pub fn literal_value(&self) -> Option<f64> {
+        match (self.function_id, self.arg_mask) {
+            (0, 0) => Some(self.arg[0] as f64),
+            (15, 0) => {
+                let bits = (self.arg[0] as u32 as u64) | ((self.arg[1] as u32 as u64) << 32);
+                Some(f64::from_bits(bits))
+            }
+            _ => None,
+        }
+    }
+    /// Builds the `Info` of a text cell such as `A1="Revenue"` (function 16), holding the
+    /// ID of the interned string.
+    pub fn text(text: &str) -> Self {
+        Info {
+            function_id: 16,
+            arg: [text::intern(text), 0],
+            ..Default::default()
+        }
+    }
+    /// Returns the string of a text cell, or `None` for any other `Info`.
+    pub fn text_value(&self) -> Option<Arc<str>> {
+        match self.function_id {
+            16 => text::lookup(self.arg[0]),
+            _ => None,
+        }
+    }
+    /// Builds the `Info` of a nested formula (function 17), holding the ID of its
+    /// interned expression tree.
+    pub fn expression(expr: Expr) -> Self {
+        Info {
+            function_id: 17,
+            arg: [expr::intern(expr), 0],
+            ..Default::default()
+        }
+    }
+    /// Returns the tree of a nested formula, or `None` for any other `Info`.
+    pub fn expression_value(&self) -> Option<Arc<Expr>> {
+        match self.function_id {
+            17 => expr::lookup(self.arg[0]),
+            _ => None,
+        }
+    }
+    /// Returns `true` if the formula calls `ROW` or `COLUMN`, whose value depends on where
+    /// cells are rather than what they hold.
+    pub fn reads_position(&self) -> bool {
+        if let Some(expr) = self.expression_value() {
+            let mut found = false;
+            expr.visit(&mut |node| {
+                found |= matches!(node, Expr::Call(id, _) if is_position_function(*id));
+            });
+            return found;
+        }
+        is_position_function(self.function_id)
+    }
+    /// Returns `true` if the formula calls `RAND` or `RANDBETWEEN`, so it must be
+    /// recomputed on every recalculation.
+    pub fn is_volatile(&self) -> bool {
+        if let Some(expr) = self.expression_value() {
+            let mut found = false;
+            expr.visit(&mut |node| match node {
+                Expr::Call(id, _) | Expr::Binary(id, ..) => found |= is_volatile_function(*id),
+                _ => {}
+            });
+            return found;
+        }
+        is_volatile_function(self.function_id)
+    }
+    /// Returns the cells and ranges the formula reads as pairs of corners: the range of
+    /// a range function, every reference of a nested formula, or the cell arguments. A
+    /// single cell `c` is the range `(c, c)`.
+    pub fn references(&self) -> Vec<(usize, usize)> {
+        if is_range_function(self.function_id) {
+            return vec![(self.arg[0] as usize, self.arg[1] as usize)];
+        }
+        if let Some(expr) = self.expression_value() {
+            return expr.ranges();
+        }
+        (0..2)
+            .filter(|&i| self.arg_mask & (1 << i) != 0)
+            .map(|i| (self.arg[i] as usize, self.arg[i] as usize))
+            .collect()
+    }
+    /// Returns the formula with every cell and range it reads rewritten through `f`, which
+    /// maps the corners of a range (a cell `c` being the range `(c, c)`) or returns `None`
+    /// if the range no longer exists.
+    ///
+    /// Returns `None` if `f` removed any of them, since the formula would then read the
+    /// wrong cells.
+    pub fn map_ranges<F>(&self, mut f: F) -> Option<Info>
+    where
+        F: FnMut(usize, usize) -> Option<(usize, usize)>,
+    {
+        let mut info = *self;
+        if let Some(expr) = self.expression_value() {
+            info.arg[0] = expr::intern(expr.map_ranges(&mut f)?);
+        } else if is_range_function(self.function_id) {
+            let (start, end) = f(self.arg[0] as usize, self.arg[1] as usize)?;
+            info.arg = [start as i32, end as i32];
+        } else {
+            for i in (0..2).filter(|&i| self.arg_mask & (1 << i) != 0) {
+                let cell = self.arg[i] as usize;
+                info.arg[i] = f(cell, cell)?.0 as i32;
+            }
+        }
+        Some(info)
+    }
+    /// Builds an error literal (function 23) that always evaluates to the error `kind`,
+    /// whose code it holds, so the kind survives recomputation. It starts out invalid.
+    pub fn error(kind: CellError) -> Self {
+        Info {
+            function_id: 23,
+            arg: [kind.code() as i32, 0],
+            error: Some(kind),
+            ..Default::default()
+        }
+    }
+    /// Returns the kind of an error literal, or `None` for any other `Info`.
+    pub fn error_value(&self) -> Option<CellError> {
+        match self.function_id {
+            23 => CellError::from_code(self.arg[0] as u8),
+            _ => None,
+        }
+    }
 }
 /// Represents information stored in a spreadsheet cell.
-#[derive(Debug, Clone, Copy, Default)]
+///
+/// A cell holds either a number or, when built from `Info::text`, a text label. Text has
+/// no numeric value: a text cell is always a `CellError::Value` error, so any formula
+/// reading it is an error, and only the renderers show its string.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
 pub struct CellInfo {
     pub info: Info,
-    pub value: i32,
+    /// The computed value. Literals are whole numbers, formulas such as `AVG` or `/` may
+    /// produce fractions.
+    pub value: f64,
     pub literal_mode: bool,
 }
+impl CellInfo {
+    /// Returns the label of a text cell, or `None` for a number or error.
+    pub fn text(&self) -> Option<Arc<str>> {
+        self.info.text_value()
+    }
+    /// Returns `true` if the cell was never assigned, or holds what an unassigned cell
+    /// holds: the literal `0`.
+    pub fn is_blank(&self) -> bool {
+        let info = self.info;
+        info.function_id == 0 && info.arg_mask == 0 && info.arg[0] == 0 && self.value == 0.0
+    }
+    /// Returns `true` if the cell is an error because its own result overflowed, rather
+    /// than because of an invalid argument, a division by zero or the like.
+    ///
+    /// Under `OverflowPolicy::Error` an overflowing formula also keeps the infinite result
+    /// as its value, which no valid cell can hold.
+    pub fn overflowed(&self) -> bool {
+        self.info.error == Some(CellError::Overflow)
+    }
+}
 /// Represents a value and whether it's a cell reference.
 #[derive(Debug, Clone, Copy, Default)]
 pub struct ValueInfo {
@@ -49,6 +303,249 @@ pub struct CommandInfo {
     pub info: Info,
 }
 
+/// A bulk command that reads a range of cells and writes literal results back.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// `CUMPCT A1:A10`: replaces a column with the cumulative percentage of its running total.
+    CumulativePercent { start: usize, end: usize },
+    /// `why A1`: traces an error back through the cell's precedents to its root cause.
+    Why { cell: usize },
+    /// `impact A1`: lists every cell that would recompute if the cell changed.
+    Impact { cell: usize },
+    /// `deps A1 [--tree]`: lists the cells A1 reads and the cells that read A1, or with
+    /// `--tree` the whole chain in both directions, indented by depth.
+    Deps { cell: usize, tree: bool },
+    /// `add A1 5`, `sub A1 5`, `mul A1 5`: adjusts a literal cell in place.
+    ///
+    /// `function_id` is the matching arithmetic formula id (2 = add, 3 = sub, 4 = mul).
+    Adjust {
+        cell: usize,
+        function_id: u8,
+        amount: i32,
+    },
+    /// `set recalc_order rowmajor|colmajor|dfs`: chooses how independent cells are ordered
+    /// during recomputation.
+    SetRecalcOrder(RecalcOrder),
+    /// `set_calc manual|auto`: in manual mode edits only compute the cells written and
+    /// leave their dependents for `recalc`; going back to auto recomputes what is pending.
+    SetCalc(CalcMode),
+    /// `recalc`: recomputes every cell in one topological pass.
+    Recalc,
+    /// `profile top 10`: lists the cells whose formulas took the most time, with how
+    /// often each ran.
+    ProfileTop(usize),
+    /// `profile reset`: forgets the recalculation statistics gathered so far.
+    ProfileReset,
+    /// `set overflow_policy error|saturate`: chooses how formulas handle results too large
+    /// to represent, recomputing every formula under the new policy.
+    SetOverflowPolicy(OverflowPolicy),
+    /// `set threads 4`: evaluates large independent parts of a recomputation on that many
+    /// threads; 1 (the default) evaluates serially.
+    SetThreads(usize),
+    /// `MOVAVG A1:A20 3 [B1] [shrink]`: writes the `window`-cell moving average of a column
+    /// down from `target` (the range itself by default).
+    ///
+    /// The first `window - 1` results are errors, or averages over the shorter window
+    /// available so far when `shrink` is set.
+    MovingAverage {
+        start: usize,
+        end: usize,
+        window: usize,
+        target: usize,
+        shrink: bool,
+    },
+    /// `WMOVAVG A1:A20 B1:B3 [C1]`: writes the weighted moving average of a column down
+    /// from `target` (the range itself by default).
+    ///
+    /// The weight range `weights_start:weights_end` sets the window size; its first weight
+    /// applies to the oldest cell of each window. The first `window - 1` results are errors.
+    WeightedMovingAverage {
+        start: usize,
+        end: usize,
+        weights_start: usize,
+        weights_end: usize,
+        target: usize,
+    },
+    /// `DELTA A1:A10 [B1]`: writes the differences between consecutive cells of a column
+    /// down from `target` (the range itself by default).
+    Delta {
+        start: usize,
+        end: usize,
+        target: usize,
+    },
+    /// `normalize_range A1:A10`: rescales a range to 0–100 between its minimum and maximum.
+    Normalize { start: usize, end: usize },
+    /// `shiftrefs A1:C10 down 1`: moves the references inside a region's formulas by a
+    /// row/column delta without moving the cells.
+    ShiftReferences {
+        start: usize,
+        end: usize,
+        rows: isize,
+        cols: isize,
+    },
+    /// `rand A1:C10 0 100` or `genrandom A1:C10 min 0 max 100`: fills a range with random
+    /// integers in `[lo, hi]`, written as literals in one batch.
+    Random {
+        start: usize,
+        end: usize,
+        lo: i32,
+        hi: i32,
+    },
+    /// `replace B7 with C9`: rewrites every reference to one cell, in every formula, into
+    /// a reference to another, keeping its `$` parts.
+    ReplaceReference { from: usize, to: usize },
+    /// `replace_text 100 with 200`: rewrites every whole-number literal equal to `from`, in
+    /// every formula and literal cell, into `to`.
+    ReplaceLiteral { from: i32, to: i32 },
+    /// `clamp_range A1:C10 0 100`: clamps every literal cell of a range into `[lo, hi]`,
+    /// leaving formula cells alone.
+    Clamp {
+        start: usize,
+        end: usize,
+        lo: i32,
+        hi: i32,
+    },
+    /// `clear A1:C5` (or `clear` on the selection): empties every cell of a range.
+    Clear { start: usize, end: usize },
+    /// `sum_selection`: prints the sum of the selected range.
+    SumRange { start: usize, end: usize },
+    /// `save sheet.csv`: writes the sheet to a CSV file, keeping formulas.
+    SaveCsv { path: String },
+    /// `export_json sheet.json`: writes every used cell, with its value, formula and
+    /// format, as a JSON document for other tools.
+    ExportJson { path: String },
+    /// `load sheet.csv`: replaces the sheet's contents with a file written by `save`.
+    LoadCsv { path: String },
+    /// `load_csv data.csv [--at C5]`: writes the fields of a CSV file from another tool
+    /// into the sheet from `at` (A1 by default), as numbers or text, keeping the other
+    /// cells.
+    ImportCsv { path: String, at: usize },
+    /// `save_session work.session`: writes the whole engine state, history included.
+    SaveSession { path: String },
+    /// `load_session work.session`: resumes a session saved by `save_session`.
+    LoadSession { path: String },
+    /// `rotate_sheet cw|ccw`: turns the whole sheet a quarter turn, swapping its row and
+    /// column counts and freezing every formula to its current value.
+    RotateSheet { clockwise: bool },
+    /// `insert_row 5`, `delete_row 5`, `insert_col C`, `delete_col C`: inserts or removes a
+    /// row or column, moving the later ones and rewriting every formula reference.
+    MoveLines { axis: Axis, edit: LineEdit },
+    /// `history`: lists the steps `undo` and `redo` would revert or re-apply.
+    History,
+    /// `set history_depth 50`: sets how many steps `undo` can revert, forgetting the
+    /// oldest ones beyond it.
+    SetHistoryDepth(usize),
+    /// `history A1`: lists every logged edit of a cell with its time and the formula
+    /// before and after, undos and redos included.
+    CellHistory { cell: usize },
+    /// `set cell_history_depth 50`: sets how many edits `history A1` keeps per cell,
+    /// forgetting the oldest ones beyond it.
+    SetCellHistoryDepth(usize),
+    /// `set autosave 30s` / `set autosave 20` / `set autosave off`: saves the session in
+    /// the background every 30 seconds, or every 20 changes, or stops.
+    SetAutosave(Option<Every>),
+    /// `set readonly` / `set noreadonly`: rejects, or accepts again, every command that
+    /// would change the sheet.
+    SetReadOnly(bool),
+    /// `set seed 42` or `seed 42`: reseeds the generator used by `rand` fills and by the
+    /// `RAND`/`RANDBETWEEN` functions, making both reproducible, and recomputes the cells
+    /// calling those.
+    SetSeed(u64),
+    /// `dupregion A1:C3 E1`: copies a rectangle so its top-left corner lands on `target`.
+    DuplicateRegion {
+        start: usize,
+        end: usize,
+        target: usize,
+    },
+    /// `fill A1:A10`: copies the formula or value of the range's top-left cell into the
+    /// rest of the range, shifting its relative references per cell.
+    Fill { start: usize, end: usize },
+    /// `mark_inputs A1:A10` / `mark_outputs D1:D5`: records the role of every cell in a
+    /// range, replacing any earlier role.
+    MarkRole {
+        start: usize,
+        end: usize,
+        role: CellRole,
+    },
+    /// `format A1 bold`, `format A1:B3 color red`: styles every cell of a range in both
+    /// the standard display and vim mode. `format A1 nobold` switches one style off and
+    /// `format A1 reset` removes them all.
+    Format {
+        start: usize,
+        end: usize,
+        change: FormatChange,
+    },
+    /// `io_map`: lists the cells marked as inputs and outputs.
+    IoMap,
+    /// `note A1 "check this figure"`: attaches a note to a cell, replacing any earlier
+    /// one. `note A1 ""` removes it.
+    Note { cell: usize, text: String },
+    /// `notes A1`: prints the note attached to a cell.
+    Notes { cell: usize },
+    /// `lock A1:B10`, `unlock A1`: protects cells from being changed, or lifts that
+    /// protection. A locked cell rejects assignments, fills and undo with `CellLocked`.
+    Lock {
+        start: usize,
+        end: usize,
+        locked: bool,
+    },
+    /// `trace on`, `trace on trace.log`, `trace off`: reports every recomputation pass,
+    /// to standard output or appended to a file, or stops doing so.
+    Trace { enabled: bool, path: Option<String> },
+    /// `sort A1:C20 by B asc`: reorders the rows of a range by the values of one of its
+    /// columns (`asc` unless `desc` is given), moving formulas with their rows.
+    Sort {
+        start: usize,
+        end: usize,
+        key: usize,
+        descending: bool,
+    },
+    /// `ismono A1:A10`: prints whether a column is increasing, decreasing or unordered.
+    Monotonicity { start: usize, end: usize },
+    /// `rangediff A1:A10 B1:B10`: prints the positions where two equal-shaped ranges differ.
+    RangeDiff {
+        start: usize,
+        end: usize,
+        other_start: usize,
+        other_end: usize,
+    },
+    /// `pivot A1:B20 into D1`: sums the second column per distinct key of the first and
+    /// writes the `(key, sum)` rows, sorted by key, down from `target`.
+    Pivot {
+        start: usize,
+        end: usize,
+        target: usize,
+    },
+}
+
+impl Command {
+    /// Returns `false` for commands that only report on the sheet or write it to a file,
+    /// and `true` for those that change its cells or settings, which a journal must keep.
+    pub fn mutates(&self) -> bool {
+        !matches!(
+            self,
+            Command::Why { .. }
+                | Command::Impact { .. }
+                | Command::Deps { .. }
+                | Command::SumRange { .. }
+                | Command::SaveCsv { .. }
+                | Command::ExportJson { .. }
+                | Command::SaveSession { .. }
+                | Command::History
+                | Command::CellHistory { .. }
+                | Command::IoMap
+                | Command::Notes { .. }
+                | Command::Trace { .. }
+                | Command::Monotonicity { .. }
+                | Command::RangeDiff { .. }
+                | Command::ProfileTop(_)
+                | Command::ProfileReset
+                | Command::SetReadOnly(_)
+                | Command::SetAutosave(_)
+        )
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -59,7 +556,7 @@ mod tests {
         // Default values should be zero/false.
         assert_eq!(info.visit, 0);
         assert_eq!(info.arg_mask, 0);
-        assert!(!info.invalid);
+        assert!(!info.invalid());
         assert_eq!(info.function_id, 0);
         assert_eq!(info.arg, [0, 0]);
     }
@@ -115,18 +612,37 @@ mod tests {
         assert!(info.is_cell_both());
     }
 
+    #[test]
+    fn test_literal_and_error_constructors() {
+        let lit = Info::literal(-7);
+        assert_eq!(lit.function_id, 0);
+        assert_eq!(lit.arg_mask, 0);
+        assert_eq!(lit.arg[0], -7);
+
+        let err = Info::error(CellError::BadRef);
+        assert_eq!(err.function_id, 23);
+        assert_eq!(err.arg_mask, 0);
+        assert_eq!(err.error_value(), Some(CellError::BadRef));
+        assert_eq!(Info::literal(0).error_value(), None);
+        for kind in CellError::ALL {
+            assert_eq!(CellError::from_marker(kind.marker()), Some(kind));
+            assert_eq!(CellError::from_code(kind.code()), Some(kind));
+        }
+    }
+
     #[test]
     fn test_cellinfo_debug_clone_copy() {
         let info = Info {
             visit: 5,
             arg_mask: 0b11,
-            invalid: true,
+            error: Some(CellError::Propagated),
             function_id: 10,
             arg: [42, -1],
+            fixed: 0,
         };
         let cell1 = CellInfo {
             info,
-            value: 100,
+            value: 100.0,
             literal_mode: false,
         };
 
@@ -167,9 +683,10 @@ mod tests {
         let new_info = Info {
             visit: 3,
             arg_mask: 1,
-            invalid: false,
+            error: None,
             function_id: 7,
             arg: [10, 20],
+            fixed: 0,
         };
         let cmd_info_custom = CommandInfo {
             lhs_cell: 42,
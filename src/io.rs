@@ -0,0 +1,476 @@
+// io.rs
+//! This module saves sheets to CSV files and reads them back, keeping formulas as
+//! `=`-prefixed fields (e.g. `=SUM(A1:A3)`) so they can be re-entered on load. It also
+//! exports sheets as JSON for tools that cannot parse the terminal display.
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::convert::num_to_alpha;
+use crate::info::Info;
+use crate::parser::{ParserContext, literal_parser};
+use crate::sheet::{COLORS, CellRole, Sheet};
+
+impl Sheet {
+    /// Writes the sheet to `path` as CSV, one line per row.
+    ///
+    /// Formula cells are written as `=` followed by their formula, literals as their value
+    /// and empty cells as empty fields. Only the rows and columns up to the last non-empty
+    /// cell are written.
+    pub fn save_csv(&self, path: &Path) -> io::Result<()> {
+        self.save_csv_with(path, |cell| {
+            let info = self.data[cell].info;
+            info.literal_value()
+                .is_none()
+                .then(|| self.formula_text(&info))
+        })
+    }
+    /// Writes the sheet like `save_csv`, taking each cell's formula from `formula` instead
+    /// of its `Info`.
+    ///
+    /// This is for editors that keep their own expression text (the vim mode does). A
+    /// formula that is a plain integer is written as a literal.
+    pub fn save_csv_with<F>(&self, path: &Path, formula: F) -> io::Result<()>
+    where
+        F: Fn(usize) -> Option<String>,
+    {
+        let text = |cell: usize| match formula(cell) {
+            Some(expr) if expr.parse::<i32>().is_err() => format!("={}", expr),
+            Some(expr) => expr,
+            None if self.data[cell].value == 0.0 && !self.data[cell].info.invalid() => {
+                String::new()
+            }
+            None => self.data[cell].value.to_string(),
+        };
+
+        let cells: Vec<Vec<String>> = (0..self.n)
+            .map(|row| {
+                (0..self.m)
+                    .map(|col| text(self.get_cell(row, col)))
+                    .collect()
+            })
+            .collect();
+        let used_rows = cells
+            .iter()
+            .rposition(|row| row.iter().any(|field| !field.is_empty()))
+            .map_or(0, |row| row + 1);
+        let used_cols = cells
+            .iter()
+            .filter_map(|row| row.iter().rposition(|field| !field.is_empty()))
+            .max()
+            .map_or(0, |col| col + 1);
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        for row in &cells[..used_rows] {
+            let fields: Vec<String> = row[..used_cols].iter().map(|f| quote(f)).collect();
+            writeln!(writer, "{}", fields.join(","))?;
+        }
+        writer.flush()
+    }
+    /// Writes the sheet to `path` as a JSON document.
+    ///
+    /// The document gives the sheet's `rows` and `columns`, the `column_names` of the used
+    /// columns, and one entry per used cell: a cell with a formula, a value, an error, a
+    /// format or a role. Each entry names the cell and its 1-based `row` and `col`, then
+    /// its `value` (`null` for an error or text), `text`, `formula` (`null` for a plain
+    /// value), `invalid` flag, `error` marker (`null` if valid), `format` and `role`:
+    ///
+    /// ```json
+    /// {"name": "B1", "row": 1, "col": 2, "value": 10, "text": null, "formula": "A1*2",
+    ///  "invalid": false, "error": null, "format": {"bold": true, "italic": false,
+    ///  "underline": false, "color": "red"}, "role": null}
+    /// ```
+    pub fn export_json(&self, path: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(self.json_document().as_bytes())?;
+        writer.flush()
+    }
+    /// Builds the document written by `export_json`.
+    fn json_document(&self) -> String {
+        let used: Vec<usize> = (0..self.n * self.m)
+            .filter(|&cell| {
+                let data = &self.data[cell];
+                data.value != 0.0
+                    || data.info.invalid()
+                    || !self.cell_text(cell, true).is_empty()
+                    || self.formats.contains_key(&cell)
+                    || self.roles.contains_key(&cell)
+            })
+            .collect();
+        let used_cols = used
+            .iter()
+            .map(|&cell| cell % self.m + 1)
+            .max()
+            .unwrap_or(0);
+        let column_names: Vec<String> = (1..=used_cols)
+            .map(|col| json_string(&num_to_alpha(col as u32)))
+            .collect();
+
+        let cells: Vec<String> = used
+            .iter()
+            .map(|&cell| format!("\n    {}", self.json_cell(cell)))
+            .collect();
+        let mut document = format!(
+            "{{\n  \"rows\": {},\n  \"columns\": {},\n  \"column_names\": [{}],\n  \"cells\": [",
+            self.n,
+            self.m,
+            column_names.join(", ")
+        );
+        document += &cells.join(",");
+        if !cells.is_empty() {
+            document += "\n  ";
+        }
+        document + "]\n}\n"
+    }
+    /// Builds the JSON entry of one cell.
+    pub fn json_cell(&self, cell: usize) -> String {
+        let data = &self.data[cell];
+        let (row, col) = self.get_row_and_column(cell);
+        let text = data.text();
+        let value = if data.info.invalid() || text.is_some() {
+            "null".to_string()
+        } else {
+            data.value.to_string()
+        };
+        let formula = match data.info.literal_value() {
+            Some(_) => "null".to_string(),
+            None if text.is_some() => "null".to_string(),
+            None => json_string(&self.formula_text(&data.info)),
+        };
+        let format = self.format(cell);
+        let color = format
+            .color
+            .and_then(|color| COLORS.iter().find(|&&(_, c)| c == color))
+            .map_or("null".to_string(), |&(name, _)| json_string(name));
+        let role = match self.roles.get(&cell) {
+            Some(CellRole::Input) => "\"input\"",
+            Some(CellRole::Output) => "\"output\"",
+            None => "null",
+        };
+        format!(
+            "{{\"name\": {}, \"row\": {}, \"col\": {}, \"value\": {}, \"text\": {}, \"formula\": {}, \"invalid\": {}, \"error\": {}, \"format\": {{\"bold\": {}, \"italic\": {}, \"underline\": {}, \"color\": {}}}, \"role\": {}}}",
+            json_string(&self.cell_name(cell)),
+            row + 1,
+            col + 1,
+            value,
+            text.map_or("null".to_string(), |text| json_string(&text)),
+            formula,
+            data.info.invalid(),
+            data.info
+                .error
+                .map_or("null".to_string(), |kind| json_string(kind.marker())),
+            format.bold,
+            format.italic,
+            format.underline,
+            color,
+            role
+        )
+    }
+    /// Reads a CSV file written by `save_csv` and parses every non-empty field for the cell
+    /// at its position.
+    ///
+    /// Nothing is written to the sheet: the parsed cells are returned so the caller can
+    /// enter them through the dependency graph. Fields starting with `=` are parsed as
+    /// formulas with `context`, anything else as an integer literal.
+    ///
+    /// # Errors
+    /// `InvalidData` if the file has more rows or columns than the sheet or a field does
+    /// not parse, naming the offending cell.
+    pub fn load_csv(
+        &self,
+        path: &Path,
+        context: &mut ParserContext,
+    ) -> io::Result<Vec<(usize, Info)>> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        let contents = fs::read_to_string(path)?;
+        let mut cells = Vec::new();
+        for (row, line) in contents.lines().enumerate() {
+            let fields = split_fields(line);
+            if row >= self.n || fields.len() > self.m {
+                return Err(invalid(format!("line {} does not fit the sheet", row + 1)));
+            }
+            for (col, field) in fields.iter().enumerate() {
+                let field = field.trim();
+                if field.is_empty() {
+                    continue;
+                }
+                let cell = self.get_cell(row, col);
+                let info = match field.strip_prefix('=') {
+                    Some(expr) => context.parse_expression(expr),
+                    None => literal_parser(field).map(Info::literal).or_else(|e| {
+                        // Fractional literals, as saved from a constant
+                        match field.parse::<f64>() {
+                            Ok(value) if value.is_finite() => Ok(Info::number(value)),
+                            _ => Err(e),
+                        }
+                    }),
+                }
+                .map_err(|e| invalid(format!("{}: {:?}", self.cell_name(cell), e)))?;
+                cells.push((cell, info));
+            }
+        }
+        Ok(cells)
+    }
+}
+/// Reads a CSV file from any tool for `load_csv`, inferring the type of each field.
+///
+/// Returns the fields row by row: `None` for an empty field, a number for a field that
+/// parses as one (whole numbers as plain literals) and text for anything else. Formulas
+/// are not parsed, so `=A1` is read as text.
+pub fn read_csv_values(path: &Path) -> io::Result<Vec<Vec<Option<Info>>>> {
+    let contents = fs::read_to_string(path)?;
+    let field_info = |field: &str| {
+        let field = field.trim();
+        if field.is_empty() {
+            return None;
+        }
+        let number = literal_parser(field).map(Info::literal).ok().or_else(|| {
+            field
+                .parse::<f64>()
+                .ok()
+                .filter(|v| v.is_finite())
+                .map(Info::number)
+        });
+        Some(number.unwrap_or_else(|| Info::text(field)))
+    };
+    Ok(contents
+        .lines()
+        .map(|line| split_fields(line).iter().map(|f| field_info(f)).collect())
+        .collect())
+}
+/// Quotes a field if it contains a comma or a quote, doubling any quotes inside.
+fn quote(field: &str) -> String {
+    if field.contains([',', '"']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+/// Writes a string as a JSON string literal, escaping quotes, backslashes and control
+/// characters.
+pub fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+/// Splits one CSV line into its fields, undoing `quote`.
+fn split_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            _ => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sheet::{TEST_COLS, TEST_ROWS, get_cell, init_test_dimensions};
+    use crate::spreadsheet::Spreadsheet;
+    use crate::status::StatusCode;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_csv_round_trip_keeps_formulas() {
+        init_test_dimensions();
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("sheet.csv");
+
+        let mut app = Spreadsheet::new(TEST_ROWS, TEST_COLS);
+        for command in [
+            "A1=5",
+            "B1=A1*2",
+            "A2=-3",
+            "C3=SUM(A1:B2)",
+            "B2=1/0",
+            "C1=\"Net, total\"",
+        ] {
+            app.execute(command).unwrap();
+        }
+        app.execute(&format!("save {}", path.display())).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "5,=A1*2,\"=\"\"Net, total\"\"\"\n-3,=1/0,\n,,=SUM(A1:B2)\n"
+        );
+
+        let mut loaded = Spreadsheet::new(TEST_ROWS, TEST_COLS);
+        loaded.execute("D4=7").unwrap();
+        loaded.execute(&format!("load {}", path.display())).unwrap();
+        let sheet = loaded.sheet.borrow();
+        assert!(sheet.get(get_cell(2, 2)).info.invalid());
+        assert!(sheet.get(get_cell(1, 1)).info.invalid());
+        assert_eq!(sheet.get(get_cell(3, 3)).value, 0.0);
+        assert_eq!(sheet.cell_text(2, false), "Net, total");
+        drop(sheet);
+        // Loaded formulas still track their precedents
+        loaded.execute("A1=6").unwrap();
+        loaded.execute("B2=1").unwrap();
+        assert_eq!(loaded.sheet.borrow().get(1).value, 12.0);
+        assert_eq!(loaded.sheet.borrow().get(get_cell(2, 2)).value, 16.0);
+    }
+
+    #[test]
+    fn test_load_keeps_error_literals_and_marks_cycles() {
+        init_test_dimensions();
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("errors.csv");
+        fs::write(&path, "=#REF,=C1,=B1+1\n").unwrap();
+
+        let mut app = Spreadsheet::new(TEST_ROWS, TEST_COLS);
+        app.execute(&format!("load {}", path.display())).unwrap();
+        let text = |app: &Spreadsheet, cell| app.sheet.borrow().cell_text(cell, false);
+        // C1 would close the cycle C1 -> B1 -> C1, so it is loaded as an error instead
+        assert_eq!(text(&app, 0), "#REF");
+        assert_eq!(text(&app, 1), "ERR");
+        assert_eq!(text(&app, 2), "#CYCLE");
+
+        app.execute(&format!("save {}", path.display())).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "=#REF,=C1,=#CYCLE\n");
+    }
+
+    #[test]
+    fn test_load_breaks_every_cycle_in_a_file() {
+        init_test_dimensions();
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("cycles.csv");
+        fs::write(&path, "=B1,=A1,5\n=B2,=A2+1,=C1+2\n").unwrap();
+
+        let mut app = Spreadsheet::new(TEST_ROWS, TEST_COLS);
+        app.execute("D4=7").unwrap();
+        app.execute(&format!("load {}", path.display())).unwrap();
+        let text = |app: &Spreadsheet, cell| app.sheet.borrow().cell_text(cell, false);
+        // Each cycle loses only the cell of it that comes last in the file
+        assert_eq!(text(&app, get_cell(0, 1)), "#CYCLE");
+        assert_eq!(text(&app, get_cell(1, 1)), "#CYCLE");
+        assert_eq!(text(&app, get_cell(0, 0)), "ERR");
+        assert_eq!(text(&app, get_cell(1, 0)), "ERR");
+        assert_eq!(app.sheet.borrow().get(get_cell(1, 2)).value, 7.0);
+        assert_eq!(app.sheet.borrow().get(get_cell(3, 3)).value, 0.0);
+        // The loaded sheet is usable, and the cycles can be closed again only by typing
+        app.execute("C1=10").unwrap();
+        assert_eq!(app.sheet.borrow().get(get_cell(1, 2)).value, 12.0);
+        assert_eq!(app.execute("B1=A1"), Err(StatusCode::CyclicDep));
+    }
+
+    #[test]
+    fn test_load_rejects_bad_files() {
+        init_test_dimensions();
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("bad.csv");
+        let sheet = Sheet::new(2, 2);
+        let mut context = ParserContext::new();
+
+        fs::write(&path, "1,2,3\n").unwrap();
+        assert!(sheet.load_csv(&path, &mut context).is_err());
+        fs::write(&path, "1,=A1+\n").unwrap();
+        let err = sheet.load_csv(&path, &mut context).unwrap_err();
+        assert!(err.to_string().starts_with("B1"));
+        assert!(
+            sheet
+                .load_csv(&dir.path().join("missing.csv"), &mut context)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_export_json_lists_used_cells() {
+        init_test_dimensions();
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("sheet.json");
+
+        let mut app = Spreadsheet::new(TEST_ROWS, TEST_COLS);
+        for command in [
+            "A1=5",
+            "B1=A1*2",
+            "C1=\"a\\b\"",
+            "A2=1/0",
+            "format B1 bold",
+            "format B1 color red",
+            "mark_inputs A1:A1",
+            "B2=3",
+            "B2=0",
+        ] {
+            app.execute(command).unwrap();
+        }
+        app.execute(&format!("export_json {}", path.display()))
+            .unwrap();
+        let expected = format!(
+            r##"{{
+  "rows": {},
+  "columns": {},
+  "column_names": ["A", "B", "C"],
+  "cells": [
+    {{"name": "A1", "row": 1, "col": 1, "value": 5, "text": null, "formula": null, "invalid": false, "error": null, "format": {{"bold": false, "italic": false, "underline": false, "color": null}}, "role": "input"}},
+    {{"name": "B1", "row": 1, "col": 2, "value": 10, "text": null, "formula": "A1*2", "invalid": false, "error": null, "format": {{"bold": true, "italic": false, "underline": false, "color": "red"}}, "role": null}},
+    {{"name": "C1", "row": 1, "col": 3, "value": null, "text": "a\\b", "formula": null, "invalid": true, "error": "#VALUE", "format": {{"bold": false, "italic": false, "underline": false, "color": null}}, "role": null}},
+    {{"name": "A2", "row": 2, "col": 1, "value": null, "text": null, "formula": "1/0", "invalid": true, "error": "#DIV/0", "format": {{"bold": false, "italic": false, "underline": false, "color": null}}, "role": null}}
+  ]
+}}
+"##,
+            TEST_ROWS, TEST_COLS
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_load_csv_infers_numbers_and_text() {
+        init_test_dimensions();
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("data.csv");
+        fs::write(&path, "Region,Sales\nNorth,12\nSouth,-3.5\n,\"1,000\"\n").unwrap();
+
+        let mut app = Spreadsheet::new(TEST_ROWS, TEST_COLS);
+        app.execute("A1=7").unwrap();
+        app.execute(&format!("load_csv {} --at C5", path.display()))
+            .unwrap();
+        let texts: Vec<String> = [(4, 2), (4, 3), (5, 2), (5, 3), (6, 3), (7, 2), (7, 3)]
+            .iter()
+            .map(|&(r, c)| app.sheet.borrow().cell_text(get_cell(r, c), false))
+            .collect();
+        assert_eq!(
+            texts,
+            ["Region", "Sales", "North", "12", "-3.5", "0", "1,000"]
+        );
+        // Cells outside the file are kept, and the import undoes as one step
+        assert_eq!(app.sheet.borrow().get(0).value, 7.0);
+        app.execute("undo").unwrap();
+        assert_eq!(app.sheet.borrow().cell_text(get_cell(4, 2), false), "0");
+
+        // A file that does not fit changes nothing
+        let at = format!("A{}", TEST_ROWS - 2);
+        let result = app.execute(&format!("load_csv {} --at {}", path.display(), at));
+        assert_eq!(result, Err(StatusCode::OutOfBounds));
+        assert_eq!(app.history().undo_steps().len(), 1);
+        let missing = dir.path().join("missing.csv");
+        let result = app.execute(&format!("load_csv {}", missing.display()));
+        assert_eq!(result, Err(StatusCode::InvalidValue));
+    }
+
+    #[test]
+    fn test_split_fields_undoes_quote() {
+        let fields = ["1", "=A1+2", "a,b", "say \"hi\"", ""];
+        let line: Vec<String> = fields.iter().map(|f| quote(f)).collect();
+        assert_eq!(split_fields(&line.join(",")), fields);
+    }
+}
@@ -0,0 +1,114 @@
+// journal.rs
+//! This module keeps an append-only journal of the commands that changed the sheet
+//! (`--journal <path>`) and rebuilds a sheet by replaying one (`--replay <path>`), so work
+//! survives a crash even though the undo history lives only in memory.
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+use crate::spreadsheet::Spreadsheet;
+use crate::status::StatusCode;
+
+/// Appends one command per line, flushing after each one.
+pub struct Journal {
+    file: File,
+}
+
+impl Journal {
+    /// Opens `path` for appending, creating it if needed.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file })
+    }
+    /// Writes one command. The line is flushed immediately, so a run that is killed
+    /// part-way still leaves every command it completed in the journal.
+    pub fn record(&mut self, command: &str) -> io::Result<()> {
+        writeln!(self.file, "{}", command)?;
+        self.file.flush()
+    }
+}
+
+/// Executes every command of the journal at `path`, in order, against `app`.
+///
+/// Returns the commands that failed, with their status. A journal only holds commands
+/// that succeeded, so this is empty unless a file the journal loads has changed since.
+pub fn replay(app: &mut Spreadsheet, path: &Path) -> io::Result<Vec<(String, StatusCode)>> {
+    let mut failed = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if let Err(status) = app.execute(&line) {
+            failed.push((line, status));
+        }
+    }
+    Ok(failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::info::Info;
+    use crate::parser::ParserContext;
+    use crate::sheet::{TEST_COLS, TEST_ROWS, get_cell, init_test_dimensions};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_replay_rebuilds_the_sheet() {
+        init_test_dimensions();
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("sheet.journal");
+        let mut app = Spreadsheet::new(TEST_ROWS, TEST_COLS);
+        app.set_journal(Journal::open(&path).unwrap());
+        app.parser_ctx.selection = Some((0, 1));
+        for command in [
+            "A1=5",
+            "B1=A1*2",
+            "A1=B1",
+            "bogus",
+            "why B1",
+            "A2=7",
+            "undo",
+            "C1=A1+B1",
+            "clear",
+            "format C1 bold",
+            "s",
+            "A3=SUM(A1:A2)",
+        ] {
+            let _ = app.execute(command);
+        }
+        // Vim's pastes are written out cell by cell
+        let pasted = ParserContext::new().parse_expression("A1+1").unwrap();
+        let writes = [(get_cell(3, 0), pasted), (get_cell(0, 2), Info::default())];
+        app.write_cells(&writes, "p").unwrap();
+        // Only commands that changed something, the selection written out
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "A1=5\nB1=A1*2\nA2=7\nundo\nC1=A1+B1\nclear A1:B1\nformat C1 bold\nA3=SUM(A1:A2)\n\
+             A4=A1+1\nclear C1:C1\n"
+        );
+
+        let mut replayed = Spreadsheet::new(TEST_ROWS, TEST_COLS);
+        assert!(replay(&mut replayed, &path).unwrap().is_empty());
+        let (sheet, other) = (app.sheet.borrow(), replayed.sheet.borrow());
+        for cell in 0..sheet.data.len() {
+            assert_eq!(sheet.data[cell], other.data[cell]);
+            assert_eq!(sheet.format(cell), other.format(cell));
+        }
+        drop((sheet, other));
+        // The replayed history undoes the same steps, the paste one cell at a time
+        let contents = |app: &Spreadsheet| -> Vec<(Info, f64)> {
+            let sheet = app.sheet.borrow();
+            sheet
+                .data
+                .iter()
+                .map(|cell| (cell.info, cell.value))
+                .collect()
+        };
+        app.execute("undo").unwrap();
+        replayed.execute("undo").unwrap();
+        replayed.execute("undo").unwrap();
+        assert!(contents(&app) == contents(&replayed));
+        app.execute("undo").unwrap();
+        replayed.execute("undo").unwrap();
+        assert!(contents(&app) == contents(&replayed));
+    }
+}
@@ -0,0 +1,425 @@
+// line_editor.rs
+//! This module implements the line editor of the standard mode: command history with
+//! Up/Down, reverse search with Ctrl-R and tab completion, drawn over crossterm raw mode.
+use crossterm::{
+    cursor,
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    execute,
+    style::Print,
+    terminal::{self, ClearType},
+};
+use std::io::{self, Write, stdout};
+
+/// Most commands the history keeps; older ones are dropped first.
+pub const HISTORY_LIMIT: usize = 1000;
+
+/// Reads command lines from the terminal, remembering the ones entered.
+#[derive(Debug, Default)]
+pub struct LineEditor {
+    /// Entered commands, oldest first, without consecutive duplicates.
+    history: Vec<String>,
+}
+
+impl LineEditor {
+    /// Creates an editor with an empty history.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Remembers `line`, unless it is empty or repeats the previous command.
+    pub fn add_history(&mut self, line: &str) {
+        if line.is_empty() || self.history.last().is_some_and(|last| last == line) {
+            return;
+        }
+        if self.history.len() == HISTORY_LIMIT {
+            self.history.remove(0);
+        }
+        self.history.push(line.to_string());
+    }
+    /// Returns the remembered commands, oldest first.
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+    /// Reads one line typed after `prompt`, adding it to the history.
+    ///
+    /// `complete` returns the completions of the word before the cursor when Tab is
+    /// pressed. Returns `None` if the user pressed Ctrl-C, or Ctrl-D on an empty line.
+    pub fn read_line(
+        &mut self,
+        prompt: &str,
+        complete: impl Fn(&str) -> Vec<String>,
+    ) -> io::Result<Option<String>> {
+        terminal::enable_raw_mode()?;
+        let result = self.edit(prompt, &complete);
+        terminal::disable_raw_mode()?;
+        execute!(stdout(), Print("\r\n"))?;
+        if let Ok(Some(line)) = &result {
+            self.add_history(line);
+        }
+        result
+    }
+    /// Runs the key loop of `read_line` in raw mode.
+    fn edit(
+        &self,
+        prompt: &str,
+        complete: &dyn Fn(&str) -> Vec<String>,
+    ) -> io::Result<Option<String>> {
+        let mut line = Line::new(&self.history);
+        let mut out = stdout();
+        loop {
+            let (text, column) = line.render(prompt);
+            execute!(
+                out,
+                cursor::MoveToColumn(0),
+                terminal::Clear(ClearType::CurrentLine),
+                Print(text),
+                cursor::MoveToColumn(column as u16)
+            )?;
+            out.flush()?;
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind == KeyEventKind::Release {
+                continue;
+            }
+            match line.handle(key, complete) {
+                Outcome::Continue => {}
+                Outcome::List(candidates) => {
+                    execute!(
+                        out,
+                        Print("\r\n"),
+                        Print(candidates.join("  ")),
+                        Print("\r\n")
+                    )?;
+                }
+                Outcome::Submit(text) => return Ok(Some(text)),
+                Outcome::Cancel => return Ok(None),
+            }
+        }
+    }
+}
+
+/// What a key press did to the line being edited.
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    /// Keep editing.
+    Continue,
+    /// The line was entered.
+    Submit(String),
+    /// The user gave up on input altogether.
+    Cancel,
+    /// Tab found several completions and could not extend the word; show them.
+    List(Vec<String>),
+}
+
+/// A reverse search in progress (Ctrl-R).
+struct Search {
+    /// The text searched for.
+    query: String,
+    /// History index of the match shown, if any.
+    found: Option<usize>,
+}
+
+/// The line being edited and where it is in the history.
+struct Line<'a> {
+    /// The text of the line.
+    chars: Vec<char>,
+    /// Position of the cursor in `chars`.
+    cursor: usize,
+    /// Commands entered before, oldest first.
+    history: &'a [String],
+    /// History entry shown; `history.len()` is the line being typed.
+    index: usize,
+    /// The line being typed, kept while browsing the history.
+    draft: Vec<char>,
+    /// The reverse search, while one is in progress.
+    search: Option<Search>,
+}
+
+impl<'a> Line<'a> {
+    /// Starts an empty line below the history.
+    fn new(history: &'a [String]) -> Self {
+        Self {
+            chars: Vec::new(),
+            cursor: 0,
+            history,
+            index: history.len(),
+            draft: Vec::new(),
+            search: None,
+        }
+    }
+    /// Returns the text of the line.
+    fn text(&self) -> String {
+        self.chars.iter().collect()
+    }
+    /// Returns what to show after `prompt` and the column the cursor goes to.
+    fn render(&self, prompt: &str) -> (String, usize) {
+        match &self.search {
+            Some(search) => {
+                let found = search.found.map_or("", |i| self.history[i].as_str());
+                let text = format!("(reverse-i-search)`{}': {}", search.query, found);
+                let column = text.chars().count();
+                (text, column)
+            }
+            None => {
+                let column = prompt.chars().count() + self.cursor;
+                (format!("{}{}", prompt, self.text()), column)
+            }
+        }
+    }
+    /// Applies one key press.
+    fn handle(&mut self, key: KeyEvent, complete: &dyn Fn(&str) -> Vec<String>) -> Outcome {
+        if self.search.is_some() {
+            return self.handle_search(key);
+        }
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Enter => return Outcome::Submit(self.text()),
+            KeyCode::Char('c') if ctrl => return Outcome::Cancel,
+            KeyCode::Char('d') if ctrl && self.chars.is_empty() => return Outcome::Cancel,
+            KeyCode::Char('r') if ctrl => {
+                self.search = Some(Search {
+                    query: String::new(),
+                    found: None,
+                });
+            }
+            KeyCode::Char('a') if ctrl => self.cursor = 0,
+            KeyCode::Char('e') if ctrl => self.cursor = self.chars.len(),
+            KeyCode::Char('u') if ctrl => {
+                self.chars.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            KeyCode::Char(c) if !ctrl => {
+                self.chars.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.chars.remove(self.cursor);
+            }
+            KeyCode::Delete if self.cursor < self.chars.len() => {
+                self.chars.remove(self.cursor);
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.chars.len()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.chars.len(),
+            KeyCode::Up if self.index > 0 => self.recall(self.index - 1),
+            KeyCode::Down if self.index < self.history.len() => self.recall(self.index + 1),
+            KeyCode::Tab => return self.complete(complete),
+            _ => {}
+        }
+        Outcome::Continue
+    }
+    /// Shows history entry `index`, or the line being typed for `history.len()`.
+    fn recall(&mut self, index: usize) {
+        if self.index == self.history.len() {
+            self.draft = self.chars.clone();
+        }
+        self.index = index;
+        self.chars = match self.history.get(index) {
+            Some(entry) => entry.chars().collect(),
+            None => self.draft.clone(),
+        };
+        self.cursor = self.chars.len();
+    }
+    /// Applies a key press during a reverse search.
+    ///
+    /// Typing refines the query, Ctrl-R moves to the next older match, Enter runs the
+    /// match and Esc or Ctrl-G abandon the search. Any other key keeps the match as the
+    /// line, to be edited further.
+    fn handle_search(&mut self, key: KeyEvent) -> Outcome {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let Some(search) = self.search.as_mut() else {
+            return Outcome::Continue;
+        };
+        match key.code {
+            KeyCode::Char('r') if ctrl => {
+                let before = search.found.unwrap_or(self.history.len());
+                if let Some(i) = find(self.history, &search.query, before) {
+                    search.found = Some(i);
+                }
+            }
+            KeyCode::Char('g') if ctrl => self.search = None,
+            KeyCode::Esc => self.search = None,
+            KeyCode::Char(c) if !ctrl => {
+                search.query.push(c);
+                // The match shown may still match the longer query
+                let before = search.found.map_or(self.history.len(), |i| i + 1);
+                search.found = find(self.history, &search.query, before);
+            }
+            KeyCode::Backspace => {
+                search.query.pop();
+                search.found = find(self.history, &search.query, self.history.len());
+            }
+            KeyCode::Enter => {
+                self.accept_search();
+                return Outcome::Submit(self.text());
+            }
+            _ => self.accept_search(),
+        }
+        Outcome::Continue
+    }
+    /// Ends the search, making its match the line.
+    fn accept_search(&mut self) {
+        if let Some(i) = self.search.take().and_then(|search| search.found) {
+            self.recall(i);
+        }
+    }
+    /// Completes the word before the cursor: fully if there is one completion, otherwise
+    /// as far as all completions agree, listing them if that adds nothing.
+    fn complete(&mut self, complete: &dyn Fn(&str) -> Vec<String>) -> Outcome {
+        let start = self.chars[..self.cursor]
+            .iter()
+            .rposition(|c| !c.is_ascii_alphanumeric())
+            .map_or(0, |i| i + 1);
+        let word: String = self.chars[start..self.cursor].iter().collect();
+        if word.is_empty() {
+            return Outcome::Continue;
+        }
+        let candidates = complete(&word);
+        let Some(first) = candidates.first() else {
+            return Outcome::Continue;
+        };
+        let common: String = first
+            .chars()
+            .enumerate()
+            .take_while(|&(i, c)| {
+                candidates
+                    .iter()
+                    .all(|other| other.chars().nth(i) == Some(c))
+            })
+            .map(|(_, c)| c)
+            .collect();
+        if candidates.len() > 1 && common.chars().count() <= word.chars().count() {
+            return Outcome::List(candidates);
+        }
+        self.chars.splice(start..self.cursor, common.chars());
+        self.cursor = start + common.chars().count();
+        Outcome::Continue
+    }
+}
+
+/// Returns the index of the most recent history entry before `before` that contains
+/// `query`.
+fn find(history: &[String], query: &str, before: usize) -> Option<usize> {
+    (0..before).rev().find(|&i| history[i].contains(query))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(line: &mut Line, code: KeyCode) -> Outcome {
+        line.handle(KeyEvent::new(code, KeyModifiers::NONE), &|_| Vec::new())
+    }
+
+    fn ctrl(line: &mut Line, c: char) -> Outcome {
+        line.handle(
+            KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL),
+            &|_| Vec::new(),
+        )
+    }
+
+    fn type_text(line: &mut Line, text: &str) {
+        for c in text.chars() {
+            press(line, KeyCode::Char(c));
+        }
+    }
+
+    #[test]
+    fn test_history_skips_repeats_and_keeps_draft() {
+        let mut editor = LineEditor::new();
+        for command in ["A1=1", "A1=1", "", "B1=A1+1"] {
+            editor.add_history(command);
+        }
+        assert_eq!(editor.history(), ["A1=1", "B1=A1+1"]);
+
+        let mut line = Line::new(editor.history());
+        type_text(&mut line, "C1");
+        press(&mut line, KeyCode::Up);
+        assert_eq!(line.text(), "B1=A1+1");
+        press(&mut line, KeyCode::Up);
+        press(&mut line, KeyCode::Up);
+        assert_eq!(line.text(), "A1=1");
+        press(&mut line, KeyCode::Down);
+        press(&mut line, KeyCode::Down);
+        assert_eq!(line.text(), "C1");
+        press(&mut line, KeyCode::Left);
+        press(&mut line, KeyCode::Backspace);
+        assert_eq!(
+            press(&mut line, KeyCode::Enter),
+            Outcome::Submit("1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_reverse_search() {
+        let history = ["A1=SUM(B1:B3)", "undo", "A2=SUM(C1:C3)", "redo"].map(String::from);
+        let mut line = Line::new(&history);
+        ctrl(&mut line, 'r');
+        type_text(&mut line, "SUM");
+        assert_eq!(
+            line.render("> ").0,
+            "(reverse-i-search)`SUM': A2=SUM(C1:C3)"
+        );
+        ctrl(&mut line, 'r');
+        assert_eq!(line.search.as_ref().unwrap().found, Some(0));
+        // An older match than the last one is kept when there is none
+        ctrl(&mut line, 'r');
+        assert_eq!(line.search.as_ref().unwrap().found, Some(0));
+        press(&mut line, KeyCode::End);
+        assert!(line.search.is_none());
+        type_text(&mut line, "+1");
+        assert_eq!(
+            press(&mut line, KeyCode::Enter),
+            Outcome::Submit("A1=SUM(B1:B3)+1".to_string())
+        );
+
+        let mut line = Line::new(&history);
+        ctrl(&mut line, 'r');
+        type_text(&mut line, "xyz");
+        assert_eq!(line.search.as_ref().unwrap().found, None);
+        press(&mut line, KeyCode::Esc);
+        assert_eq!(line.text(), "");
+    }
+
+    #[test]
+    fn test_tab_completion() {
+        let names = ["SUM(", "STDEV(", "SLEEP(", "S1", "S10"].map(String::from);
+        let complete = |word: &str| -> Vec<String> {
+            names
+                .iter()
+                .filter(|n| n.starts_with(word))
+                .cloned()
+                .collect()
+        };
+        let tab = |line: &mut Line| {
+            line.handle(KeyEvent::new(KeyCode::Tab, KeyModifiers::NONE), &complete)
+        };
+
+        let mut line = Line::new(&[]);
+        type_text(&mut line, "A1=SU");
+        assert_eq!(tab(&mut line), Outcome::Continue);
+        assert_eq!(line.text(), "A1=SUM(");
+        type_text(&mut line, "B1:B2)+S");
+        assert_eq!(
+            tab(&mut line),
+            Outcome::List(
+                names
+                    .iter()
+                    .filter(|n| n.starts_with('S'))
+                    .cloned()
+                    .collect()
+            )
+        );
+        type_text(&mut line, "1");
+        // Both S1 and S10 start with S1, so the word cannot grow
+        assert_eq!(
+            tab(&mut line),
+            Outcome::List(vec!["S1".into(), "S10".into()])
+        );
+        type_text(&mut line, "0");
+        tab(&mut line);
+        assert_eq!(line.text(), "A1=SUM(B1:B2)+S10");
+    }
+}
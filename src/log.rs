@@ -0,0 +1,78 @@
+// log.rs
+//! This module records one line per executed command, with its resulting status, so a
+//! misbehaving scripted run can be diagnosed afterwards.
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::status::StatusCode;
+
+/// Appends `<status>\t<command>` lines to a writer, flushing after each one.
+pub struct StatusLog<W: Write> {
+    /// Destination of the log lines.
+    writer: W,
+}
+
+impl StatusLog<File> {
+    /// Opens `path` for appending, creating it if needed.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(file))
+    }
+}
+
+impl<W: Write> StatusLog<W> {
+    /// Creates a log that writes to `writer`.
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+    /// Writes the status of one command, e.g. `CyclicDep\tA1=A1+1`.
+    ///
+    /// The line is flushed immediately so a run that is killed part-way still leaves
+    /// every command it executed in the log.
+    pub fn record(&mut self, status: StatusCode, command: &str) -> io::Result<()> {
+        writeln!(self.writer, "{:?}\t{}", status, command)?;
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sheet::{TEST_COLS, TEST_ROWS, init_test_dimensions};
+    use crate::spreadsheet::Spreadsheet;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_status_log_entries() {
+        init_test_dimensions();
+        let mut app = Spreadsheet::new(TEST_ROWS, TEST_COLS);
+        let mut log = StatusLog::new(Vec::new());
+        for command in ["A1=5", "B1=A1+1", "A1=B1", "bogus", "undo"] {
+            let status = app.execute(command).err().unwrap_or(StatusCode::Ok);
+            log.record(status, command).unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(log.writer).unwrap(),
+            "Ok\tA1=5\nOk\tB1=A1+1\nCyclicDep\tA1=B1\nInvalidCmd\tbogus\nOk\tundo\n"
+        );
+    }
+
+    #[test]
+    fn test_status_log_appends_to_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("status.log");
+        StatusLog::open(&path)
+            .unwrap()
+            .record(StatusCode::Ok, "A1=1")
+            .unwrap();
+        StatusLog::open(&path)
+            .unwrap()
+            .record(StatusCode::InvalidValue, "A1=x")
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "Ok\tA1=1\nInvalidValue\tA1=x\n"
+        );
+    }
+}
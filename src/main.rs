@@ -3,53 +3,153 @@
 #![cfg(not(tarpaulin_include))]
 #![allow(warnings)] //disable warnings
 use crossterm::{ExecutableCommand, terminal};
-use std::cell::RefCell;
 use std::env;
-use std::io::{self, Write};
-use std::rc::Rc;
+use std::io::{IsTerminal, Write};
+use std::path::Path;
+use std::time::SystemTime;
 
+mod autosave;
 mod basic;
+mod bench;
+mod cell_log;
+mod chart;
 mod compare;
 mod convert;
+mod date;
+mod expr;
 mod formulas;
 mod graph;
+mod history;
 mod info;
-mod list;
+mod io;
+mod journal;
+mod line_editor;
+mod log;
+mod ops;
 mod parser;
+mod profile;
+mod random;
+mod rpc;
+mod server;
+mod session;
 mod sheet;
+mod spreadsheet;
 mod status;
+mod structure;
+mod text;
+mod trace;
 mod vector;
 mod vim;
 
-use crate::info::CommandInfo;
-use crate::info::{CellInfo, Info};
-use crate::parser::ParserContext;
-use crate::status::{StatusCode, print_status, set_status_code, start_time};
+use crate::status::{CommandResult, StatusCode};
 
-/// Represents a single entry in the undo/redo history.
-struct HistoryEntry {
-    /// The cell index where the change occurred.
-    cell_idx: usize,
-    /// Information about the command execution.
-    info: Info,
-    /// The previous value before the change.
-    value: i32,
-    /// Whether literal mode was enabled.
-    literal_mode: bool,
-}
 /// The main function that runs the spreadsheet application.
 ///
 /// # Returns
 /// An `io::Result<()>` indicating success or failure.
-fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
+fn main() -> std::io::Result<()> {
+    let mut args: Vec<String> = env::args().collect();
+    // Time synthetic workloads instead of opening a sheet
+    if args.get(1).is_some_and(|arg| arg == "--bench") {
+        return run_bench(&args);
+    }
+    // Optional status log, taken out of the arguments before they are checked
+    let log_path = match args.iter().position(|arg| arg == "--log") {
+        Some(i) if i + 1 < args.len() => Some(args.drain(i..i + 2).nth(1).unwrap()),
+        Some(_) => {
+            eprintln!(
+                "Missing --log file\nUsage: {} <rows> <columns> [--log <file>]",
+                args[0]
+            );
+            return Ok(());
+        }
+        None => None,
+    };
+    // Optional journal to append changes to and one to replay first, taken out likewise
+    let mut path_flag = |flag: &str| match args.iter().position(|arg| arg == flag) {
+        Some(i) if i + 1 < args.len() => Ok(Some(args.drain(i..i + 2).nth(1).unwrap())),
+        Some(_) => Err(format!(
+            "Missing {} file\nUsage: {} <rows> <columns> [{} <file>]",
+            flag, args[0], flag
+        )),
+        None => Ok(None),
+    };
+    let (journal_path, replay_path) = match (path_flag("--journal"), path_flag("--replay")) {
+        (Ok(journal), Ok(replay)) => (journal, replay),
+        (Err(usage), _) | (_, Err(usage)) => {
+            eprintln!("{}", usage);
+            return Ok(());
+        }
+    };
+    // Read-only review of a replayed or loaded sheet, taken out like the log
+    let readonly = match args.iter().position(|arg| arg == "--readonly") {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    };
+    // Optional autosave, every so many seconds (`30s`) or changes (`20`), taken out likewise
+    let autosave_every = match args.iter().position(|arg| arg == "--autosave") {
+        Some(i) => match args
+            .get(i + 1)
+            .and_then(|every| autosave::Every::parse(every))
+        {
+            Some(every) => {
+                args.drain(i..i + 2);
+                Some(every)
+            }
+            None => {
+                eprintln!(
+                    "Invalid --autosave value\nUsage: {} <rows> <columns> [--autosave <seconds>s|<changes>]",
+                    args[0]
+                );
+                return Ok(());
+            }
+        },
+        None => None,
+    };
     // Check for vim flag
     let vim_mode = args.iter().any(|arg| arg == "--vim");
+    // Optional input wait for vim mode, in milliseconds
+    let poll_ms = match args.iter().position(|arg| arg == "--poll-ms") {
+        Some(i) => match args.get(i + 1).and_then(|ms| ms.parse::<u64>().ok()) {
+            Some(ms) if ms > 0 => ms,
+            _ => {
+                eprintln!(
+                    "Invalid --poll-ms value\nUsage: {} <rows> <columns> --vim [--poll-ms <ms>]",
+                    args[0]
+                );
+                return Ok(());
+            }
+        },
+        None => vim::DEFAULT_POLL_MS,
+    };
+    // Check for listen flag (serve commands over a Unix socket)
+    let listen_mode = args.iter().any(|arg| arg == "--listen");
+    // Check for serve flag (serve JSON requests over TCP)
+    let serve_mode = args.iter().any(|arg| arg == "--serve");
 
     if vim_mode {
         if args.len() < 3 {
             eprintln!(
-                "Vim mode : Invalid arguments\nUsage: {} <rows> <columns> [--vim]",
+                "Vim mode : Invalid arguments\nUsage: {} <rows> <columns> [--vim] [--poll-ms <ms>]",
+                args[0]
+            );
+            return Ok(());
+        }
+    } else if listen_mode {
+        if args.len() != 5 || args[3] != "--listen" {
+            eprintln!(
+                "Invalid arguments\nUsage: {} <rows> <columns> --listen <path>",
+                args[0]
+            );
+            return Ok(());
+        }
+    } else if serve_mode {
+        if args.len() != 5 || args[3] != "--serve" || args[4].parse::<u16>().is_err() {
+            eprintln!(
+                "Invalid arguments\nUsage: {} <rows> <columns> --serve <port>",
                 args[0]
             );
             return Ok(());
@@ -73,197 +173,164 @@ fn main() -> io::Result<()> {
         sheet::init_dimensions(m, n);
     }
 
-    // Initialize memory pool
-    let mem_pool = Rc::new(RefCell::new(list::ListMemPool::new()));
-    mem_pool.borrow_mut().add_block();
-
-    // Initialize sheet
-    let mut sheet = Rc::new(RefCell::new(sheet::Sheet::new(n, m)));
-
-    // Initialize graph
-    let mut graph = graph::Graph::new(n, m, sheet.clone(), mem_pool.clone());
+    let mut app = spreadsheet::Spreadsheet::new(n, m);
+    // An autosave without a live owner means its session did not exit cleanly. This runs
+    // without `--autosave` too, since `set autosave` may have turned it on in that session
+    for (path, saved) in autosave::orphans(&env::temp_dir()) {
+        if offer_recovery(&mut app, &path, saved)? {
+            break;
+        }
+    }
+    // Replay before journaling, so the same file can be resumed and extended
+    if let Some(path) = replay_path {
+        for (command, status) in journal::replay(&mut app, Path::new(&path))? {
+            eprintln!("replay: {}: {}", command, status::status_message(status));
+        }
+    }
+    if let Some(path) = journal_path {
+        app.set_journal(journal::Journal::open(Path::new(&path))?);
+    }
+    app.set_readonly(readonly);
+    if let Some(every) = autosave_every {
+        let autosave = autosave::Autosave::new(autosave::default_path(), every)?;
+        app.set_autosave(Some(autosave));
+    }
 
     // If vim mode flag is present, run in vim mode
     if vim_mode {
-        // let mut vim_editor = vim::VimEditor::new(sheet.clone());
-        // return vim_editor.run();
-        // let graph = Rc::new(RefCell::new(graph));
-        // let mut vim_editor = vim::VimEditor::new(sheet.clone(), graph);
-        let mut vim_editor = vim::VimEditor::new(sheet.clone());
+        let mut vim_editor = vim::VimEditor::new(app);
+        vim_editor.set_poll_interval(poll_ms);
         return vim_editor.run();
     }
 
-    // undo-redo stack initialization !!!
-    let mut undo_stack: Vec<HistoryEntry> = Vec::new();
-    let mut redo_stack: Vec<HistoryEntry> = Vec::new();
+    // Serve commands over a Unix socket instead of stdin
+    if listen_mode {
+        let served = server::listen(&mut app, Path::new(&args[4]));
+        app.discard_autosave();
+        return served;
+    }
 
-    let mut parser_ctx = ParserContext::new();
-    let mut stdout = io::stdout();
+    // Serve JSON requests over TCP instead of stdin
+    if serve_mode {
+        let served = rpc::serve(&mut app, args[4].parse().unwrap());
+        app.discard_autosave();
+        return served;
+    }
+
+    let mut stdout = std::io::stdout();
+    let mut status_log = match log_path {
+        Some(path) => Some(log::StatusLog::open(Path::new(&path))?),
+        None => None,
+    };
 
-    start_time();
+    let mut last = CommandResult::default();
+    // Edit commands in place when typing at a terminal; piped input is read line by line
+    let mut editor = std::io::stdin()
+        .is_terminal()
+        .then(line_editor::LineEditor::new);
 
+    let fit_terminal = std::io::stdout().is_terminal();
     loop {
-        if parser_ctx.output_enabled {
-            // sheet.display()?;
-            sheet.borrow_mut().display(&mut parser_ctx)?; // Borrow for display 
+        // Follow the terminal's size, which may have changed since the last command
+        if fit_terminal {
+            app.parser_ctx.terminal = terminal::size()
+                .ok()
+                .map(|(cols, rows)| (cols as usize, rows as usize));
         }
-
-        print_status();
-        stdout.flush()?;
-
-        set_status_code(StatusCode::Ok);
-
-        let input = read_command()?;
-        status::start_time();
-
-        let cmd_info = match parser::parse(&input, &mut parser_ctx) {
-            Ok(info) => info,
-            Err(_) => {
-                set_status_code(StatusCode::InvalidCmd);
-                continue;
-            }
-        };
-
-        if cmd_info.lhs_cell == -1 {
-            continue;
+        if app.parser_ctx.output_enabled {
+            app.sheet.borrow_mut().display(&mut app.parser_ctx)?; // Borrow for display
         }
-        if cmd_info.lhs_cell == -2 {
-            // Handle Undo
-            if let Some(entry) = undo_stack.pop() {
-                let mut temp_cell_info = CellInfo {
-                    info: entry.info.clone(),
-                    value: entry.value,
-                    literal_mode: entry.literal_mode,
-                };
-
-                // Cycle check for old dependencies
-                if !graph.iterative_dfs(entry.cell_idx as i32, &temp_cell_info) {
-                    undo_stack.push(entry);
-                    set_status_code(StatusCode::CyclicDep);
-                    continue;
-                }
-
-                // Save current state to redo stack
-                let (current_info, current_value, current_literal) = {
-                    let sheet_borrow = sheet.borrow();
-                    (
-                        sheet_borrow.data[entry.cell_idx].info.clone(),
-                        sheet_borrow.data[entry.cell_idx].value,
-                        sheet_borrow.data[entry.cell_idx].literal_mode,
-                    )
-                };
-                redo_stack.push(HistoryEntry {
-                    cell_idx: entry.cell_idx,
-                    info: current_info,
-                    value: current_value,
-                    literal_mode: current_literal,
-                });
 
-                // Revert the cell state
-                graph.delete_expression(entry.cell_idx as i32);
-                graph.add_expression(entry.cell_idx as i32, &temp_cell_info);
-
-                {
-                    let mut sheet_borrow = sheet.borrow_mut();
-                    let cell = &mut sheet_borrow.data[entry.cell_idx];
-                    cell.info = entry.info;
-                    cell.value = entry.value;
-                    cell.literal_mode = true; // Preserve historical value
-                }
-
-                graph.update_values();
-                graph.reset();
-            } else {
-                set_status_code(StatusCode::NothingToUndo);
-            }
-            continue;
-        } else if cmd_info.lhs_cell == -3 {
-            // Handle Redo (similar structure to undo)
-            if let Some(entry) = redo_stack.pop() {
-                let mut temp_cell_info = CellInfo {
-                    info: entry.info.clone(),
-                    value: entry.value,
-                    literal_mode: entry.literal_mode,
-                };
-
-                if !graph.iterative_dfs(entry.cell_idx as i32, &temp_cell_info) {
-                    redo_stack.push(entry);
-                    set_status_code(StatusCode::CyclicDep);
-                    continue;
-                }
-
-                // Save current state to undo stack
-                let (current_info, current_value, current_literal) = {
-                    let sheet_borrow = sheet.borrow();
-                    (
-                        sheet_borrow.data[entry.cell_idx].info.clone(),
-                        sheet_borrow.data[entry.cell_idx].value,
-                        sheet_borrow.data[entry.cell_idx].literal_mode,
-                    )
-                };
-                undo_stack.push(HistoryEntry {
-                    cell_idx: entry.cell_idx,
-                    info: current_info,
-                    value: current_value,
-                    literal_mode: current_literal,
-                });
-
-                // Apply redo state
-                graph.delete_expression(entry.cell_idx as i32);
-                graph.add_expression(entry.cell_idx as i32, &temp_cell_info);
-
-                {
-                    let mut sheet_borrow = sheet.borrow_mut();
-                    let cell = &mut sheet_borrow.data[entry.cell_idx];
-                    cell.info = entry.info;
-                    cell.value = entry.value;
-                    cell.literal_mode = true;
+        // A selection's aggregates are kept up to date in the prompt
+        let prompt = match app.selection_summary() {
+            Some(summary) => format!("{} {} >", last.summary(), summary),
+            None => last.status_line(),
+        };
+        let input = match editor.as_mut() {
+            Some(editor) => match editor.read_line(&prompt, |word| app.completions(word))? {
+                Some(line) => line.trim().to_string(),
+                None => {
+                    app.discard_autosave();
+                    return Ok(());
                 }
-
-                graph.update_values();
-                graph.reset();
-            } else {
-                set_status_code(StatusCode::NothingToRedo);
+            },
+            None => {
+                print!("{}", prompt);
+                stdout.flush()?;
+                read_command()?
             }
-            continue;
-        }
-
-        let cell_idx = cmd_info.lhs_cell as usize;
-
-        // Save current state to undo stack
-        let (current_info, current_value, current_literal) = {
-            let sheet_borrow = sheet.borrow();
-            (
-                sheet_borrow.data[cell_idx].info.clone(),
-                sheet_borrow.data[cell_idx].value,
-                sheet_borrow.data[cell_idx].literal_mode,
-            )
         };
-        undo_stack.push(HistoryEntry {
-            cell_idx,
-            info: current_info,
-            value: current_value,
-            literal_mode: current_literal,
-        });
 
-        match graph::update_expression(&mut graph, cell_idx as usize, &cmd_info.info) {
-            Ok(_) => {
-                redo_stack.clear();
-                sheet.borrow_mut().data[cell_idx].literal_mode = false; // Reset literal mode
-            }
-            Err(code) => {
-                set_status_code(code);
-                undo_stack.pop();
-            }
+        // `q` exits on the spot, so a clean exit removes the autosave first
+        if input == "q" {
+            app.discard_autosave();
+        }
+        last = app.run_command(&input);
+        if let Some(log) = status_log.as_mut() {
+            log.record(last.status, &input)?;
+        }
+    }
+}
+/// Reports the autosave at `path`, written at `saved` by a session that did not exit
+/// cleanly, and at a terminal offers to resume from it. A recovered autosave is removed,
+/// since the session now holds it; a declined one is kept for `load_session`.
+///
+/// Returns whether the session was recovered.
+fn offer_recovery(
+    app: &mut spreadsheet::Spreadsheet,
+    path: &Path,
+    saved: SystemTime,
+) -> std::io::Result<bool> {
+    let minutes = saved.elapsed().map_or(0, |age| age.as_secs() / 60);
+    eprint!(
+        "Found an autosave from {} min ago, left by a session that did not exit cleanly",
+        minutes
+    );
+    if !std::io::stdin().is_terminal() {
+        eprintln!(" (recover it with: load_session {})", path.display());
+        return Ok(false);
+    }
+    eprint!(". Recover it? [y/N] ");
+    if !read_command()?.eq_ignore_ascii_case("y") {
+        eprintln!("Kept at {}", path.display());
+        return Ok(false);
+    }
+    match app.load_session(path) {
+        Ok(()) => autosave::remove(path).map(|_| true),
+        Err(e) => {
+            eprintln!("Cannot recover the autosave: {}", e);
+            Ok(false)
+        }
+    }
+}
+/// Runs `--bench [cells]` and prints its table of timings.
+fn run_bench(args: &[String]) -> std::io::Result<()> {
+    let cells = match args.get(2).map(|cells| cells.parse::<usize>()) {
+        None => bench::DEFAULT_BENCH_CELLS,
+        Some(Ok(cells)) if args.len() == 3 => cells,
+        _ => {
+            eprintln!("Invalid arguments\nUsage: {} --bench [<cells>]", args[0]);
+            return Ok(());
         }
+    };
+    unsafe {
+        sheet::init_dimensions(bench::BENCH_COLS, bench::BENCH_ROWS);
+    }
+    match bench::run(cells, bench::BENCH_ROWS, bench::BENCH_COLS) {
+        Ok(results) => print!("{}", bench::report(cells, &results)),
+        Err(_) => eprintln!(
+            "Invalid cell count: between 2 and {} cells fit the benchmark sheet",
+            bench::BENCH_ROWS * bench::BENCH_COLS
+        ),
     }
+    Ok(())
 }
 /// Reads a command from standard input.
 ///
 /// # Returns
 /// The trimmed command as a `String`.
-fn read_command() -> io::Result<String> {
+fn read_command() -> std::io::Result<String> {
     let mut input = String::new();
-    io::stdin().read_line(&mut input)?;
+    std::io::stdin().read_line(&mut input)?;
     Ok(input.trim().to_string())
 }
@@ -0,0 +1,2073 @@
+// ops.rs
+//! This module implements bulk commands that read a range of cells and write
+//! literal results back through the dependency graph, so dependents recompute.
+use crate::cell_log::CellLog;
+use crate::expr;
+use crate::formulas::{ABS, DAYS, FPTR, RANDBETWEEN, is_range_function, is_scalar_function};
+use crate::graph::{CalcMode, Graph, UpdateError};
+use crate::info::{CellError, CellInfo, Command, FIXED_COL, FIXED_ROW, Info};
+use crate::io::read_csv_values;
+use crate::parser::ParserContext;
+use crate::random::Rng;
+use crate::sheet::{Axis, CellRole, LineEdit, Sheet, format_value, reset_dimensions};
+use crate::status::StatusCode;
+use crate::structure;
+use crate::trace::Trace;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Executes a bulk command against the graph and its sheet.
+///
+/// # Returns
+/// `Ok(())` on success, otherwise the `StatusCode` describing the failure. Nothing is
+/// written when the command fails.
+pub fn execute(graph: &mut Graph, command: &Command) -> Result<(), StatusCode> {
+    match *command {
+        Command::CumulativePercent { start, end } => cumulative_percent(graph, start, end),
+        Command::Impact { cell } => {
+            println!("{}", impact_report(graph, cell));
+            Ok(())
+        }
+        Command::Deps { cell, tree } => {
+            println!("{}", deps_report(graph, cell, tree));
+            Ok(())
+        }
+        Command::Why { cell } => {
+            println!("{}", why_report(graph, cell));
+            Ok(())
+        }
+        Command::Adjust {
+            cell,
+            function_id,
+            amount,
+        } => {
+            let info = adjusted_literal(graph, cell, function_id, amount)?;
+            Ok(graph.update_expression(cell, &info)?)
+        }
+        Command::SetRecalcOrder(order) => {
+            graph.recalc_order = order;
+            Ok(())
+        }
+        Command::SetCalc(mode) => {
+            graph.calc = mode;
+            if mode == CalcMode::Auto && graph.stale {
+                graph.recompute_all();
+            }
+            Ok(())
+        }
+        Command::ProfileTop(count) => {
+            println!("{}", graph.profile.report(&graph.sheet.borrow(), count));
+            Ok(())
+        }
+        Command::ProfileReset => {
+            graph.profile.clear();
+            Ok(())
+        }
+        Command::CellHistory { cell } => {
+            println!("{}", graph.cell_log.report(&graph.sheet.borrow(), cell));
+            Ok(())
+        }
+        Command::SetCellHistoryDepth(depth) => {
+            graph.cell_log.set_max_depth(depth);
+            Ok(())
+        }
+        Command::Recalc => {
+            graph.recompute_all();
+            Ok(())
+        }
+        Command::SetThreads(threads) => {
+            graph.threads = threads;
+            Ok(())
+        }
+        Command::SetOverflowPolicy(policy) => {
+            graph.sheet.borrow_mut().overflow = policy;
+            graph.recompute_all();
+            Ok(())
+        }
+        Command::MovingAverage {
+            start,
+            end,
+            window,
+            target,
+            shrink,
+        } => moving_average(graph, start, end, window, target, shrink),
+        Command::WeightedMovingAverage {
+            start,
+            end,
+            weights_start,
+            weights_end,
+            target,
+        } => weighted_moving_average(graph, start, end, weights_start, weights_end, target),
+        Command::Normalize { start, end } => normalize(graph, start, end),
+        Command::Random { start, end, lo, hi } => random_fill(graph, start, end, lo, hi),
+        Command::Clamp { start, end, lo, hi } => clamp(graph, start, end, lo, hi),
+        Command::ReplaceReference { from, to } => replace_reference(graph, from, to),
+        Command::ReplaceLiteral { from, to } => replace_literal(graph, from, to),
+        Command::Clear { start, end } => {
+            let writes: Vec<(usize, Info)> = range_cells(graph, start, end)
+                .into_iter()
+                .map(|cell| (cell, Info::default()))
+                .collect();
+            write_cells(graph, &writes)
+        }
+        Command::SumRange { start, end } => {
+            let values = read_numbers(graph, &range_cells(graph, start, end))?;
+            println!("{}", format_value(values.iter().sum()));
+            Ok(())
+        }
+        Command::SaveCsv { ref path } => graph
+            .sheet
+            .borrow()
+            .save_csv(Path::new(path))
+            .map_err(|_| StatusCode::InvalidValue),
+        Command::ExportJson { ref path } => graph
+            .sheet
+            .borrow()
+            .export_json(Path::new(path))
+            .map_err(|_| StatusCode::InvalidValue),
+        Command::LoadCsv { ref path } => load_csv(graph, path),
+        Command::ImportCsv { ref path, at } => import_csv(graph, path, at),
+        // Sessions and history commands need the undo history, which only the
+        // `Spreadsheet` holds
+        Command::SaveSession { .. } | Command::LoadSession { .. } => Err(StatusCode::InvalidCmd),
+        Command::History | Command::SetHistoryDepth(_) => Err(StatusCode::InvalidCmd),
+        Command::SetReadOnly(_) | Command::SetAutosave(_) => Err(StatusCode::InvalidCmd),
+        Command::RotateSheet { clockwise } => {
+            rotate_sheet(graph, clockwise);
+            Ok(())
+        }
+        Command::MoveLines { axis, edit } => move_lines(graph, axis, edit),
+        Command::SetSeed(seed) => {
+            graph.sheet.borrow_mut().rng = Rng::new(seed);
+            graph.recompute_volatile();
+            Ok(())
+        }
+        Command::Delta { start, end, target } => delta(graph, start, end, target),
+        Command::ShiftReferences {
+            start,
+            end,
+            rows,
+            cols,
+        } => shift_references(graph, start, end, rows, cols),
+        Command::DuplicateRegion { start, end, target } => {
+            duplicate_region(graph, start, end, target)
+        }
+        Command::Fill { start, end } => fill(graph, start, end),
+        Command::Pivot { start, end, target } => pivot(graph, start, end, target),
+        Command::MarkRole { start, end, role } => {
+            let cells = range_cells(graph, start, end);
+            let mut sheet = graph.sheet.borrow_mut();
+            for cell in cells {
+                sheet.roles.insert(cell, role);
+            }
+            Ok(())
+        }
+        Command::Monotonicity { start, end } => {
+            println!("{}", monotonicity_report(graph, start, end)?);
+            Ok(())
+        }
+        Command::RangeDiff {
+            start,
+            end,
+            other_start,
+            other_end,
+        } => {
+            let mismatches = range_diff(graph, start, end, other_start, other_end)?;
+            if mismatches.is_empty() {
+                println!("no differences");
+            }
+            for line in mismatches {
+                println!("{}", line);
+            }
+            Ok(())
+        }
+        Command::Format { start, end, change } => {
+            let cells = range_cells(graph, start, end);
+            let mut sheet = graph.sheet.borrow_mut();
+            for cell in cells {
+                sheet.change_format(cell, change, false);
+            }
+            Ok(())
+        }
+        Command::IoMap => {
+            println!("{}", io_map(&graph.sheet.borrow()));
+            Ok(())
+        }
+        Command::Lock { start, end, locked } => {
+            let cells = range_cells(graph, start, end);
+            let mut sheet = graph.sheet.borrow_mut();
+            for cell in cells {
+                if locked {
+                    sheet.locked.insert(cell);
+                } else {
+                    sheet.locked.remove(&cell);
+                }
+            }
+            Ok(())
+        }
+        Command::Note { cell, ref text } => {
+            graph.sheet.borrow_mut().set_note(cell, text);
+            Ok(())
+        }
+        Command::Sort {
+            start,
+            end,
+            key,
+            descending,
+        } => structure::sort_rows(graph, start, end, key, descending),
+        Command::Trace { enabled, ref path } => {
+            graph.trace = match (enabled, path) {
+                (false, _) => None,
+                (true, None) => Some(Trace::stdout()),
+                (true, Some(path)) => {
+                    Some(Trace::open(Path::new(path)).map_err(|_| StatusCode::InvalidValue)?)
+                }
+            };
+            Ok(())
+        }
+        Command::Notes { cell } => {
+            let sheet = graph.sheet.borrow();
+            let note = sheet.note(cell).unwrap_or("(none)");
+            println!("{}: {}", sheet.cell_name(cell), note);
+            Ok(())
+        }
+    }
+}
+/// Computes the literal that results from applying an `add`/`sub`/`mul` adjustment to a cell.
+///
+/// Only literal cells (including empty ones) can be adjusted; formula cells are rejected
+/// with `InvalidCell` rather than silently converted. An adjustment that does not fit in
+/// a cell value fails with `Overflow`.
+pub fn adjusted_literal(
+    graph: &Graph,
+    cell: usize,
+    function_id: u8,
+    amount: i32,
+) -> Result<Info, StatusCode> {
+    let data = graph.sheet.borrow().get(cell);
+    if data.info.function_id != 0 || data.info.arg_mask != 0 {
+        return Err(StatusCode::InvalidCell);
+    }
+    let current = data.info.arg[0];
+    let value = match function_id {
+        2 => current.checked_add(amount),
+        3 => current.checked_sub(amount),
+        4 => current.checked_mul(amount),
+        _ => return Err(StatusCode::InvalidCmd),
+    };
+    value.map(Info::literal).ok_or(StatusCode::Overflow)
+}
+/// Builds the `impact` report: the number of transitively dependent cells and their names.
+fn impact_report(graph: &Graph, cell: usize) -> String {
+    let dependents = graph.transitive_dependents(cell);
+    let sheet = graph.sheet.borrow();
+    let names: Vec<String> = dependents.iter().map(|&c| sheet.cell_name(c)).collect();
+    format!(
+        "{} impacts {} cell(s): {}",
+        sheet.cell_name(cell),
+        names.len(),
+        names.join(", ")
+    )
+}
+/// Builds the `deps` report: the cells `cell` reads (precedents) and the cells that read
+/// it (dependents).
+///
+/// Without `tree` only the direct neighbours are listed on one line each. With `tree`
+/// both chains are walked to the end, one cell per line indented by its depth; a cell
+/// already listed in the same chain is marked `(see above)` instead of being expanded
+/// again, so shared precedents and cycles stay finite.
+fn deps_report(graph: &Graph, cell: usize, tree: bool) -> String {
+    let sheet = graph.sheet.borrow();
+    let reads = |c: usize| {
+        let mut cells = precedents(&sheet, &sheet.get(c).info);
+        cells.sort_unstable();
+        cells.dedup();
+        cells
+    };
+    let read_by = |c: usize| {
+        let mut cells = graph.direct_dependents(c);
+        cells.sort_unstable();
+        cells.dedup();
+        cells
+    };
+    let name = sheet.cell_name(cell);
+    if !tree {
+        let names = |cells: Vec<usize>| -> Vec<String> {
+            cells.into_iter().map(|c| sheet.cell_name(c)).collect()
+        };
+        return format!(
+            "{name} depends on: {}\n{name} is used by: {}",
+            names(reads(cell)).join(", "),
+            names(read_by(cell)).join(", ")
+        );
+    }
+
+    let mut lines = Vec::new();
+    for (heading, next) in [
+        ("depends on:", &reads as &dyn Fn(usize) -> Vec<usize>),
+        ("is used by:", &read_by),
+    ] {
+        lines.push(format!("{name} {heading}"));
+        let mut seen = vec![false; sheet.data.len()];
+        seen[cell] = true;
+        // Depth-first, so each cell's own chain follows it directly
+        let mut stack: Vec<(usize, usize)> = next(cell).into_iter().rev().map(|c| (c, 1)).collect();
+        while let Some((c, depth)) = stack.pop() {
+            let indent = "  ".repeat(depth);
+            if seen[c] {
+                lines.push(format!("{indent}{} (see above)", sheet.cell_name(c)));
+                continue;
+            }
+            seen[c] = true;
+            lines.push(format!("{indent}{}", sheet.cell_name(c)));
+            stack.extend(next(c).into_iter().rev().map(|n| (n, depth + 1)));
+        }
+    }
+    lines.join("\n")
+}
+/// Builds the `why` report, following invalid precedents from `cell` to the first cell
+/// whose own formula fails, e.g. `A1 ERR because B1 ERR because C1 = 5/0 (#DIV/0)`, each
+/// cell named with its error marker.
+fn why_report(graph: &Graph, cell: usize) -> String {
+    let sheet = graph.sheet.borrow();
+    if !sheet.get(cell).info.invalid() {
+        return format!("{} is not an error", sheet.cell_name(cell));
+    }
+
+    let marker = |cell: usize| sheet.get(cell).info.error.map_or("", CellError::marker);
+    let mut steps = Vec::new();
+    let mut current = cell;
+    loop {
+        let info = sheet.get(current).info;
+        match precedents(&sheet, &info)
+            .into_iter()
+            .find(|&p| sheet.get(p).info.invalid())
+        {
+            Some(next) => {
+                steps.push(format!("{} {}", sheet.cell_name(current), marker(current)));
+                current = next;
+            }
+            None => {
+                let mut root = format!(
+                    "{} = {}",
+                    sheet.cell_name(current),
+                    sheet.formula_text(&info)
+                );
+                // Name the zero when the divisor is a cell reference
+                if info.function_id == 5 && info.is_cell_arg2() {
+                    let divisor = info.arg[1] as usize;
+                    root += &format!(" where {} = 0", sheet.cell_name(divisor));
+                }
+                root += &format!(" ({})", marker(current));
+                steps.push(root);
+                break;
+            }
+        }
+    }
+    steps.join(" because ")
+}
+/// How the values of a column are ordered, as reported by `ismono`.
+#[derive(Debug, PartialEq)]
+enum Monotonicity {
+    StrictlyIncreasing,
+    StrictlyDecreasing,
+    NonDecreasing,
+    NonIncreasing,
+    Unordered,
+}
+/// Classifies `values` in one pass over consecutive pairs.
+///
+/// The strict classes win over the non-strict ones, so fewer than two values count as
+/// strictly increasing and a constant column as non-decreasing.
+fn monotonicity(values: &[f64]) -> Monotonicity {
+    let (mut up, mut down, mut equal) = (false, false, false);
+    for pair in values.windows(2) {
+        match pair[0].total_cmp(&pair[1]) {
+            std::cmp::Ordering::Less => up = true,
+            std::cmp::Ordering::Greater => down = true,
+            std::cmp::Ordering::Equal => equal = true,
+        }
+    }
+    match (up, down, equal) {
+        (_, false, false) => Monotonicity::StrictlyIncreasing,
+        (false, true, false) => Monotonicity::StrictlyDecreasing,
+        (_, false, true) => Monotonicity::NonDecreasing,
+        (false, true, true) => Monotonicity::NonIncreasing,
+        (true, true, _) => Monotonicity::Unordered,
+    }
+}
+/// Builds the `ismono` report, e.g. `A1:A5 is strictly increasing`.
+///
+/// The check stops at the first invalid cell and the report names it instead, e.g.
+/// `A1:A5 is not checked: A3 is an error`. Returns `InvalidRange` unless the range is a
+/// single column.
+fn monotonicity_report(graph: &Graph, start: usize, end: usize) -> Result<String, StatusCode> {
+    let cells = column_cells(graph, start, end)?;
+    let sheet = graph.sheet.borrow();
+    let range = format!("{}:{}", sheet.cell_name(start), sheet.cell_name(end));
+    let mut values = Vec::with_capacity(cells.len());
+    for &cell in &cells {
+        let data = sheet.get(cell);
+        if data.info.invalid() {
+            return Ok(format!(
+                "{} is not checked: {} is an error",
+                range,
+                sheet.cell_name(cell)
+            ));
+        }
+        values.push(data.value);
+    }
+    let class = match monotonicity(&values) {
+        Monotonicity::StrictlyIncreasing => "strictly increasing",
+        Monotonicity::StrictlyDecreasing => "strictly decreasing",
+        Monotonicity::NonDecreasing => "non-decreasing",
+        Monotonicity::NonIncreasing => "non-increasing",
+        Monotonicity::Unordered => "unordered",
+    };
+    Ok(format!("{} is {}", range, class))
+}
+/// Compares two equal-shaped ranges cell by cell, in row-major order.
+///
+/// Returns one line per differing position, e.g. `A2 = 5, B2 = 7`. An invalid cell shows
+/// as `ERR` and differs from everything except another invalid cell. Returns
+/// `InvalidRange` if the ranges have different shapes.
+fn range_diff(
+    graph: &Graph,
+    start: usize,
+    end: usize,
+    other_start: usize,
+    other_end: usize,
+) -> Result<Vec<String>, StatusCode> {
+    let sheet = graph.sheet.borrow();
+    let shape = |start: usize, end: usize| {
+        let (r1, c1) = sheet.get_row_and_column(start);
+        let (r2, c2) = sheet.get_row_and_column(end);
+        (r2 - r1, c2 - c1)
+    };
+    if shape(start, end) != shape(other_start, other_end) {
+        return Err(StatusCode::InvalidRange);
+    }
+
+    let value = |cell: usize| {
+        let data = sheet.get(cell);
+        (!data.info.invalid()).then_some(data.value)
+    };
+    let text = |value: Option<f64>| value.map_or("ERR".to_string(), format_value);
+    let mismatches = range_cells(graph, start, end)
+        .into_iter()
+        .zip(range_cells(graph, other_start, other_end))
+        .filter(|&(a, b)| value(a) != value(b))
+        .map(|(a, b)| {
+            format!(
+                "{} = {}, {} = {}",
+                sheet.cell_name(a),
+                text(value(a)),
+                sheet.cell_name(b),
+                text(value(b))
+            )
+        })
+        .collect();
+    Ok(mismatches)
+}
+/// Rotates the whole sheet a quarter turn and rebuilds everything sized by its shape.
+///
+/// All formulas become literals (see `Sheet::rotate`), so the rebuilt graph starts with no
+/// dependencies. The global dimensions are swapped to match.
+fn rotate_sheet(graph: &mut Graph, clockwise: bool) {
+    let (n, m) = {
+        let mut sheet = graph.sheet.borrow_mut();
+        sheet.rotate(clockwise);
+        (sheet.n, sheet.m)
+    };
+    unsafe {
+        reset_dimensions(m, n);
+    }
+    rebuild_graph(graph, n, m);
+}
+/// Inserts or deletes a row or column, then rebuilds the graph around the moved cells.
+///
+/// Inserting pushes the last row (or column) off the sheet, so it fails with `OutOfBounds`
+/// unless that line is blank; formulas reading it become errors, like those reading a
+/// deleted line. Formulas whose ranges grew or shrank are recomputed with their dependents.
+/// Locked cells move with their lines, but a line holding one cannot be removed or pushed
+/// off, which fails with `CellLocked`.
+fn move_lines(graph: &mut Graph, axis: Axis, edit: LineEdit) -> Result<(), StatusCode> {
+    let (n, m, changed) = {
+        let mut sheet = graph.sheet.borrow_mut();
+        let (n, m) = (sheet.n, sheet.m);
+        let line = |k: usize| -> Vec<usize> {
+            match axis {
+                Axis::Row => (k * m..(k + 1) * m).collect(),
+                Axis::Column => (0..n).map(|r| r * m + k).collect(),
+            }
+        };
+        let dropped = match (edit, axis) {
+            (LineEdit::Insert(_), Axis::Row) => line(n - 1),
+            (LineEdit::Insert(_), Axis::Column) => line(m - 1),
+            (LineEdit::Delete(k), _) => line(k),
+        };
+        if dropped.iter().any(|&cell| sheet.is_locked(cell)) {
+            return Err(StatusCode::CellLocked);
+        }
+        let blank = dropped.iter().all(|&cell| sheet.get(cell).is_blank());
+        if matches!(edit, LineEdit::Insert(_)) && !blank {
+            return Err(StatusCode::OutOfBounds);
+        }
+        (n, m, sheet.move_lines(axis, edit))
+    };
+    rebuild_graph(graph, n, m);
+    for cell in 0..n * m {
+        let cell_info = graph.sheet.borrow().data[cell];
+        graph.add_expression(cell as i32, &cell_info);
+    }
+    // Locks keep formulas from being edited, not from following the lines they read
+    let locked = std::mem::take(&mut graph.sheet.borrow_mut().locked);
+    let result = changed.into_iter().try_for_each(|cell| {
+        let info = graph.sheet.borrow().get(cell).info;
+        graph.update_expression(cell, &info)
+    });
+    graph.sheet.borrow_mut().locked = locked;
+    Ok(result?)
+}
+/// Replaces the graph with an empty one for an `n` x `m` sheet, keeping its settings and
+/// registered functions. Used after the sheet's cells were replaced wholesale.
+pub fn rebuild_graph(graph: &mut Graph, n: usize, m: usize) {
+    let mut rebuilt = Graph::new(n, m, graph.sheet.clone());
+    rebuilt.recalc_order = graph.recalc_order;
+    rebuilt.threads = graph.threads;
+    rebuilt.calc = graph.calc;
+    rebuilt.stale = graph.stale;
+    rebuilt.trace = graph.trace.take();
+    rebuilt.subscribers = std::mem::take(&mut graph.subscribers);
+    rebuilt.functions = std::mem::take(&mut graph.functions);
+    rebuilt.cell_log = CellLog::new(graph.cell_log.max_depth());
+    *graph = rebuilt;
+}
+/// Replaces every cell with the contents of a CSV file written by `save`.
+///
+/// The whole file is parsed before anything changes, so an unreadable or malformed file
+/// (reported as `InvalidValue`) leaves the sheet as it was. Formulas are then entered as
+/// one batch through the graph, so their dependencies are tracked as if typed in and the
+/// sheet is computed once; of the cells of a cycle, the last in the file is loaded as a
+/// `#CYCLE` error instead, as if it had closed the cycle when typed in. Should entering
+/// them fail anyway, the previous cells are put back with their dependencies, so a load
+/// never leaves the sheet half replaced. Registered functions are saved under a
+/// placeholder name and cannot be loaded back.
+///
+/// Fails with `CellLocked`, before reading the file, if any cell is locked.
+fn load_csv(graph: &mut Graph, path: &str) -> Result<(), StatusCode> {
+    if !graph.sheet.borrow().locked.is_empty() {
+        return Err(StatusCode::CellLocked);
+    }
+    let cells = graph
+        .sheet
+        .borrow()
+        .load_csv(Path::new(path), &mut ParserContext::new())
+        .map_err(|_| StatusCode::InvalidValue)?;
+    let (n, m, previous) = {
+        let mut sheet = graph.sheet.borrow_mut();
+        let blank = vec![CellInfo::default(); sheet.n * sheet.m];
+        (sheet.n, sheet.m, std::mem::replace(&mut sheet.data, blank))
+    };
+    let cell_log = graph.cell_log.clone();
+    rebuild_graph(graph, n, m);
+    let result = enter_loaded_cells(graph, cells);
+    if result.is_err() {
+        graph.sheet.borrow_mut().data = previous;
+        rebuild_graph(graph, n, m);
+        for cell in 0..n * m {
+            let cell_info = graph.sheet.borrow().data[cell];
+            graph.add_expression(cell as i32, &cell_info);
+        }
+        graph.cell_log = cell_log;
+    }
+    result
+}
+/// Enters the cells of a loaded file into a blank sheet as one batch, loading the cell of
+/// a cycle that comes last in the file as a `#CYCLE` error, until no cycle is left.
+fn enter_loaded_cells(graph: &mut Graph, mut cells: Vec<(usize, Info)>) -> Result<(), StatusCode> {
+    loop {
+        graph.begin_batch();
+        for (cell, info) in &cells {
+            graph.update_expression(*cell, info)?;
+        }
+        let Err(UpdateError::Cycle(path)) = graph.commit_batch() else {
+            return Ok(());
+        };
+        // Break the cycle where typing the file in order would have found it
+        let closing = cells
+            .iter_mut()
+            .rev()
+            .find(|(cell, _)| path.contains(cell))
+            .ok_or(StatusCode::CyclicDep)?;
+        closing.1 = Info::error(CellError::Cycle);
+    }
+}
+/// Writes the fields of the CSV file at `path` into the sheet, the first field of the file
+/// landing on `at`, as one undoable edit. Empty fields leave their cell alone.
+///
+/// Returns `InvalidValue` if the file cannot be read and `OutOfBounds` if it does not fit
+/// between `at` and the edge of the sheet, before any cell is written.
+fn import_csv(graph: &mut Graph, path: &str, at: usize) -> Result<(), StatusCode> {
+    let rows = read_csv_values(Path::new(path)).map_err(|_| StatusCode::InvalidValue)?;
+    let writes = {
+        let sheet = graph.sheet.borrow();
+        let (r0, c0) = sheet.get_row_and_column(at);
+        let cols = rows.iter().map(Vec::len).max().unwrap_or(0);
+        if r0 + rows.len() > sheet.n || c0 + cols > sheet.m {
+            return Err(StatusCode::OutOfBounds);
+        }
+        let mut writes = Vec::new();
+        for (r, row) in rows.into_iter().enumerate() {
+            for (c, info) in row.into_iter().enumerate() {
+                if let Some(info) = info {
+                    writes.push((sheet.get_cell(r0 + r, c0 + c), info));
+                }
+            }
+        }
+        writes
+    };
+    write_cells(graph, &writes)
+}
+/// Rewrites every literal cell of `start:end` outside `[lo, hi]` as the nearest bound.
+///
+/// Formula cells and literals already in range are not touched, so their dependents are
+/// not recomputed needlessly.
+fn clamp(graph: &mut Graph, start: usize, end: usize, lo: i32, hi: i32) -> Result<(), StatusCode> {
+    let writes: Vec<(usize, Info)> = {
+        let sheet = graph.sheet.borrow();
+        range_cells(graph, start, end)
+            .into_iter()
+            .filter_map(|cell| {
+                let info = sheet.get(cell).info;
+                let literal = info.function_id == 0 && info.arg_mask == 0;
+                let value = info.arg[0];
+                (literal && !(lo..=hi).contains(&value))
+                    .then(|| (cell, Info::literal(value.clamp(lo, hi))))
+            })
+            .collect()
+    };
+    write_cells(graph, &writes)
+}
+/// Builds the `io_map` listing, e.g. `inputs: A1, A2` and `outputs: D1` on two lines.
+fn io_map(sheet: &Sheet) -> String {
+    let list = |role: CellRole| {
+        let mut cells: Vec<usize> = sheet
+            .roles
+            .iter()
+            .filter(|&(_, &r)| r == role)
+            .map(|(&cell, _)| cell)
+            .collect();
+        if cells.is_empty() {
+            return "(none)".to_string();
+        }
+        cells.sort_unstable();
+        let names: Vec<String> = cells.iter().map(|&c| sheet.cell_name(c)).collect();
+        names.join(", ")
+    };
+    format!(
+        "inputs: {}\noutputs: {}",
+        list(CellRole::Input),
+        list(CellRole::Output)
+    )
+}
+/// Returns the cells a formula reads: its cell arguments, or every cell of its ranges.
+fn precedents(sheet: &Sheet, info: &Info) -> Vec<usize> {
+    info.references()
+        .into_iter()
+        .flat_map(|(start, end)| {
+            let (r1, c1) = sheet.get_row_and_column(start);
+            let (r2, c2) = sheet.get_row_and_column(end);
+            (r1..=r2).flat_map(move |r| (c1..=c2).map(move |c| (r, c)))
+        })
+        .map(|(r, c)| sheet.get_cell(r, c))
+        .collect()
+}
+/// Returns the cell indices of a single-column range, top to bottom.
+///
+/// Returns `InvalidRange` if the range spans more than one column.
+fn column_cells(graph: &Graph, start: usize, end: usize) -> Result<Vec<usize>, StatusCode> {
+    let sheet = graph.sheet.borrow();
+    let (r1, c1) = sheet.get_row_and_column(start);
+    let (r2, c2) = sheet.get_row_and_column(end);
+    if c1 != c2 || r1 > r2 {
+        return Err(StatusCode::InvalidRange);
+    }
+    Ok((r1..=r2).map(|r| sheet.get_cell(r, c1)).collect())
+}
+/// Returns the cell indices of a rectangular range, row by row.
+fn range_cells(graph: &Graph, start: usize, end: usize) -> Vec<usize> {
+    let sheet = graph.sheet.borrow();
+    let (r1, c1) = sheet.get_row_and_column(start);
+    let (r2, c2) = sheet.get_row_and_column(end);
+    (r1..=r2)
+        .flat_map(|r| (c1..=c2).map(move |c| (r, c)))
+        .map(|(r, c)| sheet.get_cell(r, c))
+        .collect()
+}
+/// Returns `len` cells running down from `target`, failing with `InvalidRange` if they
+/// would run past the bottom of the sheet.
+fn target_column(graph: &Graph, target: usize, len: usize) -> Result<Vec<usize>, StatusCode> {
+    let sheet = graph.sheet.borrow();
+    let (row, col) = sheet.get_row_and_column(target);
+    if row + len > sheet.n {
+        return Err(StatusCode::InvalidRange);
+    }
+    Ok((row..row + len).map(|r| sheet.get_cell(r, col)).collect())
+}
+/// Reads the values of `cells`, failing with `InvalidValue` if any of them is invalid.
+fn read_numbers(graph: &Graph, cells: &[usize]) -> Result<Vec<f64>, StatusCode> {
+    let sheet = graph.sheet.borrow();
+    cells
+        .iter()
+        .map(|&cell| {
+            let data = sheet.get(cell);
+            if data.info.invalid() {
+                Err(StatusCode::InvalidValue)
+            } else {
+                Ok(data.value)
+            }
+        })
+        .collect()
+}
+/// Reads the values of `cells` as integers, for the commands that compute in integer
+/// arithmetic. Fails with `InvalidValue` if a cell is invalid or not a whole `i32`.
+fn read_values(graph: &Graph, cells: &[usize]) -> Result<Vec<i32>, StatusCode> {
+    read_numbers(graph, cells)?
+        .into_iter()
+        .map(whole_value)
+        .collect()
+}
+/// Converts a cell value to an `i32`, failing with `InvalidValue` if it has a fraction
+/// or does not fit.
+fn whole_value(value: f64) -> Result<i32, StatusCode> {
+    if value.fract() == 0.0 && value >= i32::MIN as f64 && value <= i32::MAX as f64 {
+        Ok(value as i32)
+    } else {
+        Err(StatusCode::InvalidValue)
+    }
+}
+/// Writes `info` into each paired cell as one batch, recomputing dependents once after
+/// the last write. A later write to a cell replaces an earlier one.
+///
+/// If the writes together would create a cycle or touch a locked cell, none of them is
+/// applied, so a failed command leaves the sheet unchanged.
+pub fn write_cells(graph: &mut Graph, writes: &[(usize, Info)]) -> Result<(), StatusCode> {
+    graph.begin_batch();
+    for (cell, info) in writes {
+        graph.update_expression(*cell, info)?;
+    }
+    Ok(graph.commit_batch()?)
+}
+/// Rescales a range to 0–100 as `(v - min) * 100 / (max - min)`, truncated to integers.
+///
+/// Invalid cells are left untouched and ignored when finding the minimum and maximum. A
+/// constant range becomes all 0s. Fails with `InvalidValue` if no cell in the range is valid.
+fn normalize(graph: &mut Graph, start: usize, end: usize) -> Result<(), StatusCode> {
+    let valid: Vec<(usize, i64)> = {
+        let sheet = graph.sheet.borrow();
+        range_cells(graph, start, end)
+            .into_iter()
+            .map(|cell| (cell, sheet.get(cell)))
+            .filter(|(_, data)| !data.info.invalid())
+            .map(|(cell, data)| Ok((cell, whole_value(data.value)? as i64)))
+            .collect::<Result<_, StatusCode>>()?
+    };
+    let min = valid.iter().map(|&(_, v)| v).min();
+    let max = valid.iter().map(|&(_, v)| v).max();
+    let (Some(min), Some(max)) = (min, max) else {
+        return Err(StatusCode::InvalidValue);
+    };
+
+    let writes: Vec<(usize, Info)> = valid
+        .iter()
+        .map(|&(cell, v)| {
+            let scaled = if max == min {
+                0
+            } else {
+                (v - min) * 100 / (max - min)
+            };
+            (cell, Info::literal(scaled as i32))
+        })
+        .collect();
+    write_cells(graph, &writes)
+}
+/// Fills a range with random integers in `[lo, hi]` drawn from the sheet's generator,
+/// written as literals.
+fn random_fill(
+    graph: &mut Graph,
+    start: usize,
+    end: usize,
+    lo: i32,
+    hi: i32,
+) -> Result<(), StatusCode> {
+    let cells = range_cells(graph, start, end);
+    let writes: Vec<(usize, Info)> = {
+        let rng = &mut graph.sheet.borrow_mut().rng;
+        cells
+            .into_iter()
+            .map(|cell| (cell, Info::literal(rng.range(lo, hi))))
+            .collect()
+    };
+    write_cells(graph, &writes)
+}
+/// Writes the first differences `a[i] - a[i-1]` of the column `start:end` down from
+/// `target`. The first result, which has no predecessor, is 0.
+///
+/// Returns `InvalidValue` if a source cell is invalid and `Overflow` if a difference does
+/// not fit in a cell value.
+fn delta(graph: &mut Graph, start: usize, end: usize, target: usize) -> Result<(), StatusCode> {
+    let cells = column_cells(graph, start, end)?;
+    let values = read_values(graph, &cells)?;
+    let targets = target_column(graph, target, cells.len())?;
+
+    let mut writes = vec![(targets[0], Info::literal(0))];
+    for (i, pair) in values.windows(2).enumerate() {
+        let difference = pair[1].checked_sub(pair[0]).ok_or(StatusCode::Overflow)?;
+        writes.push((targets[i + 1], Info::literal(difference)));
+    }
+    write_cells(graph, &writes)
+}
+/// Writes the `window`-cell moving average of the column `start:end` down from `target`.
+///
+/// Each result is the truncated integer mean of the current cell and the `window - 1`
+/// cells above it. The first `window - 1` results are errors unless `shrink` is set, in
+/// which case they average over the cells available so far. Returns `InvalidRange` if
+/// the results would run past the bottom of the sheet.
+fn moving_average(
+    graph: &mut Graph,
+    start: usize,
+    end: usize,
+    window: usize,
+    target: usize,
+    shrink: bool,
+) -> Result<(), StatusCode> {
+    let cells = column_cells(graph, start, end)?;
+    let values = read_values(graph, &cells)?;
+    let targets = target_column(graph, target, cells.len())?;
+
+    let writes: Vec<(usize, Info)> = targets
+        .iter()
+        .enumerate()
+        .map(|(i, &cell)| {
+            let from = (i + 1).saturating_sub(window);
+            if i + 1 < window && !shrink {
+                return (cell, Info::error(CellError::Value));
+            }
+            let slice = &values[from..=i];
+            let sum: i64 = slice.iter().map(|&v| v as i64).sum();
+            (cell, Info::literal((sum / slice.len() as i64) as i32))
+        })
+        .collect();
+    write_cells(graph, &writes)
+}
+/// Writes the weighted moving average of the column `start:end` down from `target`.
+///
+/// The weights are read from `weights_start:weights_end` and their count is the window
+/// size. Each result is `sum(weight * value) / sum(weights)` over the current cell and
+/// the cells above it, truncated to an integer, with the first weight applied to the
+/// oldest cell. The first `window - 1` results are errors. Fails with `InvalidValue` if
+/// the weights sum to zero and `Overflow` if a result does not fit in a cell.
+fn weighted_moving_average(
+    graph: &mut Graph,
+    start: usize,
+    end: usize,
+    weights_start: usize,
+    weights_end: usize,
+    target: usize,
+) -> Result<(), StatusCode> {
+    let cells = column_cells(graph, start, end)?;
+    let values = read_values(graph, &cells)?;
+    let weight_cells = range_cells(graph, weights_start, weights_end);
+    let weights = read_values(graph, &weight_cells)?;
+    let weight_sum: i64 = weights.iter().map(|&w| w as i64).sum();
+    if weights.is_empty() || weight_sum == 0 {
+        return Err(StatusCode::InvalidValue);
+    }
+    let targets = target_column(graph, target, cells.len())?;
+
+    let window = weights.len();
+    let mut writes = Vec::with_capacity(cells.len());
+    for (i, &cell) in targets.iter().enumerate() {
+        if i + 1 < window {
+            writes.push((cell, Info::error(CellError::Value)));
+            continue;
+        }
+        let weighted: i64 = values[i + 1 - window..=i]
+            .iter()
+            .zip(&weights)
+            .map(|(&v, &w)| v as i64 * w as i64)
+            .sum();
+        let average = i32::try_from(weighted / weight_sum).map_err(|_| StatusCode::Overflow)?;
+        writes.push((cell, Info::literal(average)));
+    }
+    write_cells(graph, &writes)
+}
+/// Rewrites the cell references of a formula through `f`, which takes a referenced cell
+/// and its fixed flags and returns the replacement or `None` to leave it as is.
+///
+/// Literal arguments are never touched. The two corners of a range argument are only
+/// rewritten if `f` replaces both of them and they still form a range of `sheet`.
+fn map_references<F>(sheet: &Sheet, info: &mut Info, mut f: F)
+where
+    F: FnMut(usize, u8) -> Option<usize>,
+{
+    let is_range = |start, end| sheet.is_valid_range(start, end);
+    if let Some(expr) = info.expression_value() {
+        info.arg[0] = expr::intern(expr.map_references(&mut f, &is_range));
+        return;
+    }
+    if is_range_function(info.function_id) {
+        let corners = (
+            f(info.arg[0] as usize, info.fixed(0)),
+            f(info.arg[1] as usize, info.fixed(1)),
+        );
+        if let (Some(a), Some(b)) = corners {
+            if is_range(a, b) {
+                info.arg = [a as i32, b as i32];
+            }
+        }
+        return;
+    }
+    for i in 0..2 {
+        if info.arg_mask & (1 << i) != 0 {
+            if let Some(cell) = f(info.arg[i] as usize, info.fixed(i)) {
+                info.arg[i] = cell as i32;
+            }
+        }
+    }
+}
+/// Returns the number of leading arguments of a classic formula that hold a literal
+/// unless they are references: one for a plain literal, `SLEEP`, `FACT`, `FIB`, `ABS`
+/// and registered functions, two for the operators and the two-argument functions.
+fn literal_arguments(function_id: u8) -> usize {
+    match function_id {
+        0 | 1 | 11 | 12 | ABS => 1,
+        2..=5 | RANDBETWEEN | DAYS => 2,
+        id if is_scalar_function(id) => 2,
+        id if id as usize >= FPTR.len() => 1,
+        _ => 0,
+    }
+}
+/// Rewrites every formula, across the whole sheet, whose new `Info` `rewrite` returns,
+/// as one batch: the changed formulas are checked for cycles together and recomputed
+/// once, and nothing changes if one of them is rejected.
+fn rewrite_formulas<F>(graph: &mut Graph, mut rewrite: F) -> Result<(), StatusCode>
+where
+    F: FnMut(&Sheet, Info) -> Info,
+{
+    let writes: Vec<(usize, Info)> = {
+        let sheet = graph.sheet.borrow();
+        (0..sheet.n * sheet.m)
+            .filter_map(|cell| {
+                let original = sheet.get(cell).info;
+                let mut info = rewrite(&sheet, original);
+                info.visit = original.visit;
+                (info != original).then(|| {
+                    info.visit = 0;
+                    (cell, info)
+                })
+            })
+            .collect()
+    };
+    write_cells(graph, &writes)
+}
+/// Rewrites every reference to `from` into one to `to`, keeping its fixed flags. A range
+/// with `from` as a corner gets `to` in its place if they still form a range.
+///
+/// Returns `CyclicDep` if a rewritten formula would then read its own cell.
+fn replace_reference(graph: &mut Graph, from: usize, to: usize) -> Result<(), StatusCode> {
+    rewrite_formulas(graph, |sheet, mut info| {
+        // Every reference is "replaced", by itself if need be, so a range corner can move alone
+        map_references(sheet, &mut info, |cell, _| {
+            Some(if cell == from { to } else { cell })
+        });
+        info
+    })
+}
+/// Rewrites every whole-number literal equal to `from` into `to`: plain literal cells,
+/// the literal arguments of classic formulas and the numbers inside expressions. Cell
+/// references, constants, text and error literals are left alone.
+fn replace_literal(graph: &mut Graph, from: i32, to: i32) -> Result<(), StatusCode> {
+    rewrite_formulas(graph, |_, mut info| {
+        if let Some(expr) = info.expression_value() {
+            let mut replace = |value: f64| (value == from as f64).then_some(to as f64);
+            info.arg[0] = expr::intern(expr.map_numbers(&mut replace));
+            return info;
+        }
+        for i in 0..literal_arguments(info.function_id) {
+            if info.arg_mask & (1 << i) == 0 && info.arg[i] == from {
+                info.arg[i] = to;
+            }
+        }
+        info
+    })
+}
+/// Shifts every cell reference in the formulas of `start:end` by `rows` and `cols`,
+/// leaving the cells themselves in place. Absolute references move too, since the
+/// references are what is being moved.
+///
+/// Returns `InvalidRange` if a shifted reference would leave the sheet. The rewritten
+/// formulas go through cycle detection, and nothing changes if any of them is rejected.
+fn shift_references(
+    graph: &mut Graph,
+    start: usize,
+    end: usize,
+    rows: isize,
+    cols: isize,
+) -> Result<(), StatusCode> {
+    let writes = {
+        let sheet = graph.sheet.borrow();
+        let mut out_of_bounds = false;
+        let mut shift = |cell: usize, _: u8| -> Option<usize> {
+            let (r, c) = sheet.get_row_and_column(cell);
+            match (r.checked_add_signed(rows), c.checked_add_signed(cols)) {
+                (Some(r), Some(c)) if r < sheet.n && c < sheet.m => Some(sheet.get_cell(r, c)),
+                _ => {
+                    out_of_bounds = true;
+                    None
+                }
+            }
+        };
+
+        let mut writes = Vec::new();
+        for cell in range_cells(graph, start, end) {
+            let original = sheet.get(cell).info;
+            let mut info = original;
+            info.visit = 0;
+            map_references(&sheet, &mut info, &mut shift);
+            if info.arg != original.arg {
+                writes.push((cell, info));
+            }
+        }
+        if out_of_bounds {
+            return Err(StatusCode::InvalidRange);
+        }
+        writes
+    };
+    write_cells(graph, &writes)
+}
+/// Copies the rectangle `start:end` so that its top-left corner lands on `target`.
+///
+/// References to cells inside the source rectangle are re-based onto the copy, so the
+/// block keeps its internal links, except for their `$` parts; references outside it stay
+/// absolute. A range argument is re-based only if it lies entirely inside the source
+/// rectangle. Returns `InvalidRange` if the copy would extend past the edge of the sheet.
+fn duplicate_region(
+    graph: &mut Graph,
+    start: usize,
+    end: usize,
+    target: usize,
+) -> Result<(), StatusCode> {
+    let writes = {
+        let sheet = graph.sheet.borrow();
+        let (r1, c1) = sheet.get_row_and_column(start);
+        let (r2, c2) = sheet.get_row_and_column(end);
+        let (tr, tc) = sheet.get_row_and_column(target);
+        if tr + (r2 - r1) >= sheet.n || tc + (c2 - c1) >= sheet.m {
+            return Err(StatusCode::InvalidRange);
+        }
+
+        let rebase = |cell: usize, fixed: u8| -> Option<usize> {
+            let (r, c) = sheet.get_row_and_column(cell);
+            // References outside the block are left alone, and must not be shifted
+            if !(r1..=r2).contains(&r) || !(c1..=c2).contains(&c) {
+                return None;
+            }
+            let row = if fixed & FIXED_ROW != 0 {
+                r
+            } else {
+                r - r1 + tr
+            };
+            let col = if fixed & FIXED_COL != 0 {
+                c
+            } else {
+                c - c1 + tc
+            };
+            Some(sheet.get_cell(row, col))
+        };
+
+        let mut writes = Vec::new();
+        for r in r1..=r2 {
+            for c in c1..=c2 {
+                let mut info = sheet.get(sheet.get_cell(r, c)).info;
+                info.visit = 0;
+                map_references(&sheet, &mut info, rebase);
+                writes.push((sheet.get_cell(r - r1 + tr, c - c1 + tc), info));
+            }
+        }
+        writes
+    };
+    write_cells(graph, &writes)
+}
+/// Copies the formula or value of `start`, the top-left cell of `start:end`, into every
+/// other cell of the range.
+///
+/// Each copy reads the cells at the same offset from it as the original does from `start`,
+/// so `A2=A1+1` filled down to A4 gives `A3=A2+1` and `A4=A3+1`; the `$` parts of a
+/// reference stay fixed. Returns `InvalidRange` if a shifted reference would leave the
+/// sheet. All copies go through cycle detection, and nothing changes if any is rejected.
+fn fill(graph: &mut Graph, start: usize, end: usize) -> Result<(), StatusCode> {
+    let writes = {
+        let sheet = graph.sheet.borrow();
+        let (r1, c1) = sheet.get_row_and_column(start);
+        let anchor = sheet.get(start).info;
+        let mut out_of_bounds = false;
+
+        let mut writes = Vec::new();
+        for cell in range_cells(graph, start, end).into_iter().skip(1) {
+            let (r, c) = sheet.get_row_and_column(cell);
+            let shift = |referenced: usize, fixed: u8| -> Option<usize> {
+                let (rr, rc) = sheet.get_row_and_column(referenced);
+                let row = if fixed & FIXED_ROW != 0 {
+                    rr
+                } else {
+                    rr + r - r1
+                };
+                let col = if fixed & FIXED_COL != 0 {
+                    rc
+                } else {
+                    rc + c - c1
+                };
+                if row < sheet.n && col < sheet.m {
+                    Some(sheet.get_cell(row, col))
+                } else {
+                    out_of_bounds = true;
+                    None
+                }
+            };
+            let mut info = anchor;
+            info.visit = 0;
+            map_references(&sheet, &mut info, shift);
+            writes.push((cell, info));
+        }
+        if out_of_bounds {
+            return Err(StatusCode::InvalidRange);
+        }
+        writes
+    };
+    write_cells(graph, &writes)
+}
+/// Groups the two-column range `start:end` by key and writes the per-key sums.
+///
+/// The first column holds the keys and the second the values. Rows where either cell is
+/// invalid are skipped. The distinct keys are written in ascending order down from
+/// `target`, each with its sum in the column to its right. Returns `InvalidRange` if the
+/// range is not exactly two columns wide or the output would run off the sheet, and
+/// `Overflow` if a sum does not fit in a cell. Keys and values must be whole numbers.
+fn pivot(graph: &mut Graph, start: usize, end: usize, target: usize) -> Result<(), StatusCode> {
+    let writes = {
+        let sheet = graph.sheet.borrow();
+        let (r1, c1) = sheet.get_row_and_column(start);
+        let (r2, c2) = sheet.get_row_and_column(end);
+        if c2 != c1 + 1 || r1 > r2 {
+            return Err(StatusCode::InvalidRange);
+        }
+
+        let mut sums: HashMap<i32, i64> = HashMap::new();
+        for r in r1..=r2 {
+            let key = sheet.get(sheet.get_cell(r, c1));
+            let value = sheet.get(sheet.get_cell(r, c2));
+            if key.info.invalid() || value.info.invalid() {
+                continue;
+            }
+            *sums.entry(whole_value(key.value)?).or_insert(0) += whole_value(value.value)? as i64;
+        }
+        let mut groups: Vec<(i32, i64)> = sums.into_iter().collect();
+        groups.sort_unstable();
+
+        let (tr, tc) = sheet.get_row_and_column(target);
+        if tr + groups.len() > sheet.n || tc + 1 >= sheet.m {
+            return Err(StatusCode::InvalidRange);
+        }
+        let mut writes = Vec::with_capacity(groups.len() * 2);
+        for (i, (key, sum)) in groups.into_iter().enumerate() {
+            let sum = i32::try_from(sum).map_err(|_| StatusCode::Overflow)?;
+            writes.push((sheet.get_cell(tr + i, tc), Info::literal(key)));
+            writes.push((sheet.get_cell(tr + i, tc + 1), Info::literal(sum)));
+        }
+        writes
+    };
+    write_cells(graph, &writes)
+}
+/// Replaces a column with the cumulative percentage of its running total (Pareto style).
+///
+/// Each cell becomes `running_total * 100 / grand_total`, truncated to an integer literal.
+/// A zero grand total marks every cell in the column invalid.
+fn cumulative_percent(graph: &mut Graph, start: usize, end: usize) -> Result<(), StatusCode> {
+    let cells = column_cells(graph, start, end)?;
+    let values = read_values(graph, &cells)?;
+    let total: i64 = values.iter().map(|&v| v as i64).sum();
+
+    let mut running: i64 = 0;
+    let writes: Vec<(usize, Info)> = cells
+        .iter()
+        .zip(&values)
+        .map(|(&cell, &v)| {
+            running += v as i64;
+            if total == 0 {
+                (cell, Info::error(CellError::DivByZero))
+            } else {
+                (cell, Info::literal((running * 100 / total) as i32))
+            }
+        })
+        .collect();
+    write_cells(graph, &writes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::ParserContext;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn create_test_graph(n: usize, m: usize) -> Graph {
+        let sheet = Rc::new(RefCell::new(Sheet::new(n, m)));
+        Graph::new(n, m, sheet)
+    }
+
+    fn set_column(graph: &mut Graph, col: usize, values: &[i32]) {
+        for (row, &v) in values.iter().enumerate() {
+            let cell = graph.sheet.borrow().get_cell(row, col);
+            graph.update_expression(cell, &Info::literal(v)).unwrap();
+        }
+    }
+
+    fn column_values(graph: &Graph, col: usize, rows: usize) -> Vec<Option<i32>> {
+        let sheet = graph.sheet.borrow();
+        (0..rows)
+            .map(|row| {
+                let data = sheet.get(sheet.get_cell(row, col));
+                (!data.info.invalid()).then_some(data.value as i32)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_cumulative_percent() {
+        let mut graph = create_test_graph(5, 3);
+        set_column(&mut graph, 0, &[10, 20, 20, 50]);
+        let end = graph.sheet.borrow().get_cell(3, 0);
+        execute(&mut graph, &Command::CumulativePercent { start: 0, end }).unwrap();
+        assert_eq!(
+            column_values(&graph, 0, 4),
+            vec![Some(10), Some(30), Some(50), Some(100)]
+        );
+    }
+
+    #[test]
+    fn test_cumulative_percent_recomputes_dependents() {
+        let mut graph = create_test_graph(5, 3);
+        set_column(&mut graph, 0, &[1, 1, 2]);
+        // B1 = A2 + 0
+        let (a2, b1) = {
+            let sheet = graph.sheet.borrow();
+            (sheet.get_cell(1, 0), sheet.get_cell(0, 1))
+        };
+        let info = Info {
+            function_id: 2,
+            arg_mask: 0b1,
+            arg: [a2 as i32, 0],
+            ..Default::default()
+        };
+        graph.update_expression(b1, &info).unwrap();
+
+        let end = graph.sheet.borrow().get_cell(2, 0);
+        execute(&mut graph, &Command::CumulativePercent { start: 0, end }).unwrap();
+        assert_eq!(column_values(&graph, 1, 1), vec![Some(50)]);
+    }
+
+    #[test]
+    fn test_cumulative_percent_zero_total() {
+        let mut graph = create_test_graph(5, 3);
+        set_column(&mut graph, 0, &[5, -5, 0]);
+        let end = graph.sheet.borrow().get_cell(2, 0);
+        execute(&mut graph, &Command::CumulativePercent { start: 0, end }).unwrap();
+        assert_eq!(column_values(&graph, 0, 3), vec![None, None, None]);
+    }
+
+    #[test]
+    fn test_impact_report() {
+        let mut graph = create_test_graph(3, 3);
+        // B1 = A1 + 1, C1 = B1 + 1
+        let chain = [(1, 0), (2, 1)];
+        for (cell, source) in chain {
+            let info = Info {
+                function_id: 2,
+                arg_mask: 0b1,
+                arg: [source, 1],
+                ..Default::default()
+            };
+            graph.update_expression(cell, &info).unwrap();
+        }
+        assert_eq!(impact_report(&graph, 0), "A1 impacts 2 cell(s): B1, C1");
+        assert_eq!(impact_report(&graph, 2), "C1 impacts 0 cell(s): ");
+    }
+
+    #[test]
+    fn test_deps_report_lists_both_directions() {
+        let mut graph = create_test_graph(3, 3);
+        // B1 = A1 + 1, C1 = B1 * A1, A2 = SUM(B1:C1)
+        let chain = [
+            (1, formula(2, [0, 1], 0b1)),
+            (2, formula(4, [1, 0], 0b11)),
+            (3, formula(8, [1, 2], 0b11)),
+        ];
+        for (cell, info) in &chain {
+            graph.update_expression(*cell, info).unwrap();
+        }
+        assert_eq!(
+            deps_report(&graph, 1, false),
+            "B1 depends on: A1\nB1 is used by: C1, A2"
+        );
+        assert_eq!(
+            deps_report(&graph, 3, true),
+            "A2 depends on:\n  B1\n    A1\n  C1\n    A1 (see above)\n    B1 (see above)\nA2 is used by:"
+        );
+        assert_eq!(
+            deps_report(&graph, 0, true),
+            "A1 depends on:\nA1 is used by:\n  B1\n    C1\n      A2\n    A2 (see above)\n  C1 (see above)"
+        );
+    }
+
+    #[test]
+    fn test_why_finds_root_division_by_zero() {
+        let mut graph = create_test_graph(3, 3);
+        // C1 = 5/0, B1 = C1 + 1, A1 = MAX(B1:C1), A2 = 10/A3
+        let chain = [
+            (2, formula(5, [5, 0], 0)),
+            (1, formula(2, [2, 1], 0b1)),
+            (0, formula(6, [1, 2], 0b11)),
+            (3, formula(5, [10, 6], 0b10)),
+        ];
+        for (cell, info) in &chain {
+            graph.update_expression(*cell, info).unwrap();
+        }
+        assert_eq!(
+            why_report(&graph, 0),
+            "A1 ERR because B1 ERR because C1 = 5/0 (#DIV/0)"
+        );
+        assert_eq!(why_report(&graph, 3), "A2 = 10/A3 where A3 = 0 (#DIV/0)");
+        assert_eq!(why_report(&graph, 6), "A3 is not an error");
+    }
+
+    #[test]
+    fn test_adjust_literal_cells() {
+        let mut graph = create_test_graph(3, 3);
+        set_column(&mut graph, 0, &[10]);
+        for (function_id, amount, expected) in [(2, 5, 15), (3, 3, 12), (4, 2, 24)] {
+            let command = Command::Adjust {
+                cell: 0,
+                function_id,
+                amount,
+            };
+            execute(&mut graph, &command).unwrap();
+            assert_eq!(column_values(&graph, 0, 1), vec![Some(expected)]);
+        }
+        // Empty cells count as the literal 0.
+        let command = Command::Adjust {
+            cell: 1,
+            function_id: 2,
+            amount: 4,
+        };
+        execute(&mut graph, &command).unwrap();
+        assert_eq!(column_values(&graph, 1, 1), vec![Some(4)]);
+    }
+
+    #[test]
+    fn test_adjust_rejects_formula_cells_and_overflow() {
+        let mut graph = create_test_graph(3, 3);
+        set_column(&mut graph, 0, &[i32::MAX]);
+        let formula = Info {
+            function_id: 2,
+            arg_mask: 0b1,
+            arg: [2, 1],
+            ..Default::default()
+        };
+        // B1 = C1 + 1
+        graph.update_expression(1, &formula).unwrap();
+
+        assert_eq!(
+            adjusted_literal(&graph, 1, 2, 1).err(),
+            Some(StatusCode::InvalidCell)
+        );
+        assert_eq!(
+            adjusted_literal(&graph, 0, 2, 1).err(),
+            Some(StatusCode::Overflow)
+        );
+        assert_eq!(column_values(&graph, 0, 1), vec![Some(i32::MAX)]);
+    }
+
+    fn formula(function_id: u8, arg: [i32; 2], arg_mask: u8) -> Info {
+        Info {
+            function_id,
+            arg_mask,
+            arg,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_duplicate_region_rebases_internal_references() {
+        let mut graph = create_test_graph(5, 5);
+        let cell = |r, c| graph.sheet.borrow().get_cell(r, c);
+        let (a1, b1, a2, b2, d1) = (cell(0, 0), cell(0, 1), cell(1, 0), cell(1, 1), cell(0, 3));
+        let (c3, d3, c4, d4) = (cell(2, 2), cell(2, 3), cell(3, 2), cell(3, 3));
+        // A1 = 3, B1 = A1 + 1, A2 = SUM(A1:B1), B2 = D1 * 2, D1 = 10
+        graph.update_expression(a1, &Info::literal(3)).unwrap();
+        graph.update_expression(d1, &Info::literal(10)).unwrap();
+        let region = [
+            (b1, formula(2, [a1 as i32, 1], 0b1)),
+            (a2, formula(8, [a1 as i32, b1 as i32], 0b11)),
+            (b2, formula(4, [d1 as i32, 2], 0b1)),
+        ];
+        for (cell, info) in &region {
+            graph.update_expression(*cell, info).unwrap();
+        }
+
+        let command = Command::DuplicateRegion {
+            start: a1,
+            end: b2,
+            target: c3,
+        };
+        execute(&mut graph, &command).unwrap();
+        let sheet = graph.sheet.clone();
+        let value = |cell: usize| sheet.borrow().get(cell).value as i32;
+        assert_eq!([value(c3), value(d3), value(c4), value(d4)], [3, 4, 7, 20]);
+
+        // The copy follows its own top-left corner, not the original's.
+        graph.update_expression(c3, &Info::literal(5)).unwrap();
+        graph.update_expression(a1, &Info::literal(100)).unwrap();
+        assert_eq!([value(d3), value(c4)], [6, 11]);
+        // External references stay absolute.
+        graph.update_expression(d1, &Info::literal(1)).unwrap();
+        assert_eq!([value(b2), value(d4)], [2, 2]);
+    }
+
+    #[test]
+    fn test_duplicate_region_keeps_references_above_and_left() {
+        let mut graph = create_test_graph(5, 5);
+        let cell = |r, c| graph.sheet.borrow().get_cell(r, c);
+        let (a1, b2, c2, c3, d4, e4) = (
+            cell(0, 0),
+            cell(1, 1),
+            cell(1, 2),
+            cell(2, 2),
+            cell(3, 3),
+            cell(3, 4),
+        );
+        // A1 = 3, B2 = A1 + 1, C2 = B2 * 2, with A1 above and left of the block B2:C3
+        graph.update_expression(a1, &Info::literal(3)).unwrap();
+        graph
+            .update_expression(b2, &formula(2, [a1 as i32, 1], 0b1))
+            .unwrap();
+        graph
+            .update_expression(c2, &formula(4, [b2 as i32, 2], 0b1))
+            .unwrap();
+
+        let command = Command::DuplicateRegion {
+            start: b2,
+            end: c3,
+            target: d4,
+        };
+        execute(&mut graph, &command).unwrap();
+        let sheet = graph.sheet.clone();
+        let value = |cell: usize| sheet.borrow().get(cell).value as i32;
+        assert_eq!([value(d4), value(e4)], [4, 8]);
+        graph.update_expression(a1, &Info::literal(10)).unwrap();
+        assert_eq!([value(d4), value(e4)], [11, 22]);
+    }
+
+    #[test]
+    fn test_duplicate_region_keeps_absolute_parts() {
+        let mut graph = create_test_graph(5, 5);
+        let cell = |r, c| graph.sheet.borrow().get_cell(r, c);
+        let (a1, b1, a2, b2, c3, d3, d4) = (
+            cell(0, 0),
+            cell(0, 1),
+            cell(1, 0),
+            cell(1, 1),
+            cell(2, 2),
+            cell(2, 3),
+            cell(3, 3),
+        );
+        // A1 = 3, A2 = 4, B1 = $A$1 + A2, B2 = SUM($A1:A2)
+        graph.update_expression(a1, &Info::literal(3)).unwrap();
+        graph.update_expression(a2, &Info::literal(4)).unwrap();
+        let mut sum = formula(2, [a1 as i32, a2 as i32], 0b11);
+        sum.fixed = FIXED_COL | FIXED_ROW;
+        graph.update_expression(b1, &sum).unwrap();
+        let mut total = formula(8, [a1 as i32, a2 as i32], 0b11);
+        total.fixed = FIXED_COL;
+        graph.update_expression(b2, &total).unwrap();
+
+        let command = Command::DuplicateRegion {
+            start: a1,
+            end: b2,
+            target: c3,
+        };
+        execute(&mut graph, &command).unwrap();
+        let sheet = graph.sheet.borrow();
+        let text = |cell: usize| sheet.formula_text(&sheet.get(cell).info);
+        assert_eq!([text(d3), text(d4)], ["$A$1+C4", "SUM($A3:C4)"]);
+        assert_eq!([sheet.get(d3).value, sheet.get(d4).value], [7.0, 7.0]);
+    }
+
+    #[test]
+    fn test_delete_row_moves_cells_and_references() {
+        let mut graph = create_test_graph(5, 3);
+        set_column(&mut graph, 0, &[1, 2, 3, 4, 5]);
+        // B1 = SUM(A1:A5), B2 = A3 * 10, B5 = A5 + 1, C1 = B5
+        let writes = [
+            (1, formula(8, [0, 12], 0b11)),
+            (4, formula(4, [6, 10], 0b1)),
+            (13, formula(2, [12, 1], 0b1)),
+            (2, formula(0, [13, 0], 0b1)),
+        ];
+        for (cell, info) in &writes {
+            graph.update_expression(*cell, info).unwrap();
+        }
+        graph.sheet.borrow_mut().roles.insert(12, CellRole::Input);
+
+        let delete = |row| Command::MoveLines {
+            axis: Axis::Row,
+            edit: LineEdit::Delete(row),
+        };
+        execute(&mut graph, &delete(2)).unwrap();
+        assert_eq!(
+            column_values(&graph, 0, 5),
+            vec![Some(1), Some(2), Some(4), Some(5), Some(0)]
+        );
+        let sheet = graph.sheet.clone();
+        // The sum lost A3, B2 read it and is now an error, B5 moved up to B4
+        assert_eq!(sheet.borrow().get(1).value, 12.0);
+        assert_eq!(sheet.borrow().get(1).info.arg, [0, 9]);
+        assert!(sheet.borrow().get(4).info.invalid());
+        assert_eq!(sheet.borrow().get(10).value, 6.0);
+        assert_eq!(sheet.borrow().get(2).info.arg[0], 10);
+        assert_eq!(sheet.borrow().roles.get(&9), Some(&CellRole::Input));
+
+        // The rebuilt graph still tracks the moved references
+        graph.update_expression(9, &Info::literal(10)).unwrap();
+        assert_eq!(
+            [1, 10, 2].map(|cell| sheet.borrow().get(cell).value),
+            [17.0, 11.0, 11.0]
+        );
+    }
+
+    #[test]
+    fn test_insert_column_needs_a_blank_last_column() {
+        let mut graph = create_test_graph(2, 3);
+        // A1 = 4, B1 = A1 + 1, A2 = SUM(A1:B1)
+        graph.update_expression(0, &Info::literal(4)).unwrap();
+        graph
+            .update_expression(1, &formula(2, [0, 1], 0b1))
+            .unwrap();
+        graph
+            .update_expression(3, &formula(8, [0, 1], 0b11))
+            .unwrap();
+
+        let insert = Command::MoveLines {
+            axis: Axis::Column,
+            edit: LineEdit::Insert(1),
+        };
+        execute(&mut graph, &insert).unwrap();
+        {
+            let sheet = graph.sheet.borrow();
+            assert_eq!(sheet.get(2).info.arg, [0, 1]);
+            assert_eq!(
+                (sheet.get(1).info.function_id, sheet.get(1).value),
+                (0, 0.0)
+            );
+            // The range now spans the blank column too
+            assert_eq!((sheet.get(3).info.arg, sheet.get(3).value), ([0, 2], 9.0));
+        }
+        // C1 is now in use, so another insert would push it off the sheet
+        assert_eq!(execute(&mut graph, &insert), Err(StatusCode::OutOfBounds));
+    }
+
+    #[test]
+    fn test_shift_references() {
+        let mut graph = create_test_graph(4, 3);
+        set_column(&mut graph, 0, &[1, 2, 3, 4]);
+        // B1 = A1 * 10, B2 = SUM(A1:A2), B3 = 7
+        let region = [
+            (1, formula(4, [0, 10], 0b1)),
+            (4, formula(8, [0, 3], 0b11)),
+            (7, Info::literal(7)),
+        ];
+        for (cell, info) in &region {
+            graph.update_expression(*cell, info).unwrap();
+        }
+
+        let command = Command::ShiftReferences {
+            start: 1,
+            end: 7,
+            rows: 1,
+            cols: 0,
+        };
+        execute(&mut graph, &command).unwrap();
+        assert_eq!(
+            column_values(&graph, 1, 3),
+            vec![Some(20), Some(5), Some(7)]
+        );
+        // The shifted formulas are live: A3 now feeds B2
+        graph.update_expression(6, &Info::literal(30)).unwrap();
+        assert_eq!(column_values(&graph, 1, 2), vec![Some(20), Some(32)]);
+    }
+
+    #[test]
+    fn test_shift_references_rejects_bounds_and_cycles() {
+        let mut graph = create_test_graph(3, 3);
+        // B1 = A1 + 1, B2 = A2 + 1
+        graph
+            .update_expression(1, &formula(2, [0, 1], 0b1))
+            .unwrap();
+        graph
+            .update_expression(4, &formula(2, [3, 1], 0b1))
+            .unwrap();
+
+        let up = Command::ShiftReferences {
+            start: 1,
+            end: 4,
+            rows: -1,
+            cols: 0,
+        };
+        assert_eq!(execute(&mut graph, &up), Err(StatusCode::InvalidRange));
+
+        // Shifting right makes B1 = B1 + 1
+        let right = Command::ShiftReferences {
+            start: 1,
+            end: 4,
+            rows: 0,
+            cols: 1,
+        };
+        assert_eq!(execute(&mut graph, &right), Err(StatusCode::CyclicDep));
+        let sheet = graph.sheet.borrow();
+        assert_eq!([sheet.get(1).info.arg[0], sheet.get(4).info.arg[0]], [0, 3]);
+    }
+
+    #[test]
+    fn test_duplicate_region_rejects_out_of_bounds_and_cycles() {
+        let mut graph = create_test_graph(3, 3);
+        let command = Command::DuplicateRegion {
+            start: 0,
+            end: 4,
+            target: 5,
+        };
+        assert_eq!(execute(&mut graph, &command), Err(StatusCode::InvalidRange));
+
+        // A1 = 1, B1 = C1 + 0, C1 = 7: copying A1:B1 onto B1:C1 makes C1 = C1 + 0.
+        graph.update_expression(0, &Info::literal(1)).unwrap();
+        graph.update_expression(2, &Info::literal(7)).unwrap();
+        graph
+            .update_expression(1, &formula(2, [2, 0], 0b1))
+            .unwrap();
+        let command = Command::DuplicateRegion {
+            start: 0,
+            end: 1,
+            target: 1,
+        };
+        assert_eq!(execute(&mut graph, &command), Err(StatusCode::CyclicDep));
+        let sheet = graph.sheet.borrow();
+        assert_eq!(
+            [sheet.get(0).value, sheet.get(1).value, sheet.get(2).value],
+            [1.0, 7.0, 7.0]
+        );
+        assert_eq!(sheet.get(1).info.arg_mask, 0b1);
+    }
+
+    fn moving_average_of(values: &[i32], window: usize, shrink: bool) -> Vec<Option<i32>> {
+        let mut graph = create_test_graph(8, 3);
+        set_column(&mut graph, 0, values);
+        let end = graph.sheet.borrow().get_cell(values.len() - 1, 0);
+        let command = Command::MovingAverage {
+            start: 0,
+            end,
+            window,
+            target: 1,
+            shrink,
+        };
+        execute(&mut graph, &command).unwrap();
+        column_values(&graph, 1, values.len())
+    }
+
+    #[test]
+    fn test_moving_average() {
+        let series = [2, 4, 6, 8, 10, 3];
+        assert_eq!(
+            moving_average_of(&series, 1, false),
+            series.map(Some).to_vec()
+        );
+        assert_eq!(
+            moving_average_of(&series, 3, false),
+            vec![None, None, Some(4), Some(6), Some(8), Some(7)]
+        );
+        assert_eq!(
+            moving_average_of(&series, 3, true),
+            vec![Some(2), Some(3), Some(4), Some(6), Some(8), Some(7)]
+        );
+    }
+
+    #[test]
+    fn test_moving_average_in_place_and_bounds() {
+        let mut graph = create_test_graph(4, 2);
+        set_column(&mut graph, 0, &[1, 3, 5]);
+        let end = graph.sheet.borrow().get_cell(2, 0);
+        let command = Command::MovingAverage {
+            start: 0,
+            end,
+            window: 2,
+            target: 0,
+            shrink: true,
+        };
+        execute(&mut graph, &command).unwrap();
+        assert_eq!(column_values(&graph, 0, 3), vec![Some(1), Some(2), Some(4)]);
+
+        let target = graph.sheet.borrow().get_cell(2, 1);
+        let command = Command::MovingAverage {
+            start: 0,
+            end,
+            window: 2,
+            target,
+            shrink: false,
+        };
+        assert_eq!(execute(&mut graph, &command), Err(StatusCode::InvalidRange));
+    }
+
+    #[test]
+    fn test_weighted_moving_average() {
+        let mut graph = create_test_graph(8, 3);
+        set_column(&mut graph, 0, &[2, 4, 6, 8, 10, 3]);
+        set_column(&mut graph, 2, &[1, 2, 3]);
+        let (end, weights_end) = {
+            let sheet = graph.sheet.borrow();
+            (sheet.get_cell(5, 0), sheet.get_cell(2, 2))
+        };
+        let mut command = Command::WeightedMovingAverage {
+            start: 0,
+            end,
+            weights_start: 2,
+            weights_end,
+            target: 1,
+        };
+        execute(&mut graph, &command).unwrap();
+        // e.g. the third result is (2*1 + 4*2 + 6*3) / 6 = 28 / 6
+        assert_eq!(
+            column_values(&graph, 1, 6),
+            vec![None, None, Some(4), Some(6), Some(8), Some(6)]
+        );
+
+        // Weights summing to zero cannot be divided by
+        set_column(&mut graph, 2, &[1, -1]);
+        if let Command::WeightedMovingAverage { weights_end, .. } = &mut command {
+            *weights_end = graph.sheet.borrow().get_cell(1, 2);
+        }
+        assert_eq!(execute(&mut graph, &command), Err(StatusCode::InvalidValue));
+
+        // An invalid input cell fails the whole command
+        graph
+            .update_expression(end, &Info::error(CellError::Value))
+            .unwrap();
+        set_column(&mut graph, 2, &[1, 1]);
+        assert_eq!(execute(&mut graph, &command), Err(StatusCode::InvalidValue));
+        assert_eq!(column_values(&graph, 1, 3), vec![None, None, Some(4)]);
+    }
+
+    #[test]
+    fn test_monotonicity_classes() {
+        assert_eq!(
+            monotonicity(&[1.0, 2.0, 5.0]),
+            Monotonicity::StrictlyIncreasing
+        );
+        assert_eq!(monotonicity(&[4.0]), Monotonicity::StrictlyIncreasing);
+        assert_eq!(
+            monotonicity(&[5.0, 2.0, -1.0]),
+            Monotonicity::StrictlyDecreasing
+        );
+        assert_eq!(monotonicity(&[1.0, 1.0, 3.0]), Monotonicity::NonDecreasing);
+        assert_eq!(monotonicity(&[2.0, 2.0]), Monotonicity::NonDecreasing);
+        assert_eq!(monotonicity(&[3.0, 3.0, 1.0]), Monotonicity::NonIncreasing);
+        assert_eq!(monotonicity(&[1.0, 3.0, 2.0]), Monotonicity::Unordered);
+    }
+
+    #[test]
+    fn test_monotonicity_report() {
+        let mut graph = create_test_graph(5, 2);
+        set_column(&mut graph, 0, &[1, 3, 3, 7]);
+        let (a4, a5) = {
+            let sheet = graph.sheet.borrow();
+            (sheet.get_cell(3, 0), sheet.get_cell(4, 0))
+        };
+        assert_eq!(
+            monotonicity_report(&graph, 0, a4),
+            Ok("A1:A4 is non-decreasing".to_string())
+        );
+        graph
+            .update_expression(a5, &Info::error(CellError::Value))
+            .unwrap();
+        assert_eq!(
+            monotonicity_report(&graph, 0, a5),
+            Ok("A1:A5 is not checked: A5 is an error".to_string())
+        );
+        assert_eq!(
+            monotonicity_report(&graph, 0, 1),
+            Err(StatusCode::InvalidRange)
+        );
+    }
+
+    #[test]
+    fn test_range_diff_reports_mismatches() {
+        let mut graph = create_test_graph(5, 3);
+        set_column(&mut graph, 0, &[1, 2, 3, 4, 5]);
+        set_column(&mut graph, 1, &[1, 7, 3, 4, 0]);
+        let (a5, b5, c5) = {
+            let sheet = graph.sheet.borrow();
+            (
+                sheet.get_cell(4, 0),
+                sheet.get_cell(4, 1),
+                sheet.get_cell(4, 2),
+            )
+        };
+        assert_eq!(
+            range_diff(&graph, 0, a5, 1, b5),
+            Ok(vec![
+                "A2 = 2, B2 = 7".to_string(),
+                "A5 = 5, B5 = 0".to_string()
+            ])
+        );
+
+        // Invalid cells are flagged against valid ones but match each other
+        graph
+            .update_expression(b5, &Info::error(CellError::Value))
+            .unwrap();
+        assert_eq!(
+            range_diff(&graph, a5, a5, b5, b5),
+            Ok(vec!["A5 = 5, B5 = ERR".to_string()])
+        );
+        graph
+            .update_expression(c5, &Info::error(CellError::Value))
+            .unwrap();
+        assert_eq!(range_diff(&graph, b5, b5, c5, c5), Ok(vec![]));
+
+        assert_eq!(
+            range_diff(&graph, 0, a5, 1, 1),
+            Err(StatusCode::InvalidRange)
+        );
+    }
+
+    #[test]
+    fn test_clamp_range() {
+        let mut graph = create_test_graph(4, 2);
+        set_column(&mut graph, 0, &[-5, 50, 100, 250]);
+        // A formula over the bound is left alone, but recomputes from A4
+        let b1 = graph.sheet.borrow().get_cell(0, 1);
+        graph
+            .update_expression(b1, &formula(2, [6, 200], 0b1))
+            .unwrap();
+
+        let end = graph.sheet.borrow().get_cell(3, 1);
+        let command = Command::Clamp {
+            start: 0,
+            end,
+            lo: 0,
+            hi: 100,
+        };
+        execute(&mut graph, &command).unwrap();
+        assert_eq!(
+            column_values(&graph, 0, 4),
+            vec![Some(0), Some(50), Some(100), Some(100)]
+        );
+        let sheet = graph.sheet.borrow();
+        assert_eq!(sheet.get(b1).value, 300.0);
+        assert_eq!(sheet.get(b1).info.function_id, 2);
+    }
+
+    #[test]
+    fn test_mark_roles_and_io_map() {
+        let mut graph = create_test_graph(4, 4);
+        set_column(&mut graph, 0, &[1, 2]);
+        let (a2, b1, d2) = {
+            let sheet = graph.sheet.borrow();
+            (
+                sheet.get_cell(1, 0),
+                sheet.get_cell(0, 1),
+                sheet.get_cell(1, 3),
+            )
+        };
+        let mark = |start, end, role| Command::MarkRole { start, end, role };
+        execute(&mut graph, &mark(0, a2, CellRole::Input)).unwrap();
+        execute(&mut graph, &mark(3, d2, CellRole::Output)).unwrap();
+        // Re-marking replaces the earlier role
+        execute(&mut graph, &mark(b1, b1, CellRole::Output)).unwrap();
+        execute(&mut graph, &mark(b1, b1, CellRole::Input)).unwrap();
+
+        let sheet = graph.sheet.borrow();
+        assert_eq!(sheet.roles.get(&a2), Some(&CellRole::Input));
+        assert_eq!(sheet.roles.get(&d2), Some(&CellRole::Output));
+        assert_eq!(sheet.roles.get(&2), None);
+        assert_eq!(io_map(&sheet), "inputs: A1, B1, A2\noutputs: D1, D2");
+        assert_eq!(io_map(&Sheet::new(2, 2)), "inputs: (none)\noutputs: (none)");
+
+        let grid = sheet.view_grid(&ParserContext::new());
+        assert_eq!(grid[0], vec![">1", ">0", "0", "=0"]);
+        assert_eq!(grid[1], vec![">2", "0", "0", "=0"]);
+    }
+
+    #[test]
+    fn test_pivot_group_sums() {
+        let mut graph = create_test_graph(8, 5);
+        set_column(&mut graph, 0, &[3, 1, 3, 2, 1, 2]);
+        set_column(&mut graph, 1, &[10, 5, 7, 1, 4, 0]);
+        // The row keyed 2 with an invalid value is left out of the group
+        let skipped = graph.sheet.borrow().get_cell(3, 1);
+        graph
+            .update_expression(skipped, &Info::error(CellError::Value))
+            .unwrap();
+
+        let end = graph.sheet.borrow().get_cell(5, 1);
+        let command = Command::Pivot {
+            start: 0,
+            end,
+            target: 3,
+        };
+        execute(&mut graph, &command).unwrap();
+        assert_eq!(
+            column_values(&graph, 3, 4),
+            vec![Some(1), Some(2), Some(3), Some(0)]
+        );
+        assert_eq!(
+            column_values(&graph, 4, 3),
+            vec![Some(9), Some(0), Some(17)]
+        );
+
+        let too_wide = Command::Pivot {
+            start: 0,
+            end: graph.sheet.borrow().get_cell(5, 2),
+            target: 3,
+        };
+        assert_eq!(
+            execute(&mut graph, &too_wide),
+            Err(StatusCode::InvalidRange)
+        );
+        let off_sheet = Command::Pivot {
+            start: 0,
+            end,
+            target: 4,
+        };
+        assert_eq!(
+            execute(&mut graph, &off_sheet),
+            Err(StatusCode::InvalidRange)
+        );
+    }
+
+    #[test]
+    fn test_delta_inverts_running_sum() {
+        let series = [1, 3, 6, 10];
+        let mut graph = create_test_graph(5, 2);
+        set_column(&mut graph, 0, &series);
+        let end = graph.sheet.borrow().get_cell(3, 0);
+        let command = Command::Delta {
+            start: 0,
+            end,
+            target: 1,
+        };
+        execute(&mut graph, &command).unwrap();
+        let deltas = column_values(&graph, 1, 4);
+        assert_eq!(deltas, vec![Some(0), Some(2), Some(3), Some(4)]);
+
+        // A running sum of the differences, started from the first value, gives the series back
+        let rebuilt: Vec<i32> = deltas
+            .iter()
+            .scan(series[0], |total, d| {
+                *total += d.unwrap();
+                Some(*total)
+            })
+            .collect();
+        assert_eq!(rebuilt, series);
+    }
+
+    #[test]
+    fn test_delta_rejects_invalid_source() {
+        let mut graph = create_test_graph(5, 2);
+        set_column(&mut graph, 0, &[1, 2]);
+        let a3 = graph.sheet.borrow().get_cell(2, 0);
+        graph
+            .update_expression(a3, &Info::error(CellError::Value))
+            .unwrap();
+        let end = graph.sheet.borrow().get_cell(1, 0);
+        let command = Command::Delta {
+            start: 0,
+            end,
+            target: 0,
+        };
+        execute(&mut graph, &command).unwrap();
+        assert_eq!(column_values(&graph, 0, 2), vec![Some(0), Some(1)]);
+
+        let command = Command::Delta {
+            start: 0,
+            end: a3,
+            target: 1,
+        };
+        assert_eq!(execute(&mut graph, &command), Err(StatusCode::InvalidValue));
+    }
+
+    fn seeded_fill(graph: &mut Graph, seed: u64) -> Vec<Option<i32>> {
+        execute(graph, &Command::SetSeed(seed)).unwrap();
+        let end = graph.sheet.borrow().get_cell(3, 0);
+        let command = Command::Random {
+            start: 0,
+            end,
+            lo: 10,
+            hi: 20,
+        };
+        execute(graph, &command).unwrap();
+        column_values(graph, 0, 4)
+    }
+
+    #[test]
+    fn test_random_fill_is_seeded_and_bounded() {
+        let mut graph = create_test_graph(4, 2);
+        let first = seeded_fill(&mut graph, 99);
+        assert!(first.iter().all(|v| (10..=20).contains(&v.unwrap())));
+        assert_eq!(seeded_fill(&mut create_test_graph(4, 2), 99), first);
+        assert_ne!(seeded_fill(&mut graph, 100), first);
+    }
+
+    #[test]
+    fn test_normalize() {
+        let mut graph = create_test_graph(4, 2);
+        set_column(&mut graph, 0, &[10, 20, 30]);
+        let end = graph.sheet.borrow().get_cell(2, 0);
+        execute(&mut graph, &Command::Normalize { start: 0, end }).unwrap();
+        assert_eq!(
+            column_values(&graph, 0, 3),
+            vec![Some(0), Some(50), Some(100)]
+        );
+    }
+
+    #[test]
+    fn test_normalize_constant_and_invalid_cells() {
+        let mut graph = create_test_graph(4, 2);
+        set_column(&mut graph, 0, &[7, 7, 7]);
+        let a2 = graph.sheet.borrow().get_cell(1, 0);
+        graph
+            .update_expression(a2, &Info::error(CellError::Value))
+            .unwrap();
+        let end = graph.sheet.borrow().get_cell(2, 0);
+        execute(&mut graph, &Command::Normalize { start: 0, end }).unwrap();
+        assert_eq!(column_values(&graph, 0, 3), vec![Some(0), None, Some(0)]);
+
+        let command = Command::Normalize { start: a2, end: a2 };
+        assert_eq!(execute(&mut graph, &command), Err(StatusCode::InvalidValue));
+    }
+
+    #[test]
+    fn test_cumulative_percent_rejects_multi_column() {
+        let mut graph = create_test_graph(5, 3);
+        let end = graph.sheet.borrow().get_cell(2, 1);
+        let result = execute(&mut graph, &Command::CumulativePercent { start: 0, end });
+        assert_eq!(result, Err(StatusCode::InvalidRange));
+    }
+}
@@ -1,35 +1,47 @@
 // parser.rs
 //! This module handles parsing commands and expressions for the spreadsheet system.
+use crossterm::style::Color;
 use lazy_static::lazy_static;
 use regex::Regex;
+use std::collections::HashMap;
+use std::fmt;
+use std::num::IntErrorKind;
 use std::str::FromStr;
 
+use crate::autosave::Every;
 use crate::convert;
-use crate::info::{CommandInfo, Info, ValueInfo};
-use crate::sheet::{get_cell, get_row_and_column, is_valid_cell, is_valid_range};
-use crate::status::{StatusCode, set_status_code};
+use crate::expr::{Expr, MAX_NODES};
+use crate::formulas::{
+    ABS, COUNTLABEL, DATE, DAYS, INDEX, MATCH, POSITION_FUNCTIONS, RAND, RANDBETWEEN,
+    RANGE_FUNCTIONS, SCALAR_FUNCTIONS, TODAY, VLOOKUP, is_position_function,
+};
+use crate::graph::{CalcMode, RecalcOrder};
+use crate::info::{CellError, Command, CommandInfo, FIXED_COL, FIXED_ROW, Info, ValueInfo};
+use crate::sheet::{
+    Axis, COLORS, CellRole, DEFAULT_COL_WIDTH, DEFAULT_OVERFLOW_MARKER, DEFAULT_VIEW_SIZE,
+    DISPLAY_MARGIN_ROWS, FORMULA_COL_WIDTH, FormatChange, LABEL_WIDTH, LineEdit, MAX_DECIMALS,
+    MIN_COL_WIDTH, NUMBER_FORMATS, NumberFormat, OverflowPolicy, get_cell, get_row_and_column,
+    is_valid_cell, is_valid_range, scroll_position,
+};
+use crate::status::StatusCode;
+/// Number of distinct expressions the parse cache holds before it starts over.
+const PARSE_CACHE_CAPACITY: usize = 4096;
 /// Input buffer size constant.
 const INPUT_BUFFER_SIZE: usize = 64;
 /// Maximum regex match count.
 const MAX_MATCHES: usize = 4;
-/// Offset for range-based functions.
-const RANGE_OFFSET: usize = 6;
 /// Offset for arithmetic operations.
 const ARITHMETIC_OFFSET: usize = 2;
-/// Regular expressions used for parsing different command types.
+/// Regular expressions used for parsing different command types. Formulas themselves
+/// are tokenized and parsed by `expression_parser`.
 lazy_static! {
-    static ref PATTERNS: [Regex; 7] = [
-        Regex::new(r"^([A-Z]{1,3}[1-9][0-9]{0,2}|[+-]?[0-9]+)$").unwrap(),         // ASSIGNMENT
-        Regex::new(r"^SLEEP\(([A-Z]{1,3}[1-9][0-9]{0,2}|[+-]?[0-9]+)\)$").unwrap(), // SLEEP
-        Regex::new(r"^([A-Z]{1,3}[1-9][0-9]{0,2}|[+-]?[0-9]+)([-+*/])([A-Z]{1,3}[1-9][0-9]{0,2}|[+-]?[0-9]+)$").unwrap(), // ARITHMETIC
-        Regex::new(r"^(MAX|MIN|SUM|AVG|STDEV)\(([A-Z]{1,3}[1-9][0-9]{0,2}):([A-Z]{1,3}[1-9][0-9]{0,2})\)$").unwrap(), // RANGE
-        Regex::new(r"^([A-Z]{1,3}[1-9][0-9]{0,2})=(.+)$").unwrap(),                 // EXPRESSION
-        Regex::new(r"^scroll_to ([A-Z]{1,3}[1-9][0-9]{0,2})$").unwrap(),            // SCROLL_TO
-        Regex::new(r"^[+-]?[0-9]+$").unwrap(),                                      // INTEGER
+    static ref PATTERNS: [Regex; 2] = [
+        Regex::new(r"^([A-Za-z]{1,3}[1-9][0-9]{0,2})\s*=\s*(.+)$").unwrap(),        // EXPRESSION
+        Regex::new(r"^scroll_to ([A-Za-z]{1,3}[1-9][0-9]{0,2})$").unwrap(),         // SCROLL_TO
     ];
 }
 /// Represents different types of parsing errors.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ParseError {
     /// Invalid command format.
     InvalidCommand,
@@ -39,10 +51,79 @@ pub enum ParseError {
     InvalidRange,
     /// Invalid value in an expression.
     InvalidValue,
+    /// An integer literal does not fit in a cell value.
+    ValueOverflow,
     /// Failed to parse.
     ParseFailure,
 }
 
+impl ParseError {
+    /// Maps a parse error to the status code shown to the user.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ParseError::ValueOverflow => StatusCode::Overflow,
+            _ => StatusCode::InvalidCmd,
+        }
+    }
+}
+
+/// A formula that failed to parse, with where it went wrong, e.g. for
+/// `unexpected token '*' at column 7`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxError {
+    /// What went wrong.
+    pub kind: ParseError,
+    /// Byte offset of the offending token in the parsed text.
+    pub offset: usize,
+    /// 1-based column of the offending token, counted in characters.
+    pub column: usize,
+    /// The offending token, empty if the text ended too early.
+    pub token: String,
+}
+
+impl SyntaxError {
+    /// Creates the error `kind` for the token at bytes `start..end` of `text`.
+    fn new(kind: ParseError, text: &str, start: usize, end: usize) -> Self {
+        Self {
+            kind,
+            offset: start,
+            column: text[..start].chars().count() + 1,
+            token: text[start..end].to_string(),
+        }
+    }
+    /// Returns the same error for the parsed text placed `bytes` bytes of ASCII further
+    /// right, e.g. after the `B1=` of an assignment, or further left for a negative count.
+    pub fn moved(&self, bytes: isize) -> Self {
+        Self {
+            offset: self.offset.saturating_add_signed(bytes),
+            column: self.column.saturating_add_signed(bytes).max(1),
+            ..self.clone()
+        }
+    }
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let what = match self.kind {
+            _ if self.token.is_empty() => {
+                return write!(f, "unexpected end of formula at column {}", self.column);
+            }
+            ParseError::InvalidCell => "invalid cell",
+            ParseError::InvalidRange => "invalid range",
+            ParseError::ValueOverflow => "number too large",
+            ParseError::InvalidValue => "invalid number",
+            _ => "unexpected token",
+        };
+        write!(f, "{} '{}' at column {}", what, self.token, self.column)
+    }
+}
+
+impl From<SyntaxError> for ParseError {
+    fn from(error: SyntaxError) -> Self {
+        error.kind
+    }
+}
+
 /// Stores parser context information.
 pub struct ParserContext {
     /// Current row position in the sheet.
@@ -51,6 +132,39 @@ pub struct ParserContext {
     pub py: usize,
     /// Controls whether output is enabled.
     pub output_enabled: bool,
+    /// Shows each cell's formula instead of its value (`set view formulas` or
+    /// `show_formulas`).
+    pub show_formulas: bool,
+    /// Swaps rows and columns in the display only (`set view transpose`).
+    pub transpose: bool,
+    /// Spaces between rendered columns (`set colgap <n>`).
+    pub colgap: usize,
+    /// Fills cells too wide for their column (`set overflow <char>`).
+    pub overflow_marker: char,
+    /// Rows shown by the standard display and scrolled by `w`/`s` (`set_view_rows <n>`).
+    pub view_rows: usize,
+    /// Columns shown by the standard display and scrolled by `a`/`d` (`set_view_cols <n>`).
+    pub view_cols: usize,
+    /// Width of each displayed column (`set_col_width <n>`).
+    pub col_width: usize,
+    /// `(columns, rows)` of the terminal the standard display must fit, when known.
+    pub terminal: Option<(usize, usize)>,
+    /// Names of functions registered through `Spreadsheet::register_function`.
+    pub functions: HashMap<String, u8>,
+    /// Corners of the range chosen with `select A1:C5`, used by `clear` and `sum_selection`.
+    pub selection: Option<(usize, usize)>,
+    /// Corners of the range drawn as a bar chart under the grid (`chart bar A1:A10`), until
+    /// `chart off`.
+    pub chart: Option<(usize, usize)>,
+    /// Right-hand side of the last successful edit, replayed by `.`.
+    pub last_expr: Option<String>,
+    /// Parsed form of recently seen expressions, keyed by their text.
+    parse_cache: HashMap<String, Info>,
+    /// Number of expressions actually run through `expression_parser` (cache misses).
+    pub parse_count: usize,
+    /// Where the last formula that failed to parse went wrong, its offset within the
+    /// command for an assignment or array formula. Cleared by the next formula parsed.
+    pub last_error: Option<SyntaxError>,
 }
 
 impl ParserContext {
@@ -65,6 +179,149 @@ impl ParserContext {
             px: 0,
             py: 0,
             output_enabled: true,
+            show_formulas: false,
+            transpose: false,
+            colgap: 1,
+            overflow_marker: DEFAULT_OVERFLOW_MARKER,
+            view_rows: DEFAULT_VIEW_SIZE,
+            view_cols: DEFAULT_VIEW_SIZE,
+            col_width: DEFAULT_COL_WIDTH,
+            terminal: None,
+            functions: HashMap::new(),
+            selection: None,
+            chart: None,
+            last_expr: None,
+            parse_cache: HashMap::new(),
+            parse_count: 0,
+            last_error: None,
+        }
+    }
+    /// Returns the rows and columns of the sheet the standard display shows, and the width
+    /// of its columns.
+    ///
+    /// These are `view_rows`, `view_cols` and `col_width` (at least `FORMULA_COL_WIDTH`
+    /// while formulas are shown, and never narrower than a label such as `AAA`), cut down
+    /// to fit `terminal` if it is known: columns are first narrowed, down to
+    /// `MIN_COL_WIDTH`, and only then dropped. At least one row and column is always shown.
+    pub fn view_size(&self) -> (usize, usize, usize) {
+        let width = if self.show_formulas {
+            self.col_width.max(FORMULA_COL_WIDTH)
+        } else {
+            self.col_width
+        };
+        let width = width.max(LABEL_WIDTH);
+        let Some((term_cols, term_rows)) = self.terminal else {
+            return (self.view_rows, self.view_cols, width);
+        };
+        // Lines run down the screen, the fields of each line across it
+        let (lines, fields) = match self.transpose {
+            true => (self.view_cols, self.view_rows),
+            false => (self.view_rows, self.view_cols),
+        };
+        let lines = lines
+            .min(term_rows.saturating_sub(DISPLAY_MARGIN_ROWS + self.chart_lines()))
+            .max(1);
+        let space = term_cols.saturating_sub(LABEL_WIDTH + 1); // Past the labels
+        let width = width
+            .min((space / fields).saturating_sub(self.colgap))
+            .max(MIN_COL_WIDTH);
+        let fields = fields.min(space / (width + self.colgap)).max(1);
+        match self.transpose {
+            true => (fields, lines, width),
+            false => (lines, fields, width),
+        }
+    }
+    /// Returns the number of lines the chart under the grid takes, a blank line and one
+    /// bar per cell, or 0 without a chart.
+    pub fn chart_lines(&self) -> usize {
+        self.chart.map_or(0, |(start, end)| {
+            let (top, left) = get_row_and_column(start);
+            let (bottom, right) = get_row_and_column(end);
+            (bottom - top + 1) * (right - left + 1) + 1
+        })
+    }
+    /// Parses the right-hand side of an assignment, running the regexes only the first
+    /// time a given expression text is seen.
+    ///
+    /// A parsed `Info` holds cells as indices into a sheet of the current dimensions, so
+    /// commands that change them must call `clear_parse_cache`. Otherwise cached results
+    /// stay valid, since registered functions can be added but never renamed. Failed
+    /// parses are not cached. The cache is emptied when it reaches `PARSE_CACHE_CAPACITY`
+    /// entries.
+    ///
+    /// A formula that does not parse leaves where it went wrong in `last_error`.
+    pub fn parse_expression(&mut self, expr: &str) -> Result<Info, ParseError> {
+        self.last_error = None;
+        if let Some(info) = self.parse_cache.get(expr) {
+            return Ok(*info);
+        }
+        self.parse_count += 1;
+        let info = parse_formula(expr, &self.functions).map_err(|e| {
+            self.last_error = Some(e.clone());
+            e.kind
+        })?;
+        if self.parse_cache.len() >= PARSE_CACHE_CAPACITY {
+            self.parse_cache.clear();
+        }
+        self.parse_cache.insert(expr.to_string(), info);
+        Ok(info)
+    }
+    /// Forgets every cached parse, e.g. once the sheet's dimensions changed and the cell
+    /// indices of the cached formulas point at other cells, or at none.
+    pub fn clear_parse_cache(&mut self) {
+        self.parse_cache.clear();
+    }
+    /// Parses an array formula such as `C1=A1:A10+B1:B10`, whose bare ranges are read
+    /// element by element. Its result spills down and right from the assigned cell over a
+    /// block of their shape, each cell an ordinary formula: `C1=A1+B1`, `C2=A2+B2`, and
+    /// so on. Range functions such as `SUM(A1:A10)` still read the whole range.
+    ///
+    /// # Returns
+    /// `Ok(None)` for anything but an assignment reading a bare range, otherwise each cell
+    /// of the block with its formula, in row-major order. `ParseError::InvalidRange` if
+    /// the bare ranges differ in shape, `ParseError::InvalidCell` if the block does not
+    /// fit the sheet. A formula that does not parse leaves where it went wrong in
+    /// `last_error`.
+    pub fn parse_array(&mut self, input: &str) -> Result<Option<Vec<(usize, Info)>>, ParseError> {
+        self.last_error = None;
+        let Some(caps) = PATTERNS[0].captures(input) else {
+            return Ok(None);
+        };
+        let expr = caps.get(2).unwrap().as_str();
+        if !expr.contains(':') || text_literal(expr).is_some() {
+            return Ok(None);
+        }
+        let (row, col) = get_row_and_column(cell_parser(caps.get(1).unwrap().as_str())?);
+        let parsed = parse_tree(expr, &self.functions, Some((0, 0)));
+        let (_, shapes) = parsed.map_err(|e| {
+            let error = e.moved(caps.get(2).unwrap().start() as isize);
+            self.last_error = Some(error);
+            e.kind
+        })?;
+        let Some(&(rows, cols)) = shapes.first() else {
+            return Ok(None);
+        };
+        if shapes.iter().any(|&shape| shape != (rows, cols)) {
+            return Err(ParseError::InvalidRange);
+        }
+        if !is_valid_cell(row + rows - 1, col + cols - 1) {
+            return Err(ParseError::InvalidCell);
+        }
+        let mut writes = Vec::with_capacity(rows * cols);
+        for i in 0..rows {
+            for j in 0..cols {
+                let (tree, _) = parse_tree(expr, &self.functions, Some((i, j)))?;
+                writes.push((get_cell(row + i, col + j), lower(tree)?));
+            }
+        }
+        Ok(Some(writes))
+    }
+    /// Records a successfully applied `<cell>=<expr>` command so that `.` can repeat it.
+    ///
+    /// Commands without an `=` (like `.` itself) leave the recorded edit unchanged.
+    pub fn record_edit(&mut self, input: &str) {
+        if let Some((_, expr)) = input.split_once('=') {
+            self.last_expr = Some(expr.to_string());
         }
     }
 }
@@ -96,140 +353,523 @@ pub fn parse_sheet_dimensions(n_str: &str, m_str: &str) -> Result<(usize, usize)
 }
 /// Parses an expression and stores the result in `Info`.
 ///
+/// A quoted string such as `"Revenue"` makes a text cell. Anything else is tokenized and
+/// parsed with operator precedence, so `(B1+C1)*2-SUM(D1:D5)/3` is accepted, as is
+/// `(b1 + c1) * 2 - sum(d1:d5) / 3`: names ignore case and spaces may go between tokens.
+/// Formulas of the classic forms are stored as such (see `lower`); only nested ones
+/// become expression trees, and either is shown in its canonical form.
+///
 /// # Arguments
 /// - `expr`: Expression string.
 /// - `info`: Storage for parsed information.
+/// - `functions`: Names of registered single-argument functions, with their IDs.
 ///
 /// # Returns
 /// Returns `Ok(())` if parsed successfully, otherwise `ParseError`.
-pub fn expression_parser(expr: &str, info: &mut Info) -> Result<(), ParseError> {
-    for (match_type, re) in PATTERNS.iter().enumerate() {
-        // Skip the SCROLL_TO pattern (index 5) as it's handled by handle_other_commands
-
-        if match_type == 5 {
-            continue;
-        }
-        if let Some(caps) = re.captures(expr) {
-            return match match_type {
-                0 | 1 => handle_assignment(&caps, info, match_type),
-                2 => handle_arithmetic(&caps, info),
-                3 => handle_range(&caps, info),
-                4 => handle_expression(&caps, info),
-                6 => handle_integer(&caps, info),
-                _ => Err(ParseError::InvalidCommand),
-            };
-        }
-    }
-    Err(ParseError::InvalidCommand)
-}
-/// Handles assignment expressions like `A1` or `42`, storing parsed result in `info`.
-///
-/// # Arguments
-/// - `caps`: Regex captures from the matched assignment expression.
-/// - `info`: Target `Info` structure to populate.
-/// - `match_type`: Type of assignment pattern (0 for literal, 1 for SLEEP).
-///
-/// # Returns
-/// `Ok(())` if parsed successfully, otherwise a `ParseError`.
-
-fn handle_assignment(
-    caps: &regex::Captures,
+pub fn expression_parser(
+    expr: &str,
     info: &mut Info,
-    match_type: usize,
+    functions: &HashMap<String, u8>,
 ) -> Result<(), ParseError> {
-    let value_str = caps.get(1).unwrap().as_str();
-    let mut value_info = ValueInfo::default();
-    value_parser(value_str, &mut value_info)?;
-
-    info.arg_mask = value_info.is_cell as u8;
-    info.arg[0] = value_info.value as i32;
-    info.function_id = match_type as u8;
+    *info = parse_formula(expr, functions)?;
     Ok(())
 }
-/// Parses arithmetic expressions like `A1+10` or `20/B3`, filling in the `Info` struct.
-///
-/// # Arguments
-/// - `caps`: Captured groups from the arithmetic regex.
-/// - `info`: Target `Info` structure to populate.
+/// Parses a formula like `expression_parser`, telling where it went wrong if it does
+/// not parse, e.g. so an editor can highlight the offending token.
 ///
-/// # Returns
-/// `Ok(())` if parsing was successful, or `ParseError`.
-
-fn handle_arithmetic(caps: &regex::Captures, info: &mut Info) -> Result<(), ParseError> {
-    let op = caps.get(2).unwrap().as_str();
-    let op_index = "+-*/".find(op).ok_or(ParseError::InvalidCommand)?;
-
-    info.function_id = (ARITHMETIC_OFFSET + op_index) as u8;
-
-    for j in 0..=1 {
-        let value_str = caps.get(j * 2 + 1).unwrap().as_str();
-        let mut value_info = ValueInfo::default();
-        value_parser(value_str, &mut value_info)?;
-        info.arg_mask |= (value_info.is_cell as u8) << j;
-        info.arg[j] = value_info.value as i32;
+/// # Examples
+/// ```
+/// let error = parse_formula("2**3", &HashMap::new()).unwrap_err();
+/// assert_eq!(error.to_string(), "unexpected token '*' at column 3");
+/// ```
+pub fn parse_formula(expr: &str, functions: &HashMap<String, u8>) -> Result<Info, SyntaxError> {
+    if let Some(text) = text_literal(expr) {
+        return Ok(Info::text(text));
     }
-    Ok(())
+    // An error marker, as saved for an error literal, e.g. `#REF`
+    if let Some(kind) = CellError::from_marker(expr.trim()) {
+        return Ok(Info::error(kind));
+    }
+    let (tree, _) = parse_tree(expr, functions, None)?;
+    lower(tree).map_err(|kind| {
+        // Only a registered function outside the classic form fails here, so it is the
+        // first one written that is to blame
+        let canonical = capitals(expr);
+        let (tokens, spans) = tokenize(&canonical).unwrap_or_default();
+        let (start, end) = tokens
+            .iter()
+            .zip(spans)
+            .find(|(token, _)| matches!(token, Token::Name(name) if functions.contains_key(*name)))
+            .map_or((0, expr.len()), |(_, span)| span);
+        SyntaxError::new(kind, expr, start, end)
+    })
 }
-/// Parses range-based function calls like `SUM(A1:B2)` into `Info`.
+/// Tokenizes and parses a formula into its tree.
 ///
-/// # Arguments
-/// - `caps`: Captured groups from the range function regex.
-/// - `info`: Target `Info` structure to populate.
+/// With `element` set to a (row, column) offset, a bare range such as `A1:A10` is
+/// accepted as an operand and stands for its cell at that offset, and the shape of every
+/// bare range is returned as (rows, columns) in the order they are written. Without it a
+/// bare range is an error.
 ///
-/// # Returns
-/// `Ok(())` if the range is valid, else `ParseError::InvalidRange`.
-
-fn handle_range(caps: &regex::Captures, info: &mut Info) -> Result<(), ParseError> {
-    let func_name = caps.get(1).unwrap().as_str();
-    let func_index = ["MAX", "MIN", "SUM", "AVG", "STDEV"]
-        .iter()
-        .position(|&s| s == func_name)
-        .ok_or(ParseError::InvalidCommand)?;
-
-    info.function_id = (RANGE_OFFSET + func_index) as u8;
-    info.arg_mask = 0b11;
-
-    for j in 0..=1 {
-        let cell_str = caps.get(j + 2).unwrap().as_str();
-        let cell = cell_parser(cell_str)?;
-        info.arg[j] = cell as i32;
+/// An error points at the token the parser stopped at, or at the whole range for an
+/// invalid range.
+fn parse_tree(
+    expr: &str,
+    functions: &HashMap<String, u8>,
+    element: Option<(usize, usize)>,
+) -> Result<(Expr, Vec<(usize, usize)>), SyntaxError> {
+    // Names are matched in capitals; the offsets stay those of `expr`
+    let canonical = capitals(expr);
+    let (tokens, spans) = tokenize(&canonical)
+        .map_err(|e| SyntaxError::new(e.kind, expr, e.offset, e.offset + e.token.len()))?;
+    let at = |kind, first: usize, last: usize| match (spans.get(first), spans.get(last)) {
+        (Some(&(start, _)), Some(&(_, end))) => SyntaxError::new(kind, expr, start, end),
+        _ => SyntaxError::new(kind, expr, expr.len(), expr.len()),
+    };
+    // Every node takes at least one token, so this bounds the size of the tree
+    if tokens.len() > MAX_NODES {
+        return Err(at(ParseError::InvalidCommand, MAX_NODES, MAX_NODES));
     }
-
-    if !is_valid_range(info.arg[0] as usize, info.arg[1] as usize) {
-        Err(ParseError::InvalidRange)
-    } else {
-        Ok(())
+    let mut parser = ExprParser {
+        tokens,
+        pos: 0,
+        functions,
+        element,
+        shapes: Vec::new(),
+        failed: None,
+    };
+    let tree = parser.expression(0).map_err(|kind| {
+        // The parser stops just past the token it could not use
+        let last = parser.pos.saturating_sub(1);
+        let (first, last) = parser.failed.unwrap_or((last, last));
+        at(kind, first, last)
+    })?;
+    if parser.pos != parser.tokens.len() {
+        return Err(at(ParseError::InvalidCommand, parser.pos, parser.pos));
     }
+    Ok((tree, parser.shapes))
+}
+/// A token of a formula.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token<'a> {
+    /// An unsigned number literal such as `42`, `1_000_000`, `2.5` or `2.5E3`.
+    Number(&'a str),
+    /// A cell reference such as `B12` or `$B$12`.
+    Cell(&'a str),
+    /// A function name such as `SUM`.
+    Name(&'a str),
+    /// The text between double quotes, such as the pattern of `COUNTLABEL`.
+    Text(&'a str),
+    /// One of `+`, `-`, `*` and `/`.
+    Operator(char),
+    LeftParen,
+    RightParen,
+    Colon,
+    Comma,
+}
+/// Returns `expr` in capitals, as `tokenize` expects, except for text between double
+/// quotes, which is kept as written. Byte offsets are unchanged.
+fn capitals(expr: &str) -> String {
+    let mut quoted = false;
+    expr.chars()
+        .map(|c| {
+            quoted ^= c == '"';
+            if quoted { c } else { c.to_ascii_uppercase() }
+        })
+        .collect()
 }
-/// Handles recursive parsing of expressions of the form `A1=SUM(A1:A2)`.
+/// Splits a formula into tokens.
 ///
-/// # Arguments
-/// - `caps`: Regex captures from expression assignment.
-/// - `info`: Target `Info` to store parsed result.
+/// A run of capital letters is a cell reference if digits follow it and a function name
+/// otherwise. A cell reference has at most three letters and a row of one to three digits
+/// without a leading zero, each optionally marked absolute by a leading `$`. A number may
+/// have a fraction and an exponent, and underscores between its digits. Whitespace
+/// only separates tokens. `parse_tree` passes the formula in capitals, so `sum(a1:a3)`
+/// reads as `SUM(A1:A3)`, but text in double quotes keeps its case.
 ///
 /// # Returns
-/// `Ok(())` if successfully parsed, otherwise `ParseError`.
+/// The tokens with the byte span of each, `ParseError::InvalidCell` for a malformed cell
+/// reference, `ParseError::InvalidValue` for a misplaced underscore in a number, or
+/// `ParseError::InvalidCommand` for any other character.
+fn tokenize(expr: &str) -> Result<(Vec<Token<'_>>, Vec<(usize, usize)>), SyntaxError> {
+    let bytes = expr.as_bytes();
+    let run =
+        |from: usize, f: fn(&u8) -> bool| from + bytes[from..].iter().take_while(|b| f(b)).count();
+    let mut tokens = Vec::new();
+    let mut spans = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        if bytes[i].is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+        let token = match bytes[i] {
+            b'0'..=b'9' => {
+                let digit = |i: usize| bytes.get(i).is_some_and(u8::is_ascii_digit);
+                let digits = |from: usize| run(from, |&b| b.is_ascii_digit() || b == b'_');
+                i = digits(i);
+                if bytes.get(i) == Some(&b'.') && digit(i + 1) {
+                    i = digits(i + 1);
+                }
+                // An exponent only counts with digits, so `2E` stays a number and a name
+                let sign = (i + 1 < bytes.len() && matches!(bytes[i + 1], b'+' | b'-')) as usize;
+                if bytes.get(i) == Some(&b'E') && digit(i + 1 + sign) {
+                    i = digits(i + 1 + sign);
+                }
+                let text = &expr[start..i];
+                let misplaced = text
+                    .match_indices('_')
+                    .any(|(j, _)| !digit(start + j - 1) || !digit(start + j + 1));
+                if misplaced {
+                    return Err(SyntaxError::new(ParseError::InvalidValue, expr, start, i));
+                }
+                Token::Number(text)
+            }
+            b'A'..=b'Z' | b'$' => {
+                let letters = i + (bytes[i] == b'$') as usize;
+                let letters_end = run(letters, u8::is_ascii_uppercase);
+                let digits = letters_end + (bytes.get(letters_end) == Some(&b'$')) as usize;
+                i = run(digits, u8::is_ascii_digit);
+                if i == letters_end && letters == start {
+                    Token::Name(&expr[start..i])
+                } else if letters_end == letters
+                    || letters_end - letters > 3
+                    || i == digits
+                    || i - digits > 3
+                    || bytes[digits] == b'0'
+                {
+                    return Err(SyntaxError::new(ParseError::InvalidCell, expr, start, i));
+                } else {
+                    Token::Cell(&expr[start..i])
+                }
+            }
+            b'"' => {
+                let Some(len) = expr[start + 1..].find('"') else {
+                    return Err(SyntaxError::new(
+                        ParseError::InvalidCommand,
+                        expr,
+                        start,
+                        start + 1,
+                    ));
+                };
+                i = start + len + 2;
+                Token::Text(&expr[start + 1..i - 1])
+            }
+            byte => {
+                i += 1;
+                match byte {
+                    b'+' | b'-' | b'*' | b'/' => Token::Operator(byte as char),
+                    b'(' => Token::LeftParen,
+                    b')' => Token::RightParen,
+                    b':' => Token::Colon,
+                    b',' => Token::Comma,
+                    _ => {
+                        let end = start + expr[start..].chars().next().unwrap().len_utf8();
+                        return Err(SyntaxError::new(
+                            ParseError::InvalidCommand,
+                            expr,
+                            start,
+                            end,
+                        ));
+                    }
+                }
+            }
+        };
+        tokens.push(token);
+        spans.push((start, i));
+    }
+    Ok((tokens, spans))
+}
+/// Binding power of negation, tighter than any binary operator.
+const PREFIX_POWER: u8 = 3;
+/// Returns the binding power of a binary operator: `*` and `/` bind tighter than `+`
+/// and `-`.
+fn binding_power(op: char) -> u8 {
+    match op {
+        '*' | '/' => 2,
+        _ => 1,
+    }
+}
+/// A Pratt parser over the tokens of one formula.
+struct ExprParser<'a, 'f> {
+    /// The tokens of the formula.
+    tokens: Vec<Token<'a>>,
+    /// Index of the next token.
+    pos: usize,
+    /// Registered single-argument functions, looked up after the builtins.
+    functions: &'f HashMap<String, u8>,
+    /// The offset a bare range is read at, in an array formula.
+    element: Option<(usize, usize)>,
+    /// The shape of each bare range read so far.
+    shapes: Vec<(usize, usize)>,
+    /// The first and last token of an invalid range, which is to blame as a whole.
+    failed: Option<(usize, usize)>,
+}
 
-fn handle_expression(caps: &regex::Captures, info: &mut Info) -> Result<(), ParseError> {
-    let expr = caps.get(2).unwrap().as_str();
-    expression_parser(expr, info)
+impl<'a> ExprParser<'a, '_> {
+    /// Returns the next token without consuming it.
+    fn peek(&self) -> Option<Token<'a>> {
+        self.tokens.get(self.pos).copied()
+    }
+    /// Consumes and returns the next token.
+    fn next(&mut self) -> Option<Token<'a>> {
+        let token = self.peek();
+        self.pos += 1;
+        token
+    }
+    /// Consumes the next token, which must be `expected`.
+    fn expect(&mut self, expected: Token) -> Result<(), ParseError> {
+        if self.next() == Some(expected) {
+            Ok(())
+        } else {
+            Err(ParseError::InvalidCommand)
+        }
+    }
+    /// Consumes a cell reference and returns its index and fixed flags.
+    fn cell(&mut self) -> Result<(usize, u8), ParseError> {
+        match self.next() {
+            Some(Token::Cell(cell)) => reference_parser(cell),
+            _ => Err(ParseError::InvalidCommand),
+        }
+    }
+    /// Parses an expression whose binary operators all bind tighter than `min_power`.
+    ///
+    /// Operators of equal power group to the left, so `A1-B1-C1` is `(A1-B1)-C1`.
+    fn expression(&mut self, min_power: u8) -> Result<Expr, ParseError> {
+        let mut lhs = self.operand()?;
+        while let Some(Token::Operator(op)) = self.peek() {
+            let power = binding_power(op);
+            if power <= min_power {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.expression(power)?;
+            let function_id = (ARITHMETIC_OFFSET + "+-*/".find(op).unwrap()) as u8;
+            lhs = Expr::Binary(function_id, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+    /// Parses a literal, a cell, a signed operand, a parenthesized expression or a call.
+    ///
+    /// A minus sign directly before digits is part of the literal, so `-2147483648` fits.
+    fn operand(&mut self) -> Result<Expr, ParseError> {
+        let expr = match self.next() {
+            Some(Token::Number(digits)) => Expr::Number(number_parser(digits)?),
+            // `MIN2` and `MAX2` read like cell names, so the parenthesis decides
+            Some(Token::Cell(name))
+                if self.peek() == Some(Token::LeftParen)
+                    && SCALAR_FUNCTIONS.iter().any(|&(n, _)| n == name) =>
+            {
+                self.pos += 1;
+                let call = self.call(name)?;
+                self.expect(Token::RightParen)?;
+                call
+            }
+            Some(Token::Cell(cell)) => {
+                let (cell, fixed) = reference_parser(cell)?;
+                match self.element {
+                    Some((i, j)) if self.peek() == Some(Token::Colon) => {
+                        self.pos += 1;
+                        let (end, _) = self.cell()?;
+                        if !is_valid_range(cell, end) {
+                            self.failed = Some((self.pos - 3, self.pos - 1));
+                            return Err(ParseError::InvalidRange);
+                        }
+                        let (r1, c1) = get_row_and_column(cell);
+                        let (r2, c2) = get_row_and_column(end);
+                        self.shapes.push((r2 - r1 + 1, c2 - c1 + 1));
+                        Expr::Cell(get_cell(r1 + i, c1 + j), fixed)
+                    }
+                    _ => Expr::Cell(cell, fixed),
+                }
+            }
+            Some(Token::Operator('-')) => match self.peek() {
+                Some(Token::Number(digits)) => {
+                    self.pos += 1;
+                    Expr::Number(number_parser(&format!("-{}", digits))?)
+                }
+                _ => Expr::Neg(Box::new(self.expression(PREFIX_POWER)?)),
+            },
+            Some(Token::Operator('+')) => self.expression(PREFIX_POWER)?,
+            Some(Token::LeftParen) => {
+                let inner = self.expression(0)?;
+                self.expect(Token::RightParen)?;
+                inner
+            }
+            Some(Token::Name(name)) => {
+                self.expect(Token::LeftParen)?;
+                let call = self.call(name)?;
+                self.expect(Token::RightParen)?;
+                call
+            }
+            _ => return Err(ParseError::InvalidCommand),
+        };
+        Ok(expr)
+    }
+    /// Parses the arguments of the function `name`, up to its closing parenthesis: a range
+    /// for a range function, two comma-separated expressions for a two-argument scalar
+    /// function, `RANDBETWEEN` or `DAYS`, three for `DATE`, a range and one or two
+    /// expressions for a lookup function, a range and a quoted pattern for `COUNTLABEL`,
+    /// a cell or nothing for a position function,
+    /// nothing for `RAND` and `TODAY`, any expression for the others.
+    ///
+    /// A position function without an argument, `RAND` and `TODAY` are stored with the
+    /// argument `0`.
+    fn call(&mut self, name: &str) -> Result<Expr, ParseError> {
+        if let Some(&(_, function_id)) = POSITION_FUNCTIONS.iter().find(|&&(n, _)| n == name) {
+            let arg = match self.peek() {
+                Some(Token::RightParen) => Expr::Number(0.0),
+                _ => {
+                    let (cell, fixed) = self.cell()?;
+                    Expr::Cell(cell, fixed)
+                }
+            };
+            return Ok(Expr::Call(function_id, Box::new(arg)));
+        }
+        if let Some(&(_, function_id)) = RANGE_FUNCTIONS.iter().find(|&&(n, _)| n == name) {
+            return self.range(function_id);
+        }
+        if let Some(&(_, function_id)) = SCALAR_FUNCTIONS.iter().find(|&&(n, _)| n == name) {
+            let first = self.expression(0)?;
+            if function_id == ABS {
+                return Ok(Expr::Call(function_id, Box::new(first)));
+            }
+            self.expect(Token::Comma)?;
+            let second = self.expression(0)?;
+            return Ok(Expr::Binary(function_id, Box::new(first), Box::new(second)));
+        }
+        match name {
+            "RAND" | "TODAY" => {
+                let function_id = if name == "RAND" { RAND } else { TODAY };
+                return Ok(Expr::Call(function_id, Box::new(Expr::Number(0.0))));
+            }
+            "RANDBETWEEN" | "DAYS" => {
+                let first = self.expression(0)?;
+                self.expect(Token::Comma)?;
+                let second = self.expression(0)?;
+                let function_id = if name == "DAYS" { DAYS } else { RANDBETWEEN };
+                return Ok(Expr::Binary(function_id, Box::new(first), Box::new(second)));
+            }
+            "DATE" => {
+                let year = self.expression(0)?;
+                self.expect(Token::Comma)?;
+                let month = self.expression(0)?;
+                self.expect(Token::Comma)?;
+                let day = self.expression(0)?;
+                let (year, month, day) = (Box::new(year), Box::new(month), Box::new(day));
+                return Ok(Expr::Ternary(DATE, year, month, day));
+            }
+            "INDEX" => {
+                let table = self.range(INDEX)?;
+                self.expect(Token::Comma)?;
+                let row = self.expression(0)?;
+                self.expect(Token::Comma)?;
+                let col = self.expression(0)?;
+                let (table, row, col) = (Box::new(table), Box::new(row), Box::new(col));
+                return Ok(Expr::Ternary(INDEX, table, row, col));
+            }
+            "MATCH" | "VLOOKUP" => {
+                let value = Box::new(self.expression(0)?);
+                self.expect(Token::Comma)?;
+                let function_id = if name == "MATCH" { MATCH } else { VLOOKUP };
+                let table = Box::new(self.range(function_id)?);
+                if function_id == MATCH {
+                    return Ok(Expr::Binary(MATCH, value, table));
+                }
+                self.expect(Token::Comma)?;
+                let col = Box::new(self.expression(0)?);
+                return Ok(Expr::Ternary(VLOOKUP, value, table, col));
+            }
+            "COUNTLABEL" => {
+                let table = Box::new(self.range(COUNTLABEL)?);
+                self.expect(Token::Comma)?;
+                let Some(Token::Text(pattern)) = self.next() else {
+                    return Err(ParseError::InvalidCommand);
+                };
+                let pattern = Box::new(Expr::Text(pattern.into()));
+                return Ok(Expr::Binary(COUNTLABEL, table, pattern));
+            }
+            _ => {}
+        }
+        let function_id = match name {
+            "SLEEP" => 1,
+            "FACT" => 11,
+            "FIB" => 12,
+            name => *self.functions.get(name).ok_or(ParseError::InvalidCommand)?,
+        };
+        Ok(Expr::Call(function_id, Box::new(self.expression(0)?)))
+    }
+    /// Parses a range such as `A1:B3` as the range node of `function_id`: a range function,
+    /// or the table a lookup function searches.
+    fn range(&mut self, function_id: u8) -> Result<Expr, ParseError> {
+        let (start, start_fixed) = self.cell()?;
+        self.expect(Token::Colon)?;
+        let (end, end_fixed) = self.cell()?;
+        if !is_valid_range(start, end) {
+            self.failed = Some((self.pos - 3, self.pos - 1));
+            return Err(ParseError::InvalidRange);
+        }
+        let fixed = start_fixed | end_fixed << 2;
+        Ok(Expr::Range(function_id, start, end, fixed))
+    }
 }
-/// Parses a numeric literal into a simple assignment function.
-///
-/// # Arguments
-/// - `caps`: Regex match result containing the integer.
-/// - `info`: Target `Info` structure to populate.
+/// Converts a parsed tree to the `Info` stored in its cell.
 ///
-/// # Returns
-/// `Ok(())` if valid integer, else `ParseError::InvalidValue`.
-
-fn handle_integer(caps: &regex::Captures, info: &mut Info) -> Result<(), ParseError> {
-    let value =
-        i32::from_str(caps.get(0).unwrap().as_str()).map_err(|_| ParseError::InvalidValue)?;
-    info.arg_mask = 0; // Not a cell
-    info.arg[0] = value;
-    info.function_id = 0; // Assignment function
-    Ok(())
+/// A literal, a reference, one operator between two operands, a range function, or a
+/// call whose argument is a cell or whole number keeps its classic encoding, so `A1+5`
+/// is still an `add`. Anything else becomes an expression cell. A registered function
+/// can only be called in the classic form, since its formula reads its argument from the
+/// `Info`.
+fn lower(expr: Expr) -> Result<Info, ParseError> {
+    let (function_id, operands) = match &expr {
+        Expr::Number(value) => return Ok(Info::number(*value)),
+        Expr::Range(function_id, start, end, fixed) => {
+            return Ok(Info {
+                function_id: *function_id,
+                arg_mask: 0b11,
+                arg: [*start as i32, *end as i32],
+                fixed: *fixed,
+                ..Default::default()
+            });
+        }
+        Expr::Cell(..) => (0, vec![&expr]),
+        Expr::Call(function_id, arg) => (*function_id, vec![&**arg]),
+        Expr::Binary(function_id, lhs, rhs) => (*function_id, vec![&**lhs, &**rhs]),
+        Expr::Neg(_) | Expr::Ternary(..) | Expr::Text(_) => (0, vec![]),
+    };
+    let mut info = Info {
+        function_id,
+        ..Default::default()
+    };
+    let mut classic = !operands.is_empty();
+    for (i, operand) in operands.into_iter().enumerate() {
+        match *operand {
+            Expr::Cell(cell, fixed) => {
+                info.arg_mask |= 1 << i;
+                info.arg[i] = cell as i32;
+                info.fixed |= fixed << (2 * i);
+            }
+            Expr::Number(value) if Info::number(value).function_id == 0 => {
+                info.arg[i] = value as i32;
+            }
+            _ => classic = false,
+        }
+    }
+    if classic {
+        return Ok(info);
+    }
+    let mut builtin = true;
+    expr.visit(&mut |node| {
+        if let Expr::Call(function_id, _) = node {
+            builtin &= matches!(*function_id, 1 | 11 | 12 | ABS | RAND | TODAY)
+                || is_position_function(*function_id);
+        }
+    });
+    if !builtin {
+        return Err(ParseError::InvalidCommand);
+    }
+    Ok(Info::expression(expr))
 }
 /// Parses a string as either a cell reference or an integer literal.
 ///
@@ -246,15 +886,69 @@ fn handle_integer(caps: &regex::Captures, info: &mut Info) -> Result<(), ParseEr
 /// value_parser("A1", &mut vi).unwrap();
 /// ```
 pub fn value_parser(value_str: &str, value_info: &mut ValueInfo) -> Result<(), ParseError> {
-    if value_str.chars().next().unwrap().is_ascii_uppercase() {
+    if value_str.chars().next().unwrap().is_ascii_alphabetic() {
         value_info.is_cell = true;
         value_info.value = cell_parser(value_str)? as i32;
     } else {
         value_info.is_cell = false;
-        value_info.value = i32::from_str(value_str).map_err(|_| ParseError::InvalidValue)?;
+        value_info.value = literal_parser(value_str)?;
     }
     Ok(())
 }
+/// Returns the text of a string literal such as `"Revenue"`, without its quotes.
+///
+/// The text may hold anything but a double quote, including spaces.
+///
+/// # Example
+/// ```
+/// assert_eq!(text_literal("\"Q1 sales\""), Some("Q1 sales"));
+/// assert_eq!(text_literal("A1"), None);
+/// ```
+pub fn text_literal(expr: &str) -> Option<&str> {
+    let text = expr.strip_prefix('"')?.strip_suffix('"')?;
+    (!text.contains('"')).then_some(text)
+}
+/// Parses a signed integer literal, distinguishing out-of-range numbers from malformed ones.
+///
+/// # Returns
+/// The parsed value, `ParseError::ValueOverflow` if it does not fit in an `i32`, or
+/// `ParseError::InvalidValue` for any other malformed input.
+///
+/// # Example
+/// ```
+/// assert_eq!(literal_parser("-42"), Ok(-42));
+/// assert_eq!(literal_parser("99999999999"), Err(ParseError::ValueOverflow));
+/// ```
+pub fn literal_parser(value_str: &str) -> Result<i32, ParseError> {
+    i32::from_str(value_str).map_err(|e| match e.kind() {
+        IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => ParseError::ValueOverflow,
+        _ => ParseError::InvalidValue,
+    })
+}
+/// Parses a number literal of a formula: a signed integer as `literal_parser` does, or one
+/// with a fraction or an exponent such as `2.5` or `-1E6`, and with underscores between
+/// its digits ignored, so `1_000_000` is a million.
+///
+/// # Returns
+/// The parsed value, `ParseError::ValueOverflow` for an integer beyond `i32` or any number
+/// beyond `f64`, or `ParseError::InvalidValue` for any other malformed input.
+///
+/// # Example
+/// ```
+/// assert_eq!(number_parser("2.5e3"), Ok(2500.0));
+/// assert_eq!(number_parser("1_000_000"), Ok(1e6));
+/// ```
+pub fn number_parser(value_str: &str) -> Result<f64, ParseError> {
+    let text = value_str.replace('_', "");
+    if !text.contains(['.', 'e', 'E']) {
+        return literal_parser(&text).map(f64::from);
+    }
+    match f64::from_str(&text) {
+        Ok(value) if value.is_infinite() => Err(ParseError::ValueOverflow),
+        Ok(value) => Ok(value),
+        Err(_) => Err(ParseError::InvalidValue),
+    }
+}
 /// Parses a spreadsheet-style cell reference like "A1" into its linear index.
 ///
 /// # Arguments
@@ -273,8 +967,13 @@ pub fn cell_parser(cell_str: &str) -> Result<usize, ParseError> {
         .ok_or(ParseError::InvalidCell)?;
     let (col_str, row_str) = cell_str.split_at(split_pos);
 
-    let col = convert::alpha_to_num(col_str).ok_or(ParseError::InvalidCell)?;
-    let row = usize::from_str(row_str).map_err(|_| ParseError::InvalidCell)? - 1;
+    let col = convert::alpha_to_num(&col_str.to_ascii_uppercase())
+        .filter(|&col| col > 0)
+        .ok_or(ParseError::InvalidCell)?;
+    let row = usize::from_str(row_str)
+        .ok()
+        .and_then(|row| row.checked_sub(1))
+        .ok_or(ParseError::InvalidCell)?;
 
     if !is_valid_cell(row, col - 1) {
         Err(ParseError::InvalidCell)
@@ -282,6 +981,33 @@ pub fn cell_parser(cell_str: &str) -> Result<usize, ParseError> {
         Ok(get_cell(row, col - 1))
     }
 }
+/// Parses a cell reference inside a formula, which may mark its column and row absolute
+/// with `$` as in `$A$1`, `$A1` or `A$1`.
+///
+/// # Returns
+/// The linear index of the cell and its `FIXED_COL`/`FIXED_ROW` flags, or
+/// `ParseError::InvalidCell` if parsing fails.
+///
+/// # Example
+/// ```
+/// assert_eq!(reference_parser("A$2"), Ok((get_cell(1, 0), FIXED_ROW)));
+/// ```
+pub fn reference_parser(reference: &str) -> Result<(usize, u8), ParseError> {
+    let (col_fixed, rest) = match reference.strip_prefix('$') {
+        Some(rest) => (FIXED_COL, rest),
+        None => (0, reference),
+    };
+    let split_pos = rest
+        .find(|c: char| c == '$' || c.is_ascii_digit())
+        .ok_or(ParseError::InvalidCell)?;
+    let (col, row) = rest.split_at(split_pos);
+    let (row_fixed, row) = match row.strip_prefix('$') {
+        Some(row) => (FIXED_ROW, row),
+        None => (0, row),
+    };
+    let cell = cell_parser(&format!("{}{}", col, row))?;
+    Ok((cell, col_fixed | row_fixed))
+}
 /// Parses an input command and converts it into `CommandInfo`.
 ///
 /// # Arguments
@@ -295,7 +1021,14 @@ pub fn parse(input: &str, context: &mut ParserContext) -> Result<CommandInfo, Pa
         return Err(ParseError::InvalidCommand);
     }
 
-    if input.len() == 1 {
+    if input == "." {
+        return repeat_last_edit(context);
+    }
+
+    if input.len() == 1
+        || input.starts_with("scroll ")
+        || matches!(input, "page_up" | "page_down" | "home" | "end")
+    {
         let mut cmd_info = CommandInfo::default();
         cmd_info.lhs_cell = -1;
         control_parser(input, context)?;
@@ -305,24 +1038,479 @@ pub fn parse(input: &str, context: &mut ParserContext) -> Result<CommandInfo, Pa
 
     let result = handle_other_commands(input, context);
 
-    if result.is_ok() {
+    // A recognised setting with a bad value is reported as such, not as an unknown command
+    if matches!(result, Ok(_) | Err(ParseError::InvalidValue)) {
         return result;
     }
 
-    if let Some(caps) = PATTERNS[4].captures(input) {
+    if let Some(caps) = PATTERNS[0].captures(input) {
         let lhs_str = caps.get(1).unwrap().as_str();
         let cell = cell_parser(lhs_str)?;
         let mut cmd_info = CommandInfo::default();
         cmd_info.lhs_cell = cell as i32;
 
         let expr = caps.get(2).unwrap().as_str();
-        expression_parser(expr, &mut cmd_info.info)?;
+        cmd_info.info = context.parse_expression(expr).inspect_err(|_| {
+            // Point into the whole command, past the `B1=`
+            let offset = caps.get(2).unwrap().start() as isize;
+            context.last_error = context.last_error.take().map(|e| e.moved(offset));
+        })?;
 
         Ok(cmd_info)
     } else {
         Err(ParseError::InvalidCommand)
     }
 }
+/// Re-applies the last recorded edit to the current cell (the top-left cell of the viewport,
+/// as set by `scroll_to`).
+///
+/// The expression is replayed verbatim: cell references inside it are not shifted relative
+/// to the new location, so `A1=B1+1` repeated at `C5` assigns `C5=B1+1`.
+///
+/// # Returns
+/// The `CommandInfo` for the current cell, or `ParseError::InvalidCommand` if nothing has
+/// been edited yet.
+fn repeat_last_edit(context: &mut ParserContext) -> Result<CommandInfo, ParseError> {
+    let expr = context
+        .last_expr
+        .clone()
+        .ok_or(ParseError::InvalidCommand)?;
+    let mut cmd_info = CommandInfo::default();
+    cmd_info.lhs_cell = get_cell(context.px, context.py) as i32;
+    cmd_info.info = context.parse_expression(&expr)?;
+    Ok(cmd_info)
+}
+/// Parses a 1-based row number such as `5` into a row index of the sheet.
+///
+/// # Returns
+/// The row index, or `ParseError::InvalidValue` if it is malformed or outside the sheet.
+pub fn row_parser(row_str: &str) -> Result<usize, ParseError> {
+    match row_str.parse::<usize>() {
+        Ok(row) if row >= 1 && is_valid_cell(row - 1, 0) => Ok(row - 1),
+        _ => Err(ParseError::InvalidValue),
+    }
+}
+/// Parses column letters such as `C` into a column index of the sheet.
+///
+/// # Returns
+/// The column index, or `ParseError::InvalidValue` if it is malformed or outside the sheet.
+pub fn column_parser(col_str: &str) -> Result<usize, ParseError> {
+    // Longer names would not fit the sheet, and could overflow `alpha_to_num`
+    if col_str.len() > 3 {
+        return Err(ParseError::InvalidValue);
+    }
+    match convert::alpha_to_num(col_str) {
+        Some(col) if is_valid_cell(0, col - 1) => Ok(col - 1),
+        _ => Err(ParseError::InvalidValue),
+    }
+}
+/// Parses a range reference like `A1:B5` into its two corner cell indices.
+///
+/// # Returns
+/// `(start, end)` if both cells are valid and form a valid range, else a `ParseError`.
+pub fn range_parser(range_str: &str) -> Result<(usize, usize), ParseError> {
+    let (first, second) = range_str.split_once(':').ok_or(ParseError::InvalidRange)?;
+    let start = cell_parser(first)?;
+    let end = cell_parser(second)?;
+    if !is_valid_range(start, end) {
+        return Err(ParseError::InvalidRange);
+    }
+    Ok((start, end))
+}
+/// Parses either a range (`A1:B3`) or a single cell (`A1`, as the range `A1:A1`).
+pub fn cells_parser(cells: &str) -> Result<(usize, usize), ParseError> {
+    if cells.contains(':') {
+        range_parser(cells)
+    } else {
+        let cell = cell_parser(cells)?;
+        Ok((cell, cell))
+    }
+}
+/// Parses a color name from `COLORS`, in any case.
+pub fn color_parser(name: &str) -> Result<Color, ParseError> {
+    COLORS
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(name))
+        .map(|&(_, color)| color)
+        .ok_or(ParseError::InvalidValue)
+}
+/// Parses a number format: one of `NUMBER_FORMATS` by name, or `fixed <n>` with up to
+/// `MAX_DECIMALS` decimals.
+///
+/// # Example
+/// ```
+/// assert_eq!(number_format_parser(&["fixed", "2"]), Ok(NumberFormat::Fixed(2)));
+/// ```
+pub fn number_format_parser(words: &[&str]) -> Result<NumberFormat, ParseError> {
+    match words {
+        ["fixed", decimals] => match decimals.parse::<u8>() {
+            Ok(decimals) if decimals <= MAX_DECIMALS => Ok(NumberFormat::Fixed(decimals)),
+            _ => Err(ParseError::InvalidValue),
+        },
+        [name] => NUMBER_FORMATS
+            .iter()
+            .find(|&&(known, _)| known == *name)
+            .map(|&(_, number)| number)
+            .ok_or(ParseError::InvalidCommand),
+        _ => Err(ParseError::InvalidCommand),
+    }
+}
+/// Parses keyword commands such as `CUMPCT A1:A10`, `dupregion A1:C3 E1` or `impact A1`.
+///
+/// # Returns
+/// `Ok(None)` if the input is not a keyword command, `Ok(Some(command))` if it is, and a
+/// `ParseError` if the keyword matched but its arguments are malformed.
+pub fn parse_command(input: &str) -> Result<Option<Command>, ParseError> {
+    let parts: Vec<&str> = input.split_whitespace().collect();
+    let command = match parts.as_slice() {
+        ["CUMPCT", range] => {
+            let (start, end) = range_parser(range)?;
+            Command::CumulativePercent { start, end }
+        }
+        ["MOVAVG", range, window, rest @ ..] => {
+            let (start, end) = range_parser(range)?;
+            let window = match window.parse::<usize>() {
+                Ok(k) if k > 0 => k,
+                _ => return Err(ParseError::InvalidValue),
+            };
+            let (target, shrink) = match rest {
+                [] => (start, false),
+                ["shrink"] => (start, true),
+                [target] => (cell_parser(target)?, false),
+                [target, "shrink"] => (cell_parser(target)?, true),
+                _ => return Err(ParseError::InvalidCommand),
+            };
+            Command::MovingAverage {
+                start,
+                end,
+                window,
+                target,
+                shrink,
+            }
+        }
+        ["WMOVAVG", range, weights, target @ ..] if target.len() <= 1 => {
+            let (start, end) = range_parser(range)?;
+            let (weights_start, weights_end) = range_parser(weights)?;
+            let target = match target {
+                [target] => cell_parser(target)?,
+                _ => start,
+            };
+            Command::WeightedMovingAverage {
+                start,
+                end,
+                weights_start,
+                weights_end,
+                target,
+            }
+        }
+        ["DELTA", range, target @ ..] if target.len() <= 1 => {
+            let (start, end) = range_parser(range)?;
+            let target = match target {
+                [target] => cell_parser(target)?,
+                _ => start,
+            };
+            Command::Delta { start, end, target }
+        }
+        ["normalize_range", range] => {
+            let (start, end) = range_parser(range)?;
+            Command::Normalize { start, end }
+        }
+        ["shiftrefs", range, direction, amount] => {
+            let (start, end) = range_parser(range)?;
+            let amount = amount
+                .parse::<isize>()
+                .ok()
+                .filter(|&a| a >= 0)
+                .ok_or(ParseError::InvalidValue)?;
+            let (rows, cols) = match *direction {
+                "up" => (-amount, 0),
+                "down" => (amount, 0),
+                "left" => (0, -amount),
+                "right" => (0, amount),
+                _ => return Err(ParseError::InvalidCommand),
+            };
+            Command::ShiftReferences {
+                start,
+                end,
+                rows,
+                cols,
+            }
+        }
+        ["rand", range, lo, hi] | ["genrandom", range, "min", lo, "max", hi] => {
+            let (start, end) = range_parser(range)?;
+            let (lo, hi) = (literal_parser(lo)?, literal_parser(hi)?);
+            if lo > hi {
+                return Err(ParseError::InvalidValue);
+            }
+            Command::Random { start, end, lo, hi }
+        }
+        ["replace", from, "with", to] => Command::ReplaceReference {
+            from: cell_parser(from)?,
+            to: cell_parser(to)?,
+        },
+        ["replace_text", from, "with", to] => Command::ReplaceLiteral {
+            from: literal_parser(from)?,
+            to: literal_parser(to)?,
+        },
+        ["clamp_range", range, lo, hi] => {
+            let (start, end) = range_parser(range)?;
+            let (lo, hi) = (literal_parser(lo)?, literal_parser(hi)?);
+            if lo > hi {
+                return Err(ParseError::InvalidValue);
+            }
+            Command::Clamp { start, end, lo, hi }
+        }
+        ["rotate_sheet", direction @ ("cw" | "ccw")] => Command::RotateSheet {
+            clockwise: *direction == "cw",
+        },
+        ["dupregion", range, target] => {
+            let (start, end) = range_parser(range)?;
+            Command::DuplicateRegion {
+                start,
+                end,
+                target: cell_parser(target)?,
+            }
+        }
+        ["fill", range] => {
+            let (start, end) = range_parser(range)?;
+            Command::Fill { start, end }
+        }
+        ["pivot", range, "into", target] => {
+            let (start, end) = range_parser(range)?;
+            Command::Pivot {
+                start,
+                end,
+                target: cell_parser(target)?,
+            }
+        }
+        [keyword @ ("mark_inputs" | "mark_outputs"), range] => {
+            let (start, end) = range_parser(range)?;
+            let role = if *keyword == "mark_inputs" {
+                CellRole::Input
+            } else {
+                CellRole::Output
+            };
+            Command::MarkRole { start, end, role }
+        }
+        ["format", cells, style @ ..] => {
+            let (start, end) = cells_parser(cells)?;
+            let change = match style {
+                ["bold"] => FormatChange::Bold,
+                ["italic"] => FormatChange::Italic,
+                ["underline"] => FormatChange::Underline,
+                ["nobold"] => FormatChange::NoBold,
+                ["noitalic"] => FormatChange::NoItalic,
+                ["nounderline"] => FormatChange::NoUnderline,
+                ["reset"] => FormatChange::Reset,
+                ["color", name] => FormatChange::Color(color_parser(name)?),
+                number => FormatChange::Number(number_format_parser(number)?),
+            };
+            Command::Format { start, end, change }
+        }
+        ["io_map"] => Command::IoMap,
+        ["note", cell, _, ..] => {
+            // The note keeps its own spacing, so take it from the input itself
+            let rest = input.trim_start().strip_prefix("note").unwrap_or_default();
+            let rest = rest.trim_start().strip_prefix(cell).unwrap_or_default();
+            Command::Note {
+                cell: cell_parser(cell)?,
+                text: text_literal(rest.trim())
+                    .ok_or(ParseError::InvalidValue)?
+                    .to_string(),
+            }
+        }
+        ["sort", range, "by", column, order @ ..] => {
+            let (start, end) = range_parser(range)?;
+            let key = column_parser(column)?;
+            let descending = match order {
+                [] | ["asc"] => false,
+                ["desc"] => true,
+                _ => return Err(ParseError::InvalidCommand),
+            };
+            Command::Sort {
+                start,
+                end,
+                key,
+                descending,
+            }
+        }
+        ["trace", "on"] => Command::Trace {
+            enabled: true,
+            path: None,
+        },
+        ["trace", "on", path] => Command::Trace {
+            enabled: true,
+            path: Some(path.to_string()),
+        },
+        ["trace", "off"] => Command::Trace {
+            enabled: false,
+            path: None,
+        },
+        ["lock", cells] => {
+            let (start, end) = cells_parser(cells)?;
+            Command::Lock {
+                start,
+                end,
+                locked: true,
+            }
+        }
+        ["unlock", cells] => {
+            let (start, end) = cells_parser(cells)?;
+            Command::Lock {
+                start,
+                end,
+                locked: false,
+            }
+        }
+        ["notes", cell] => Command::Notes {
+            cell: cell_parser(cell)?,
+        },
+        ["rangediff", range, other] => {
+            let (start, end) = range_parser(range)?;
+            let (other_start, other_end) = range_parser(other)?;
+            Command::RangeDiff {
+                start,
+                end,
+                other_start,
+                other_end,
+            }
+        }
+        ["save", path] => Command::SaveCsv {
+            path: path.to_string(),
+        },
+        ["export_json", path] => Command::ExportJson {
+            path: path.to_string(),
+        },
+        ["load", path] => Command::LoadCsv {
+            path: path.to_string(),
+        },
+        ["load_csv", path, at @ ..] => Command::ImportCsv {
+            path: path.to_string(),
+            at: match at {
+                [] => 0,
+                ["--at", cell] => cell_parser(cell)?,
+                _ => return Err(ParseError::InvalidCommand),
+            },
+        },
+        ["save_session", path] => Command::SaveSession {
+            path: path.to_string(),
+        },
+        ["load_session", path] => Command::LoadSession {
+            path: path.to_string(),
+        },
+        ["clear", range] => {
+            let (start, end) = range_parser(range)?;
+            Command::Clear { start, end }
+        }
+        ["ismono", range] => {
+            let (start, end) = range_parser(range)?;
+            Command::Monotonicity { start, end }
+        }
+        [
+            action @ ("insert_row" | "delete_row" | "insert_col" | "delete_col"),
+            line,
+        ] => {
+            let (axis, index) = if action.ends_with("row") {
+                (Axis::Row, row_parser(line)?)
+            } else {
+                (Axis::Column, column_parser(line)?)
+            };
+            let edit = if action.starts_with("insert") {
+                LineEdit::Insert(index)
+            } else {
+                LineEdit::Delete(index)
+            };
+            Command::MoveLines { axis, edit }
+        }
+        ["impact", cell] => Command::Impact {
+            cell: cell_parser(cell)?,
+        },
+        ["deps", cell] => Command::Deps {
+            cell: cell_parser(cell)?,
+            tree: false,
+        },
+        ["deps", cell, "--tree"] => Command::Deps {
+            cell: cell_parser(cell)?,
+            tree: true,
+        },
+        ["why", cell] => Command::Why {
+            cell: cell_parser(cell)?,
+        },
+        [op @ ("add" | "sub" | "mul"), cell, amount] => {
+            let op_index = ["add", "sub", "mul"].iter().position(|s| s == op).unwrap();
+            Command::Adjust {
+                cell: cell_parser(cell)?,
+                function_id: (ARITHMETIC_OFFSET + op_index) as u8,
+                amount: literal_parser(amount)?,
+            }
+        }
+        ["set", "recalc_order", order] => Command::SetRecalcOrder(match *order {
+            "rowmajor" => RecalcOrder::RowMajor,
+            "colmajor" => RecalcOrder::ColMajor,
+            "dfs" => RecalcOrder::Dfs,
+            _ => return Err(ParseError::InvalidValue),
+        }),
+        ["set", "overflow_policy", policy] => Command::SetOverflowPolicy(match *policy {
+            "error" => OverflowPolicy::Error,
+            "saturate" => OverflowPolicy::Saturate,
+            _ => return Err(ParseError::InvalidValue),
+        }),
+        ["set", "threads", threads] => match threads.parse() {
+            Ok(threads) if threads > 0 => Command::SetThreads(threads),
+            _ => return Err(ParseError::InvalidValue),
+        },
+        ["set", "seed", seed] | ["seed", seed] => {
+            Command::SetSeed(seed.parse().map_err(|_| ParseError::InvalidValue)?)
+        }
+        ["set", "history_depth", depth] => {
+            Command::SetHistoryDepth(depth.parse().map_err(|_| ParseError::InvalidValue)?)
+        }
+        ["history"] => Command::History,
+        ["history", cell] => Command::CellHistory {
+            cell: cell_parser(cell)?,
+        },
+        ["set", "autosave", "off"] => Command::SetAutosave(None),
+        ["set", "autosave", every] => {
+            Command::SetAutosave(Some(Every::parse(every).ok_or(ParseError::InvalidValue)?))
+        }
+        ["set", "readonly"] => Command::SetReadOnly(true),
+        ["set", "noreadonly"] => Command::SetReadOnly(false),
+        ["set", "cell_history_depth", depth] => {
+            Command::SetCellHistoryDepth(depth.parse().map_err(|_| ParseError::InvalidValue)?)
+        }
+        ["set_calc", mode] => Command::SetCalc(match *mode {
+            "manual" => CalcMode::Manual,
+            "auto" => CalcMode::Auto,
+            _ => return Err(ParseError::InvalidValue),
+        }),
+        ["recalc"] => Command::Recalc,
+        ["profile", "top", count] => match count.parse() {
+            Ok(count) if count > 0 => Command::ProfileTop(count),
+            _ => return Err(ParseError::InvalidValue),
+        },
+        ["profile", "reset"] => Command::ProfileReset,
+        _ => return Ok(None),
+    };
+    Ok(Some(command))
+}
+/// Parses the commands that act on the current selection: `clear` and `sum_selection`.
+///
+/// # Returns
+/// `Ok(None)` if the input is not a selection command, and `ParseError::InvalidRange` if it
+/// is but nothing has been selected yet.
+pub fn parse_selection_command(
+    input: &str,
+    context: &ParserContext,
+) -> Result<Option<Command>, ParseError> {
+    if !matches!(input, "clear" | "sum_selection") {
+        return Ok(None);
+    }
+    let (start, end) = context.selection.ok_or(ParseError::InvalidRange)?;
+    Ok(Some(match input {
+        "clear" => Command::Clear { start, end },
+        _ => Command::SumRange { start, end },
+    }))
+}
 /// Handles special keywords like `undo`, `redo`, `scroll_to A1`, `enable_output`, etc.
 ///
 /// # Arguments
@@ -359,8 +1547,81 @@ fn handle_other_commands(
             cmd_info.lhs_cell = -1;
             Ok(cmd_info)
         }
+        "set view formulas" | "set view values" | "show_formulas" | "show_values" => {
+            context.show_formulas = input.ends_with("formulas");
+            let mut cmd_info = CommandInfo::default();
+            cmd_info.lhs_cell = -1;
+            Ok(cmd_info)
+        }
+        "set view transpose" => {
+            context.transpose = !context.transpose;
+            let mut cmd_info = CommandInfo::default();
+            cmd_info.lhs_cell = -1;
+            Ok(cmd_info)
+        }
+        _ if input.starts_with("set overflow ") => {
+            let mut marker = input["set overflow ".len()..].chars();
+            match (marker.next(), marker.next()) {
+                (Some(c), None) if !c.is_whitespace() => context.overflow_marker = c,
+                _ => return Err(ParseError::InvalidValue),
+            }
+            let mut cmd_info = CommandInfo::default();
+            cmd_info.lhs_cell = -1;
+            Ok(cmd_info)
+        }
+        "chart off" => {
+            context.chart = None;
+            let mut cmd_info = CommandInfo::default();
+            cmd_info.lhs_cell = -1;
+            Ok(cmd_info)
+        }
+        _ if input.starts_with("chart bar ") => {
+            let range = input["chart bar ".len()..].trim();
+            context.chart = Some(range_parser(range).map_err(|_| ParseError::InvalidValue)?);
+            let mut cmd_info = CommandInfo::default();
+            cmd_info.lhs_cell = -1;
+            Ok(cmd_info)
+        }
+        "select off" => {
+            context.selection = None;
+            let mut cmd_info = CommandInfo::default();
+            cmd_info.lhs_cell = -1;
+            Ok(cmd_info)
+        }
+        _ if input.starts_with("select ") => {
+            let range = input["select ".len()..].trim();
+            context.selection = Some(range_parser(range).map_err(|_| ParseError::InvalidValue)?);
+            let mut cmd_info = CommandInfo::default();
+            cmd_info.lhs_cell = -1;
+            Ok(cmd_info)
+        }
+        _ if input.starts_with("set_view_rows ")
+            || input.starts_with("set_view_cols ")
+            || input.starts_with("set_col_width ") =>
+        {
+            let (setting, size) = input.split_once(' ').unwrap();
+            let size = match size.trim().parse::<usize>() {
+                Ok(size) if size > 0 => size,
+                _ => return Err(ParseError::InvalidValue),
+            };
+            match setting {
+                "set_view_rows" => context.view_rows = size,
+                "set_view_cols" => context.view_cols = size,
+                _ => context.col_width = size,
+            }
+            let mut cmd_info = CommandInfo::default();
+            cmd_info.lhs_cell = -1;
+            Ok(cmd_info)
+        }
+        _ if input.starts_with("set colgap ") => {
+            let gap = input["set colgap ".len()..].trim();
+            context.colgap = gap.parse().map_err(|_| ParseError::InvalidValue)?;
+            let mut cmd_info = CommandInfo::default();
+            cmd_info.lhs_cell = -1;
+            Ok(cmd_info)
+        }
         _ => {
-            if let Some(caps) = PATTERNS[5].captures(input) {
+            if let Some(caps) = PATTERNS[1].captures(input) {
                 let cell_str = caps.get(1).unwrap().as_str();
                 let cell = cell_parser(cell_str)?;
                 let (row, col) = get_row_and_column(cell);
@@ -375,62 +1636,1011 @@ fn handle_other_commands(
         }
     }
 }
-/// Handles navigation commands like `w`, `a`, `s`, `d`, and exits on `q`.
+/// Handles navigation commands and exits on `q`.
+///
+/// `w`/`s` and `page_up`/`page_down` scroll a viewport height, `a`/`d` a viewport width,
+/// `scroll <rows> <cols>` any amount (negative is up or left), and `home`/`end` jump to
+/// the first and last page. The clamping is `sheet::scroll_position`, over the global
+/// sheet dimensions.
 ///
 /// # Arguments
-/// - `input`: A single character command.
+/// - `input`: The navigation command.
 /// - `context`: Parser context, updated if scrolling is valid.
 ///
 /// # Returns
 /// `Ok(())` if command is valid and executed, or `ParseError::InvalidCommand`.
 
 fn control_parser(input: &str, context: &mut ParserContext) -> Result<(), ParseError> {
-    match input {
+    let (view_rows, view_cols, _) = context.view_size();
+    let (rows, cols) = (view_rows as isize, view_cols as isize);
+    let delta = match input {
         "q" => std::process::exit(0),
-        "w" | "a" | "s" | "d" => {
-            // Get sheet dimensions
-            let n = crate::sheet::N_MAX();
-            let m = crate::sheet::M_MAX();
-            let viewport_size = 10; // Assuming 10x10 viewport
-
-            // Calculate max valid scroll positions
-            let max_px = n.saturating_sub(viewport_size);
-            let max_py = m.saturating_sub(viewport_size);
-
-            // Calculate delta with boundary checks
-            let (new_px, new_py) = match input {
-                "w" => (
-                    // Up
-                    context.px.saturating_sub(10),
-                    context.py,
-                ),
-                "s" => (
-                    // Down
-                    context.px.saturating_add(10).min(max_px),
-                    context.py,
-                ),
-                "a" => (
-                    // Left
-                    context.px,
-                    context.py.saturating_sub(10),
-                ),
-                "d" => (
-                    // Right
-                    context.px,
-                    context.py.saturating_add(10).min(max_py),
-                ),
-                _ => unreachable!(),
-            };
+        "w" | "page_up" => (-rows, 0),
+        "s" | "page_down" => (rows, 0),
+        "a" => (0, -cols),
+        "d" => (0, cols),
+        "home" => (isize::MIN, isize::MIN),
+        "end" => (isize::MAX, isize::MAX),
+        _ => match input.split_whitespace().collect::<Vec<_>>()[..] {
+            ["scroll", dx, dy] => match (dx.parse(), dy.parse()) {
+                (Ok(dx), Ok(dy)) => (dx, dy),
+                _ => return Err(ParseError::InvalidValue),
+            },
+            _ => return Err(ParseError::InvalidCommand),
+        },
+    };
+    (context.px, context.py) = scroll_position(
+        (context.px, context.py),
+        delta,
+        (view_rows, view_cols),
+        (crate::sheet::N_MAX(), crate::sheet::M_MAX()),
+    );
+    Ok(())
+}
 
-            // Only update if position changed
-            if new_px != context.px || new_py != context.py {
-                context.px = new_px;
-                context.py = new_py;
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sheet::{Sheet, TEST_COLS, TEST_ROWS, init_test_dimensions};
+    use std::time::Duration;
 
-            Ok(())
+    #[test]
+    fn test_set_view_toggles_formula_display() {
+        init_test_dimensions();
+        let mut context = ParserContext::new();
+        let cmd = parse("set view formulas", &mut context).unwrap();
+        assert_eq!(cmd.lhs_cell, -1);
+        assert!(context.show_formulas);
+        parse("set view values", &mut context).unwrap();
+        assert!(!context.show_formulas);
+        parse("show_formulas", &mut context).unwrap();
+        assert!(context.show_formulas);
+        parse("show_values", &mut context).unwrap();
+        assert!(!context.show_formulas);
+        parse("set view transpose", &mut context).unwrap();
+        assert!(context.transpose);
+        parse("set view transpose", &mut context).unwrap();
+        assert!(!context.transpose);
+        assert_eq!(context.colgap, 1);
+        parse("set colgap 3", &mut context).unwrap();
+        assert_eq!(context.colgap, 3);
+        assert_eq!(context.overflow_marker, '#');
+        parse("set overflow *", &mut context).unwrap();
+        assert_eq!(context.overflow_marker, '*');
+        assert_eq!(
+            parse("set overflow ab", &mut context).err(),
+            Some(ParseError::InvalidValue)
+        );
+        assert_eq!(
+            parse("set colgap -1", &mut context).err(),
+            Some(ParseError::InvalidValue)
+        );
+        assert_eq!(context.colgap, 3);
+        assert!(parse("set view sideways", &mut context).is_err());
+    }
+
+    #[test]
+    fn test_viewport_size_sets_scroll_step() {
+        init_test_dimensions();
+        let mut context = ParserContext::new();
+        parse("set_view_rows 25", &mut context).unwrap();
+        parse("set_view_cols 4", &mut context).unwrap();
+        parse("set_col_width 16", &mut context).unwrap();
+        assert_eq!(
+            (context.view_rows, context.view_cols, context.col_width),
+            (25, 4, 16)
+        );
+        assert_eq!(
+            parse("set_view_rows 0", &mut context).err(),
+            Some(ParseError::InvalidValue)
+        );
+        assert_eq!(
+            parse("set_col_width wide", &mut context).err(),
+            Some(ParseError::InvalidValue)
+        );
+
+        parse("s", &mut context).unwrap();
+        parse("d", &mut context).unwrap();
+        assert_eq!((context.px, context.py), (25, 4));
+        // The last page still fills the viewport
+        for _ in 0..5 {
+            parse("s", &mut context).unwrap();
         }
-        _ => Err(ParseError::InvalidCommand),
+        assert_eq!(context.px, TEST_ROWS - 25);
+        parse("w", &mut context).unwrap();
+        assert_eq!(context.px, TEST_ROWS - 50);
     }
-}
 
+    #[test]
+    fn test_scroll_by_any_amount() {
+        init_test_dimensions();
+        let mut context = ParserContext::new();
+        parse("scroll 5 3", &mut context).unwrap();
+        assert_eq!((context.px, context.py), (5, 3));
+        parse("scroll -2 -10", &mut context).unwrap();
+        assert_eq!((context.px, context.py), (3, 0));
+        parse("page_down", &mut context).unwrap();
+        assert_eq!(context.px, 13);
+        parse("page_up", &mut context).unwrap();
+        assert_eq!(context.px, 3);
+        parse("end", &mut context).unwrap();
+        assert_eq!(
+            (context.px, context.py),
+            (TEST_ROWS - DEFAULT_VIEW_SIZE, TEST_COLS - DEFAULT_VIEW_SIZE)
+        );
+        parse("scroll 1000 1000", &mut context).unwrap();
+        assert_eq!(context.px, TEST_ROWS - DEFAULT_VIEW_SIZE);
+        parse("home", &mut context).unwrap();
+        assert_eq!((context.px, context.py), (0, 0));
+        assert_eq!(
+            parse("scroll 5 left", &mut context).err(),
+            Some(ParseError::InvalidValue)
+        );
+        assert!(parse("scroll 5", &mut context).is_err());
+    }
+
+    #[test]
+    fn test_select_feeds_selection_commands() {
+        init_test_dimensions();
+        let mut context = ParserContext::new();
+        assert_eq!(
+            parse_selection_command("clear", &context),
+            Err(ParseError::InvalidRange)
+        );
+        assert_eq!(parse("select A1:B2", &mut context).unwrap().lhs_cell, -1);
+        assert_eq!(context.selection, Some((0, get_cell(1, 1))));
+        assert_eq!(
+            parse_selection_command("sum_selection", &context),
+            Ok(Some(Command::SumRange {
+                start: 0,
+                end: get_cell(1, 1)
+            }))
+        );
+        assert_eq!(parse_selection_command("clear A1:A2", &context), Ok(None));
+        assert_eq!(
+            parse("select B2:A1", &mut context).err(),
+            Some(ParseError::InvalidValue)
+        );
+        assert_eq!(context.selection, Some((0, get_cell(1, 1))));
+        parse("select off", &mut context).unwrap();
+        assert_eq!(context.selection, None);
+    }
+
+    #[test]
+    fn test_chart_takes_rows_from_the_grid() {
+        init_test_dimensions();
+        let mut context = ParserContext::new();
+        context.terminal = Some((80, 12));
+        assert_eq!(context.view_size().0, 9);
+        assert_eq!(parse("chart bar A1:A3", &mut context).unwrap().lhs_cell, -1);
+        assert_eq!(context.chart, Some((0, get_cell(2, 0))));
+        assert_eq!(context.chart_lines(), 4);
+        assert_eq!(context.view_size().0, 5);
+        assert_eq!(
+            parse("chart bar A3:A1", &mut context).err(),
+            Some(ParseError::InvalidValue)
+        );
+        parse("chart off", &mut context).unwrap();
+        assert_eq!(context.chart_lines(), 0);
+    }
+
+    #[test]
+    fn test_parse_cumpct_command() {
+        init_test_dimensions();
+        assert_eq!(
+            parse_command("CUMPCT A1:A4"),
+            Ok(Some(Command::CumulativePercent {
+                start: 0,
+                end: get_cell(3, 0)
+            }))
+        );
+        assert_eq!(parse_command("CUMPCT A4:A1"), Err(ParseError::InvalidRange));
+        assert_eq!(parse_command("A1=5"), Ok(None));
+        assert_eq!(
+            parse_command("sub B1 -3"),
+            Ok(Some(Command::Adjust {
+                cell: 1,
+                function_id: 3,
+                amount: -3
+            }))
+        );
+        assert_eq!(parse_command("mul A1 x"), Err(ParseError::InvalidValue));
+        assert_eq!(
+            parse_command("set recalc_order colmajor"),
+            Ok(Some(Command::SetRecalcOrder(RecalcOrder::ColMajor)))
+        );
+        assert_eq!(
+            parse_command("set overflow_policy saturate"),
+            Ok(Some(Command::SetOverflowPolicy(OverflowPolicy::Saturate)))
+        );
+        assert_eq!(
+            parse_command("set threads 4"),
+            Ok(Some(Command::SetThreads(4)))
+        );
+        assert_eq!(
+            parse_command("set threads 0"),
+            Err(ParseError::InvalidValue)
+        );
+        assert_eq!(
+            parse_command("set overflow_policy wrap"),
+            Err(ParseError::InvalidValue)
+        );
+        assert_eq!(
+            parse_command("set recalc_order diagonal"),
+            Err(ParseError::InvalidValue)
+        );
+        assert_eq!(
+            parse_command("MOVAVG A1:A3 2 B1 shrink"),
+            Ok(Some(Command::MovingAverage {
+                start: 0,
+                end: get_cell(2, 0),
+                window: 2,
+                target: 1,
+                shrink: true
+            }))
+        );
+        assert_eq!(
+            parse_command("MOVAVG A1:A3 0"),
+            Err(ParseError::InvalidValue)
+        );
+        assert_eq!(
+            parse_command("WMOVAVG A1:A3 C1:C2 B1"),
+            Ok(Some(Command::WeightedMovingAverage {
+                start: 0,
+                end: get_cell(2, 0),
+                weights_start: 2,
+                weights_end: get_cell(1, 2),
+                target: 1
+            }))
+        );
+        assert_eq!(
+            parse_command("pivot A1:B4 into D1"),
+            Ok(Some(Command::Pivot {
+                start: 0,
+                end: get_cell(3, 1),
+                target: 3
+            }))
+        );
+        assert_eq!(parse_command("pivot A1:B4 D1"), Ok(None));
+        assert_eq!(
+            parse_command("note  B2   \"check  this = figure\" "),
+            Ok(Some(Command::Note {
+                cell: get_cell(1, 1),
+                text: "check  this = figure".to_string()
+            }))
+        );
+        assert_eq!(
+            parse_command("note A1 \"\""),
+            Ok(Some(Command::Note {
+                cell: 0,
+                text: String::new()
+            }))
+        );
+        assert_eq!(
+            parse_command("note A1 check"),
+            Err(ParseError::InvalidValue)
+        );
+        assert_eq!(
+            parse_command("sort A1:C20 by B desc"),
+            Ok(Some(Command::Sort {
+                start: 0,
+                end: get_cell(19, 2),
+                key: 1,
+                descending: true
+            }))
+        );
+        assert_eq!(
+            parse_command("sort A1:C20 by AA"),
+            Ok(Some(Command::Sort {
+                start: 0,
+                end: get_cell(19, 2),
+                key: 26,
+                descending: false
+            }))
+        );
+        assert_eq!(
+            parse_command("sort A1:C20 by B1"),
+            Err(ParseError::InvalidValue)
+        );
+        assert_eq!(
+            parse_command("sort A1:C20 by B up"),
+            Err(ParseError::InvalidCommand)
+        );
+        assert_eq!(
+            parse_command("trace on trace.log"),
+            Ok(Some(Command::Trace {
+                enabled: true,
+                path: Some("trace.log".to_string())
+            }))
+        );
+        assert_eq!(
+            parse_command("trace off"),
+            Ok(Some(Command::Trace {
+                enabled: false,
+                path: None
+            }))
+        );
+        assert_eq!(
+            parse_command("notes A1"),
+            Ok(Some(Command::Notes { cell: 0 }))
+        );
+        assert_eq!(
+            parse_command("set_calc manual"),
+            Ok(Some(Command::SetCalc(CalcMode::Manual)))
+        );
+        assert_eq!(parse_command("recalc"), Ok(Some(Command::Recalc)));
+        assert_eq!(parse_command("seed 7"), Ok(Some(Command::SetSeed(7))));
+        assert_eq!(
+            parse_command("profile top 10"),
+            Ok(Some(Command::ProfileTop(10)))
+        );
+        assert_eq!(
+            parse_command("profile reset"),
+            Ok(Some(Command::ProfileReset))
+        );
+        assert_eq!(
+            parse_command("profile top 0"),
+            Err(ParseError::InvalidValue)
+        );
+        assert_eq!(
+            parse_command("set_calc later"),
+            Err(ParseError::InvalidValue)
+        );
+        assert_eq!(
+            parse_command("lock A1:B2"),
+            Ok(Some(Command::Lock {
+                start: 0,
+                end: 101,
+                locked: true
+            }))
+        );
+        assert_eq!(
+            parse_command("unlock B1"),
+            Ok(Some(Command::Lock {
+                start: 1,
+                end: 1,
+                locked: false
+            }))
+        );
+        assert_eq!(
+            parse_command("mark_outputs D1:D5"),
+            Ok(Some(Command::MarkRole {
+                start: 3,
+                end: get_cell(4, 3),
+                role: CellRole::Output
+            }))
+        );
+        assert_eq!(parse_command("io_map"), Ok(Some(Command::IoMap)));
+        assert_eq!(parse_command("history"), Ok(Some(Command::History)));
+        assert_eq!(
+            parse_command("history B2"),
+            Ok(Some(Command::CellHistory {
+                cell: get_cell(1, 1)
+            }))
+        );
+        assert_eq!(
+            parse_command("set cell_history_depth 5"),
+            Ok(Some(Command::SetCellHistoryDepth(5)))
+        );
+        assert_eq!(
+            parse_command("format B2 color Red"),
+            Ok(Some(Command::Format {
+                start: get_cell(1, 1),
+                end: get_cell(1, 1),
+                change: FormatChange::Color(Color::Red)
+            }))
+        );
+        assert_eq!(
+            parse_command("format A1:B2 bold"),
+            Ok(Some(Command::Format {
+                start: 0,
+                end: get_cell(1, 1),
+                change: FormatChange::Bold
+            }))
+        );
+        assert_eq!(
+            parse_command("format A1:B2 nobold"),
+            Ok(Some(Command::Format {
+                start: 0,
+                end: get_cell(1, 1),
+                change: FormatChange::NoBold
+            }))
+        );
+        assert_eq!(
+            parse_command("format A1 color mauve"),
+            Err(ParseError::InvalidValue)
+        );
+        assert_eq!(
+            parse_command("format A1 blink"),
+            Err(ParseError::InvalidCommand)
+        );
+        assert_eq!(
+            parse_command("format C1 fixed 2"),
+            Ok(Some(Command::Format {
+                start: 2,
+                end: 2,
+                change: FormatChange::Number(NumberFormat::Fixed(2))
+            }))
+        );
+        assert_eq!(
+            parse_command("format B1 comma"),
+            Ok(Some(Command::Format {
+                start: 1,
+                end: 1,
+                change: FormatChange::Number(NumberFormat::Comma)
+            }))
+        );
+        assert_eq!(
+            parse_command("format C1 fixed 11"),
+            Err(ParseError::InvalidValue)
+        );
+        assert_eq!(
+            parse_command("set history_depth 20"),
+            Ok(Some(Command::SetHistoryDepth(20)))
+        );
+        assert_eq!(
+            parse_command("set history_depth -1"),
+            Err(ParseError::InvalidValue)
+        );
+        assert_eq!(
+            parse_command("rotate_sheet ccw"),
+            Ok(Some(Command::RotateSheet { clockwise: false }))
+        );
+        assert_eq!(parse_command("rotate_sheet up"), Ok(None));
+        assert_eq!(
+            parse_command("set autosave 30s"),
+            Ok(Some(Command::SetAutosave(Some(Every::Interval(
+                Duration::from_secs(30)
+            )))))
+        );
+        assert_eq!(
+            parse_command("set autosave off"),
+            Ok(Some(Command::SetAutosave(None)))
+        );
+        assert_eq!(
+            parse_command("set autosave 0"),
+            Err(ParseError::InvalidValue)
+        );
+        assert_eq!(
+            parse_command("set readonly"),
+            Ok(Some(Command::SetReadOnly(true)))
+        );
+        assert_eq!(
+            parse_command("set noreadonly"),
+            Ok(Some(Command::SetReadOnly(false)))
+        );
+        assert_eq!(
+            parse_command("replace B7 with C9"),
+            Ok(Some(Command::ReplaceReference {
+                from: get_cell(6, 1),
+                to: get_cell(8, 2)
+            }))
+        );
+        assert_eq!(
+            parse_command("replace_text 100 with -200"),
+            Ok(Some(Command::ReplaceLiteral {
+                from: 100,
+                to: -200
+            }))
+        );
+        assert_eq!(
+            parse_command("clamp_range A1:B2 0 100"),
+            Ok(Some(Command::Clamp {
+                start: 0,
+                end: get_cell(1, 1),
+                lo: 0,
+                hi: 100
+            }))
+        );
+        assert_eq!(
+            parse_command("clamp_range A1:B2 5 1"),
+            Err(ParseError::InvalidValue)
+        );
+        assert_eq!(
+            parse_command("rangediff A1:A2 B1:B2"),
+            Ok(Some(Command::RangeDiff {
+                start: 0,
+                end: get_cell(1, 0),
+                other_start: 1,
+                other_end: get_cell(1, 1)
+            }))
+        );
+        assert_eq!(
+            parse_command("ismono A1:A3"),
+            Ok(Some(Command::Monotonicity {
+                start: 0,
+                end: get_cell(2, 0)
+            }))
+        );
+        assert_eq!(
+            parse_command("mark_inputs A1"),
+            Err(ParseError::InvalidRange)
+        );
+        assert_eq!(
+            parse_command("DELTA A1:A4 B1"),
+            Ok(Some(Command::Delta {
+                start: 0,
+                end: get_cell(3, 0),
+                target: 1
+            }))
+        );
+        assert_eq!(
+            parse_command("rand A1:A2 -5 5"),
+            Ok(Some(Command::Random {
+                start: 0,
+                end: get_cell(1, 0),
+                lo: -5,
+                hi: 5
+            }))
+        );
+        assert_eq!(
+            parse_command("genrandom A1:A2 min -5 max 5"),
+            parse_command("rand A1:A2 -5 5")
+        );
+        assert_eq!(parse_command("genrandom A1:A2 max 5 min -5"), Ok(None));
+        assert_eq!(
+            parse_command("rand A1:A2 5 1"),
+            Err(ParseError::InvalidValue)
+        );
+        assert_eq!(parse_command("set seed 7"), Ok(Some(Command::SetSeed(7))));
+        assert_eq!(
+            parse_command("normalize_range A1:B2"),
+            Ok(Some(Command::Normalize {
+                start: 0,
+                end: get_cell(1, 1)
+            }))
+        );
+        assert_eq!(
+            parse_command("dupregion A1:B2 C1"),
+            Ok(Some(Command::DuplicateRegion {
+                start: 0,
+                end: get_cell(1, 1),
+                target: 2
+            }))
+        );
+        assert_eq!(
+            parse_command("fill A2:A10"),
+            Ok(Some(Command::Fill {
+                start: get_cell(1, 0),
+                end: get_cell(9, 0)
+            }))
+        );
+        assert_eq!(
+            parse_command("load_csv data.csv --at C5"),
+            Ok(Some(Command::ImportCsv {
+                path: "data.csv".to_string(),
+                at: get_cell(4, 2)
+            }))
+        );
+        assert_eq!(
+            parse_command("load_csv data.csv C5"),
+            Err(ParseError::InvalidCommand)
+        );
+        assert_eq!(
+            parse_command("impact B1"),
+            Ok(Some(Command::Impact { cell: 1 }))
+        );
+        assert_eq!(parse_command("why C1"), Ok(Some(Command::Why { cell: 2 })));
+        assert_eq!(
+            parse_command("deps B1"),
+            Ok(Some(Command::Deps {
+                cell: 1,
+                tree: false
+            }))
+        );
+        assert_eq!(
+            parse_command("deps B1 --tree"),
+            Ok(Some(Command::Deps {
+                cell: 1,
+                tree: true
+            }))
+        );
+        assert_eq!(
+            parse_command("shiftrefs A1:A2 left 2"),
+            Ok(Some(Command::ShiftReferences {
+                start: 0,
+                end: get_cell(1, 0),
+                rows: 0,
+                cols: -2
+            }))
+        );
+        assert_eq!(
+            parse_command("delete_row 3"),
+            Ok(Some(Command::MoveLines {
+                axis: Axis::Row,
+                edit: LineEdit::Delete(2)
+            }))
+        );
+        assert_eq!(
+            parse_command("insert_col C"),
+            Ok(Some(Command::MoveLines {
+                axis: Axis::Column,
+                edit: LineEdit::Insert(2)
+            }))
+        );
+        for bad in [
+            "insert_row 0",
+            "insert_row 101",
+            "delete_col c",
+            "delete_col AAAAAAAAAAAAAAAA",
+        ] {
+            assert_eq!(parse_command(bad), Err(ParseError::InvalidValue), "{}", bad);
+        }
+        assert_eq!(
+            parse_command("shiftrefs A1:A2 sideways 2"),
+            Err(ParseError::InvalidCommand)
+        );
+    }
+
+    #[test]
+    fn test_literal_overflow_is_reported() {
+        init_test_dimensions();
+        let mut ctx = ParserContext::new();
+        let err = parse("A1=99999999999", &mut ctx).unwrap_err();
+        assert_eq!(err, ParseError::ValueOverflow);
+        assert_eq!(err.status_code(), StatusCode::Overflow);
+
+        assert_eq!(
+            parse("A1=-99999999999", &mut ctx).err(),
+            Some(ParseError::ValueOverflow)
+        );
+        assert_eq!(
+            parse("A1=B1+99999999999", &mut ctx).err(),
+            Some(ParseError::ValueOverflow)
+        );
+    }
+
+    #[test]
+    fn test_literal_parser_bounds() {
+        assert_eq!(literal_parser("2147483647"), Ok(i32::MAX));
+        assert_eq!(literal_parser("-2147483648"), Ok(i32::MIN));
+        assert_eq!(literal_parser("2147483648"), Err(ParseError::ValueOverflow));
+        assert_eq!(literal_parser("12a"), Err(ParseError::InvalidValue));
+        assert_eq!(
+            ParseError::InvalidValue.status_code(),
+            StatusCode::InvalidCmd
+        );
+    }
+
+    #[test]
+    fn test_scientific_and_underscored_literals() {
+        init_test_dimensions();
+        assert_eq!(number_parser("1_000_000"), Ok(1e6));
+        assert_eq!(number_parser("-2.5E3"), Ok(-2500.0));
+        assert_eq!(
+            number_parser("3_000_000_000"),
+            Err(ParseError::ValueOverflow)
+        );
+        assert_eq!(number_parser("1e400"), Err(ParseError::ValueOverflow));
+
+        let mut ctx = ParserContext::new();
+        let sheet = Sheet::new(TEST_ROWS, TEST_COLS);
+        for (input, formula) in [
+            ("A1=1e6", "1000000"),
+            ("A1=2.5e3+B1", "2500+B1"),
+            ("A1=1_000*B1", "1000*B1"),
+            ("A1=-1.5E-7", "-1.5e-7"),
+            ("A1=3e20", "3e20"),
+            ("A1=B1*0.125", "B1*0.125"),
+        ] {
+            let info = parse(input, &mut ctx).unwrap().info;
+            assert_eq!(sheet.formula_text(&info), formula, "{}", input);
+        }
+        // A large literal is a constant, not an `i32`
+        assert_eq!(
+            parse("A1=3e20", &mut ctx).unwrap().info.literal_value(),
+            Some(3e20)
+        );
+
+        let functions = HashMap::new();
+        let error = |expr: &str| parse_formula(expr, &functions).unwrap_err().to_string();
+        assert_eq!(error("1__0+2"), "invalid number '1__0' at column 1");
+        assert_eq!(error("2+1_"), "invalid number '1_' at column 3");
+        assert_eq!(error("1e999"), "number too large '1e999' at column 1");
+        assert_eq!(error("2E+A1"), "unexpected token 'E' at column 2");
+    }
+
+    #[test]
+    fn test_parse_text_cell() {
+        init_test_dimensions();
+        let mut ctx = ParserContext::new();
+        let cmd = parse("B2=\"Q1 sales\"", &mut ctx).unwrap();
+        assert_eq!(cmd.lhs_cell, get_cell(1, 1) as i32);
+        assert_eq!(cmd.info.text_value().as_deref(), Some("Q1 sales"));
+        assert_eq!(parse("A1=\"\"", &mut ctx).unwrap().info.function_id, 16);
+        for bad in ["A1=\"Q1", "A1=\"a\"b\"", "A1=Revenue"] {
+            assert!(parse(bad, &mut ctx).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_parse_nested_expressions() {
+        init_test_dimensions();
+        let mut ctx = ParserContext::new();
+        let info = parse("A1=(B1+C1)*2-SUM(D1:D5)/3", &mut ctx).unwrap().info;
+        assert_eq!(info.function_id, 17);
+        let sheet = Sheet::new(TEST_ROWS, TEST_COLS);
+        assert_eq!(sheet.formula_text(&info), "(B1+C1)*2-SUM(D1:D5)/3");
+        assert_eq!(info.references(), vec![(1, 1), (2, 2), (3, get_cell(4, 3))]);
+
+        // Precedence, associativity and signs, shown by where parentheses are kept
+        for (input, rendered) in [
+            ("1+2*3", "1+2*3"),
+            ("(1+2)*3", "(1+2)*3"),
+            ("A1-B1-C1", "A1-B1-C1"),
+            ("A1-(B1-C1)", "A1-(B1-C1)"),
+            ("((A1))*-B1", "A1*-B1"),
+            ("-(A1+1)/+2", "-(A1+1)/2"),
+            ("FACT(A1+1)", "FACT(A1+1)"),
+            ("MOD(A1,2)*-ABS(B1-3)", "MOD(A1,2)*-ABS(B1-3)"),
+            ("MIN2(A1+1,(2))", "MIN2(A1+1,2)"),
+            ("-2147483648*A1+1", "-2147483648*A1+1"),
+            ("ROW()-1", "ROW()-1"),
+            ("COLUMN($B1)", "COLUMN($B1)"),
+            // Any case and spacing, shown canonically
+            (" sum( a1 : b2 ) / 2", "SUM(A1:B2)/2"),
+            ("min2(a$1 , -$b2) * fact(c3)", "MIN2(A$1,-$B2)*FACT(C3)"),
+        ] {
+            let info = parse(&format!("A1={}", input), &mut ctx).unwrap().info;
+            assert_eq!(sheet.formula_text(&info), rendered, "{}", input);
+        }
+
+        // Formulas of the classic forms keep their encoding
+        let info = parse("A1=(B1)+-5", &mut ctx).unwrap().info;
+        assert_eq!(
+            (info.function_id, info.arg_mask, info.arg),
+            (2, 0b1, [1, -5])
+        );
+        assert_eq!(parse("A1=(7)", &mut ctx).unwrap().info.arg, [7, 0]);
+        assert_eq!(parse("A1=FIB(-B1)", &mut ctx).unwrap().info.function_id, 17);
+        let info = parse("A1=MAX2(B1,7)", &mut ctx).unwrap().info;
+        assert_eq!(
+            (info.function_id, info.arg_mask, info.arg),
+            (22, 0b1, [1, 7])
+        );
+        assert_eq!(parse("A1=ABS(-B1)", &mut ctx).unwrap().info.function_id, 17);
+
+        for (bad, err) in [
+            ("A1=(B1+1", ParseError::InvalidCommand),
+            ("A1=B1+*2", ParseError::InvalidCommand),
+            ("A1=2(B1)", ParseError::InvalidCommand),
+            ("A1=SUM(B1)+1", ParseError::InvalidCommand),
+            ("A1=MOD(B1)", ParseError::InvalidCommand),
+            ("A1=ABS(B1,2)", ParseError::InvalidCommand),
+            ("A1=B1,2", ParseError::InvalidCommand),
+            ("A1=SUM(B2:A1)*2", ParseError::InvalidRange),
+            ("A1=B1:B3+1", ParseError::InvalidCommand),
+            ("A1=ROW(1)", ParseError::InvalidCommand),
+            ("A1=COLUMN(B1+1)", ParseError::InvalidCommand),
+            ("A1=B0+1", ParseError::InvalidCell),
+            ("A1=B 1+1", ParseError::InvalidCommand),
+            ("A1=a 1+1", ParseError::InvalidCommand),
+            ("A1=1+2147483648", ParseError::ValueOverflow),
+        ] {
+            assert_eq!(parse(bad, &mut ctx).err(), Some(err), "{}", bad);
+        }
+        assert!(parse(&format!("A1={}1", "1+".repeat(MAX_NODES)), &mut ctx).is_err());
+
+        let cmd = parse("b2 = a1 + 3", &mut ctx).unwrap();
+        assert_eq!(cmd.lhs_cell, get_cell(1, 1) as i32);
+        assert_eq!(sheet.formula_text(&cmd.info), "A1+3");
+        assert_eq!(cell_parser("aa10"), cell_parser("AA10"));
+        for bad in ["12", "A0", "A"] {
+            assert_eq!(cell_parser(bad), Err(ParseError::InvalidCell), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_syntax_errors_point_at_the_token() {
+        init_test_dimensions();
+        let functions = HashMap::from([("DOUBLE".to_string(), 40)]);
+        let error = |expr: &str| parse_formula(expr, &functions).unwrap_err().to_string();
+        assert_eq!(error("2**3"), "unexpected token '*' at column 3");
+        assert_eq!(error("(A1+2"), "unexpected end of formula at column 6");
+        assert_eq!(error("A1+2)"), "unexpected token ')' at column 5");
+        assert_eq!(error("1+A0"), "invalid cell 'A0' at column 3");
+        assert_eq!(error("SUM(B2:A1)*2"), "invalid range 'B2:A1' at column 5");
+        assert_eq!(
+            error("1+99999999999"),
+            "number too large '99999999999' at column 3"
+        );
+        assert_eq!(error("1+é"), "unexpected token 'é' at column 3");
+        assert_eq!(
+            error("1+DOUBLE(A1)"),
+            "unexpected token 'DOUBLE' at column 3"
+        );
+
+        // Within a command, the error points past the `B1=`
+        let mut context = ParserContext::new();
+        assert_eq!(
+            parse("B1=A1+*2", &mut context).err(),
+            Some(ParseError::InvalidCommand)
+        );
+        let error = context.last_error.clone().unwrap();
+        assert_eq!(
+            (error.offset, error.column, error.token.as_str()),
+            (6, 7, "*")
+        );
+        assert!(parse("B1=A1+2", &mut context).is_ok());
+        assert_eq!(context.last_error, None);
+        assert_eq!(
+            context.parse_array("C1=A1:A2+)").err(),
+            Some(ParseError::InvalidCommand)
+        );
+        assert_eq!(context.last_error.unwrap().column, 10);
+    }
+
+    #[test]
+    fn test_parse_array_formulas() {
+        init_test_dimensions();
+        let mut ctx = ParserContext::new();
+        let sheet = Sheet::new(TEST_ROWS, TEST_COLS);
+        let mut spilled = |input: &str| {
+            let writes = ctx.parse_array(input).unwrap().unwrap();
+            writes
+                .iter()
+                .map(|(cell, info)| {
+                    format!("{}={}", sheet.cell_name(*cell), sheet.formula_text(info))
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            spilled("C1=A1:A3+B1:B3"),
+            ["C1=A1+B1", "C2=A2+B2", "C3=A3+B3"]
+        );
+        assert_eq!(
+            spilled("D5=(A1:B2-SUM(C1:C9))*$E$1:$F$2"),
+            [
+                "D5=(A1-SUM(C1:C9))*$E$1",
+                "E5=(B1-SUM(C1:C9))*$F$1",
+                "D6=(A2-SUM(C1:C9))*$E$2",
+                "E6=(B2-SUM(C1:C9))*$F$2"
+            ]
+        );
+        // Element formulas of the classic forms keep their encoding
+        let writes = ctx.parse_array("B1=A1:A2*2").unwrap().unwrap();
+        assert_eq!(writes[1].1.function_id, 4);
+
+        for input in [
+            "A1=SUM(B1:B3)+SUM(C1:C3)",
+            "A1=B1+1",
+            "A1=\"a:b\"",
+            "sum_selection",
+        ] {
+            assert!(matches!(ctx.parse_array(input), Ok(None)), "{}", input);
+        }
+        assert_eq!(
+            ctx.parse_array("C1=A1:A3+B1:B2").err(),
+            Some(ParseError::InvalidRange)
+        );
+        let last = format!("A{}", TEST_ROWS);
+        assert_eq!(
+            ctx.parse_array(&format!("B{}=A1:A2", TEST_ROWS)).err(),
+            Some(ParseError::InvalidCell)
+        );
+        assert_eq!(
+            ctx.parse_array(&format!("B1=A2:{}+1", last))
+                .unwrap()
+                .unwrap()
+                .len(),
+            TEST_ROWS - 1
+        );
+    }
+
+    #[test]
+    fn test_parse_absolute_references() {
+        init_test_dimensions();
+        let mut ctx = ParserContext::new();
+        let sheet = Sheet::new(TEST_ROWS, TEST_COLS);
+        for input in [
+            "$A$1+B$2",
+            "SUM($A1:B$3)",
+            "$B2*2-A$1",
+            "-$C$3",
+            "FACT($A1)",
+        ] {
+            let info = parse(&format!("D1={}", input), &mut ctx).unwrap().info;
+            assert_eq!(sheet.formula_text(&info), input);
+        }
+        let info = parse("D1=$A$1+B$2", &mut ctx).unwrap().info;
+        assert_eq!((info.function_id, info.arg_mask), (2, 0b11));
+        assert_eq!(
+            (info.fixed(0), info.fixed(1)),
+            (FIXED_COL | FIXED_ROW, FIXED_ROW)
+        );
+        assert_eq!(
+            reference_parser("$AB$12"),
+            Ok((get_cell(11, 27), FIXED_COL | FIXED_ROW))
+        );
+
+        for bad in [
+            "D1=$$A1",
+            "D1=A$$1",
+            "D1=$1",
+            "D1=A$",
+            "D1=$A+1",
+            "D1=$SUM(A1:A2)",
+        ] {
+            assert!(parse(bad, &mut ctx).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_registered_functions_only_in_classic_form() {
+        init_test_dimensions();
+        let mut ctx = ParserContext::new();
+        ctx.functions.insert("DOUBLE".to_string(), 40);
+        let info = parse("A1=DOUBLE(B1)", &mut ctx).unwrap().info;
+        assert_eq!((info.function_id, info.arg_mask), (40, 0b1));
+        assert_eq!(
+            parse("A1=DOUBLE(B1)+1", &mut ctx).err(),
+            Some(ParseError::InvalidCommand)
+        );
+    }
+
+    #[test]
+    fn test_repeat_without_edit_is_invalid() {
+        init_test_dimensions();
+        let mut ctx = ParserContext::new();
+        assert_eq!(parse(".", &mut ctx).err(), Some(ParseError::InvalidCommand));
+    }
+
+    #[test]
+    fn test_repeat_last_edit_at_current_cell() {
+        init_test_dimensions();
+        let mut ctx = ParserContext::new();
+        let first = parse("A1=5", &mut ctx).unwrap();
+        assert_eq!(first.lhs_cell, 0);
+        ctx.record_edit("A1=5");
+
+        // Move the current cell to B2 and repeat.
+        parse("scroll_to B2", &mut ctx).unwrap();
+        let repeated = parse(".", &mut ctx).unwrap();
+        assert_eq!(repeated.lhs_cell, get_cell(1, 1) as i32);
+        assert_eq!(repeated.info.function_id, 0);
+        assert_eq!(repeated.info.arg_mask, 0);
+        assert_eq!(repeated.info.arg[0], 5);
+    }
+
+    #[test]
+    fn test_repeat_keeps_references_verbatim() {
+        init_test_dimensions();
+        let mut ctx = ParserContext::new();
+        ctx.record_edit("A1=B1+1");
+        ctx.record_edit(".");
+        parse("scroll_to C5", &mut ctx).unwrap();
+        let repeated = parse(".", &mut ctx).unwrap();
+        assert_eq!(repeated.lhs_cell, get_cell(4, 2) as i32);
+        assert_eq!(repeated.info.function_id, 2);
+        assert_eq!(repeated.info.arg[0], get_cell(0, 1) as i32);
+        assert_eq!(repeated.info.arg[1], 1);
+    }
+
+    #[test]
+    fn test_parse_cache_reuses_identical_expressions() {
+        init_test_dimensions();
+        let mut ctx = ParserContext::new();
+        let first = parse("A1=SUM(B1:B9)", &mut ctx).unwrap();
+        let second = parse("C3=SUM(B1:B9)", &mut ctx).unwrap();
+        assert_eq!(ctx.parse_count, 1);
+        assert_eq!(second.lhs_cell, get_cell(2, 2) as i32);
+        assert_eq!(first.info.function_id, second.info.function_id);
+        assert_eq!(first.info.arg, second.info.arg);
+        assert_eq!(first.info.arg_mask, second.info.arg_mask);
+
+        parse("A2=B1+1", &mut ctx).unwrap();
+        assert_eq!(ctx.parse_count, 2);
+        // Failures are not cached, so they are re-parsed and fail again
+        assert!(parse("A1=B1+", &mut ctx).is_err());
+        assert!(parse("A2=B1+", &mut ctx).is_err());
+        assert_eq!(ctx.parse_count, 4);
+        // `.` replays the recorded expression through the same cache
+        ctx.record_edit("A2=B1+1");
+        parse(".", &mut ctx).unwrap();
+        assert_eq!(ctx.parse_count, 4);
+
+        ctx.clear_parse_cache();
+        parse("C3=SUM(B1:B9)", &mut ctx).unwrap();
+        assert_eq!(ctx.parse_count, 5);
+    }
+}
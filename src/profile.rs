@@ -0,0 +1,126 @@
+// profile.rs
+//! This module keeps recalculation statistics for every cell: how many times its formula
+//! ran and the time spent in it, so `profile top 10` can point at the hottest cells.
+use std::time::Duration;
+
+use crate::sheet::Sheet;
+
+/// What `Profile` knows about one cell.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CellStats {
+    /// Number of times the cell's formula ran.
+    pub runs: u64,
+    /// Time spent in those runs.
+    pub time: Duration,
+}
+
+/// Statistics for every cell, in a vector parallel to `Sheet::data`.
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    cells: Vec<CellStats>,
+}
+
+impl Profile {
+    /// Creates empty statistics for a sheet of `cells` cells.
+    pub fn new(cells: usize) -> Self {
+        Self {
+            cells: vec![CellStats::default(); cells],
+        }
+    }
+    /// Counts one recomputation of `cell` that took `elapsed`, if a formula ran at all.
+    pub fn record(&mut self, cell: usize, elapsed: Option<Duration>) {
+        if let (Some(elapsed), Some(stats)) = (elapsed, self.cells.get_mut(cell)) {
+            stats.runs += 1;
+            stats.time += elapsed;
+        }
+    }
+    /// Returns the statistics of `cell`.
+    pub fn get(&self, cell: usize) -> CellStats {
+        self.cells.get(cell).copied().unwrap_or_default()
+    }
+    /// Forgets every recorded run.
+    pub fn clear(&mut self) {
+        self.cells.fill(CellStats::default());
+    }
+    /// Returns up to `count` cells that ran, most time first, then most runs, then in
+    /// sheet order.
+    pub fn top(&self, count: usize) -> Vec<(usize, CellStats)> {
+        let mut cells: Vec<(usize, CellStats)> = self
+            .cells
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|(_, stats)| stats.runs > 0)
+            .collect();
+        cells.sort_by(|(a, x), (b, y)| (y.time, y.runs).cmp(&(x.time, x.runs)).then(a.cmp(b)));
+        cells.truncate(count);
+        cells
+    }
+    /// Builds the `profile top` report, one cell per line, e.g.
+    ///
+    /// ```text
+    /// cell      runs  total ms   mean us
+    /// B1          12     0.104      8.67
+    /// ```
+    pub fn report(&self, sheet: &Sheet, count: usize) -> String {
+        let top = self.top(count);
+        if top.is_empty() {
+            return "no cells recomputed yet".to_string();
+        }
+        let mut report = format!(
+            "{:<8}{:>6}{:>10}{:>10}",
+            "cell", "runs", "total ms", "mean us"
+        );
+        for (cell, stats) in top {
+            let total = stats.time.as_secs_f64();
+            report += &format!(
+                "\n{:<8}{:>6}{:>10.3}{:>10.2}",
+                sheet.cell_name(cell),
+                stats.runs,
+                total * 1e3,
+                total * 1e6 / stats.runs as f64
+            );
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sheet::{TEST_COLS, TEST_ROWS, get_cell, init_test_dimensions};
+    use crate::spreadsheet::Spreadsheet;
+
+    #[test]
+    fn test_profile_counts_runs_per_cell() {
+        init_test_dimensions();
+        let mut app = Spreadsheet::new(TEST_ROWS, TEST_COLS);
+        for command in ["A1=1", "B1=A1+1", "C1=SUM(A1:B1)", "A1=2", "A1=3", "D1=7"] {
+            app.execute(command).unwrap();
+        }
+        let profile = &app.graph.profile;
+        let runs = |cell| profile.get(cell).runs;
+        // Every cell ran when set, B1 and C1 again on each change of A1
+        assert_eq!(
+            [runs(0), runs(1), runs(2), runs(3), runs(4)],
+            [3, 3, 3, 1, 0]
+        );
+        let top: Vec<usize> = profile.top(10).iter().map(|&(cell, _)| cell).collect();
+        assert_eq!(top.len(), 4);
+        assert!(!top.contains(&4));
+        assert_eq!(profile.top(1).len(), 1);
+
+        let report = profile.report(&app.sheet.borrow(), 1);
+        assert_eq!(report.lines().count(), 2);
+        assert!(report.starts_with("cell"));
+
+        app.execute("profile reset").unwrap();
+        assert!(app.graph.profile.top(10).is_empty());
+        assert_eq!(
+            app.graph.profile.report(&app.sheet.borrow(), 10),
+            "no cells recomputed yet"
+        );
+        app.execute("A1=4").unwrap();
+        assert_eq!(app.graph.profile.get(get_cell(0, 2)).runs, 1);
+    }
+}
@@ -0,0 +1,484 @@
+// rpc.rs
+//! This module serves the spreadsheet engine over TCP with a line-delimited JSON protocol
+//! (`--serve <port>`), so a GUI or web frontend can drive the same `Sheet` and `Graph` as
+//! the terminal.
+//!
+//! Each request is one JSON object on its own line, naming its `method` and optionally an
+//! `id` that is echoed in the reply:
+//!
+//! ```text
+//! {"id": 1, "method": "set", "cell": "A1", "formula": "B1+1"}
+//! {"id": 2, "method": "get", "cell": "A1"}
+//! {"id": 3, "method": "range", "range": "A1:B2"}
+//! {"id": 4, "method": "undo"}                      (or "redo")
+//! {"id": 5, "method": "exec", "command": "sort A1:B9 A"}
+//! {"id": 6, "method": "subscribe"}                 (or "unsubscribe")
+//! ```
+//!
+//! Every request gets one reply line with its status, e.g.
+//! `{"id": 1, "status": "ok", "cell": {...}}` or
+//! `{"id": 1, "status": "cyclic dependency found", "message": "cycle: A1 -> B1 -> A1", ...}`,
+//! where cells are described as by `export_json`. After a request changes any cell, every
+//! subscribed client is also sent `{"event": "change", "cells": [...]}` with the cells
+//! whose contents or values changed.
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::iter::Peekable;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::str::Chars;
+use std::thread;
+use std::time::Duration;
+
+use crate::info::CellInfo;
+use crate::io::json_string;
+use crate::parser;
+use crate::spreadsheet::Spreadsheet;
+use crate::status::{CommandResult, StatusCode, status_message};
+
+/// How long `serve` sleeps when no client had anything to say.
+const IDLE_WAIT: Duration = Duration::from_millis(5);
+
+/// A connected client.
+struct Client {
+    stream: TcpStream,
+    /// Bytes received after the last complete line.
+    pending: Vec<u8>,
+    /// Whether the client is sent change events.
+    subscribed: bool,
+    /// Set once the client hung up or could not be written to.
+    closed: bool,
+}
+
+impl Client {
+    /// Reads whatever the client has sent without waiting, returning the complete lines.
+    fn read_lines(&mut self) -> Vec<String> {
+        let mut buf = [0; 4096];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    self.closed = true;
+                    break;
+                }
+                Ok(read) => self.pending.extend_from_slice(&buf[..read]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => {
+                    self.closed = true;
+                    break;
+                }
+            }
+        }
+        let mut lines = Vec::new();
+        while let Some(end) = self.pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line).trim().to_string();
+            if !line.is_empty() {
+                lines.push(line);
+            }
+        }
+        lines
+    }
+    /// Sends one line, waiting until it is written; a client that cannot take it is closed.
+    fn send(&mut self, line: &str) {
+        let sent = self
+            .stream
+            .set_nonblocking(false)
+            .and_then(|_| self.stream.write_all(format!("{}\n", line).as_bytes()))
+            .and_then(|_| self.stream.set_nonblocking(true));
+        if sent.is_err() {
+            self.closed = true;
+        }
+    }
+}
+
+/// Accepts clients on a TCP socket and answers their requests, one at a time, against a
+/// single `Spreadsheet`.
+pub struct Server {
+    listener: TcpListener,
+    clients: Vec<Client>,
+}
+
+impl Server {
+    /// Listens on `port` of the loopback interface; port 0 picks a free one.
+    pub fn bind(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            clients: Vec::new(),
+        })
+    }
+    /// Returns the address the server listens on.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+    /// Accepts waiting clients and answers every complete request received so far, without
+    /// blocking. Returns whether there was anything to do.
+    pub fn poll(&mut self, app: &mut Spreadsheet) -> io::Result<bool> {
+        let mut active = false;
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(true)?;
+                    self.clients.push(Client {
+                        stream,
+                        pending: Vec::new(),
+                        subscribed: false,
+                        closed: false,
+                    });
+                    active = true;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        for i in 0..self.clients.len() {
+            for line in self.clients[i].read_lines() {
+                active = true;
+                // Only worth comparing the cells if someone is listening for changes
+                let before = self
+                    .clients
+                    .iter()
+                    .any(|client| client.subscribed)
+                    .then(|| app.sheet.borrow().data.clone());
+                let reply = respond(app, &line, &mut self.clients[i].subscribed);
+                self.clients[i].send(&reply);
+                if let Some(before) = before {
+                    self.broadcast(app, &before);
+                }
+            }
+        }
+        self.clients.retain(|client| !client.closed);
+        Ok(active)
+    }
+    /// Sends subscribed clients the cells that differ from `before`, if any.
+    fn broadcast(&mut self, app: &Spreadsheet, before: &[CellInfo]) {
+        let sheet = app.sheet.borrow();
+        let cells: Vec<String> = (0..sheet.data.len())
+            .filter(|&cell| before.get(cell) != Some(&sheet.data[cell]))
+            .map(|cell| sheet.json_cell(cell))
+            .collect();
+        if cells.is_empty() {
+            return;
+        }
+        let event = format!(
+            "{{\"event\": \"change\", \"cells\": [{}]}}",
+            cells.join(", ")
+        );
+        for client in self.clients.iter_mut().filter(|client| client.subscribed) {
+            client.send(&event);
+        }
+    }
+}
+
+/// Serves `app` on `port` until the listening socket fails.
+pub fn serve(app: &mut Spreadsheet, port: u16) -> io::Result<()> {
+    let mut server = Server::bind(port)?;
+    loop {
+        if !server.poll(app)? {
+            thread::sleep(IDLE_WAIT);
+        }
+    }
+}
+
+/// A field of a request: a string, or any other JSON scalar as it was written.
+#[derive(Debug, PartialEq)]
+enum Field {
+    Text(String),
+    Literal(String),
+}
+
+/// Answers one request line, updating `subscribed` for `subscribe` and `unsubscribe`.
+///
+/// Edits are written as commands and run through `Spreadsheet::run_command`, so they are
+/// parsed, checked for cycles and journaled for undo exactly like typed ones.
+fn respond(app: &mut Spreadsheet, line: &str, subscribed: &mut bool) -> String {
+    let Some(request) = parse_request(line) else {
+        return reply(None, CommandResult::new(StatusCode::InvalidCmd), Vec::new());
+    };
+    let id = request.get("id").map(|id| match id {
+        Field::Text(text) => json_string(text),
+        Field::Literal(literal) => literal.clone(),
+    });
+    let text = |name: &str| match request.get(name) {
+        Some(Field::Text(text)) => Some(text.as_str()),
+        _ => None,
+    };
+    let cell_json = |app: &Spreadsheet, cell: usize| ("cell", app.sheet.borrow().json_cell(cell));
+
+    let (result, extra) = match text("method") {
+        Some("set") => match (text("cell").map(parser::cell_parser), text("formula")) {
+            (Some(Ok(cell)), Some(formula)) => {
+                let name = app.sheet.borrow().cell_name(cell);
+                let result = app.run_command(&format!("{}={}", name, formula));
+                (result, vec![cell_json(app, cell)])
+            }
+            (Some(Err(e)), _) => (CommandResult::new(e.status_code()), Vec::new()),
+            _ => (CommandResult::new(StatusCode::InvalidCmd), Vec::new()),
+        },
+        Some("get") => match text("cell").map(parser::cell_parser) {
+            Some(Ok(cell)) => (CommandResult::default(), vec![cell_json(app, cell)]),
+            Some(Err(e)) => (CommandResult::new(e.status_code()), Vec::new()),
+            None => (CommandResult::new(StatusCode::InvalidCmd), Vec::new()),
+        },
+        Some("range") => match text("range").map(parser::range_parser) {
+            Some(Ok((start, end))) => {
+                let sheet = app.sheet.borrow();
+                let (r1, c1) = sheet.get_row_and_column(start);
+                let (r2, c2) = sheet.get_row_and_column(end);
+                let cells: Vec<String> = (r1..=r2)
+                    .flat_map(|r| (c1..=c2).map(move |c| (r, c)))
+                    .map(|(r, c)| sheet.json_cell(sheet.get_cell(r, c)))
+                    .collect();
+                let cells = format!("[{}]", cells.join(", "));
+                (CommandResult::default(), vec![("cells", cells)])
+            }
+            Some(Err(e)) => (CommandResult::new(e.status_code()), Vec::new()),
+            None => (CommandResult::new(StatusCode::InvalidCmd), Vec::new()),
+        },
+        Some(method @ ("undo" | "redo")) => (app.run_command(method), Vec::new()),
+        // `q` would stop the whole server, not just this client
+        Some("exec") => match text("command").map(str::trim) {
+            Some(command) if command != "q" => {
+                let result = app.run_command(command);
+                let extra = result.cell.map(|cell| cell_json(app, cell));
+                (result, extra.into_iter().collect())
+            }
+            _ => (CommandResult::new(StatusCode::InvalidCmd), Vec::new()),
+        },
+        Some(method @ ("subscribe" | "unsubscribe")) => {
+            *subscribed = method == "subscribe";
+            (CommandResult::default(), Vec::new())
+        }
+        _ => (CommandResult::new(StatusCode::InvalidCmd), Vec::new()),
+    };
+    reply(id, result, extra)
+}
+
+/// Formats a reply: the request's `id` if it had one, the status message, the status
+/// detail if any, then `extra` fields holding JSON values.
+fn reply(id: Option<String>, result: CommandResult, extra: Vec<(&str, String)>) -> String {
+    let mut fields = Vec::new();
+    if let Some(id) = id {
+        fields.push(format!("\"id\": {}", id));
+    }
+    fields.push(format!(
+        "\"status\": {}",
+        json_string(status_message(result.status))
+    ));
+    if let Some(message) = result.message {
+        fields.push(format!("\"message\": {}", json_string(&message)));
+    }
+    for (name, value) in extra {
+        fields.push(format!("\"{}\": {}", name, value));
+    }
+    format!("{{{}}}", fields.join(", "))
+}
+
+/// Parses a request: a flat JSON object whose values are strings, numbers, booleans or
+/// `null`. Returns `None` for anything else.
+fn parse_request(line: &str) -> Option<HashMap<String, Field>> {
+    let mut chars = line.trim().chars().peekable();
+    let mut fields = HashMap::new();
+    if chars.next()? != '{' {
+        return None;
+    }
+    skip_whitespace(&mut chars);
+    if chars.next_if_eq(&'}').is_none() {
+        loop {
+            skip_whitespace(&mut chars);
+            if chars.next()? != '"' {
+                return None;
+            }
+            let name = string_body(&mut chars)?;
+            skip_whitespace(&mut chars);
+            if chars.next()? != ':' {
+                return None;
+            }
+            skip_whitespace(&mut chars);
+            let value = if chars.next_if_eq(&'"').is_some() {
+                Field::Text(string_body(&mut chars)?)
+            } else {
+                let mut literal = String::new();
+                while let Some(c) =
+                    chars.next_if(|&c| !matches!(c, ',' | '}') && !c.is_whitespace())
+                {
+                    literal.push(c);
+                }
+                let scalar = matches!(literal.as_str(), "true" | "false" | "null")
+                    || literal.parse::<f64>().is_ok();
+                if !scalar {
+                    return None;
+                }
+                Field::Literal(literal)
+            };
+            fields.insert(name, value);
+            skip_whitespace(&mut chars);
+            match chars.next()? {
+                ',' => continue,
+                '}' => break,
+                _ => return None,
+            }
+        }
+    }
+    chars.next().is_none().then_some(fields)
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+/// Reads the rest of a JSON string whose opening quote was consumed, undoing its escapes.
+fn string_body(chars: &mut Peekable<Chars>) -> Option<String> {
+    let mut text = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(text),
+            '\\' => text.push(match chars.next()? {
+                c @ ('"' | '\\' | '/') => c,
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                'b' => '\u{8}',
+                'f' => '\u{c}',
+                'u' => {
+                    let hex: String = (0..4).map(|_| chars.next()).collect::<Option<_>>()?;
+                    char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+                }
+                _ => return None,
+            }),
+            c => text.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sheet::{TEST_COLS, TEST_ROWS, init_test_dimensions};
+    use std::io::{BufRead, BufReader};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, mpsc};
+
+    #[test]
+    fn test_parse_request_fields() {
+        let request =
+            parse_request(r#" {"id": 7, "method":"set", "formula": "\"a\\bA\"", "x": null} "#)
+                .unwrap();
+        assert_eq!(request["id"], Field::Literal("7".to_string()));
+        assert_eq!(request["method"], Field::Text("set".to_string()));
+        assert_eq!(request["formula"], Field::Text("\"a\\bA\"".to_string()));
+        assert_eq!(request["x"], Field::Literal("null".to_string()));
+        assert_eq!(parse_request("{}").unwrap().len(), 0);
+        for bad in [
+            "",
+            "[1]",
+            r#"{"a": 1"#,
+            r#"{"a": [1]}"#,
+            r#"{"a": nope}"#,
+            r#"{"a" 1}"#,
+            r#"{"a": 1} x"#,
+        ] {
+            assert_eq!(parse_request(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_respond_edits_through_the_engine() {
+        init_test_dimensions();
+        let mut app = Spreadsheet::new(TEST_ROWS, TEST_COLS);
+        let mut subscribed = false;
+        let mut ask = |app: &mut Spreadsheet, line: &str| respond(app, line, &mut subscribed);
+
+        let set = ask(
+            &mut app,
+            r#"{"id": 1, "method": "set", "cell": "A1", "formula": "5"}"#,
+        );
+        assert!(set.starts_with(r#"{"id": 1, "status": "ok", "cell": {"name": "A1""#));
+        assert!(set.contains(r#""value": 5,"#));
+        ask(
+            &mut app,
+            r#"{"method": "set", "cell": "B1", "formula": "A1*2"}"#,
+        );
+        let cycle = ask(
+            &mut app,
+            r#"{"id": "c", "method": "set", "cell": "A1", "formula": "B1"}"#,
+        );
+        assert!(cycle.starts_with(
+            r#"{"id": "c", "status": "cyclic dependency found", "message": "cycle: A1 -> B1 -> A1""#
+        ));
+        assert!(cycle.contains(r#""value": 5,"#));
+
+        let range = ask(&mut app, r#"{"method": "range", "range": "A1:B1"}"#);
+        assert!(range.contains(r#""value": 5,"#) && range.contains(r#""value": 10,"#));
+        assert!(ask(&mut app, r#"{"method": "undo"}"#).starts_with(r#"{"status": "ok""#));
+        let get = ask(&mut app, r#"{"method": "get", "cell": "B1"}"#);
+        assert!(get.contains(r#""value": 0,"#));
+        ask(&mut app, r#"{"method": "redo"}"#);
+
+        assert_eq!(
+            ask(&mut app, r#"{"method": "exec", "command": "q"}"#),
+            r#"{"status": "invalid command"}"#
+        );
+        assert_eq!(
+            ask(&mut app, r#"{"method": "get", "cell": "ZZ1"}"#),
+            r#"{"status": "invalid command"}"#
+        );
+        assert_eq!(ask(&mut app, "A1=3"), r#"{"status": "invalid command"}"#);
+        ask(&mut app, r#"{"method": "subscribe"}"#);
+        assert!(subscribed);
+    }
+
+    #[test]
+    fn test_subscribers_hear_about_changes() {
+        init_test_dimensions();
+        let (addr_tx, addr_rx) = mpsc::channel();
+        let done = Arc::new(AtomicBool::new(false));
+        let stop = done.clone();
+        let server = thread::spawn(move || {
+            let mut app = Spreadsheet::new(TEST_ROWS, TEST_COLS);
+            let mut server = Server::bind(0).unwrap();
+            addr_tx.send(server.local_addr().unwrap()).unwrap();
+            while !stop.load(Ordering::SeqCst) {
+                if !server.poll(&mut app).unwrap() {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+        });
+        let addr = addr_rx.recv().unwrap();
+        let connect = || {
+            let stream = TcpStream::connect(addr).unwrap();
+            let lines = BufReader::new(stream.try_clone().unwrap()).lines();
+            (stream, lines.map(|line| line.unwrap()))
+        };
+        let (mut watcher, mut heard) = connect();
+        let (mut editor, mut replies) = connect();
+
+        writeln!(watcher, r#"{{"method": "subscribe"}}"#).unwrap();
+        assert_eq!(heard.next().unwrap(), r#"{"status": "ok"}"#);
+        let mut changed = |formula: &str| {
+            let request = r#"{"method": "set", "cell": "CELL", "formula": "F"}"#;
+            let (cell, formula) = formula.split_once('=').unwrap();
+            writeln!(
+                editor,
+                "{}",
+                request.replace("CELL", cell).replace('F', formula)
+            )
+            .unwrap();
+            assert!(replies.next().unwrap().starts_with(r#"{"status": "ok""#));
+            let event = heard.next().unwrap();
+            assert!(event.starts_with(r#"{"event": "change", "cells": ["#));
+            event
+                .match_indices(r#""name": ""#)
+                .map(|(at, _)| event[at + 9..].split('"').next().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(changed("A1=2"), ["A1"]);
+        assert_eq!(changed("B1=A1+1"), ["B1"]);
+        assert_eq!(changed("A1=4"), ["A1", "B1"]);
+
+        done.store(true, Ordering::SeqCst);
+        server.join().unwrap();
+    }
+}
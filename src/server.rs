@@ -0,0 +1,161 @@
+// server.rs
+//! This module serves the spreadsheet engine over a Unix domain socket, so it can run
+//! as a calculation daemon driven by other processes.
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+use crate::sheet::format_value;
+use crate::spreadsheet::Spreadsheet;
+use crate::status::{StatusCode, status_message};
+
+/// Binds a Unix socket at `path` and serves connections one after another, forever.
+///
+/// A stale socket file left behind by a previous run is removed before binding. Only
+/// failing to bind is an error; see `serve` for what happens to a failing connection.
+pub fn listen(app: &mut Spreadsheet, path: &Path) -> io::Result<()> {
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    serve(app, listener.incoming());
+    Ok(())
+}
+/// Serves `connections` one after another with `handle_connection`.
+///
+/// A failed accept or a connection that fails, such as a client that disconnects before
+/// reading its replies, is reported on stderr and the next connection is served, so one
+/// misbehaving client never stops the daemon.
+pub fn serve(app: &mut Spreadsheet, connections: impl Iterator<Item = io::Result<UnixStream>>) {
+    for stream in connections {
+        let result = stream.and_then(|stream| handle_connection(app, stream));
+        if let Err(e) = result {
+            eprintln!("Connection failed: {}", e);
+        }
+    }
+}
+/// Reads newline-delimited commands from `stream` and answers each with one line.
+///
+/// Every command goes through `Spreadsheet::execute`, exactly like in the interactive
+/// mode; a line that is not valid UTF-8 is answered as an invalid command. `q` closes the
+/// connection instead of stopping the daemon.
+pub fn handle_connection(app: &mut Spreadsheet, stream: UnixStream) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).split(b'\n') {
+        let line = line?;
+        let response = match std::str::from_utf8(&line).map(str::trim) {
+            Ok("q") => break,
+            Ok(command) => respond(app, command),
+            Err(_) => status_message(StatusCode::InvalidCmd).to_string(),
+        };
+        writeln!(writer, "{}", response)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+/// Executes one command and formats the reply line.
+///
+/// The reply is the status message, followed by `<cell>=<value>` (or an error marker
+/// such as `=#DIV/0`, or a text cell's label) when the command assigned a cell, e.g.
+/// `ok A1=5` or `cyclic dependency found`.
+fn respond(app: &mut Spreadsheet, command: &str) -> String {
+    match app.execute(command) {
+        Ok(Some(cell)) => {
+            let sheet = app.sheet.borrow();
+            let data = sheet.get(cell);
+            let value = if let Some(text) = data.text() {
+                text.to_string()
+            } else if let Some(kind) = data.info.error {
+                kind.marker().to_string()
+            } else {
+                format_value(data.value)
+            };
+            format!(
+                "{} {}={}",
+                status_message(StatusCode::Ok),
+                sheet.cell_name(cell),
+                value
+            )
+        }
+        Ok(None) => status_message(StatusCode::Ok).to_string(),
+        Err(code) => status_message(code).to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sheet::{TEST_COLS, TEST_ROWS, init_test_dimensions};
+    use std::thread;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_socket_set_and_read_back() {
+        init_test_dimensions();
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("sheet.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let server = thread::spawn(move || {
+            let mut app = Spreadsheet::new(TEST_ROWS, TEST_COLS);
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(&mut app, stream).unwrap();
+        });
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        writeln!(client, "A1=5\nB1=A1+2\nB1=B1+1\nA1=A1/0\nq").unwrap();
+        let replies: Vec<String> = BufReader::new(client.try_clone().unwrap())
+            .lines()
+            .map(|l| l.unwrap())
+            .collect();
+        server.join().unwrap();
+
+        assert_eq!(
+            replies,
+            vec![
+                "ok A1=5",
+                "ok B1=7",
+                "cyclic dependency found",
+                "cyclic dependency found",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bad_client_does_not_stop_the_server() {
+        init_test_dimensions();
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("sheet.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let server = thread::spawn(move || {
+            let mut app = Spreadsheet::new(TEST_ROWS, TEST_COLS);
+            serve(&mut app, listener.incoming().take(2));
+        });
+
+        // Invalid bytes are answered, then the client leaves without reading the rest
+        let mut bad = UnixStream::connect(&path).unwrap();
+        bad.write_all(b"\xff\xfe\nA1=5\n").unwrap();
+        let mut replies = BufReader::new(bad.try_clone().unwrap()).lines();
+        assert_eq!(replies.next().unwrap().unwrap(), "invalid command");
+        assert_eq!(replies.next().unwrap().unwrap(), "ok A1=5");
+        writeln!(bad, "B1=A1+1\nB2=B1+1").unwrap();
+        drop(replies);
+        drop(bad);
+
+        let mut good = UnixStream::connect(&path).unwrap();
+        writeln!(good, "C1=A1*2\nq").unwrap();
+        let replies: Vec<String> = BufReader::new(good).lines().map(|l| l.unwrap()).collect();
+        server.join().unwrap();
+        assert_eq!(replies, vec!["ok C1=10"]);
+    }
+
+    #[test]
+    fn test_respond_to_non_edit_commands() {
+        init_test_dimensions();
+        let mut app = Spreadsheet::new(TEST_ROWS, TEST_COLS);
+        assert_eq!(respond(&mut app, "disable_output"), "ok");
+        assert_eq!(respond(&mut app, "B1=1/0"), "ok B1=#DIV/0");
+        assert_eq!(respond(&mut app, "nonsense"), "invalid command");
+    }
+}
@@ -0,0 +1,598 @@
+// session.rs
+//! This module defines the compact binary encoding used by `save_session` and
+//! `load_session` to store a whole engine state, history included.
+//!
+//! All integers are little-endian. A file starts with `MAGIC`, and each cell is stored as
+//! `function_id`, `arg_mask`, the `fixed` flags of absolute references, a flag byte (bit 0
+//! `invalid`, bit 1 `literal_mode`, bits 2 and up the `CellError` code), both arguments
+//! and the value's `f64` bits: 20 bytes per cell. A text cell is followed by its string,
+//! as a length and UTF-8 bytes, since the ID in its first argument only has a meaning
+//! within one process. For the same reason a nested formula is followed by its
+//! expression tree, written parents first: a tag byte per node (0 number, 1 cell,
+//! 2 negation, 3 operator, 4 range function, 5 call, 6 three-argument call, 7 text), then
+//! its function ID, number bits, cell indices or string, a reference ending with its fixed
+//! flags.
+use std::io::{self, Read, Write};
+
+use crate::expr::{self, Expr, MAX_NODES};
+use crate::formulas::{
+    ABS, COUNTLABEL, DATE, DAYS, INDEX, MATCH, RAND, RANDBETWEEN, TODAY, VLOOKUP,
+    is_lookup_function, is_position_function, is_range_function, is_scalar_function,
+};
+use crate::graph::RecalcOrder;
+use crate::info::{CellError, CellInfo, Info};
+use crate::sheet::{
+    COLORS, CellFormat, CellRole, MAX_DECIMALS, NUMBER_FORMATS, NumberFormat, OverflowPolicy,
+};
+use crate::text;
+
+/// Identifies a session file and the version of its layout.
+pub const MAGIC: &[u8; 4] = b"SHSA";
+
+/// Writes session fields in the binary layout.
+pub struct Encoder<W: Write> {
+    /// Destination of the encoded bytes.
+    writer: W,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Creates an encoder writing to `writer`.
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+    /// Writes `MAGIC`, which starts every session file.
+    pub fn magic(&mut self) -> io::Result<()> {
+        self.writer.write_all(MAGIC)
+    }
+    /// Writes one byte.
+    pub fn u8(&mut self, value: u8) -> io::Result<()> {
+        self.writer.write_all(&[value])
+    }
+    /// Writes a count or cell index, which must fit in 32 bits.
+    pub fn usize(&mut self, value: usize) -> io::Result<()> {
+        let value = u32::try_from(value)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "value too large"))?;
+        self.writer.write_all(&value.to_le_bytes())
+    }
+    /// Writes a signed 32-bit integer.
+    pub fn i32(&mut self, value: i32) -> io::Result<()> {
+        self.writer.write_all(&value.to_le_bytes())
+    }
+    /// Writes an unsigned 64-bit integer.
+    pub fn u64(&mut self, value: u64) -> io::Result<()> {
+        self.writer.write_all(&value.to_le_bytes())
+    }
+    /// Writes a cell, leaving out the DFS-only `visit` counter.
+    pub fn cell(&mut self, cell: &CellInfo) -> io::Result<()> {
+        self.u8(cell.info.function_id)?;
+        self.u8(cell.info.arg_mask)?;
+        self.u8(cell.info.fixed)?;
+        let error = cell.info.error.map_or(0, |kind| 0b1 | kind.code() << 2);
+        self.u8(error | (cell.literal_mode as u8) << 1)?;
+        self.i32(cell.info.arg[0])?;
+        self.i32(cell.info.arg[1])?;
+        self.u64(cell.value.to_bits())?;
+        if let Some(expr) = cell.info.expression_value() {
+            return self.expr(&expr);
+        }
+        match cell.text() {
+            Some(text) => self.text(&text),
+            None => Ok(()),
+        }
+    }
+    /// Writes a string as its length and UTF-8 bytes.
+    pub fn text(&mut self, text: &str) -> io::Result<()> {
+        self.usize(text.len())?;
+        self.writer.write_all(text.as_bytes())
+    }
+    /// Writes an expression tree, parents first.
+    fn expr(&mut self, expr: &Expr) -> io::Result<()> {
+        match expr {
+            Expr::Number(value) => {
+                self.u8(0)?;
+                self.u64(value.to_bits())
+            }
+            Expr::Text(text) => {
+                self.u8(7)?;
+                self.text(text)
+            }
+            Expr::Cell(cell, fixed) => {
+                self.u8(1)?;
+                self.usize(*cell)?;
+                self.u8(*fixed)
+            }
+            Expr::Neg(operand) => {
+                self.u8(2)?;
+                self.expr(operand)
+            }
+            Expr::Binary(function_id, lhs, rhs) => {
+                self.u8(3)?;
+                self.u8(*function_id)?;
+                self.expr(lhs)?;
+                self.expr(rhs)
+            }
+            Expr::Range(function_id, start, end, fixed) => {
+                self.u8(4)?;
+                self.u8(*function_id)?;
+                self.usize(*start)?;
+                self.usize(*end)?;
+                self.u8(*fixed)
+            }
+            Expr::Call(function_id, arg) => {
+                self.u8(5)?;
+                self.u8(*function_id)?;
+                self.expr(arg)
+            }
+            Expr::Ternary(function_id, first, second, third) => {
+                self.u8(6)?;
+                self.u8(*function_id)?;
+                self.expr(first)?;
+                self.expr(second)?;
+                self.expr(third)
+            }
+        }
+    }
+    /// Writes the recalculation order as one byte.
+    pub fn recalc_order(&mut self, order: RecalcOrder) -> io::Result<()> {
+        self.u8(match order {
+            RecalcOrder::Dfs => 0,
+            RecalcOrder::RowMajor => 1,
+            RecalcOrder::ColMajor => 2,
+        })
+    }
+    /// Writes the overflow policy as one byte.
+    pub fn overflow_policy(&mut self, policy: OverflowPolicy) -> io::Result<()> {
+        self.u8(match policy {
+            OverflowPolicy::Error => 0,
+            OverflowPolicy::Saturate => 1,
+        })
+    }
+    /// Writes a cell role as one byte.
+    pub fn role(&mut self, role: CellRole) -> io::Result<()> {
+        self.u8(match role {
+            CellRole::Input => 0,
+            CellRole::Output => 1,
+        })
+    }
+    /// Writes a cell format as a flag byte (bit 0 bold, bit 1 italic, bit 2 underline), a
+    /// color byte, 0 for none or the position in `COLORS` plus one, and a number format
+    /// byte: the position in `NUMBER_FORMATS`, or for `fixed <n>` their count plus `n`.
+    pub fn format(&mut self, format: CellFormat) -> io::Result<()> {
+        self.u8(format.bold as u8 | (format.italic as u8) << 1 | (format.underline as u8) << 2)?;
+        let color = format
+            .color
+            .and_then(|color| COLORS.iter().position(|&(_, known)| known == color));
+        self.u8(color.map_or(0, |i| i as u8 + 1))?;
+        self.u8(match format.number {
+            NumberFormat::Fixed(decimals) => NUMBER_FORMATS.len() as u8 + decimals,
+            number => NUMBER_FORMATS
+                .iter()
+                .position(|&(_, known)| known == number)
+                .unwrap_or(0) as u8,
+        })
+    }
+    /// Flushes the underlying writer.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reads session fields written by `Encoder`.
+pub struct Decoder<R: Read> {
+    /// Source of the encoded bytes.
+    reader: R,
+}
+
+impl<R: Read> Decoder<R> {
+    /// Creates a decoder reading from `reader`.
+    pub fn new(reader: R) -> Self {
+        Self { reader }
+    }
+    /// Reads exactly `N` bytes.
+    fn bytes<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut buf = [0; N];
+        self.reader.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+    /// Checks that the input starts with `MAGIC`.
+    pub fn magic(&mut self) -> io::Result<()> {
+        if &self.bytes::<4>()? != MAGIC {
+            return Err(invalid_data("not a session file"));
+        }
+        Ok(())
+    }
+    /// Reads one byte.
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes::<1>()?[0])
+    }
+    /// Reads a count or cell index.
+    pub fn usize(&mut self) -> io::Result<usize> {
+        Ok(u32::from_le_bytes(self.bytes()?) as usize)
+    }
+    /// Reads a signed 32-bit integer.
+    pub fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_le_bytes(self.bytes()?))
+    }
+    /// Reads an unsigned 64-bit integer.
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes()?))
+    }
+    /// Reads a cell written by `Encoder::cell`.
+    pub fn cell(&mut self) -> io::Result<CellInfo> {
+        let function_id = self.u8()?;
+        let arg_mask = self.u8()?;
+        let fixed = self.u8()?;
+        let flags = self.u8()?;
+        let mut arg = [self.i32()?, self.i32()?];
+        let value = f64::from_bits(self.u64()?);
+        let error = match flags & 0b1 {
+            0 => None,
+            _ => Some(
+                CellError::from_code(flags >> 2).ok_or_else(|| invalid_data("unknown error"))?,
+            ),
+        };
+        if function_id == 16 {
+            arg[0] = text::intern(&self.text()?);
+        }
+        if function_id == 17 {
+            let mut budget = MAX_NODES;
+            arg[0] = expr::intern(self.expr(&mut budget)?);
+        }
+        Ok(CellInfo {
+            info: Info {
+                visit: 0,
+                arg_mask,
+                error,
+                function_id,
+                arg,
+                fixed,
+            },
+            value,
+            literal_mode: flags & 0b10 != 0,
+        })
+    }
+    /// Reads a string written by `Encoder::text`.
+    pub fn text(&mut self) -> io::Result<String> {
+        // Read through `take` so a corrupt length cannot allocate more than the file
+        let len = self.usize()?;
+        let mut bytes = Vec::new();
+        (&mut self.reader)
+            .take(len as u64)
+            .read_to_end(&mut bytes)?;
+        if bytes.len() != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        String::from_utf8(bytes).map_err(|_| invalid_data("bad text"))
+    }
+    /// Reads an expression tree written by `Encoder::expr`, of at most `budget` nodes.
+    ///
+    /// Only the operators and functions the parser produces are accepted, and a tree
+    /// larger than the parser allows is rejected before it can exhaust the stack.
+    fn expr(&mut self, budget: &mut usize) -> io::Result<Expr> {
+        *budget = budget
+            .checked_sub(1)
+            .ok_or_else(|| invalid_data("formula too large"))?;
+        let expr = match self.u8()? {
+            0 => Expr::Number(f64::from_bits(self.u64()?)),
+            1 => Expr::Cell(self.usize()?, self.u8()?),
+            2 => Expr::Neg(Box::new(self.expr(budget)?)),
+            3 => match self.u8()? {
+                function_id
+                    if (2..=5).contains(&function_id)
+                        || (is_scalar_function(function_id) && function_id != ABS)
+                        || function_id == RANDBETWEEN
+                        || function_id == DAYS
+                        || function_id == MATCH
+                        || function_id == COUNTLABEL =>
+                {
+                    let lhs = self.expr(budget)?;
+                    Expr::Binary(function_id, Box::new(lhs), Box::new(self.expr(budget)?))
+                }
+                _ => return Err(invalid_data("unknown operator")),
+            },
+            4 => match self.u8()? {
+                function_id
+                    if is_range_function(function_id)
+                        || is_lookup_function(function_id)
+                        || function_id == COUNTLABEL =>
+                {
+                    Expr::Range(function_id, self.usize()?, self.usize()?, self.u8()?)
+                }
+                _ => return Err(invalid_data("unknown range function")),
+            },
+            5 => match self.u8()? {
+                function_id
+                    if matches!(function_id, 1 | 11 | 12 | ABS | RAND | TODAY)
+                        || is_position_function(function_id) =>
+                {
+                    Expr::Call(function_id, Box::new(self.expr(budget)?))
+                }
+                _ => return Err(invalid_data("unknown function")),
+            },
+            6 => match self.u8()? {
+                DATE => {
+                    let (first, second) = (self.expr(budget)?, self.expr(budget)?);
+                    let third = self.expr(budget)?;
+                    Expr::Ternary(DATE, Box::new(first), Box::new(second), Box::new(third))
+                }
+                function_id @ (INDEX | VLOOKUP) => {
+                    let (first, second) = (self.expr(budget)?, self.expr(budget)?);
+                    let third = self.expr(budget)?;
+                    let (first, second, third) =
+                        (Box::new(first), Box::new(second), Box::new(third));
+                    Expr::Ternary(function_id, first, second, third)
+                }
+                _ => return Err(invalid_data("unknown function")),
+            },
+            7 => Expr::Text(self.text()?.into()),
+            _ => return Err(invalid_data("unknown formula node")),
+        };
+        Ok(expr)
+    }
+    /// Reads a recalculation order written by `Encoder::recalc_order`.
+    pub fn recalc_order(&mut self) -> io::Result<RecalcOrder> {
+        match self.u8()? {
+            0 => Ok(RecalcOrder::Dfs),
+            1 => Ok(RecalcOrder::RowMajor),
+            2 => Ok(RecalcOrder::ColMajor),
+            _ => Err(invalid_data("unknown recalculation order")),
+        }
+    }
+    /// Reads an overflow policy written by `Encoder::overflow_policy`.
+    pub fn overflow_policy(&mut self) -> io::Result<OverflowPolicy> {
+        match self.u8()? {
+            0 => Ok(OverflowPolicy::Error),
+            1 => Ok(OverflowPolicy::Saturate),
+            _ => Err(invalid_data("unknown overflow policy")),
+        }
+    }
+    /// Reads a cell role written by `Encoder::role`.
+    pub fn role(&mut self) -> io::Result<CellRole> {
+        match self.u8()? {
+            0 => Ok(CellRole::Input),
+            1 => Ok(CellRole::Output),
+            _ => Err(invalid_data("unknown cell role")),
+        }
+    }
+    /// Reads a cell format written by `Encoder::format`.
+    pub fn format(&mut self) -> io::Result<CellFormat> {
+        let flags = self.u8()?;
+        let color = match self.u8()? {
+            0 => None,
+            i => Some(
+                COLORS
+                    .get(i as usize - 1)
+                    .ok_or_else(|| invalid_data("unknown color"))?
+                    .1,
+            ),
+        };
+        if flags > 0b111 {
+            return Err(invalid_data("unknown format"));
+        }
+        let number = match self.u8()? as usize {
+            i if i < NUMBER_FORMATS.len() => NUMBER_FORMATS[i].1,
+            i if i - NUMBER_FORMATS.len() <= MAX_DECIMALS as usize => {
+                NumberFormat::Fixed((i - NUMBER_FORMATS.len()) as u8)
+            }
+            _ => return Err(invalid_data("unknown number format")),
+        };
+        Ok(CellFormat {
+            bold: flags & 0b1 != 0,
+            italic: flags & 0b10 != 0,
+            underline: flags & 0b100 != 0,
+            color,
+            number,
+        })
+    }
+}
+/// Builds the error returned for a malformed session file.
+pub fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::info::{FIXED_COL, FIXED_ROW};
+    use crate::sheet::{TEST_COLS, TEST_ROWS, get_cell, init_test_dimensions};
+    use crate::spreadsheet::Spreadsheet;
+    use crate::status::StatusCode;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_cell_encoding_round_trip() {
+        let cell = CellInfo {
+            info: Info {
+                visit: 3,
+                arg_mask: 0b10,
+                error: Some(CellError::DivByZero),
+                function_id: 5,
+                arg: [-7, 12],
+                fixed: FIXED_COL << 2,
+            },
+            value: -2.5,
+            literal_mode: true,
+        };
+        let mut bytes = Vec::new();
+        let mut out = Encoder::new(&mut bytes);
+        out.cell(&cell).unwrap();
+        out.finish().unwrap();
+        assert_eq!(bytes.len(), 20);
+
+        let decoded = Decoder::new(bytes.as_slice()).cell().unwrap();
+        let info = decoded.info;
+        assert_eq!(
+            (info.visit, info.arg_mask, info.invalid(), info.function_id),
+            (0, 0b10, true, 5)
+        );
+        assert_eq!(
+            (info.arg, info.fixed, decoded.value),
+            ([-7, 12], 0b0100, -2.5)
+        );
+        assert!(decoded.literal_mode);
+        assert!(Decoder::new(&bytes[..19]).cell().is_err());
+
+        // Text cells carry their string, not the process-local ID
+        let label = CellInfo {
+            info: Info::text("Total"),
+            ..Default::default()
+        };
+        let mut bytes = Vec::new();
+        Encoder::new(&mut bytes).cell(&label).unwrap();
+        assert_eq!(bytes.len(), 20 + 4 + 5);
+        let decoded = Decoder::new(bytes.as_slice()).cell().unwrap();
+        assert_eq!(decoded.text().as_deref(), Some("Total"));
+        assert!(Decoder::new(&bytes[..28]).cell().is_err());
+
+        // So do nested formulas, with their tree
+        let tree = Expr::Binary(
+            4,
+            Box::new(Expr::Neg(Box::new(Expr::Cell(3, FIXED_ROW)))),
+            Box::new(Expr::Range(8, 0, 5, FIXED_COL)),
+        );
+        let nested = CellInfo {
+            info: Info::expression(tree.clone()),
+            ..Default::default()
+        };
+        let mut bytes = Vec::new();
+        Encoder::new(&mut bytes).cell(&nested).unwrap();
+        assert_eq!(bytes.len(), 20 + 2 + 1 + 6 + 11);
+        let decoded = Decoder::new(bytes.as_slice()).cell().unwrap();
+        assert_eq!(decoded.info.expression_value().as_deref(), Some(&tree));
+        assert!(Decoder::new(&bytes[..39]).cell().is_err());
+        // An operator the parser never produces is rejected
+        bytes[21] = 9;
+        assert!(Decoder::new(bytes.as_slice()).cell().is_err());
+        bytes[21] = ABS;
+        assert!(Decoder::new(bytes.as_slice()).cell().is_err());
+        // Two-argument scalar functions are stored as operators
+        bytes[21] = 18;
+        assert!(Decoder::new(bytes.as_slice()).cell().is_ok());
+    }
+
+    /// Asserts that two engines hold the same cells, formulas and flags.
+    fn assert_same_cells(a: &Spreadsheet, b: &Spreadsheet) {
+        let (a, b) = (a.sheet.borrow(), b.sheet.borrow());
+        for cell in 0..TEST_ROWS * TEST_COLS {
+            let (x, y) = (a.get(cell), b.get(cell));
+            assert_eq!(
+                (x.value, x.literal_mode, x.info.invalid()),
+                (y.value, y.literal_mode, y.info.invalid()),
+                "cell {}",
+                cell
+            );
+            assert_eq!(
+                (
+                    x.info.function_id,
+                    x.info.arg_mask,
+                    x.info.arg,
+                    x.info.fixed
+                ),
+                (
+                    y.info.function_id,
+                    y.info.arg_mask,
+                    y.info.arg,
+                    y.info.fixed
+                ),
+                "cell {}",
+                cell
+            );
+        }
+    }
+
+    #[test]
+    fn test_session_resumes_with_history() {
+        init_test_dimensions();
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("work.session");
+
+        let mut app = Spreadsheet::new(TEST_ROWS, TEST_COLS);
+        for command in [
+            "A1=4",
+            "B1=A1*3",
+            "C1=SUM($A$1:B1)",
+            "E1=DATE(2024,A1,31)+DAYS(A1,1)",
+            "F1=VLOOKUP(5,A1:C1,2)+MATCH(15,A1:B1)",
+            "G1=\"North\"",
+            "G2=COUNTLABEL(F1:G1,\"No*\")",
+            "A1=5",
+            "B1=A1+1",
+            "undo",
+        ] {
+            app.execute(command).unwrap();
+        }
+        app.execute("scroll_to B2").unwrap();
+        app.execute("format A1:B1 color cyan").unwrap();
+        app.execute("format B1 underline").unwrap();
+        app.execute("format E1 date").unwrap();
+        app.execute("format C1 fixed 2").unwrap();
+        app.execute("set recalc_order colmajor").unwrap();
+        app.execute("set overflow_policy saturate").unwrap();
+        app.execute("note C1 \"total so far\"").unwrap();
+        app.execute("lock D1:D2").unwrap();
+        app.execute(&format!("save_session {}", path.display()))
+            .unwrap();
+
+        let mut resumed = Spreadsheet::new(TEST_ROWS, TEST_COLS);
+        resumed.execute("D4=9").unwrap();
+        resumed
+            .execute(&format!("load_session {}", path.display()))
+            .unwrap();
+        assert_eq!(resumed.graph.recalc_order, RecalcOrder::ColMajor);
+        assert_eq!(resumed.sheet.borrow().overflow, OverflowPolicy::Saturate);
+        assert_eq!((resumed.parser_ctx.px, resumed.parser_ctx.py), (1, 1));
+        assert_same_cells(&app, &resumed);
+        assert_eq!(resumed.sheet.borrow().formats, app.sheet.borrow().formats);
+        assert!(resumed.sheet.borrow().format(1).underline);
+        assert_eq!(resumed.sheet.borrow().cell_text(4, false), "2024-06-04");
+        assert_eq!(resumed.sheet.borrow().cell_text(2, false), "20.00");
+        assert_eq!(resumed.sheet.borrow().get(5).value, 17.0);
+        let g2 = get_cell(1, 6);
+        assert_eq!(resumed.sheet.borrow().get(g2).value, 1.0);
+        assert_eq!(
+            resumed.sheet.borrow().cell_text(g2, true),
+            "COUNTLABEL(F1:G1,\"No*\")"
+        );
+        assert_eq!(resumed.sheet.borrow().note(2), Some("total so far"));
+        assert_eq!(resumed.sheet.borrow().locked, app.sheet.borrow().locked);
+        // The undo restored B1 in literal mode
+        assert!(resumed.sheet.borrow().get(1).literal_mode);
+
+        // Dependencies were rebuilt and both history stacks carried over
+        for command in ["redo", "A1=10", "undo", "undo", "undo", "undo", "redo"] {
+            assert_eq!(
+                resumed.execute(command),
+                app.execute(command),
+                "{}",
+                command
+            );
+            assert_same_cells(&app, &resumed);
+        }
+        assert_eq!(resumed.sheet.borrow().get(get_cell(3, 3)).value, 0.0);
+        assert_eq!(resumed.execute("undo"), app.execute("undo"));
+
+        // Formulas parsed before a load are parsed again for the loaded sheet
+        resumed.execute("D4=9").unwrap();
+        let parsed = resumed.parser_ctx.parse_count;
+        resumed
+            .execute(&format!("load_session {}", path.display()))
+            .unwrap();
+        resumed.execute("D4=9").unwrap();
+        assert_eq!(resumed.parser_ctx.parse_count, parsed + 1);
+    }
+
+    #[test]
+    fn test_load_session_rejects_other_files() {
+        init_test_dimensions();
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        std::fs::write(&path, "A1=5\n").unwrap();
+        let mut app = Spreadsheet::new(TEST_ROWS, TEST_COLS);
+        app.execute("A1=3").unwrap();
+        assert_eq!(
+            app.execute(&format!("load_session {}", path.display())),
+            Err(StatusCode::InvalidValue)
+        );
+        assert_eq!(app.sheet.borrow().get(0).value, 3.0);
+    }
+}
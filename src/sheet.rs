@@ -2,12 +2,23 @@
 //! This module provides a spreadsheet-like structure for managing cell data.
 use std::cell::RefCell;
 use std::cmp::min;
+use std::collections::{HashMap, HashSet};
 use std::io;
 use std::rc::Rc;
 
+use crossterm::style::{Color, StyledContent, Stylize};
+
+use crate::chart;
 use crate::convert::num_to_alpha;
-use crate::info::CellInfo;
+use crate::date;
+use crate::formulas::{
+    ABS, DATE_FUNCTIONS, DAYS, POSITION_FUNCTIONS, RAND, RANDBETWEEN, RANDOM_FUNCTIONS,
+    RANGE_FUNCTIONS, SCALAR_FUNCTIONS, TODAY, is_position_function, is_range_function,
+    is_scalar_function, range_value,
+};
+use crate::info::{CellError, CellInfo, FIXED_COL, FIXED_ROW, Info};
 use crate::parser::ParserContext;
+use crate::random::Rng;
 use crate::status::StatusCode;
 
 // pub const N_MAX: usize = 999;
@@ -44,6 +55,37 @@ pub unsafe fn init_dimensions(m: usize, n: usize) {
     N_INTERNAL = n;
     INIT_DONE = true;
 }
+/// Changes the global dimensions after `init_dimensions`, for commands that reshape the
+/// sheet such as `rotate_sheet`.
+///
+/// # Safety
+/// Must not race with other readers of the dimensions.
+///
+/// # Panics
+/// Panics if the dimensions were never initialized.
+pub unsafe fn reset_dimensions(m: usize, n: usize) {
+    if !INIT_DONE {
+        panic!("Not initialized");
+    }
+    M_INTERNAL = m;
+    N_INTERNAL = n;
+}
+/// Row count used for the global dimensions in unit tests.
+#[cfg(test)]
+pub const TEST_ROWS: usize = 100;
+/// Column count used for the global dimensions in unit tests.
+#[cfg(test)]
+pub const TEST_COLS: usize = 100;
+
+/// Initializes the global dimensions to `TEST_ROWS` x `TEST_COLS` exactly once.
+///
+/// Tests run in parallel and share the globals, so every test that goes through the
+/// parser calls this instead of `init_dimensions`.
+#[cfg(test)]
+pub fn init_test_dimensions() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| unsafe { init_dimensions(TEST_COLS, TEST_ROWS) });
+}
 /// Returns the maximum column count.
 ///
 /// # Panics
@@ -78,6 +120,205 @@ pub fn N_MAX() -> usize {
         N_INTERNAL
     }
 }
+/// Rows or columns, as named by `insert_row`, `delete_col` and the like.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Axis {
+    Row,
+    Column,
+}
+/// An edit that moves the rows (or columns) of the sheet, which keeps its size.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineEdit {
+    /// Inserts a blank line before line `k`, pushing the last line off the end.
+    Insert(usize),
+    /// Removes line `k`, moving the later lines back and leaving a blank line at the end.
+    Delete(usize),
+}
+
+impl LineEdit {
+    /// Returns where the lines `a..=b` of an axis with `count` lines end up, or `None` if
+    /// every one of them is removed.
+    ///
+    /// A span that loses a line shrinks and one that gains a line inside it grows, so
+    /// `SUM(A1:A5)` becomes `SUM(A1:A4)` when row 3 is deleted.
+    pub fn move_span(self, count: usize, a: usize, b: usize) -> Option<(usize, usize)> {
+        match self {
+            LineEdit::Delete(k) => {
+                if a == k && b == k {
+                    return None;
+                }
+                Some((
+                    if a > k { a - 1 } else { a },
+                    if b >= k { b - 1 } else { b },
+                ))
+            }
+            LineEdit::Insert(k) => {
+                let (a, b) = LineEdit::Delete(count - 1).move_span(count, a, b)?;
+                Some((a + (a >= k) as usize, b + (b >= k) as usize))
+            }
+        }
+    }
+}
+/// Documents how a cell is meant to be used, set by `mark_inputs`/`mark_outputs`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CellRole {
+    /// A cell the user is expected to edit, shown as `>5`.
+    Input,
+    /// A cell the user is expected to read, shown as `=5`.
+    Output,
+}
+
+impl CellRole {
+    /// The marker prefixed to the cell's text in the display.
+    pub fn marker(self) -> char {
+        match self {
+            CellRole::Input => '>',
+            CellRole::Output => '=',
+        }
+    }
+}
+/// The marker appended to the displayed text of a cell that has a note.
+pub const NOTE_MARKER: char = '*';
+/// Names accepted by `format A1 color <name>`, with the terminal color each one stands for.
+pub const COLORS: [(&str, Color); 8] = [
+    ("red", Color::Red),
+    ("green", Color::Green),
+    ("blue", Color::Blue),
+    ("yellow", Color::Yellow),
+    ("cyan", Color::Cyan),
+    ("magenta", Color::Magenta),
+    ("white", Color::White),
+    ("black", Color::Black),
+];
+
+/// How a cell's text is styled in both the standard display and vim mode.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CellFormat {
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    /// One of `COLORS`, or `None` for the terminal's default.
+    pub color: Option<Color>,
+    /// How the cell's number is written.
+    pub number: NumberFormat,
+}
+
+/// How a number is written in the grid, set by `format A1 date` etc. Only the text
+/// changes; formulas still read the stored value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NumberFormat {
+    /// As `format_value` writes it, e.g. `3` or `2.5`.
+    #[default]
+    General,
+    /// As the date of a serial number (see the `date` module), e.g. `2024-01-31`.
+    Date,
+    /// Multiplied by 100 with a percent sign, e.g. `0.125` as `12.5%`.
+    Percent,
+    /// With a comma between groups of three digits, e.g. `1,234,567.5`.
+    Comma,
+    /// With exactly this many decimals, rounded, e.g. `3` as `3.00` with 2.
+    Fixed(u8),
+}
+
+/// Number formats that take no argument, by the name `format A1 <name>` gives them, in
+/// their session order.
+pub const NUMBER_FORMATS: [(&str, NumberFormat); 4] = [
+    ("general", NumberFormat::General),
+    ("date", NumberFormat::Date),
+    ("percent", NumberFormat::Percent),
+    ("comma", NumberFormat::Comma),
+];
+/// Most decimals `format A1 fixed <n>` may ask for.
+pub const MAX_DECIMALS: u8 = 10;
+
+impl NumberFormat {
+    /// Writes `value` in the format. A value the format cannot show, such as a date
+    /// outside the years 1–9999 or a number too large for its digits to be exact, is
+    /// written as by `format_value`.
+    pub fn render(self, value: f64) -> String {
+        match self {
+            NumberFormat::General => format_value(value),
+            NumberFormat::Date => date::format(value).unwrap_or_else(|| format_value(value)),
+            NumberFormat::Percent => format!("{}%", format_value(value * 100.0)),
+            NumberFormat::Comma => {
+                let text = format_value(value);
+                let (sign, digits) = match text.strip_prefix('-') {
+                    Some(digits) => ("-", digits),
+                    None => ("", text.as_str()),
+                };
+                let (whole, fraction) = digits.split_at(digits.find('.').unwrap_or(digits.len()));
+                if !whole.bytes().all(|b| b.is_ascii_digit()) {
+                    return text;
+                }
+                let mut grouped = String::new();
+                for (i, digit) in whole.chars().enumerate() {
+                    if i > 0 && (whole.len() - i) % 3 == 0 {
+                        grouped.push(',');
+                    }
+                    grouped.push(digit);
+                }
+                format!("{}{}{}", sign, grouped, fraction)
+            }
+            NumberFormat::Fixed(_) if value.abs() >= 1e15 => format_value(value),
+            NumberFormat::Fixed(decimals) => {
+                let text = format!("{:.*}", decimals as usize, value);
+                // Tiny negative values round to zero, which must not print as "-0.00"
+                match text.strip_prefix('-') {
+                    Some(digits) if digits.bytes().all(|b| b == b'0' || b == b'.') => {
+                        digits.to_string()
+                    }
+                    _ => text,
+                }
+            }
+        }
+    }
+}
+
+/// One change made by `format A1 bold`, `format A1 color red`, `:b` in vim, etc.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FormatChange {
+    Bold,
+    Italic,
+    Underline,
+    /// Switches bold back off, like `NoItalic` and `NoUnderline` for their styles.
+    NoBold,
+    NoItalic,
+    NoUnderline,
+    Color(Color),
+    /// Writes the cell's number in another format.
+    Number(NumberFormat),
+    /// Removes every style from the cell.
+    Reset,
+}
+
+impl CellFormat {
+    /// Styles `text`, already padded to its column, with the format.
+    pub fn style(&self, text: String) -> StyledContent<String> {
+        let mut styled = text.stylize();
+        if let Some(color) = self.color {
+            styled = styled.with(color);
+        }
+        if self.bold {
+            styled = styled.bold();
+        }
+        if self.italic {
+            styled = styled.italic();
+        }
+        if self.underline {
+            styled = styled.underlined();
+        }
+        styled
+    }
+}
+/// What a formula does with a result too large to represent.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum OverflowPolicy {
+    /// The cell becomes an error, reported as `Overflow` by the command that caused it.
+    #[default]
+    Error,
+    /// The result is clamped to the largest value of its sign.
+    Saturate,
+}
 /// Represents a spreadsheet sheet that holds cell data.
 pub struct Sheet {
     /// Vector holding all cell information.
@@ -90,6 +331,21 @@ pub struct Sheet {
     pub px: usize,
     /// Current column cursor position.
     pub py: usize,
+    /// Random number source for generated values, reseeded by `set seed <n>`.
+    pub rng: Rng,
+    /// Where `RAND()` and `RANDBETWEEN` draw from during the current recalculation; the
+    /// graph draws a new one from `rng` before each.
+    pub volatile_seed: u64,
+    /// Input/output roles of the marked cells; unmarked cells have no entry.
+    pub roles: HashMap<usize, CellRole>,
+    /// Styles of the formatted cells; unformatted cells have no entry.
+    pub formats: HashMap<usize, CellFormat>,
+    /// Notes attached with `note A1 "..."`; cells without a note have no entry.
+    pub notes: HashMap<usize, String>,
+    /// Cells protected by `lock`, whose formulas cannot be changed until `unlock`.
+    pub locked: HashSet<usize>,
+    /// How formulas handle results too large to represent (`set overflow_policy`).
+    pub overflow: OverflowPolicy,
 }
 
 impl Sheet {
@@ -113,6 +369,13 @@ impl Sheet {
             m,
             px: 0,
             py: 0,
+            rng: Rng::from_time(),
+            volatile_seed: 0,
+            roles: HashMap::new(),
+            formats: HashMap::new(),
+            notes: HashMap::new(),
+            locked: HashSet::new(),
+            overflow: OverflowPolicy::default(),
         }
     }
     /// Sets the cursor position within the sheet.
@@ -137,22 +400,17 @@ impl Sheet {
         self.py = y;
         Ok(())
     }
-    /// Scrolls the cursor by a relative amount.
-    ///
-    /// # Arguments
-    /// - `dx`: Rows to move.
-    /// - `dy`: Columns to move.
+    /// Scrolls the viewport by `dx` rows and `dy` columns, keeping a window of
+    /// `view = (rows, cols)` cells inside the sheet (see `scroll_position`).
     ///
     /// # Examples
     /// ```
-    /// let mut sheet = Sheet::new(10, 5);
-    /// assert!(sheet.scroll(1, 1).is_ok());
+    /// let mut sheet = Sheet::new(30, 5);
+    /// sheet.scroll(25, 1, (10, 10));
+    /// assert_eq!((sheet.px, sheet.py), (20, 0));
     /// ```
-    pub fn scroll(&mut self, dx: isize, dy: isize) -> Result<(), StatusCode> {
-        let new_x = self.px.saturating_add_signed(dx);
-        let new_y = self.py.saturating_add_signed(dy);
-
-        self.set_position(new_x, new_y)
+    pub fn scroll(&mut self, dx: isize, dy: isize, view: (usize, usize)) {
+        (self.px, self.py) = scroll_position((self.px, self.py), (dx, dy), view, (self.n, self.m));
     }
     /// Displays the sheet data in tabular format.
     ///
@@ -168,31 +426,127 @@ impl Sheet {
     pub fn display(&mut self, context: &mut ParserContext) -> io::Result<()> {
         self.px = context.px;
         self.py = context.py;
-        print!("{:3} ", ' '); // Space for row numbers column
-        for j in self.py..min(self.py + 10, self.m) {
-            let col_heading = num_to_alpha((j + 1) as u32);
-            print!("{:>11} ", col_heading); // Right-align headers
+        let (view_rows, view_cols, width) = context.view_size();
+        let gap = context.colgap;
+        let marker = context.overflow_marker;
+        let rows = self.px..min(self.px + view_rows, self.n);
+        let cols = self.py..min(self.py + view_cols, self.m);
+        let row_label = |i: usize| (i + 1).to_string();
+        let col_label = |j: usize| num_to_alpha((j + 1) as u32);
+
+        print!("{:w$} ", ' ', w = LABEL_WIDTH); // Space for row numbers column
+        if context.transpose {
+            for i in rows.clone() {
+                print!(
+                    "{}",
+                    pad_cell(&row_label(i), width, gap, Align::Right, marker)
+                );
+            }
+        } else {
+            for j in cols.clone() {
+                print!(
+                    "{}",
+                    pad_cell(&col_label(j), width, gap, Align::Right, marker)
+                );
+            }
         }
         println!();
 
-        // Print each row
-        for i in self.px..min(self.px + 10, self.n) {
-            print!("{:3} ", i + 1); // Row number right-aligned in 3 characters
-            for j in self.py..min(self.py + 10, self.m) {
-                let cell_index = self.get_cell(i, j);
-                let cell = &self.data[cell_index];
-
-                if cell.info.invalid {
-                    print!("{:>11} ", "ERR"); // Right-align "ERR"
+        // Print each row of the view
+        for (k, line) in self.view_grid(context).iter().enumerate() {
+            let label = if context.transpose {
+                col_label(self.py + k)
+            } else {
+                row_label(self.px + k)
+            };
+            print!("{:>w$} ", label, w = LABEL_WIDTH);
+            for (l, text) in line.iter().enumerate() {
+                let (r, c) = if context.transpose {
+                    (self.px + l, self.py + k)
                 } else {
-                    print!("{:>11} ", cell.value); // Right-align cell value
+                    (self.px + k, self.py + l)
+                };
+                // Left-align text, right-align the value, error marker or formula
+                let is_text = self.get(self.get_cell(r, c)).text().is_some();
+                let align = if is_text && !context.show_formulas {
+                    Align::Left
+                } else {
+                    Align::Right
+                };
+                let padded = pad_cell(text, width, gap, align, marker);
+                match self.formats.get(&self.get_cell(r, c)) {
+                    Some(format) => print!("{}", format.style(padded)),
+                    None => print!("{}", padded),
                 }
             }
             println!();
         }
 
+        if let Some((start, end)) = context.chart {
+            let width = context
+                .terminal
+                .map_or(chart::DEFAULT_WIDTH, |(cols, _)| cols);
+            println!();
+            for line in chart::bar_chart(self, start, end, width) {
+                println!("{}", line);
+            }
+        }
         Ok(())
     }
+    /// Returns the text of the visible window (10x10 unless `set_view_rows`/`set_view_cols`
+    /// changed it or the terminal is smaller, see `ParserContext::view_size`), one `Vec`
+    /// per displayed line.
+    ///
+    /// Cells marked as inputs or outputs are prefixed with their role's marker, and cells
+    /// with a note end with `NOTE_MARKER`.
+    ///
+    /// Normally line `k` is sheet row `px + k`. With `context.transpose` set, line `k` is
+    /// sheet column `py + k` instead, so the cell at `(r, c)` appears at `(c, r)`; only the
+    /// rendering changes, never the data.
+    pub fn view_grid(&self, context: &ParserContext) -> Vec<Vec<String>> {
+        let (view_rows, view_cols, _) = context.view_size();
+        let rows = context.px..min(context.px + view_rows, self.n);
+        let cols = context.py..min(context.py + view_cols, self.m);
+        let text = |r: usize, c: usize| {
+            let cell = self.get_cell(r, c);
+            let text = self.cell_text(cell, context.show_formulas);
+            let text = match self.roles.get(&cell) {
+                Some(role) => format!("{}{}", role.marker(), text),
+                None => text,
+            };
+            self.with_note_marker(cell, text)
+        };
+        if context.transpose {
+            cols.map(|c| rows.clone().map(|r| text(r, c)).collect())
+                .collect()
+        } else {
+            rows.map(|r| cols.clone().map(|c| text(r, c)).collect())
+                .collect()
+        }
+    }
+    /// Returns the text shown for a cell: its value in the cell's `NumberFormat` (or its
+    /// error's marker, see `CellError::marker`) or label, or with `show_formulas` its
+    /// formula.
+    ///
+    /// In the formula view, cells holding the default literal `0` are shown blank, since a
+    /// cell that was never assigned cannot be told apart from one assigned `0`.
+    pub fn cell_text(&self, cell: usize, show_formulas: bool) -> String {
+        let data = &self.data[cell];
+        if show_formulas {
+            let info = &data.info;
+            if info.function_id == 0 && info.arg_mask == 0 && info.arg[0] == 0 {
+                String::new()
+            } else {
+                self.formula_text(info)
+            }
+        } else if let Some(text) = data.text() {
+            text.to_string()
+        } else if let Some(kind) = data.info.error {
+            kind.marker().to_string()
+        } else {
+            self.format(cell).number.render(data.value)
+        }
+    }
     /// Determines if a cell is valid within the sheet.
     // Helper functions for cell access and validation
     pub fn is_valid_cell(&self, r: usize, c: usize) -> bool {
@@ -223,6 +577,333 @@ impl Sheet {
         let col = cell % self.m;
         (row, col)
     }
+    /// Returns the A1-style name of a cell index, e.g. `B3`.
+    pub fn cell_name(&self, cell: usize) -> String {
+        let (row, col) = self.get_row_and_column(cell);
+        format!("{}{}", num_to_alpha((col + 1) as u32), row + 1)
+    }
+    /// Returns the name of a reference with its absolute parts marked, e.g. `$B3` or
+    /// `$B$3`, from its `FIXED_COL`/`FIXED_ROW` flags.
+    pub fn reference_name(&self, cell: usize, fixed: u8) -> String {
+        let (row, col) = self.get_row_and_column(cell);
+        let sign = |flag: u8| if fixed & flag != 0 { "$" } else { "" };
+        format!(
+            "{}{}{}{}",
+            sign(FIXED_COL),
+            num_to_alpha((col + 1) as u32),
+            sign(FIXED_ROW),
+            row + 1
+        )
+    }
+    /// Renders a cell's formula back into input syntax, e.g. `A1+5` or `SUM(A1:B3)`.
+    pub fn formula_text(&self, info: &Info) -> String {
+        let arg = |i: usize| {
+            if info.arg_mask & (1 << i) != 0 {
+                self.reference_name(info.arg[i] as usize, info.fixed(i))
+            } else {
+                info.arg[i].to_string()
+            }
+        };
+        match info.function_id {
+            0 => arg(0),
+            1 => format!("SLEEP({})", arg(0)),
+            11 => format!("FACT({})", arg(0)),
+            15 => format_literal(info.literal_value().unwrap_or(f64::NAN)),
+            16 => format!("\"{}\"", info.text_value().unwrap_or_default()),
+            23 => info.error_value().map_or("", CellError::marker).to_string(),
+            17 => info.expression_value().map_or_else(String::new, |expr| {
+                expr.render(&|cell, fixed| self.reference_name(cell, fixed))
+            }),
+            12 => format!("FIB({})", arg(0)),
+            id if is_position_function(id) => {
+                let name = POSITION_FUNCTIONS
+                    .iter()
+                    .find(|&&(_, position)| position == id)
+                    .map_or("?", |&(name, _)| name);
+                let cell = if info.is_cell_arg1() {
+                    arg(0)
+                } else {
+                    String::new()
+                };
+                format!("{}({})", name, cell)
+            }
+            id if is_scalar_function(id) => {
+                let name = SCALAR_FUNCTIONS
+                    .iter()
+                    .find(|&&(_, scalar)| scalar == id)
+                    .map_or("?", |&(name, _)| name);
+                if id == ABS {
+                    format!("{}({})", name, arg(0))
+                } else {
+                    format!("{}({},{})", name, arg(0), arg(1))
+                }
+            }
+            id @ (RAND | RANDBETWEEN | TODAY | DAYS) => {
+                let name = RANDOM_FUNCTIONS
+                    .iter()
+                    .chain(DATE_FUNCTIONS.iter())
+                    .find(|&&(_, function)| function == id)
+                    .map_or("?", |&(name, _)| name);
+                match id {
+                    RAND | TODAY => format!("{}()", name),
+                    _ => format!("{}({},{})", name, arg(0), arg(1)),
+                }
+            }
+            2..=5 => {
+                let op = ["+", "-", "*", "/"][info.function_id as usize - 2];
+                format!("{}{}{}", arg(0), op, arg(1))
+            }
+            id if !is_range_function(id) => {
+                // Registered functions are named by the parser context, not the sheet
+                format!("FN{}({})", id, arg(0))
+            }
+            _ => {
+                let name = RANGE_FUNCTIONS
+                    .iter()
+                    .find(|&&(_, id)| id == info.function_id)
+                    .map_or("?", |&(name, _)| name);
+                format!(
+                    "{}({}:{})",
+                    name,
+                    self.reference_name(info.arg[0] as usize, info.fixed(0)),
+                    self.reference_name(info.arg[1] as usize, info.fixed(1))
+                )
+            }
+        }
+    }
+    /// Rotates the sheet a quarter turn, clockwise or counter-clockwise, swapping `n` and `m`.
+    ///
+    /// Rotation breaks the geometry that formulas refer to, so every cell becomes a literal
+    /// of its current value; invalid cells stay errors and text cells keep their text. Cell
+    /// roles, formats, notes and locks move with their cells and the view goes back to the
+    /// top-left corner. The global dimensions and the dependency graph are not touched here.
+    pub fn rotate(&mut self, clockwise: bool) {
+        let (n, m) = (self.n, self.m);
+        // Clockwise, (r, c) moves to (c, n - 1 - r) of the m x n result
+        let target = |cell: usize| {
+            let (r, c) = (cell / m, cell % m);
+            let (row, col) = if clockwise {
+                (c, n - 1 - r)
+            } else {
+                (m - 1 - c, r)
+            };
+            row * n + col
+        };
+
+        let mut data = vec![CellInfo::default(); n * m];
+        for (cell, old) in self.data.iter().enumerate() {
+            let info = match old.info.error {
+                _ if old.text().is_some() => old.info,
+                Some(kind) => Info::error(kind),
+                None => Info::number(old.value),
+            };
+            data[target(cell)] = CellInfo {
+                info,
+                value: old.value,
+                literal_mode: false,
+            };
+        }
+        self.data = data;
+        self.roles = self
+            .roles
+            .iter()
+            .map(|(&cell, &role)| (target(cell), role))
+            .collect();
+        self.formats = self
+            .formats
+            .iter()
+            .map(|(&cell, &format)| (target(cell), format))
+            .collect();
+        self.notes = self
+            .notes
+            .drain()
+            .map(|(cell, note)| (target(cell), note))
+            .collect();
+        self.locked = self.locked.drain().map(target).collect();
+        self.n = m;
+        self.m = n;
+        self.px = 0;
+        self.py = 0;
+    }
+    /// Applies `edit` to the rows or columns of the sheet, moving the cells, their roles,
+    /// formats, notes and locks, and the references of every formula.
+    ///
+    /// A formula that reads a removed cell becomes an error. Returns the cells whose
+    /// formulas must be recomputed: those that became errors, those whose ranges grew
+    /// or shrank, and those calling `ROW` or `COLUMN` that moved or read a moved cell.
+    /// The dependency graph is not touched here.
+    pub fn move_lines(&mut self, axis: Axis, edit: LineEdit) -> Vec<usize> {
+        let (n, m) = (self.n, self.m);
+        // Moves the span between two corners along the axis
+        let span = |start: usize, end: usize| {
+            let ((r1, c1), (r2, c2)) = ((start / m, start % m), (end / m, end % m));
+            match axis {
+                Axis::Row => edit
+                    .move_span(n, r1, r2)
+                    .map(|(r1, r2)| (r1 * m + c1, r2 * m + c2)),
+                Axis::Column => edit
+                    .move_span(m, c1, c2)
+                    .map(|(c1, c2)| (r1 * m + c1, r2 * m + c2)),
+            }
+        };
+        let target = |cell: usize| span(cell, cell).map(|(cell, _)| cell);
+
+        let mut data = vec![CellInfo::default(); n * m];
+        let mut changed = Vec::new();
+        for (cell, old) in self.data.iter().enumerate() {
+            let Some(target) = target(cell) else {
+                continue;
+            };
+            let mut resized = false;
+            let info = old.info.map_ranges(|start, end| {
+                let (new_start, new_end) = span(start, end)?;
+                resized |= new_end - new_start != end - start;
+                Some((new_start, new_end))
+            });
+            let mut moved = *old;
+            match info {
+                Some(info) => moved.info = info,
+                None => {
+                    moved.info = Info::error(CellError::BadRef);
+                    moved.literal_mode = false;
+                }
+            }
+            let repositioned = old.info.reads_position()
+                && (target != cell || info.is_some_and(|info| info.arg != old.info.arg));
+            if info.is_none() || ((resized || repositioned) && !old.literal_mode) {
+                changed.push(target);
+            }
+            data[target] = moved;
+        }
+        self.data = data;
+        self.roles = self
+            .roles
+            .iter()
+            .filter_map(|(&cell, &role)| Some((target(cell)?, role)))
+            .collect();
+        self.formats = self
+            .formats
+            .iter()
+            .filter_map(|(&cell, &format)| Some((target(cell)?, format)))
+            .collect();
+        self.notes = self
+            .notes
+            .drain()
+            .filter_map(|(cell, note)| Some((target(cell)?, note)))
+            .collect();
+        self.locked = self.locked.drain().filter_map(target).collect();
+        changed
+    }
+    /// Returns the note attached to a cell, if any.
+    pub fn note(&self, cell: usize) -> Option<&str> {
+        self.notes.get(&cell).map(String::as_str)
+    }
+    /// Attaches `note` to a cell, replacing any earlier one; an empty note removes it.
+    pub fn set_note(&mut self, cell: usize, note: &str) {
+        if note.is_empty() {
+            self.notes.remove(&cell);
+        } else {
+            self.notes.insert(cell, note.to_string());
+        }
+    }
+    /// Returns whether a cell is protected by `lock`.
+    pub fn is_locked(&self, cell: usize) -> bool {
+        self.locked.contains(&cell)
+    }
+    /// Appends `NOTE_MARKER` to the displayed text of a cell that has a note.
+    pub fn with_note_marker(&self, cell: usize, mut text: String) -> String {
+        if self.notes.contains_key(&cell) {
+            text.push(NOTE_MARKER);
+        }
+        text
+    }
+    /// Returns the format of a cell, the default one if it has none.
+    pub fn format(&self, cell: usize) -> CellFormat {
+        self.formats.get(&cell).copied().unwrap_or_default()
+    }
+    /// Applies `change` to the format of a cell. Bold, italic and underline are toggled
+    /// if `toggle` is set, and switched on otherwise; their `No` changes switch them off.
+    pub fn change_format(&mut self, cell: usize, change: FormatChange, toggle: bool) {
+        let mut format = self.format(cell);
+        let (flag, on) = match change {
+            FormatChange::Bold => (&mut format.bold, true),
+            FormatChange::Italic => (&mut format.italic, true),
+            FormatChange::Underline => (&mut format.underline, true),
+            FormatChange::NoBold => (&mut format.bold, false),
+            FormatChange::NoItalic => (&mut format.italic, false),
+            FormatChange::NoUnderline => (&mut format.underline, false),
+            FormatChange::Color(color) => {
+                format.color = Some(color);
+                return self.set_format(cell, format);
+            }
+            FormatChange::Number(number) => {
+                format.number = number;
+                return self.set_format(cell, format);
+            }
+            FormatChange::Reset => return self.set_format(cell, CellFormat::default()),
+        };
+        *flag = on && !(toggle && *flag);
+        self.set_format(cell, format);
+    }
+    /// Replaces the format of a cell, dropping its entry if the format is the default.
+    pub fn set_format(&mut self, cell: usize, format: CellFormat) {
+        if format == CellFormat::default() {
+            self.formats.remove(&cell);
+        } else {
+            self.formats.insert(cell, format);
+        }
+    }
+    /// Summarizes `start:end` for the status line, e.g.
+    /// `SUM=10 AVG=2.5 COUNT=4 MIN=1 MAX=4`.
+    ///
+    /// The aggregates are computed by the range functions of the same names, so, like
+    /// `SUM(A1:B2)`, they are an error such as `#VALUE` or `ERR` if a cell is text or an error.
+    /// `COUNT` is the number of cells that are not blank.
+    pub fn range_summary(&self, start: usize, end: usize) -> String {
+        let aggregate = |name: &str| {
+            let (_, function_id) = RANGE_FUNCTIONS
+                .into_iter()
+                .find(|&(function, _)| function == name)
+                .unwrap();
+            let value = match range_value(function_id, start, end, self) {
+                Ok(value) => format_value(value),
+                Err(error) => error.marker().to_string(),
+            };
+            format!("{}={}", name, value)
+        };
+        let (top, left) = self.get_row_and_column(start);
+        let (bottom, right) = self.get_row_and_column(end);
+        let count = (top..=bottom)
+            .flat_map(|row| (left..=right).map(move |col| (row, col)))
+            .filter(|&(row, col)| !self.data[self.get_cell(row, col)].is_blank())
+            .count();
+        format!(
+            "{} {} COUNT={} {} {}",
+            aggregate("SUM"),
+            aggregate("AVG"),
+            count,
+            aggregate("MIN"),
+            aggregate("MAX")
+        )
+    }
+    /// Returns the valid cell holding the largest value (or smallest, if `max` is false).
+    ///
+    /// Invalid cells are skipped and ties go to the first cell in row-major order.
+    /// Returns `None` if every cell is invalid.
+    pub fn extreme_cell(&self, max: bool) -> Option<usize> {
+        let valid = (0..self.n)
+            .flat_map(|r| (0..self.m).map(move |c| (r, c)))
+            .map(|(r, c)| self.get_cell(r, c))
+            .filter(|&cell| !self.data[cell].info.invalid());
+        if max {
+            // `max_by` keeps the last of equal maxima, so scan backwards
+            valid
+                .rev()
+                .max_by(|&a, &b| self.data[a].value.total_cmp(&self.data[b].value))
+        } else {
+            valid.min_by(|&a, &b| self.data[a].value.total_cmp(&self.data[b].value))
+        }
+    }
     /// Gets the cell information from the sheet.
     pub fn get(&self, cell: usize) -> CellInfo {
         self.data[cell].clone()
@@ -259,6 +940,113 @@ pub fn parse_dimensions(rows_str: &str, cols_str: &str) -> Result<(usize, usize)
     Ok((n, m))
 }
 
+/// How a cell's text is placed within its column.
+#[derive(Debug, Clone, Copy)]
+pub enum Align {
+    /// Flush against the left edge, for text cells.
+    Left,
+    /// Flush against the right edge, as in the standard display.
+    Right,
+    /// Centered, as in the vim editor.
+    Center,
+}
+/// Formats a cell value for display: whole numbers without a decimal point, anything else
+/// with up to 6 decimals and no trailing zeros, e.g. `3`, `2.5` or `0.333333`. Values of
+/// `1e15` and beyond are written in scientific notation with up to 7 significant digits,
+/// e.g. `1.234568e20`.
+pub fn format_value(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        return format!("{}", value as i64);
+    }
+    if value.is_finite() && value.abs() >= 1e15 {
+        let text = format!("{:.6e}", value);
+        let (mantissa, exponent) = text.split_once('e').unwrap();
+        let mantissa = mantissa.trim_end_matches('0').trim_end_matches('.');
+        return format!("{}e{}", mantissa, exponent);
+    }
+    let text = format!("{:.6}", value);
+    let text = text.trim_end_matches('0').trim_end_matches('.');
+    // Tiny values round to zero, which must not print as "-0"
+    if text == "-0" {
+        "0".to_string()
+    } else {
+        text.to_string()
+    }
+}
+/// Formats a number written in a formula so that it reads back as exactly the same value:
+/// whole numbers without a decimal point, very large or small ones in scientific notation
+/// and anything else with as many decimals as it takes, e.g. `3`, `0.1234567` or `2.5e-7`.
+pub fn format_literal(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{}", value as i64)
+    } else if value.abs() >= 1e15 || value.abs() < 1e-4 {
+        format!("{:e}", value)
+    } else {
+        format!("{}", value)
+    }
+}
+/// Default character filling a cell whose text is wider than its column, like Excel's `####`.
+pub const DEFAULT_OVERFLOW_MARKER: char = '#';
+/// Moves the top-left corner `start` of a viewport by `delta = (rows, cols)`, clamped so
+/// that a `view`-sized window stays inside a sheet of `size = (rows, cols)`.
+///
+/// Shared by the terminal sheet's scrolling commands and vim's page keys, so both stop at
+/// the same place: the last page still fills the window, unless the sheet is smaller.
+pub fn scroll_position(
+    start: (usize, usize),
+    delta: (isize, isize),
+    view: (usize, usize),
+    size: (usize, usize),
+) -> (usize, usize) {
+    let clamp = |start: usize, delta: isize, view: usize, size: usize| {
+        start
+            .saturating_add_signed(delta)
+            .min(size.saturating_sub(view))
+    };
+    (
+        clamp(start.0, delta.0, view.0, size.0),
+        clamp(start.1, delta.1, view.1, size.1),
+    )
+}
+/// Default number of rows and columns in the standard display (`set_view_rows`,
+/// `set_view_cols`).
+pub const DEFAULT_VIEW_SIZE: usize = 10;
+/// Default column width of the standard display (`set_col_width`).
+pub const DEFAULT_COL_WIDTH: usize = 11;
+/// Narrowest column used while formulas are shown, since formulas such as
+/// `STDEV(AB10:AC999)` need wider columns than values.
+pub const FORMULA_COL_WIDTH: usize = 20;
+/// Narrowest column the standard display squeezes columns to before it shows fewer of
+/// them on a small terminal.
+pub const MIN_COL_WIDTH: usize = 6;
+/// Width of the widest row or column label, which both renderers keep every column and
+/// the row label column at: rows are numbered up to `999` and columns up to `ZZZ`.
+pub const LABEL_WIDTH: usize = 3;
+/// Terminal lines the standard display leaves around the sheet rows: the column
+/// headers, the prompt and a line of command output.
+pub const DISPLAY_MARGIN_ROWS: usize = 3;
+/// Pads `text` to `width` columns and appends `gap` spaces before the next column.
+///
+/// Text wider than the column is replaced by `width` copies of `overflow`, so a long value
+/// never pushes its neighbours out of line. Left-aligned text is a label and is cut to the
+/// column instead. This is the shared cell formatting of both renderers; `gap` is the
+/// `set colgap` setting and `overflow` the `set overflow` marker.
+pub fn pad_cell(text: &str, width: usize, gap: usize, align: Align, overflow: char) -> String {
+    let padded = if text.chars().count() > width {
+        match align {
+            Align::Left => text.chars().take(width).collect(),
+            _ => overflow.to_string().repeat(width),
+        }
+    } else {
+        match align {
+            Align::Left => format!("{:<width$}", text),
+            Align::Right => format!("{:>width$}", text),
+            Align::Center => format!("{:^width$}", text),
+        }
+    };
+    padded + &" ".repeat(gap)
+}
+
 /// returns cell index
 pub fn get_cell(row: usize, col: usize) -> usize {
     row * M_MAX() + col
@@ -342,16 +1130,19 @@ mod tests {
     fn test_scroll() {
         let mut sheet = Sheet::new(10, 10);
         // Scroll by a positive delta.
-        let res = sheet.scroll(2, 3);
-        assert!(res.is_ok());
+        sheet.scroll(2, 3, (5, 5));
         assert_eq!(sheet.px, 2);
         assert_eq!(sheet.py, 3);
         // Scroll by a negative delta (using saturating_add_signed).
-        let res2 = sheet.scroll(-1, -1);
-        assert!(res2.is_ok());
+        sheet.scroll(-1, -1, (5, 5));
         // When scrolling down from (2,3) by (-1,-1), expect (1,2).
         assert_eq!(sheet.px, 1);
         assert_eq!(sheet.py, 2);
+        // The window stops at the edges of the sheet
+        sheet.scroll(100, -100, (5, 5));
+        assert_eq!((sheet.px, sheet.py), (5, 0));
+        sheet.scroll(1, 1, (20, 20));
+        assert_eq!((sheet.px, sheet.py), (0, 0));
     }
 
     #[test]
@@ -361,7 +1152,7 @@ mod tests {
         for i in 0..15 {
             for j in 0..15 {
                 let idx = sheet.get_cell(i, j);
-                sheet.data[idx].value = (i * 15 + j) as i32;
+                sheet.data[idx].value = (i * 15 + j) as f64;
             }
         }
         // Create a dummy parser context with offsets.
@@ -374,6 +1165,258 @@ mod tests {
         assert_eq!(sheet.py, 3);
     }
 
+    #[test]
+    fn test_rotate_sheet() {
+        // 1 2 3
+        // 4 5 6
+        let mut sheet = Sheet::new(2, 3);
+        for (cell, data) in sheet.data.iter_mut().enumerate() {
+            data.value = cell as f64 + 1.0;
+        }
+        sheet.data[1].info = Info {
+            function_id: 2,
+            arg_mask: 0b1,
+            ..Default::default()
+        };
+        sheet.data[5].info.error = Some(CellError::Value);
+        sheet.roles.insert(2, CellRole::Input);
+
+        sheet.rotate(true);
+        assert_eq!((sheet.n, sheet.m), (3, 2));
+        let values: Vec<f64> = sheet.data.iter().map(|d| d.value).collect();
+        assert_eq!(values, vec![4.0, 1.0, 5.0, 2.0, 6.0, 3.0]);
+        // The formula became a literal, the error stayed an error
+        let b = sheet.get(sheet.get_cell(1, 1)).info;
+        assert_eq!((b.function_id, b.arg_mask, b.arg[0]), (0, 0, 2));
+        assert!(sheet.get(sheet.get_cell(2, 0)).info.invalid());
+        assert_eq!(
+            sheet.roles.get(&sheet.get_cell(2, 1)),
+            Some(&CellRole::Input)
+        );
+
+        sheet.rotate(false);
+        assert_eq!((sheet.n, sheet.m), (2, 3));
+        let values: Vec<f64> = sheet.data.iter().map(|d| d.value).collect();
+        assert_eq!(values, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        assert_eq!(sheet.roles.get(&2), Some(&CellRole::Input));
+    }
+
+    #[test]
+    fn test_formats_follow_their_cells() {
+        let mut sheet = Sheet::new(2, 3);
+        sheet.change_format(1, FormatChange::Bold, false);
+        sheet.change_format(1, FormatChange::Bold, false);
+        assert!(sheet.format(1).bold);
+        sheet.change_format(1, FormatChange::Bold, true);
+        assert_eq!(sheet.formats.get(&1), None);
+        sheet.change_format(1, FormatChange::NoBold, false);
+        assert_eq!(sheet.formats.get(&1), None);
+
+        sheet.change_format(2, FormatChange::Color(Color::Red), false);
+        sheet.change_format(2, FormatChange::Italic, true);
+        sheet.rotate(true);
+        let format = sheet.format(sheet.get_cell(2, 1));
+        assert_eq!((format.color, format.italic), (Some(Color::Red), true));
+        sheet.move_lines(Axis::Row, LineEdit::Delete(2));
+        assert!(sheet.formats.is_empty());
+
+        sheet.change_format(0, FormatChange::Underline, false);
+        sheet.change_format(0, FormatChange::Reset, false);
+        assert_eq!(sheet.format(0), CellFormat::default());
+    }
+
+    #[test]
+    fn test_notes_are_marked_and_follow_their_cells() {
+        let mut sheet = Sheet::new(2, 3);
+        sheet.data[1].value = 7.0;
+        sheet.set_note(1, "check this figure");
+        sheet.set_note(2, "");
+        assert_eq!(sheet.note(1), Some("check this figure"));
+        assert_eq!(sheet.note(2), None);
+        let grid = sheet.view_grid(&ParserContext::new());
+        assert_eq!((grid[0][1].as_str(), grid[0][2].as_str()), ("7*", "0"));
+
+        sheet.rotate(true);
+        assert_eq!(sheet.note(sheet.get_cell(1, 1)), Some("check this figure"));
+        sheet.move_lines(Axis::Row, LineEdit::Delete(1));
+        assert!(sheet.notes.is_empty());
+
+        sheet.set_note(0, "a");
+        sheet.set_note(0, "");
+        assert_eq!(sheet.note(0), None);
+    }
+
+    #[test]
+    fn test_extreme_cell() {
+        let mut sheet = Sheet::new(3, 3);
+        let cell = sheet.get_cell(1, 2);
+        sheet.data[cell].value = 9.0;
+        let other = sheet.get_cell(2, 0);
+        sheet.data[other].value = 9.0;
+        let low = sheet.get_cell(0, 1);
+        sheet.data[low].value = -4.0;
+        assert_eq!(sheet.extreme_cell(true), Some(cell));
+        assert_eq!(sheet.extreme_cell(false), Some(low));
+
+        // Invalid cells are ignored, however large their stale value
+        let stale = sheet.get_cell(2, 2);
+        sheet.data[stale].value = 100.0;
+        sheet.data[stale].info.error = Some(CellError::Value);
+        assert_eq!(sheet.extreme_cell(true), Some(cell));
+
+        for cell in sheet.data.iter_mut() {
+            cell.info.error = Some(CellError::Value);
+        }
+        assert_eq!(sheet.extreme_cell(false), None);
+    }
+
+    #[test]
+    fn test_format_value() {
+        assert_eq!(format_value(3.0), "3");
+        assert_eq!(format_value(-2147483648.0), "-2147483648");
+        assert_eq!(format_value(2.5), "2.5");
+        assert_eq!(format_value(1.0 / 3.0), "0.333333");
+        assert_eq!(format_value(-1e-9), "0");
+        assert_eq!(format_value(-0.0), "0");
+        assert_eq!(format_value(1e20), "1e20");
+        assert_eq!(format_value(-123456789e12), "-1.234568e20");
+    }
+
+    #[test]
+    fn test_format_literal_reads_back_exactly() {
+        for (value, text) in [
+            (3.0, "3"),
+            (0.1234567, "0.1234567"),
+            (2.5e-7, "2.5e-7"),
+            (-1e20, "-1e20"),
+        ] {
+            assert_eq!(format_literal(value), text);
+            assert_eq!(text.parse::<f64>(), Ok(value));
+        }
+    }
+
+    #[test]
+    fn test_number_formats_only_change_the_text() {
+        let percent = NumberFormat::Percent;
+        assert_eq!(percent.render(0.125), "12.5%");
+        assert_eq!(percent.render(-2.0), "-200%");
+        let comma = NumberFormat::Comma;
+        assert_eq!(comma.render(1234567.5), "1,234,567.5");
+        assert_eq!(comma.render(-1000.0), "-1,000");
+        assert_eq!(comma.render(999.0), "999");
+        assert_eq!(NumberFormat::Fixed(2).render(3.0), "3.00");
+        assert_eq!(NumberFormat::Fixed(2).render(-0.001), "0.00");
+        assert_eq!(NumberFormat::Fixed(0).render(2.5), "2");
+        assert_eq!(
+            NumberFormat::Fixed(3).render(f64::MAX),
+            format_value(f64::MAX)
+        );
+
+        let mut sheet = Sheet::new(1, 2);
+        sheet.data[0].value = 1234.5;
+        sheet.change_format(0, FormatChange::Number(NumberFormat::Comma), false);
+        assert_eq!(sheet.cell_text(0, false), "1,234.5");
+        assert_eq!(sheet.data[0].value, 1234.5);
+        sheet.change_format(0, FormatChange::Reset, false);
+        assert_eq!(sheet.cell_text(0, false), "1234.5");
+    }
+
+    #[test]
+    fn test_pad_cell_column_gap() {
+        assert_eq!(pad_cell("42", 5, 1, Align::Right, '#'), "   42 ");
+        assert_eq!(pad_cell("42", 5, 0, Align::Right, '#'), "   42");
+        assert_eq!(pad_cell("42", 6, 3, Align::Center, '#'), "  42     ");
+        // Text wider than the column becomes the marker, the gap still follows
+        assert_eq!(pad_cell("123456", 4, 2, Align::Right, '#'), "####  ");
+    }
+
+    #[test]
+    fn test_pad_cell_overflow_keeps_alignment() {
+        let row: String = ["7", "-2147483648", "12"]
+            .iter()
+            .map(|text| pad_cell(text, 6, 1, Align::Right, '*'))
+            .collect();
+        assert_eq!(row, "     7 ******     12 ");
+        assert_eq!(pad_cell("123456", 6, 0, Align::Center, '#'), "123456");
+        assert_eq!(pad_cell("1234567", 6, 0, Align::Center, '#'), "######");
+    }
+
+    #[test]
+    fn test_text_cells_are_left_aligned_labels() {
+        assert_eq!(pad_cell("Sales", 6, 1, Align::Left, '#'), "Sales  ");
+        assert_eq!(pad_cell("Revenue", 6, 0, Align::Left, '#'), "Revenu");
+
+        let mut sheet = Sheet::new(1, 2);
+        sheet.data[0].info = Info::text("Revenue");
+        sheet.data[0].info.error = Some(CellError::Value);
+        sheet.data[1].info = Info::error(CellError::Value);
+        assert_eq!(sheet.cell_text(0, false), "Revenue");
+        assert_eq!(sheet.cell_text(0, true), "\"Revenue\"");
+        assert_eq!(sheet.cell_text(1, false), "#VALUE");
+    }
+
+    #[test]
+    fn test_transposed_view_grid() {
+        let mut sheet = Sheet::new(3, 12);
+        for (idx, cell) in sheet.data.iter_mut().enumerate() {
+            cell.value = idx as f64;
+        }
+        let mut context = ParserContext::new();
+        let normal = sheet.view_grid(&context);
+        assert_eq!((normal.len(), normal[0].len()), (3, 10));
+
+        context.transpose = true;
+        let transposed = sheet.view_grid(&context);
+        assert_eq!((transposed.len(), transposed[0].len()), (10, 3));
+        for r in 0..3 {
+            for c in 0..10 {
+                let expected = sheet.data[sheet.get_cell(r, c)].value.to_string();
+                assert_eq!(transposed[c][r], expected);
+                assert_eq!(normal[r][c], expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_view_grid_follows_viewport_size() {
+        let sheet = Sheet::new(30, 30);
+        let mut context = ParserContext::new();
+        context.view_rows = 25;
+        context.view_cols = 4;
+        let grid = sheet.view_grid(&context);
+        assert_eq!((grid.len(), grid[0].len()), (25, 4));
+
+        // The window stops at the edge of the sheet
+        context.px = 20;
+        assert_eq!(sheet.view_grid(&context).len(), 10);
+    }
+
+    #[test]
+    fn test_view_fits_the_terminal() {
+        let sheet = Sheet::new(30, 30);
+        let mut context = ParserContext::new();
+        assert_eq!(context.view_size(), (10, 10, DEFAULT_COL_WIDTH));
+        // Wide enough for everything
+        context.terminal = Some((200, 50));
+        assert_eq!(context.view_size(), (10, 10, DEFAULT_COL_WIDTH));
+        // Columns narrow to fit 10 into 80 characters, rows stop above the prompt
+        context.terminal = Some((84, 8));
+        assert_eq!(context.view_size(), (5, 10, 7));
+        let grid = sheet.view_grid(&context);
+        assert_eq!((grid.len(), grid[0].len()), (5, 10));
+        // Too narrow even at the minimum width, so columns are dropped
+        context.terminal = Some((40, 8));
+        assert_eq!(context.view_size(), (5, 5, MIN_COL_WIDTH));
+        context.transpose = true;
+        assert_eq!(context.view_size(), (5, 5, MIN_COL_WIDTH));
+        context.terminal = Some((1, 1));
+        assert_eq!(context.view_size(), (1, 1, MIN_COL_WIDTH));
+        // Never too narrow for a label such as `AAA`
+        context.terminal = None;
+        context.col_width = 1;
+        assert_eq!(context.view_size().2, LABEL_WIDTH);
+    }
+
     #[test]
     fn test_is_valid_cell() {
         let sheet = Sheet::new(5, 10);
@@ -403,16 +1446,85 @@ mod tests {
         assert_eq!(col, 3);
     }
 
+    #[test]
+    fn test_cell_name() {
+        let sheet = Sheet::new(5, 30);
+        assert_eq!(sheet.cell_name(0), "A1");
+        assert_eq!(sheet.cell_name(31), "B2");
+        assert_eq!(sheet.cell_name(sheet.get_cell(4, 27)), "AB5");
+    }
+
+    #[test]
+    fn test_line_edit_moves_spans() {
+        let delete = LineEdit::Delete(2);
+        assert_eq!(delete.move_span(5, 0, 1), Some((0, 1)));
+        assert_eq!(delete.move_span(5, 3, 4), Some((2, 3)));
+        assert_eq!(delete.move_span(5, 2, 4), Some((2, 3)));
+        assert_eq!(delete.move_span(5, 0, 2), Some((0, 1)));
+        assert_eq!(delete.move_span(5, 2, 2), None);
+        // The last line falls off: it is deleted before the new line goes in
+        let insert = LineEdit::Insert(1);
+        assert_eq!(insert.move_span(5, 0, 0), Some((0, 0)));
+        assert_eq!(insert.move_span(5, 0, 2), Some((0, 3)));
+        assert_eq!(insert.move_span(5, 1, 4), Some((2, 4)));
+        assert_eq!(insert.move_span(5, 4, 4), None);
+    }
+
+    #[test]
+    fn test_formula_text() {
+        let sheet = Sheet::new(5, 5);
+        let info = |function_id, arg, arg_mask| Info {
+            function_id,
+            arg,
+            arg_mask,
+            ..Default::default()
+        };
+        assert_eq!(sheet.formula_text(&info(0, [7, 0], 0)), "7");
+        assert_eq!(sheet.formula_text(&info(1, [6, 0], 0b1)), "SLEEP(B2)");
+        assert_eq!(sheet.formula_text(&info(11, [6, 0], 0b1)), "FACT(B2)");
+        assert_eq!(sheet.formula_text(&info(12, [10, 0], 0)), "FIB(10)");
+        assert_eq!(sheet.formula_text(&info(5, [1, 0], 0b10)), "1/A1");
+        assert_eq!(sheet.formula_text(&info(8, [0, 12], 0b11)), "SUM(A1:C3)");
+        assert_eq!(
+            sheet.formula_text(&info(13, [0, 5], 0b11)),
+            "HARMEAN(A1:A2)"
+        );
+        assert_eq!(sheet.formula_text(&info(20, [1, 0], 0b1)), "ABS(B1)");
+        assert_eq!(sheet.formula_text(&info(18, [1, 3], 0b1)), "MOD(B1,3)");
+        assert_eq!(sheet.formula_text(&info(40, [1, 0], 0b1)), "FN40(B1)");
+    }
+
+    #[test]
+    fn test_cell_text_views() {
+        let mut sheet = Sheet::new(3, 3);
+        sheet.data[1].info = Info {
+            function_id: 4,
+            arg: [0, 3],
+            arg_mask: 0b1,
+            ..Default::default()
+        };
+        sheet.data[1].value = 12.0;
+        sheet.data[2].info.error = Some(CellError::DivByZero);
+        sheet.data[2].info.function_id = 5;
+
+        assert_eq!(sheet.cell_text(1, false), "12");
+        assert_eq!(sheet.cell_text(1, true), "A1*3");
+        assert_eq!(sheet.cell_text(2, false), "#DIV/0");
+        assert_eq!(sheet.cell_text(2, true), "0/0");
+        assert_eq!(sheet.cell_text(0, false), "0");
+        assert_eq!(sheet.cell_text(0, true), "");
+    }
+
     #[test]
     fn test_get_cell_and_set_get() {
         let mut sheet = Sheet::new(5, 10);
         let idx = sheet.get_cell(2, 3);
         let mut cell = sheet.get(idx);
-        assert_eq!(cell.value, 0);
-        cell.value = 777;
+        assert_eq!(cell.value, 0.0);
+        cell.value = 777.0;
         sheet.set(idx, cell);
         let new_cell = sheet.get(idx);
-        assert_eq!(new_cell.value, 777);
+        assert_eq!(new_cell.value, 777.0);
     }
 
     #[test]
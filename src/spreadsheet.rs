@@ -0,0 +1,1907 @@
+// spreadsheet.rs
+//! This module ties the sheet, dependency graph, parser context and undo/redo history
+//! together into a single engine that executes one command at a time.
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Instant;
+
+use crate::autosave::{self, Autosave};
+use crate::formulas::{
+    DATE_FUNCTIONS, Formula, LABEL_FUNCTIONS, LOOKUP_FUNCTIONS, POSITION_FUNCTIONS,
+    RANDOM_FUNCTIONS, RANGE_FUNCTIONS, SCALAR_FUNCTIONS,
+};
+use crate::graph::{self, Graph, UpdateError};
+use crate::history::{CellState, DEFAULT_HISTORY_DEPTH, History, Transaction};
+use crate::info::{CellInfo, Command, Info};
+use crate::journal::Journal;
+use crate::ops;
+use crate::parser::{self, ParserContext, SyntaxError};
+use crate::random::Rng;
+use crate::session::{Decoder, Encoder, invalid_data};
+use crate::sheet::{Sheet, reset_dimensions};
+use crate::status::{CommandResult, StatusCode};
+use crate::trace::CellChange;
+
+/// The spreadsheet engine: a sheet, its dependency graph and the command history.
+pub struct Spreadsheet {
+    /// Shared cell storage.
+    pub sheet: Rc<RefCell<Sheet>>,
+    /// Dependency graph over the sheet.
+    pub graph: Graph,
+    /// Viewport and parser state.
+    pub parser_ctx: ParserContext,
+    /// Steps that `undo` reverts and `redo` re-applies.
+    history: History,
+    /// The cycle that made the last command fail, if it did.
+    last_cycle: Option<Vec<usize>>,
+    /// Where the commands that change the sheet are appended (`--journal`).
+    command_journal: Option<Journal>,
+    /// What the command being run adds to the journal once it has succeeded.
+    journal_entries: Vec<String>,
+    /// Whether commands that change the sheet are rejected (`--readonly`).
+    readonly: bool,
+    /// Where the session is saved every so often (`--autosave`, `set autosave`).
+    autosave: Option<Autosave>,
+}
+
+impl Spreadsheet {
+    /// Creates an empty spreadsheet with `n` rows and `m` columns.
+    ///
+    /// The global dimensions (`sheet::init_dimensions`) must already be set to the same
+    /// size, since the parser resolves cell names through them.
+    pub fn new(n: usize, m: usize) -> Self {
+        let sheet = Rc::new(RefCell::new(Sheet::new(n, m)));
+        let graph = Graph::new(n, m, sheet.clone());
+
+        Self {
+            sheet,
+            graph,
+            parser_ctx: ParserContext::new(),
+            history: History::new(DEFAULT_HISTORY_DEPTH),
+            last_cycle: None,
+            command_journal: None,
+            journal_entries: Vec::new(),
+            readonly: false,
+            autosave: None,
+        }
+    }
+    /// Registers a custom single-argument formula under `name`, e.g. `DOUBLE`, so that
+    /// commands such as `B1=DOUBLE(A1)` use it. Dependencies on the argument cell are
+    /// tracked like for any builtin.
+    ///
+    /// The formula reads its argument from `arg[0]` (a cell index if bit 0 of `arg_mask` is
+    /// set) and must set `value` and `error`. Returns `InvalidValue` if `name` is not made
+    /// of uppercase letters, is already a function name, or if `id` is taken.
+    pub fn register_function(
+        &mut self,
+        id: u8,
+        name: &str,
+        formula: Formula,
+    ) -> Result<(), StatusCode> {
+        let builtin = ["SLEEP", "FACT", "FIB"].contains(&name)
+            || RANGE_FUNCTIONS.iter().any(|&(n, _)| n == name)
+            || SCALAR_FUNCTIONS.iter().any(|&(n, _)| n == name)
+            || POSITION_FUNCTIONS.iter().any(|&(n, _)| n == name)
+            || RANDOM_FUNCTIONS.iter().any(|&(n, _)| n == name)
+            || DATE_FUNCTIONS.iter().any(|&(n, _)| n == name)
+            || LOOKUP_FUNCTIONS.iter().any(|&(n, _)| n == name)
+            || LABEL_FUNCTIONS.iter().any(|&(n, _)| n == name);
+        if name.is_empty()
+            || !name.chars().all(|c| c.is_ascii_uppercase())
+            || builtin
+            || self.parser_ctx.functions.contains_key(name)
+        {
+            return Err(StatusCode::InvalidValue);
+        }
+        self.graph.register_function(id, formula)?;
+        self.parser_ctx.functions.insert(name.to_string(), id);
+        Ok(())
+    }
+    /// Calls `callback` after every recomputation pass that changed the value, error or
+    /// text of any cell, with those cells in the order they were computed, e.g. after an
+    /// edit, `undo`, `redo` or `recalc`.
+    ///
+    /// The callback runs while the command is still executing: it may read the sheet
+    /// through a clone of `sheet`, but must not change it.
+    pub fn subscribe(&mut self, callback: impl FnMut(CellChange) + 'static) {
+        self.graph.subscribers.push(Box::new(callback));
+    }
+    /// Appends every command that changes the sheet to `journal` from now on, so that
+    /// `journal::replay` can rebuild the sheet. Commands that fail, and those that only
+    /// report, are left out.
+    pub fn set_journal(&mut self, journal: Journal) {
+        self.command_journal = Some(journal);
+    }
+    /// Rejects, with `ReadOnly`, every command that would change the sheet from now on, or
+    /// accepts them again. Moving around, display settings, exports and queries such as
+    /// `deps` still work, as do `load` and `load_session`, which open another sheet.
+    pub fn set_readonly(&mut self, readonly: bool) {
+        self.readonly = readonly;
+    }
+    /// Returns the aggregates of the range chosen with `select A1:B10`, e.g.
+    /// `SUM=10 AVG=2.5 COUNT=4 MIN=1 MAX=4`, or `None` without one.
+    pub fn selection_summary(&self) -> Option<String> {
+        let (start, end) = self.parser_ctx.selection?;
+        Some(self.sheet.borrow().range_summary(start, end))
+    }
+    /// Returns whether commands that change the sheet are rejected.
+    pub fn readonly(&self) -> bool {
+        self.readonly
+    }
+    /// Saves the session to `autosave` whenever it is due from now on, or stops, removing
+    /// the autosave file of the one it replaces.
+    pub fn set_autosave(&mut self, autosave: Option<Autosave>) {
+        if let Some(old) = std::mem::replace(&mut self.autosave, autosave) {
+            let _ = old.discard();
+        }
+    }
+    /// Starts saving the session in the background if the autosave is due. Every command
+    /// that changes the sheet checks, and an idle editor may check as well so a save by
+    /// time is not held back until the next edit.
+    ///
+    /// Returns the error of the previous save, if it failed.
+    pub fn autosave_if_due(&mut self) -> io::Result<()> {
+        if !self.autosave.as_ref().is_some_and(Autosave::due) {
+            return Ok(());
+        }
+        let mut session = Vec::new();
+        self.write_session(&mut session)?;
+        self.autosave.as_mut().unwrap().save(session)
+    }
+    /// Waits for the autosave being written, if any, and returns its outcome.
+    pub fn wait_for_autosave(&mut self) -> io::Result<()> {
+        self.autosave.as_mut().map_or(Ok(()), Autosave::wait)
+    }
+    /// Stops autosaving and removes the autosave file, as on a clean exit.
+    pub fn discard_autosave(&mut self) {
+        self.set_autosave(None);
+    }
+    /// Writes the entries the last command queued to the journal, if there is one.
+    fn write_journal(&mut self) -> io::Result<()> {
+        let entries = std::mem::take(&mut self.journal_entries);
+        if let (Some(autosave), false) = (self.autosave.as_mut(), entries.is_empty()) {
+            autosave.record_change();
+        }
+        match self.command_journal.as_mut() {
+            Some(journal) => entries.iter().try_for_each(|entry| journal.record(entry)),
+            None => Ok(()),
+        }
+    }
+    /// Executes one command and describes its outcome: the status, the assigned cell, the
+    /// time taken and, when an assignment closes a cycle, the cycle as its message.
+    ///
+    /// An assignment that makes the cell or one of its dependents overflow is kept, but
+    /// reported as `Overflow` with the overflowing cells as its message, e.g.
+    /// `overflowed: B1, C1`. Otherwise, in manual calculation mode, a command that leaves
+    /// cells waiting for `recalc` says so with the message `recalc pending`. A command that
+    /// succeeded but could not be added to the journal says so instead.
+    pub fn run_command(&mut self, input: &str) -> CommandResult {
+        let start = Instant::now();
+        self.last_cycle = None;
+        self.parser_ctx.last_error = None;
+        self.journal_entries.clear();
+        let result = self.run(input);
+        let journaled = match result {
+            Ok(_) => self.write_journal(),
+            Err(_) => Ok(()),
+        };
+        let autosaved = self.autosave_if_due();
+        let cell = result.ok().flatten();
+        let overflowed = cell.map_or_else(Vec::new, |cell| self.overflowed_from(cell));
+        let status = match result {
+            Err(status) => status,
+            Ok(_) if !overflowed.is_empty() => StatusCode::Overflow,
+            Ok(_) => StatusCode::Ok,
+        };
+        let message = self.cycle_text().or_else(|| {
+            let error = self.last_parse_error().filter(|_| result.is_err());
+            error.map(|error| error.to_string())
+        });
+        let message = message.or_else(|| {
+            let sheet = self.sheet.borrow();
+            let names: Vec<String> = overflowed.iter().map(|&c| sheet.cell_name(c)).collect();
+            (!names.is_empty()).then(|| format!("overflowed: {}", names.join(", ")))
+        });
+        let message = message.or_else(|| self.graph.stale.then(|| "recalc pending".to_string()));
+        let message = match (journaled, autosaved) {
+            (Err(e), _) => Some(format!("journal not written: {}", e)),
+            (_, Err(e)) => Some(format!("autosave not written: {}", e)),
+            _ => message,
+        };
+        CommandResult {
+            status,
+            cell,
+            elapsed: start.elapsed(),
+            message,
+        }
+    }
+    /// Returns `cell` and the cells recomputed after it that overflowed, by cell index.
+    fn overflowed_from(&self, cell: usize) -> Vec<usize> {
+        let sheet = self.sheet.borrow();
+        let mut cells = self.graph.transitive_dependents(cell);
+        cells.push(cell);
+        cells.sort_unstable();
+        cells.dedup();
+        cells.retain(|&c| sheet.get(c).overflowed());
+        cells
+    }
+    /// Executes one command, like `run_command`.
+    ///
+    /// # Returns
+    /// `Ok(Some(cell))` if the command assigned a cell, `Ok(None)` for any other successful
+    /// command, or the failing `StatusCode`. `Overflow` is the one failure whose edit is
+    /// kept, like any other undoable step.
+    pub fn execute(&mut self, input: &str) -> Result<Option<usize>, StatusCode> {
+        self.run_command(input).into_result()
+    }
+    /// Returns the cycle that made the last command fail, each cell referring to the
+    /// next, e.g. `[A1, C1, B1, A1]`.
+    pub fn last_cycle(&self) -> Option<&[usize]> {
+        self.last_cycle.as_deref()
+    }
+    /// Returns where the formula of the last command went wrong, if it did not parse, with
+    /// its offset and column counted in the whole command, e.g. for `A1=2**3` the second
+    /// `*` at column 6.
+    pub fn last_parse_error(&self) -> Option<&SyntaxError> {
+        self.parser_ctx.last_error.as_ref()
+    }
+    /// Describes the last command's cycle, e.g. `cycle: A1 -> C1 -> B1 -> A1`.
+    pub fn cycle_text(&self) -> Option<String> {
+        let sheet = self.sheet.borrow();
+        let names: Vec<String> = self
+            .last_cycle()?
+            .iter()
+            .map(|&cell| sheet.cell_name(cell))
+            .collect();
+        Some(format!("cycle: {}", names.join(" -> ")))
+    }
+    /// Parses and applies one command.
+    fn run(&mut self, input: &str) -> Result<Option<usize>, StatusCode> {
+        let command = match parser::parse_selection_command(input, &self.parser_ctx) {
+            Ok(None) => parser::parse_command(input),
+            other => other,
+        };
+        if let Some(command) = command.map_err(|e| e.status_code())? {
+            let opens_sheet = matches!(
+                command,
+                Command::LoadCsv { .. } | Command::LoadSession { .. }
+            );
+            if self.readonly && command.mutates() && !opens_sheet {
+                return Err(StatusCode::ReadOnly);
+            }
+            // Single-cell adjustments are ordinary, undoable edits.
+            if let Command::Adjust {
+                cell,
+                function_id,
+                amount,
+            } = command
+            {
+                let info = ops::adjusted_literal(&self.graph, cell, function_id, amount)?;
+                self.journal_entries.push(input.to_string());
+                return self.assign(cell, &info, input).map(Some);
+            }
+            match command {
+                Command::SaveSession { ref path } => {
+                    return self
+                        .save_session(Path::new(path))
+                        .map(|_| None)
+                        .map_err(|_| StatusCode::InvalidValue);
+                }
+                Command::LoadSession { ref path } => {
+                    self.journal_entries.push(input.to_string());
+                    return self
+                        .load_session(Path::new(path))
+                        .map(|_| None)
+                        .map_err(|_| StatusCode::InvalidValue);
+                }
+                Command::History => {
+                    println!("{}", self.history.report(&self.sheet.borrow()));
+                    return Ok(None);
+                }
+                Command::SetAutosave(every) => {
+                    match (every, self.autosave.as_mut()) {
+                        (Some(every), Some(autosave)) => autosave.set_every(every),
+                        (Some(every), None) => {
+                            let autosave = Autosave::new(autosave::default_path(), every)
+                                .map_err(|_| StatusCode::InvalidValue)?;
+                            self.autosave = Some(autosave);
+                        }
+                        (None, _) => self.discard_autosave(),
+                    }
+                    return Ok(None);
+                }
+                Command::SetReadOnly(readonly) => {
+                    self.readonly = readonly;
+                    return Ok(None);
+                }
+                Command::SetHistoryDepth(depth) => {
+                    self.journal_entries.push(input.to_string());
+                    self.history.set_max_depth(depth);
+                    return Ok(None);
+                }
+                _ => {}
+            }
+            // Every cell the command writes is journaled, so it undoes as one step
+            self.graph.journal = Some(Vec::new());
+            let result = ops::execute(&mut self.graph, &command);
+            let journal = self.graph.journal.take().unwrap_or_default();
+            result?;
+            self.record(input, journal);
+            if command.mutates() {
+                // A command on the selection is written with its range, which a replay
+                // does not otherwise know
+                let entry = match command {
+                    Command::Clear { start, end } => {
+                        let sheet = self.sheet.borrow();
+                        format!("clear {}:{}", sheet.cell_name(start), sheet.cell_name(end))
+                    }
+                    _ => input.to_string(),
+                };
+                self.journal_entries.push(entry);
+            }
+            match command {
+                Command::RotateSheet { .. } | Command::LoadCsv { .. } => {
+                    // Every cell moved, so neither the history nor the view still applies,
+                    // and a rotation changed the shape cached formulas were indexed for
+                    self.history.clear();
+                    self.parser_ctx.clear_parse_cache();
+                    self.parser_ctx.px = 0;
+                    self.parser_ctx.py = 0;
+                    self.parser_ctx.selection = None;
+                    self.parser_ctx.chart = None;
+                }
+                Command::MoveLines { .. } => {
+                    // Cells moved, so the history would restore them in the wrong place
+                    self.history.clear();
+                    self.graph.cell_log.clear();
+                    self.parser_ctx.selection = None;
+                    self.parser_ctx.chart = None;
+                }
+                _ => {}
+            }
+            return Ok(None);
+        }
+
+        let writes = self
+            .parser_ctx
+            .parse_array(input)
+            .map_err(|e| e.status_code())?;
+        if let Some(writes) = writes {
+            if self.readonly {
+                return Err(StatusCode::ReadOnly);
+            }
+            self.journal_entries.push(input.to_string());
+            return self.spill(&writes, input).map(Some);
+        }
+
+        let cmd_info = parser::parse(input, &mut self.parser_ctx).map_err(|e| e.status_code())?;
+
+        match cmd_info.lhs_cell {
+            -1 => return Ok(None),
+            _ if self.readonly => return Err(StatusCode::ReadOnly),
+            -2 => {
+                self.journal_entries.push(input.to_string());
+                return self.undo().map(|_| None);
+            }
+            -3 => {
+                self.journal_entries.push(input.to_string());
+                return self.redo().map(|_| None);
+            }
+            _ => {}
+        }
+        self.journal_entries.push(input.to_string());
+
+        self.assign(cmd_info.lhs_cell as usize, &cmd_info.info, input)
+            .map(Some)
+    }
+    /// Writes `info` into each paired cell as one undoable step made by the command
+    /// `input`, recomputing dependents. Nothing changes if any write fails, e.g. with
+    /// `CyclicDep`, or if the sheet is read-only.
+    ///
+    /// The journal gets one assignment, or `clear`, per cell, so after a replay the cells
+    /// undo one at a time. A journal that cannot be written is reported as `InvalidValue`,
+    /// after the cells were written.
+    pub fn write_cells(&mut self, writes: &[(usize, Info)], input: &str) -> Result<(), StatusCode> {
+        if self.readonly {
+            return Err(StatusCode::ReadOnly);
+        }
+        self.begin_batch();
+        for (cell, info) in writes {
+            self.graph.update_expression(*cell, info)?;
+        }
+        self.commit_batch(input)?;
+        if self.command_journal.is_some() {
+            let sheet = self.sheet.borrow();
+            self.journal_entries = writes
+                .iter()
+                .map(|(cell, info)| {
+                    let name = sheet.cell_name(*cell);
+                    match sheet.get(*cell).is_blank() {
+                        true => format!("clear {}:{}", name, name),
+                        false => format!("{}={}", name, sheet.formula_text(info)),
+                    }
+                })
+                .collect();
+        }
+        self.write_journal().map_err(|_| StatusCode::InvalidValue)
+    }
+    /// Starts a transaction: the `update_expression` calls made on `graph` until
+    /// `commit_batch` are queued rather than applied, and the sheet keeps its old cells
+    /// and values meanwhile.
+    pub fn begin_batch(&mut self) {
+        self.graph.journal = Some(Vec::new());
+        self.graph.begin_batch();
+    }
+    /// Applies the writes queued since `begin_batch` as one undoable step made by the
+    /// command `input`. They are checked for cycles together and the sheet is recomputed
+    /// once; nothing changes if they close a cycle or touch a locked cell.
+    pub fn commit_batch(&mut self, input: &str) -> Result<(), StatusCode> {
+        let result = self.graph.commit_batch();
+        let journal = self.graph.journal.take().unwrap_or_default();
+        result.map_err(|error| {
+            if let UpdateError::Cycle(path) = &error {
+                self.last_cycle = Some(path.clone());
+            }
+            error.status_code()
+        })?;
+        self.record(input, journal);
+        Ok(())
+    }
+    /// Returns the undo and redo history.
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+    /// Returns the completions of `prefix` for the line editor, sorted: function names
+    /// with their opening parenthesis, e.g. `SUM(`, and the names of non-empty cells.
+    /// The prefix is matched ignoring case.
+    pub fn completions(&self, prefix: &str) -> Vec<String> {
+        let prefix = prefix.to_ascii_uppercase();
+        let functions = RANGE_FUNCTIONS
+            .iter()
+            .chain(SCALAR_FUNCTIONS.iter())
+            .chain(RANDOM_FUNCTIONS.iter())
+            .chain(DATE_FUNCTIONS.iter())
+            .chain(LOOKUP_FUNCTIONS.iter())
+            .chain(POSITION_FUNCTIONS.iter())
+            .chain(LABEL_FUNCTIONS.iter())
+            .map(|&(name, _)| name)
+            .chain(["SLEEP", "FACT", "FIB"])
+            .chain(self.parser_ctx.functions.keys().map(String::as_str))
+            .map(|name| format!("{}(", name));
+        let sheet = self.sheet.borrow();
+        let cells = (0..sheet.data.len())
+            .filter(|&cell| !sheet.get(cell).is_blank())
+            .map(|cell| sheet.cell_name(cell));
+        let mut completions: Vec<String> = functions
+            .chain(cells)
+            .filter(|name| name.starts_with(&prefix))
+            .collect();
+        completions.sort();
+        completions
+    }
+    /// Writes the whole session to `path` in the binary layout of the `session` module.
+    ///
+    /// After `MAGIC` come the dimensions, the viewport position, the recalculation order,
+    /// the overflow policy, the random generator state, the cell roles, formats and notes,
+    /// the locked cells, every cell, the history depth and the undo and redo stacks. Each
+    /// step is stored as its command text, a cell count and `(cell index, cell)` pairs. The
+    /// dependency graph is not stored, since it follows from the formulas.
+    pub fn save_session(&self, path: &Path) -> io::Result<()> {
+        self.write_session(BufWriter::new(File::create(path)?))
+    }
+    /// Writes the whole session to `writer`, as `save_session` writes it to a file.
+    pub fn write_session<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut out = Encoder::new(writer);
+        let sheet = self.sheet.borrow();
+        out.magic()?;
+        out.usize(sheet.n)?;
+        out.usize(sheet.m)?;
+        out.usize(self.parser_ctx.px)?;
+        out.usize(self.parser_ctx.py)?;
+        out.recalc_order(self.graph.recalc_order)?;
+        out.overflow_policy(sheet.overflow)?;
+        out.u64(sheet.rng.state())?;
+
+        let mut roles: Vec<_> = sheet.roles.iter().collect();
+        roles.sort_unstable_by_key(|&(&cell, _)| cell);
+        out.usize(roles.len())?;
+        for (&cell, &role) in roles {
+            out.usize(cell)?;
+            out.role(role)?;
+        }
+        let mut formats: Vec<_> = sheet.formats.iter().collect();
+        formats.sort_unstable_by_key(|&(&cell, _)| cell);
+        out.usize(formats.len())?;
+        for (&cell, &format) in formats {
+            out.usize(cell)?;
+            out.format(format)?;
+        }
+        let mut notes: Vec<_> = sheet.notes.iter().collect();
+        notes.sort_unstable_by_key(|&(&cell, _)| cell);
+        out.usize(notes.len())?;
+        for (&cell, note) in notes {
+            out.usize(cell)?;
+            out.text(note)?;
+        }
+        let mut locked: Vec<usize> = sheet.locked.iter().copied().collect();
+        locked.sort_unstable();
+        out.usize(locked.len())?;
+        for cell in locked {
+            out.usize(cell)?;
+        }
+        for cell in &sheet.data {
+            out.cell(cell)?;
+        }
+        out.usize(self.history.max_depth())?;
+        let undo: Vec<&Transaction> = self.history.undo_steps().collect();
+        let redo: Vec<&Transaction> = self.history.redo_steps().iter().collect();
+        for stack in [undo, redo] {
+            out.usize(stack.len())?;
+            for transaction in stack {
+                out.text(&transaction.command)?;
+                out.usize(transaction.cells.len())?;
+                for state in &transaction.cells {
+                    out.usize(state.cell_idx)?;
+                    out.cell(&CellInfo {
+                        info: state.info,
+                        value: state.value,
+                        literal_mode: state.literal_mode,
+                    })?;
+                }
+            }
+        }
+        out.finish()
+    }
+    /// Replaces the whole session with one written by `save_session`.
+    ///
+    /// The file is read and checked completely before anything changes: an unknown
+    /// function ID or a cell reference outside the saved sheet is `InvalidData`. The
+    /// dependency graph is rebuilt from the formulas without recomputing any value, so
+    /// cells, including literal-mode ones, resume exactly as saved. The global dimensions
+    /// follow the saved sheet.
+    pub fn load_session(&mut self, path: &Path) -> io::Result<()> {
+        let mut input = Decoder::new(BufReader::new(File::open(path)?));
+        input.magic()?;
+        let (n, m) = (input.usize()?, input.usize()?);
+        if n == 0 || m == 0 {
+            return Err(invalid_data("empty sheet"));
+        }
+        let (px, py) = (input.usize()?, input.usize()?);
+        let recalc_order = input.recalc_order()?;
+        let overflow = input.overflow_policy()?;
+        let rng = Rng::new(input.u64()?);
+
+        let cells = n * m;
+        let check = |cell: &CellInfo| {
+            let info = &cell.info;
+            let known = matches!(
+                self.graph.functions.get(info.function_id as usize),
+                Some(Some(_))
+            );
+            let references_ok = info
+                .references()
+                .iter()
+                .all(|&(start, end)| start < cells && end < cells);
+            if known && references_ok {
+                Ok(*cell)
+            } else {
+                Err(invalid_data("bad cell formula"))
+            }
+        };
+
+        let mut roles = HashMap::new();
+        for _ in 0..input.usize()? {
+            let cell = input.usize()?;
+            let role = input.role()?;
+            if cell >= cells {
+                return Err(invalid_data("role outside the sheet"));
+            }
+            roles.insert(cell, role);
+        }
+        let mut formats = HashMap::new();
+        for _ in 0..input.usize()? {
+            let cell = input.usize()?;
+            let format = input.format()?;
+            if cell >= cells {
+                return Err(invalid_data("format outside the sheet"));
+            }
+            formats.insert(cell, format);
+        }
+        let mut notes = HashMap::new();
+        for _ in 0..input.usize()? {
+            let cell = input.usize()?;
+            let note = input.text()?;
+            if cell >= cells {
+                return Err(invalid_data("note outside the sheet"));
+            }
+            notes.insert(cell, note);
+        }
+        let mut locked = HashSet::new();
+        for _ in 0..input.usize()? {
+            let cell = input.usize()?;
+            if cell >= cells {
+                return Err(invalid_data("lock outside the sheet"));
+            }
+            locked.insert(cell);
+        }
+        let data = (0..cells)
+            .map(|_| check(&input.cell()?))
+            .collect::<io::Result<Vec<CellInfo>>>()?;
+        let max_depth = input.usize()?;
+        let mut stacks = Vec::new();
+        for _ in 0..2 {
+            let mut stack = Vec::new();
+            for _ in 0..input.usize()? {
+                let command = input.text()?;
+                let mut states = Vec::new();
+                for _ in 0..input.usize()? {
+                    let cell_idx = input.usize()?;
+                    let cell = check(&input.cell()?)?;
+                    if cell_idx >= cells {
+                        return Err(invalid_data("history outside the sheet"));
+                    }
+                    states.push(CellState {
+                        cell_idx,
+                        info: cell.info,
+                        value: cell.value,
+                        literal_mode: cell.literal_mode,
+                    });
+                }
+                stack.push(Transaction {
+                    command,
+                    cells: states,
+                });
+            }
+            stacks.push(stack);
+        }
+
+        // Everything is valid: switch over to the saved session
+        {
+            let mut sheet = self.sheet.borrow_mut();
+            if (sheet.n, sheet.m) != (n, m) {
+                unsafe {
+                    reset_dimensions(m, n);
+                }
+            }
+            sheet.n = n;
+            sheet.m = m;
+            sheet.data = data;
+            sheet.rng = rng;
+            sheet.overflow = overflow;
+            sheet.roles = roles;
+            sheet.formats = formats;
+            sheet.notes = notes;
+            sheet.locked = locked;
+        }
+        ops::rebuild_graph(&mut self.graph, n, m);
+        self.graph.recalc_order = recalc_order;
+        for cell in 0..cells {
+            let cell_info = self.sheet.borrow().data[cell];
+            self.graph.add_expression(cell as i32, &cell_info);
+        }
+        let redo = stacks.pop().unwrap();
+        let undo = stacks.pop().unwrap();
+        self.history.set_max_depth(max_depth);
+        self.history.replace(undo, redo);
+        self.parser_ctx.px = px.min(n - 1);
+        self.parser_ctx.py = py.min(m - 1);
+        self.parser_ctx.selection = None;
+        // Cached formulas may be indexed for the shape of the sheet before
+        self.parser_ctx.clear_parse_cache();
+        Ok(())
+    }
+    /// Assigns `info` to a cell as one undoable edit made by the command `input`.
+    fn assign(&mut self, cell_idx: usize, info: &Info, input: &str) -> Result<usize, StatusCode> {
+        let state = self.snapshot(cell_idx);
+        graph::update_expression(&mut self.graph, cell_idx, info).map_err(|error| {
+            if let UpdateError::Cycle(path) = &error {
+                self.last_cycle = Some(path.clone());
+            }
+            error.status_code()
+        })?;
+        self.sheet.borrow_mut().data[cell_idx].literal_mode = false; // Reset literal mode
+        self.history.record(Transaction {
+            command: input.to_string(),
+            cells: vec![state],
+        });
+        self.parser_ctx.record_edit(input);
+        Ok(cell_idx)
+    }
+    /// Writes the block of an array formula as one undoable edit made by the command
+    /// `input`, all cells at once, and returns its top-left cell. Nothing changes if the
+    /// block would close a cycle or covers a locked cell.
+    fn spill(&mut self, writes: &[(usize, Info)], input: &str) -> Result<usize, StatusCode> {
+        self.graph.journal = Some(Vec::new());
+        let result = self.graph.update_expressions(writes);
+        let journal = self.graph.journal.take().unwrap_or_default();
+        result.map_err(|error| {
+            if let UpdateError::Cycle(path) = &error {
+                self.last_cycle = Some(path.clone());
+            }
+            error.status_code()
+        })?;
+        self.record(input, journal);
+        Ok(writes[0].0)
+    }
+    /// Records the cells a bulk command overwrote, in the order it wrote them, as one
+    /// undoable step. A cell written twice keeps its state from before the first write.
+    ///
+    /// A command that wrote nothing records no step, but still clears the redo stack.
+    fn record(&mut self, input: &str, journal: Vec<(usize, CellInfo)>) {
+        let mut cells: Vec<CellState> = Vec::new();
+        let mut seen = HashSet::new();
+        for (cell_idx, cell) in journal {
+            if seen.insert(cell_idx) {
+                cells.push(CellState {
+                    cell_idx,
+                    info: cell.info,
+                    value: cell.value,
+                    literal_mode: cell.literal_mode,
+                });
+            }
+        }
+        if cells.is_empty() {
+            self.history.clear_redo();
+        } else {
+            self.history.record(Transaction {
+                command: input.to_string(),
+                cells,
+            });
+        }
+    }
+    /// Captures the current state of a cell.
+    fn snapshot(&self, cell_idx: usize) -> CellState {
+        let sheet_borrow = self.sheet.borrow();
+        CellState {
+            cell_idx,
+            info: sheet_borrow.data[cell_idx].info,
+            value: sheet_borrow.data[cell_idx].value,
+            literal_mode: sheet_borrow.data[cell_idx].literal_mode,
+        }
+    }
+    /// Reverts the most recent step, moving it onto the redo stack.
+    fn undo(&mut self) -> Result<(), StatusCode> {
+        let transaction = self.history.pop_undo().ok_or(StatusCode::NothingToUndo)?;
+        match self.revert(transaction) {
+            Ok(current) => {
+                self.history.push_redo(current);
+                Ok(())
+            }
+            Err((transaction, status)) => {
+                self.history.push_undo(transaction);
+                Err(status)
+            }
+        }
+    }
+    /// Re-applies the most recently undone step, moving it back onto the undo stack.
+    fn redo(&mut self) -> Result<(), StatusCode> {
+        let transaction = self.history.pop_redo().ok_or(StatusCode::NothingToRedo)?;
+        match self.revert(transaction) {
+            Ok(current) => {
+                self.history.push_undo(current);
+                Ok(())
+            }
+            Err((transaction, status)) => {
+                self.history.push_redo(transaction);
+                Err(status)
+            }
+        }
+    }
+    /// Puts every cell of a step back into its recorded state, last cell first.
+    ///
+    /// A single restored cell keeps its recorded value in literal mode. The cells of a bulk
+    /// step are recomputed instead, since a formula cell's recorded value may already
+    /// reflect earlier writes of the same command.
+    ///
+    /// # Returns
+    /// The step that reverts this one, or `transaction` back if restoring any cell would
+    /// create a cyclic dependency, with `CyclicDep`. The cells already restored are then
+    /// put back. A step touching a locked cell is returned untried, with `CellLocked`.
+    fn revert(
+        &mut self,
+        transaction: Transaction,
+    ) -> Result<Transaction, (Transaction, StatusCode)> {
+        let locked = {
+            let sheet = self.sheet.borrow();
+            transaction
+                .cells
+                .iter()
+                .any(|state| sheet.is_locked(state.cell_idx))
+        };
+        if locked {
+            return Err((transaction, StatusCode::CellLocked));
+        }
+        let freeze = transaction.cells.len() == 1;
+        let mut current = Vec::with_capacity(transaction.cells.len());
+        for &state in transaction.cells.iter().rev() {
+            match self.restore(state, freeze) {
+                Ok(previous) => current.push(previous),
+                Err(_) => {
+                    for &previous in current.iter().rev() {
+                        let _ = self.restore(previous, freeze);
+                    }
+                    return Err((transaction, StatusCode::CyclicDep));
+                }
+            }
+        }
+        Ok(Transaction {
+            command: transaction.command,
+            cells: current,
+        })
+    }
+    /// Puts a cell back into the state recorded in `state`, in literal mode if `freeze`
+    /// is set.
+    ///
+    /// # Returns
+    /// The cell's state before the restore, or `state` back if restoring it would
+    /// create a cyclic dependency.
+    fn restore(&mut self, state: CellState, freeze: bool) -> Result<CellState, CellState> {
+        let temp_cell_info = CellInfo {
+            info: state.info,
+            value: state.value,
+            literal_mode: state.literal_mode,
+        };
+
+        // Cycle check for old dependencies
+        if self
+            .graph
+            .iterative_dfs(state.cell_idx as i32, &temp_cell_info)
+            .is_err()
+        {
+            self.graph.reset();
+            return Err(state);
+        }
+
+        let current = self.snapshot(state.cell_idx);
+
+        // Revert the cell state
+        let original = self.sheet.borrow().data[state.cell_idx];
+        self.graph.remember(state.cell_idx, original);
+        self.graph.delete_expression(state.cell_idx as i32);
+        self.graph
+            .add_expression(state.cell_idx as i32, &temp_cell_info);
+
+        {
+            let mut sheet_borrow = self.sheet.borrow_mut();
+            let cell = &mut sheet_borrow.data[state.cell_idx];
+            cell.info = state.info;
+            cell.value = state.value;
+            cell.literal_mode = freeze || state.literal_mode; // Preserve historical value
+        }
+        self.graph
+            .cell_log
+            .record(state.cell_idx, &original, &temp_cell_info);
+
+        self.graph.update_values();
+        self.graph.reset();
+        Ok(current)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::date;
+    use crate::sheet::{TEST_COLS, TEST_ROWS, get_cell, init_test_dimensions};
+
+    fn create_test_spreadsheet() -> Spreadsheet {
+        init_test_dimensions();
+        Spreadsheet::new(TEST_ROWS, TEST_COLS)
+    }
+
+    fn value_of(app: &Spreadsheet, cell: usize) -> i32 {
+        app.sheet.borrow().data[cell].value as i32
+    }
+
+    #[test]
+    fn test_run_command_describes_the_outcome() {
+        let mut app = create_test_spreadsheet();
+        let result = app.run_command("B1=A1+1");
+        assert_eq!((result.status, result.cell), (StatusCode::Ok, Some(1)));
+        assert_eq!(result.message, None);
+
+        let result = app.run_command("A1=B1");
+        assert_eq!((result.status, result.cell), (StatusCode::CyclicDep, None));
+        assert_eq!(result.message.as_deref(), Some("cycle: A1 -> B1 -> A1"));
+        assert!(
+            result
+                .status_line()
+                .ends_with("(cyclic dependency found, cycle: A1 -> B1 -> A1) >")
+        );
+    }
+
+    #[test]
+    fn test_cycle_error_names_the_path() {
+        let mut app = create_test_spreadsheet();
+        app.execute("B1=A1+1").unwrap();
+        app.execute("C1=SUM(A2:B2)").unwrap();
+        app.execute("A2=B1*2").unwrap();
+        assert_eq!(app.execute("A1=C1"), Err(StatusCode::CyclicDep));
+        assert_eq!(
+            app.cycle_text().as_deref(),
+            Some("cycle: A1 -> C1 -> A2 -> B1 -> A1")
+        );
+
+        // The next command starts without a cycle
+        app.execute("A1=1").unwrap();
+        assert_eq!(app.last_cycle(), None);
+    }
+
+    #[test]
+    fn test_execute_assignment_and_dependents() {
+        let mut app = create_test_spreadsheet();
+        assert_eq!(app.execute("A1=5"), Ok(Some(0)));
+        assert_eq!(app.execute("B1=A1*3"), Ok(Some(1)));
+        app.execute("A1=7").unwrap();
+        assert_eq!(value_of(&app, 1), 21);
+    }
+
+    #[test]
+    fn test_execute_reports_errors() {
+        let mut app = create_test_spreadsheet();
+        assert_eq!(app.execute("A1=A1+1"), Err(StatusCode::CyclicDep));
+        assert_eq!(app.last_cycle(), Some(&[0, 0][..]));
+        assert_eq!(app.execute("bogus"), Err(StatusCode::InvalidCmd));
+        assert_eq!(app.execute("undo"), Err(StatusCode::NothingToUndo));
+        assert_eq!(app.execute("redo"), Err(StatusCode::NothingToRedo));
+    }
+
+    #[test]
+    fn test_completions_name_functions_and_filled_cells() {
+        let mut app = create_test_spreadsheet();
+        app.execute("B1=A1+1").unwrap();
+        app.execute("B12=7").unwrap();
+        app.execute("B2=0").unwrap();
+        assert_eq!(app.completions("b1"), ["B1", "B12"]);
+        // B2 holds a plain zero, like a cell never written
+        assert!(!app.completions("B").contains(&"B2".to_string()));
+        assert_eq!(app.completions("su"), ["SUM("]);
+        assert_eq!(app.completions("F"), ["FACT(", "FIB("]);
+        assert_eq!(app.completions("countl"), ["COUNTLABEL("]);
+        assert_eq!(app.completions("COL"), ["COLUMN("]);
+    }
+
+    #[test]
+    fn test_clear_empties_exactly_the_selection() {
+        let mut app = create_test_spreadsheet();
+        for row in 1..=4 {
+            for col in 0..3 {
+                let cell = get_cell(row - 1, col);
+                let name = app.sheet.borrow().cell_name(cell);
+                app.execute(&format!("{}={}", name, row * 10 + col))
+                    .unwrap();
+            }
+        }
+        app.execute("B3=A1+1").unwrap();
+        app.execute("D1=SUM(A1:C4)").unwrap();
+        assert_eq!(app.execute("clear"), Err(StatusCode::InvalidCmd));
+
+        app.execute("select B2:C3").unwrap();
+        assert_eq!(app.execute("clear"), Ok(None));
+        for row in 0..4 {
+            for col in 0..3 {
+                let data = app.sheet.borrow().get(get_cell(row, col));
+                let selected = (1..=2).contains(&row) && (1..=2).contains(&col);
+                if selected {
+                    assert_eq!((data.value, data.info.function_id), (0.0, 0));
+                } else {
+                    assert_eq!(data.value, ((row + 1) * 10 + col) as f64);
+                }
+            }
+        }
+        // 10+11+12 + 20 + 30 + 40+41+42, recomputed after the clear
+        assert_eq!(value_of(&app, get_cell(0, 3)), 206);
+        // The selection survives, so a formula in it can be cleared again
+        app.execute("B2=A1").unwrap();
+        app.execute("clear").unwrap();
+        assert_eq!(value_of(&app, get_cell(1, 1)), 0);
+        app.execute("A1=99").unwrap();
+        assert_eq!(value_of(&app, get_cell(1, 1)), 0);
+    }
+
+    #[test]
+    fn test_undo_and_redo() {
+        let mut app = create_test_spreadsheet();
+        app.execute("A1=5").unwrap();
+        app.execute("A1=9").unwrap();
+        assert_eq!(app.execute("undo"), Ok(None));
+        assert_eq!(value_of(&app, 0), 5);
+        assert_eq!(app.execute("redo"), Ok(None));
+        assert_eq!(value_of(&app, 0), 9);
+    }
+
+    #[test]
+    fn test_fact_and_fib_propagate() {
+        let mut app = create_test_spreadsheet();
+        app.execute("A1=5").unwrap();
+        app.execute("B1=FACT(A1)").unwrap();
+        app.execute("C1=FIB(10)").unwrap();
+        assert_eq!(value_of(&app, 1), 120);
+        assert_eq!(value_of(&app, 2), 55);
+        app.execute("A1=4").unwrap();
+        assert_eq!(value_of(&app, 1), 24);
+        app.execute("A1=-1").unwrap();
+        assert!(app.sheet.borrow().data[1].info.invalid());
+        // A bare range is an array formula, taking the factorial of each cell
+        app.execute("D1=FACT(A1:A2)").unwrap();
+        assert!(app.sheet.borrow().data[3].info.invalid());
+        assert_eq!(value_of(&app, get_cell(1, 3)), 1);
+    }
+
+    fn double(cell_info: &mut CellInfo, sheet: &Sheet) {
+        crate::formulas::assignment(cell_info, sheet);
+        cell_info.value *= 2.0;
+    }
+
+    #[test]
+    fn test_register_custom_function() {
+        let mut app = create_test_spreadsheet();
+        assert_eq!(app.execute("B1=DOUBLE(A1)"), Err(StatusCode::InvalidCmd));
+        assert_eq!(app.register_function(40, "DOUBLE", double), Ok(()));
+
+        app.execute("A1=4").unwrap();
+        app.execute("B1=DOUBLE(A1)").unwrap();
+        app.execute("C1=B1+1").unwrap();
+        assert_eq!(value_of(&app, 1), 8);
+        app.execute("A1=5").unwrap();
+        assert_eq!(value_of(&app, 1), 10);
+        assert_eq!(value_of(&app, 2), 11);
+        assert_eq!(app.execute("A1=DOUBLE(B1)"), Err(StatusCode::CyclicDep));
+
+        // Taken IDs, builtin names and duplicates are refused
+        assert_eq!(
+            app.register_function(40, "TWICE", double),
+            Err(StatusCode::InvalidValue)
+        );
+        assert_eq!(
+            app.register_function(3, "TWICE", double),
+            Err(StatusCode::InvalidValue)
+        );
+        for name in ["SUM", "FACT", "MOD", "DOUBLE", "Twice"] {
+            assert_eq!(
+                app.register_function(41, name, double),
+                Err(StatusCode::InvalidValue)
+            );
+        }
+    }
+
+    #[test]
+    fn test_scalar_functions_recompute_and_fail_as_errors() {
+        let mut app = create_test_spreadsheet();
+        app.execute("A1=-7").unwrap();
+        app.execute("B1=MOD(A1,3)").unwrap();
+        app.execute("C1=POW(2,ABS(A1))+MAX2(A1,B1)").unwrap();
+        assert_eq!(value_of(&app, 1), 2);
+        assert_eq!(value_of(&app, 2), 130);
+        app.execute("A1=4").unwrap();
+        assert_eq!((value_of(&app, 1), value_of(&app, 2)), (1, 20));
+
+        // The overflowing formula is kept, but the command reports why its cell is ERR
+        assert_eq!(app.execute("D1=POW(A1,1000)"), Err(StatusCode::Overflow));
+        app.execute("E1=MOD(A1,B2)").unwrap();
+        let sheet = app.sheet.borrow();
+        assert!(sheet.get(3).info.invalid());
+        assert!(sheet.get(4).info.invalid());
+        assert_eq!(
+            sheet.formula_text(&sheet.get(2).info),
+            "POW(2,ABS(A1))+MAX2(A1,B1)"
+        );
+        assert_eq!(sheet.formula_text(&sheet.get(4).info), "MOD(A1,B2)");
+    }
+
+    #[test]
+    fn test_parallel_recompute_matches_serial() {
+        let mut serial = create_test_spreadsheet();
+        let mut parallel = create_test_spreadsheet();
+        parallel.execute("set threads 4").unwrap();
+        // A level of 100 cells reading A1, wide enough to be split, then cells reading it
+        let mut commands = vec!["A1=3".to_string()];
+        for row in 1..=100 {
+            commands.push(format!("B{}=A1*{}+MOD(A1,{})", row, row, row));
+        }
+        commands.extend(["C1=SUM(B1:B100)", "C2=C1-B7", "A1=11"].map(String::from));
+        for command in &commands {
+            assert_eq!(parallel.execute(command), serial.execute(command));
+        }
+        let (a, b) = (serial.sheet.borrow(), parallel.sheet.borrow());
+        for cell in 0..a.n * a.m {
+            assert_eq!(a.get(cell).value, b.get(cell).value, "cell {}", cell);
+        }
+        assert_eq!(value_of(&parallel, 2), 11 * 5050 + 1001);
+    }
+
+    #[test]
+    fn test_overflow_is_reported_and_policy_recomputes() {
+        let mut app = create_test_spreadsheet();
+        app.execute("A1=10").unwrap();
+        app.execute("B1=A1*2").unwrap();
+        app.execute("C1=B1-A1").unwrap();
+        let result = app.run_command("B1=POW(A1,400)*2");
+        assert_eq!(result.status, StatusCode::Overflow);
+        assert_eq!(result.message.as_deref(), Some("overflowed: B1"));
+        // The edit is kept; C1 is an error because B1 is, not an overflow of its own
+        assert!(app.sheet.borrow().get(1).overflowed());
+        assert!(app.sheet.borrow().get(2).info.invalid());
+        assert!(!app.sheet.borrow().get(2).overflowed());
+
+        app.execute("set overflow_policy saturate").unwrap();
+        assert_eq!(app.sheet.borrow().get(1).value, f64::MAX);
+        assert!(!app.sheet.borrow().get(2).info.invalid());
+        assert_eq!(app.execute("D1=FACT(20)"), Ok(Some(3)));
+        assert_eq!(value_of(&app, 3), i32::MAX);
+
+        app.execute("set overflow_policy error").unwrap();
+        assert!(app.sheet.borrow().get(1).overflowed());
+        assert!(app.sheet.borrow().get(3).overflowed());
+    }
+
+    #[test]
+    fn test_text_cells_are_errors_in_formulas() {
+        let mut app = create_test_spreadsheet();
+        for cmd in [
+            "A1=\"Units sold\"",
+            "A2=4",
+            "B1=A1+1",
+            "B2=SUM(A1:A2)",
+            "B3=A2*2",
+        ] {
+            app.execute(cmd).unwrap();
+        }
+        let text = |app: &Spreadsheet, cell: usize| app.sheet.borrow().cell_text(cell, false);
+        assert_eq!(text(&app, 0), "Units sold");
+        // Reading text is an error of the formula itself, not one inherited from A1
+        assert_eq!(text(&app, 1), "#VALUE");
+        assert_eq!(text(&app, get_cell(1, 1)), "#VALUE");
+        assert_eq!(value_of(&app, get_cell(2, 1)), 8);
+
+        // Replacing the label with a number clears the errors, and undo brings it back
+        app.execute("A1=3").unwrap();
+        assert_eq!((value_of(&app, 1), value_of(&app, get_cell(1, 1))), (4, 7));
+        app.execute("undo").unwrap();
+        assert_eq!(text(&app, 0), "Units sold");
+        assert_eq!(text(&app, 1), "#VALUE");
+    }
+
+    #[test]
+    fn test_errors_name_their_cause() {
+        let mut app = create_test_spreadsheet();
+        for cmd in [
+            "A1=1/0",
+            "A2=A1+1",
+            "A3=SUM(A1:A2)",
+            "B1=FACT(0-1)",
+            "B2=POW(10,400)",
+            "B3=MOD(5,0)",
+            "C1=D1*2",
+        ] {
+            app.execute(cmd).unwrap_or_default();
+        }
+        app.execute("delete_col D").unwrap();
+        let text = |app: &Spreadsheet, cell: usize| app.sheet.borrow().cell_text(cell, false);
+        let column = |app: &Spreadsheet, col| -> Vec<String> {
+            (0..3).map(|r| text(app, get_cell(r, col))).collect()
+        };
+        assert_eq!(column(&app, 0), ["#DIV/0", "ERR", "ERR"]);
+        assert_eq!(column(&app, 1), ["#VALUE", "#OVER", "#DIV/0"]);
+        assert_eq!(text(&app, 2), "#REF");
+
+        // Fixing the cause clears the errors inherited from it
+        app.execute("A1=2").unwrap();
+        assert_eq!(column(&app, 0), ["2", "3", "5"]);
+    }
+
+    #[test]
+    fn test_nested_formula_tracks_every_reference() {
+        let mut app = create_test_spreadsheet();
+        for cmd in ["B1=4", "C1=2", "D1=1", "D2=2", "D3=6"] {
+            app.execute(cmd).unwrap();
+        }
+        app.execute("A1=(B1+C1)*2-SUM(D1:D3)/3").unwrap();
+        assert_eq!(value_of(&app, 0), 9);
+
+        // Both the cells and the range feed the formula
+        app.execute("C1=5").unwrap();
+        assert_eq!(value_of(&app, 0), 15);
+        app.execute("D2=-7").unwrap();
+        assert_eq!(value_of(&app, 0), 18);
+
+        // Errors propagate from any operand, and a reference to itself is a cycle
+        app.execute("D3=1/0").unwrap();
+        assert!(app.sheet.borrow().get(0).info.invalid());
+        assert_eq!(app.execute("B1=-(A1+1)*2"), Err(StatusCode::CyclicDep));
+        app.execute("D3=6").unwrap();
+        assert_eq!(value_of(&app, 0), 18);
+        assert_eq!(app.execute("E1=B1/(C1-5)"), Ok(Some(4)));
+        assert!(app.sheet.borrow().get(4).info.invalid());
+    }
+
+    #[test]
+    fn test_harmean_tracks_its_range() {
+        let mut app = create_test_spreadsheet();
+        for cmd in ["A1=1", "A2=2", "A3=4", "B1=HARMEAN(A1:A3)"] {
+            app.execute(cmd).unwrap();
+        }
+        let harmean = |app: &Spreadsheet| app.sheet.borrow().data[1].value;
+        assert_eq!(harmean(&app), 12.0 / 7.0);
+        app.execute("A3=2").unwrap();
+        // 3 / (1 + 1/2 + 1/2)
+        assert_eq!(harmean(&app), 1.5);
+        app.execute("A1=2").unwrap();
+        assert_eq!(harmean(&app), 2.0);
+        app.execute("A2=0").unwrap();
+        assert!(app.sheet.borrow().data[1].info.invalid());
+    }
+
+    #[test]
+    fn test_two_level_aggregate_chain() {
+        // The top cell must never compute before its aggregates, whatever the order
+        for order in ["dfs", "rowmajor", "colmajor"] {
+            let mut app = create_test_spreadsheet();
+            app.execute(&format!("set recalc_order {}", order)).unwrap();
+            for cmd in ["D1=SUM(A1:A3)", "D2=SUM(B1:B3)", "D3=D1+D2"] {
+                app.execute(cmd).unwrap();
+            }
+            for (cmd, expected) in [("A1=1", 1), ("B2=10", 11), ("A3=5", 16), ("B1=-2", 14)] {
+                app.execute(cmd).unwrap();
+                assert_eq!(
+                    value_of(&app, get_cell(2, 3)),
+                    expected,
+                    "{}: {}",
+                    order,
+                    cmd
+                );
+            }
+            assert_eq!(value_of(&app, get_cell(0, 3)), 6);
+            assert_eq!(value_of(&app, get_cell(1, 3)), 8);
+        }
+    }
+
+    #[test]
+    fn test_three_level_aggregate_chain() {
+        let mut app = create_test_spreadsheet();
+        // Row sums, a sum of those, then a max over every level at once
+        for cmd in [
+            "C1=SUM(A1:B1)",
+            "C2=SUM(A2:B2)",
+            "C3=SUM(C1:C2)",
+            "E1=MAX(C1:C3)",
+            "E2=E1*2",
+        ] {
+            app.execute(cmd).unwrap();
+        }
+        app.execute("A1=3").unwrap();
+        app.execute("B2=4").unwrap();
+        assert_eq!(value_of(&app, get_cell(2, 2)), 7);
+        assert_eq!(value_of(&app, get_cell(0, 4)), 7);
+        assert_eq!(value_of(&app, get_cell(1, 4)), 14);
+
+        // A change at the bottom reaches the top through every level
+        app.execute("B1=-10").unwrap();
+        assert_eq!(value_of(&app, get_cell(2, 2)), -3);
+        assert_eq!(value_of(&app, get_cell(0, 4)), 4);
+        assert_eq!(value_of(&app, get_cell(1, 4)), 8);
+
+        // Errors propagate up the chain and clear again
+        app.execute("A2=1/0").unwrap();
+        assert!(app.sheet.borrow().data[get_cell(1, 4)].info.invalid());
+        app.execute("A2=0").unwrap();
+        assert_eq!(value_of(&app, get_cell(1, 4)), 8);
+    }
+
+    #[test]
+    fn test_adjust_is_undoable() {
+        let mut app = create_test_spreadsheet();
+        app.execute("A1=10").unwrap();
+        app.execute("B1=A1+1").unwrap();
+        assert_eq!(app.execute("mul A1 3"), Ok(Some(0)));
+        assert_eq!(value_of(&app, 1), 31);
+        assert_eq!(app.execute("add B1 1"), Err(StatusCode::InvalidCell));
+        app.execute("undo").unwrap();
+        assert_eq!(value_of(&app, 0), 10);
+        assert_eq!(value_of(&app, 1), 11);
+    }
+
+    #[test]
+    fn test_bulk_command_undoes_as_one_step() {
+        let mut app = create_test_spreadsheet();
+        for cmd in ["A1=1", "B1=2", "A2=3", "B2=A1+B1", "C1=SUM(A1:B2)"] {
+            app.execute(cmd).unwrap();
+        }
+        app.execute("clear A1:B2").unwrap();
+        assert_eq!(value_of(&app, 2), 0);
+
+        // One undo brings back every cleared cell, formulas included
+        app.execute("undo").unwrap();
+        assert_eq!(value_of(&app, 2), 9);
+        assert_eq!(app.sheet.borrow().get(get_cell(1, 1)).info.function_id, 2);
+
+        // Redo clears all of them again, and the restored formulas stay live
+        app.execute("redo").unwrap();
+        assert_eq!(value_of(&app, 2), 0);
+        app.execute("undo").unwrap();
+        app.execute("A1=5").unwrap();
+        assert_eq!(value_of(&app, get_cell(1, 1)), 7);
+        assert_eq!(value_of(&app, 2), 17);
+    }
+
+    #[test]
+    fn test_batch_commits_as_one_recalculation_and_undo_step() {
+        let mut app = create_test_spreadsheet();
+        app.execute("C1=SUM(A1:B1)").unwrap();
+        let runs = |app: &Spreadsheet| app.graph.profile.get(2).runs;
+        assert_eq!(runs(&app), 1);
+
+        let parse = |app: &mut Spreadsheet, expr| app.parser_ctx.parse_expression(expr).unwrap();
+        // B1 refers to A1 before A1 is written, and nothing shows until the commit
+        let b1 = parse(&mut app, "A1+1");
+        app.begin_batch();
+        app.graph.update_expression(1, &b1).unwrap();
+        app.graph.update_expression(0, &Info::literal(4)).unwrap();
+        assert_eq!(value_of(&app, 1), 0);
+        app.commit_batch("batch").unwrap();
+        assert_eq!([value_of(&app, 1), value_of(&app, 2)], [5, 9]);
+        assert_eq!(runs(&app), 2);
+        app.execute("undo").unwrap();
+        assert_eq!([value_of(&app, 0), value_of(&app, 1)], [0, 0]);
+
+        // A cycle is found among the queued writes, and none of them is applied
+        let (a1, b1) = (parse(&mut app, "B1"), parse(&mut app, "A1"));
+        app.begin_batch();
+        app.graph.update_expression(0, &a1).unwrap();
+        app.graph.update_expression(1, &b1).unwrap();
+        assert_eq!(app.commit_batch("batch"), Err(StatusCode::CyclicDep));
+        assert_eq!(app.sheet.borrow().cell_text(1, true), "");
+
+        // A fill recomputes the sum once, not once per copied cell
+        app.execute("A1=1").unwrap();
+        let before = runs(&app);
+        app.execute("fill A1:B1").unwrap();
+        assert_eq!(value_of(&app, 2), 2);
+        assert_eq!(runs(&app), before + 1);
+    }
+
+    #[test]
+    fn test_parse_errors_say_where() {
+        let mut app = create_test_spreadsheet();
+        let result = app.run_command("A1=2**3");
+        assert_eq!(result.status, StatusCode::InvalidCmd);
+        assert_eq!(
+            result.message.as_deref(),
+            Some("unexpected token '*' at column 6")
+        );
+        assert_eq!(app.last_parse_error().map(|e| e.offset), Some(5));
+        // Only formulas have a position to report
+        assert_eq!(app.run_command("bogus").message, None);
+        assert_eq!(app.last_parse_error(), None);
+    }
+
+    #[test]
+    fn test_selection_summary_follows_the_cells() {
+        let mut app = create_test_spreadsheet();
+        assert_eq!(app.selection_summary(), None);
+        for command in ["A1=1", "B1=4", "A2=A1+1", "select A1:B2"] {
+            app.execute(command).unwrap();
+        }
+        assert_eq!(
+            app.selection_summary().as_deref(),
+            Some("SUM=7 AVG=1.75 COUNT=3 MIN=0 MAX=4")
+        );
+        // The aggregates are no formulas, so nothing depends on the selection
+        assert_eq!(app.graph.transitive_dependents(0), [get_cell(1, 0)]);
+        app.execute("A1=1/0").unwrap();
+        assert_eq!(
+            app.selection_summary().as_deref(),
+            Some("SUM=ERR AVG=ERR COUNT=3 MIN=ERR MAX=ERR")
+        );
+        app.execute("select off").unwrap();
+        assert_eq!(app.selection_summary(), None);
+    }
+
+    #[test]
+    fn test_countlabel_counts_matching_labels() {
+        let mut app = create_test_spreadsheet();
+        for command in [
+            "A1=\"North\"",
+            "A2=\"Northeast\"",
+            "A3=\"South\"",
+            "A4=5",
+            "B1=COUNTLABEL(A1:A5,\"North*\")",
+            "B2=countlabel( a1:a5 , \"?o*\" )*10",
+            "B3=COUNTLABEL(A1:A5,\"north*\")",
+        ] {
+            app.execute(command).unwrap();
+        }
+        assert_eq!(value_of(&app, 1), 2);
+        assert_eq!(value_of(&app, get_cell(1, 1)), 30);
+        // The pattern keeps its case and matches it
+        assert_eq!(value_of(&app, get_cell(2, 1)), 0);
+        assert_eq!(
+            app.sheet
+                .borrow()
+                .formula_text(&app.sheet.borrow().get(get_cell(1, 1)).info),
+            "COUNTLABEL(A1:A5,\"?o*\")*10"
+        );
+
+        // Editing a label of the range recalculates the count
+        app.execute("A5=\"Northwest\"").unwrap();
+        assert_eq!(value_of(&app, 1), 3);
+        app.execute("A2=7").unwrap();
+        assert_eq!(value_of(&app, 1), 2);
+
+        for bad in [
+            "C1=COUNTLABEL(A1:A5)",
+            "C1=COUNTLABEL(A1:A5,North)",
+            "C1=COUNTLABEL(A1:A5,\"North)",
+            "C1=1+\"North\"",
+        ] {
+            assert!(app.execute(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_rotate_sheet_forgets_cached_formulas() {
+        let mut app = create_test_spreadsheet();
+        app.execute("A1=A50").unwrap();
+        let parsed = app.parser_ctx.parse_count;
+        app.execute("rotate_sheet cw").unwrap();
+        // The cached `A50` was indexed for the old shape, so it is parsed again
+        app.execute("B1=A50").unwrap();
+        assert_eq!(app.parser_ctx.parse_count, parsed + 1);
+        app.execute("rotate_sheet ccw").unwrap();
+    }
+
+    #[test]
+    fn test_genrandom_fills_a_range_as_one_undo_step() {
+        let mut app = create_test_spreadsheet();
+        app.execute("K1=SUM(A1:J100)").unwrap();
+        app.execute("genrandom A1:J100 min 0 max 50").unwrap();
+        let end = get_cell(99, 9);
+        let sheet = app.sheet.borrow();
+        let values: Vec<i32> = (0..=end)
+            .filter(|&cell| sheet.get_row_and_column(cell).1 < 10)
+            .map(|cell| sheet.data[cell].value as i32)
+            .collect();
+        drop(sheet);
+        assert_eq!(values.len(), 1000);
+        assert!(values.iter().all(|v| (0..=50).contains(v)));
+        assert_eq!(value_of(&app, 10), values.iter().sum::<i32>());
+
+        app.execute("undo").unwrap();
+        assert_eq!(value_of(&app, 0), 0);
+        assert_eq!(value_of(&app, end), 0);
+        assert_eq!(value_of(&app, 10), 0);
+    }
+
+    #[test]
+    fn test_readonly_rejects_every_edit() {
+        let mut app = create_test_spreadsheet();
+        app.execute("A1=2").unwrap();
+        app.execute("B1=A1*3").unwrap();
+        app.set_readonly(true);
+        for command in [
+            "A1=5",
+            "C1=A1:A2+1",
+            "clear A1:B1",
+            "undo",
+            "lock A1",
+            "fill A1:B1",
+        ] {
+            assert_eq!(
+                app.execute(command),
+                Err(StatusCode::ReadOnly),
+                "{}",
+                command
+            );
+        }
+        let writes = [(0, Info::literal(9))];
+        assert_eq!(app.write_cells(&writes, "p"), Err(StatusCode::ReadOnly));
+        // Views and queries still work
+        for command in ["w", "select A1:B1", "deps B1", "disable_output"] {
+            assert_eq!(app.execute(command), Ok(None), "{}", command);
+        }
+        assert_eq!([value_of(&app, 0), value_of(&app, 1)], [2, 6]);
+
+        app.execute("set noreadonly").unwrap();
+        app.execute("A1=5").unwrap();
+        assert_eq!(value_of(&app, 1), 15);
+        app.execute("set readonly").unwrap();
+        assert!(app.readonly());
+    }
+
+    #[test]
+    fn test_replace_rewrites_every_formula_as_one_undo_step() {
+        let mut app = create_test_spreadsheet();
+        for cmd in [
+            "A1=3",
+            "C1=4",
+            "B1=A1*100",
+            "A2=SUM(A1:D1)",
+            "B2=$A$1+100/2",
+        ] {
+            app.execute(cmd).unwrap();
+        }
+        app.execute("replace A1 with C1").unwrap();
+        let formula = |app: &Spreadsheet, cell| app.sheet.borrow().cell_text(cell, true);
+        assert_eq!(formula(&app, 1), "C1*100");
+        // A moved corner still makes a range, and fixed parts stay fixed
+        assert_eq!(formula(&app, get_cell(1, 0)), "SUM(C1:D1)");
+        assert_eq!(formula(&app, get_cell(1, 1)), "$C$1+100/2");
+        assert_eq!(value_of(&app, 1), 400);
+
+        app.execute("replace_text 100 with 10").unwrap();
+        assert_eq!(formula(&app, 1), "C1*10");
+        assert_eq!(formula(&app, get_cell(1, 1)), "$C$1+10/2");
+        assert_eq!(value_of(&app, get_cell(1, 0)), 4);
+
+        // Each replace is undone in one step
+        app.execute("undo").unwrap();
+        app.execute("undo").unwrap();
+        assert_eq!(formula(&app, 1), "A1*100");
+        assert_eq!(formula(&app, get_cell(1, 1)), "$A$1+100/2");
+        assert_eq!(value_of(&app, 1), 300);
+
+        // B1 would read itself, so nothing is rewritten
+        assert_eq!(
+            app.execute("replace A1 with B1"),
+            Err(StatusCode::CyclicDep)
+        );
+        assert_eq!(formula(&app, get_cell(1, 0)), "SUM(A1:D1)");
+    }
+
+    #[test]
+    fn test_fill_shifts_relative_references() {
+        let mut app = create_test_spreadsheet();
+        for cmd in [
+            "A1=1",
+            "A2=A1+1",
+            "B1=SUM($A$1:A1)",
+            "fill A2:A5",
+            "fill B1:B3",
+        ] {
+            app.execute(cmd).unwrap();
+        }
+        assert_eq!(value_of(&app, get_cell(4, 0)), 5);
+        assert_eq!(value_of(&app, get_cell(2, 1)), 6);
+        let formula = |app: &Spreadsheet, cell| app.sheet.borrow().cell_text(cell, true);
+        assert_eq!(formula(&app, get_cell(4, 0)), "A4+1");
+        assert_eq!(formula(&app, get_cell(2, 1)), "SUM($A$1:A3)");
+
+        // The copies stay live, and the whole fill undoes as one step
+        app.execute("A1=10").unwrap();
+        assert_eq!(value_of(&app, get_cell(4, 0)), 14);
+        app.execute("undo").unwrap();
+        app.execute("undo").unwrap();
+        assert_eq!(formula(&app, get_cell(2, 1)), "");
+        assert_eq!(formula(&app, get_cell(4, 0)), "A4+1");
+
+        // A copy that would read past the sheet rejects the whole fill
+        let last = format!("C{}", TEST_ROWS);
+        app.execute("C1=A2").unwrap();
+        assert_eq!(
+            app.execute(&format!("fill C1:{}", last)),
+            Err(StatusCode::InvalidRange)
+        );
+        assert_eq!(formula(&app, get_cell(1, 2)), "");
+    }
+
+    #[test]
+    fn test_locked_cells_reject_changes() {
+        let mut app = create_test_spreadsheet();
+        for cmd in ["A1=1", "A2=A1+1", "B1=5", "lock A2:B2", "lock B1"] {
+            app.execute(cmd).unwrap();
+        }
+        assert_eq!(app.execute("B1=6"), Err(StatusCode::CellLocked));
+        assert_eq!(app.execute("fill A1:A3"), Err(StatusCode::CellLocked));
+        assert_eq!(
+            app.execute("dupregion A1:A1 B1"),
+            Err(StatusCode::CellLocked)
+        );
+        assert_eq!(app.execute("clear A1:B1"), Err(StatusCode::CellLocked));
+        assert_eq!(app.execute("delete_row 2"), Err(StatusCode::CellLocked));
+        assert_eq!(value_of(&app, 0), 1);
+
+        // Locked formulas still recompute, and move with their rows
+        app.execute("A1=3").unwrap();
+        assert_eq!(value_of(&app, get_cell(1, 0)), 4);
+        app.execute("insert_row 1").unwrap();
+        assert!(app.sheet.borrow().is_locked(get_cell(2, 0)));
+        assert_eq!(value_of(&app, get_cell(2, 0)), 4);
+        app.execute("delete_row 1").unwrap();
+
+        // Undo does not reach past a locked cell until it is unlocked
+        app.execute("unlock B1").unwrap();
+        app.execute("B1=6").unwrap();
+        app.execute("lock B1").unwrap();
+        assert_eq!(app.execute("undo"), Err(StatusCode::CellLocked));
+        app.execute("unlock A1:B1").unwrap();
+        app.execute("undo").unwrap();
+        assert_eq!(value_of(&app, 1), 5);
+    }
+
+    #[test]
+    fn test_row_and_column_follow_their_cells() {
+        let mut app = create_test_spreadsheet();
+        for cmd in [
+            "B3=ROW()",
+            "C2=COLUMN()",
+            "D4=ROW()*100+COLUMN()",
+            "A1=ROW(C7)+COLUMN(E2)",
+        ] {
+            app.execute(cmd).unwrap();
+        }
+        let value =
+            |app: &Spreadsheet, name: &str| value_of(app, parser::cell_parser(name).unwrap());
+        assert_eq!(value(&app, "B3"), 3);
+        assert_eq!(value(&app, "C2"), 3);
+        assert_eq!(value(&app, "D4"), 404);
+        assert_eq!(value(&app, "A1"), 12);
+        assert_eq!(
+            app.sheet.borrow().cell_text(get_cell(3, 3), true),
+            "ROW()*100+COLUMN()"
+        );
+
+        // Moving a cell, or the cell it names, changes the position
+        app.execute("insert_row 1").unwrap();
+        app.execute("insert_col A").unwrap();
+        assert_eq!(value(&app, "C4"), 4);
+        assert_eq!(value(&app, "E5"), 505);
+        assert_eq!(value(&app, "B2"), 14);
+        assert_eq!(
+            app.sheet.borrow().cell_text(get_cell(1, 1), true),
+            "ROW(D8)+COLUMN(F3)"
+        );
+        // Each copy of a filled formula reads its own position
+        app.execute("A10=ROW()").unwrap();
+        app.execute("fill A10:A12").unwrap();
+        assert_eq!(value(&app, "A12"), 12);
+    }
+
+    #[test]
+    fn test_random_functions_recompute_on_every_edit() {
+        let values = |app: &Spreadsheet| {
+            let sheet = app.sheet.borrow();
+            [0, 1, 2].map(|col| sheet.get(get_cell(0, col)).value)
+        };
+        let run = || {
+            let mut app = create_test_spreadsheet();
+            app.execute("seed 42").unwrap();
+            for cmd in ["A1=RAND()", "B1=RANDBETWEEN(1,6)", "C1=B1*10+RAND()"] {
+                app.execute(cmd).unwrap();
+            }
+            app
+        };
+        let mut app = run();
+        assert_eq!(values(&app), values(&run()));
+        assert_eq!(app.sheet.borrow().cell_text(0, true), "RAND()");
+        assert_eq!(app.sheet.borrow().cell_text(2, true), "B1*10+RAND()");
+
+        // An unrelated edit draws new numbers, within their bounds
+        let mut rolls = HashSet::new();
+        for i in 0..50 {
+            let before = values(&app);
+            app.execute(&format!("E1={}", i)).unwrap();
+            let [a, b, c] = values(&app);
+            assert_ne!(a, before[0]);
+            assert!((0.0..1.0).contains(&a));
+            assert!((1.0..=6.0).contains(&b) && b.fract() == 0.0);
+            assert_eq!((c / 10.0).floor(), b);
+            rolls.insert(b as i32);
+        }
+        assert!(rolls.len() > 1);
+
+        // Reseeding recomputes them, reproducibly
+        app.execute("seed 7").unwrap();
+        let reseeded = values(&app);
+        app.execute("seed 7").unwrap();
+        assert_eq!(values(&app), reseeded);
+
+        app.execute("D1=RANDBETWEEN(5,1)").unwrap();
+        assert!(app.sheet.borrow().get(3).info.invalid());
+        app.execute("A1=1").unwrap();
+        assert_eq!(
+            app.graph.volatile.iter().copied().collect::<Vec<_>>(),
+            [1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn test_dates_are_day_numbers_shown_as_dates() {
+        let mut app = create_test_spreadsheet();
+        for cmd in [
+            "A1=DATE(2024,1,31)",
+            "B1=A1+30",
+            "C1=DAYS(B1,A1)",
+            "D1=TODAY()",
+            "E1=DATE(2024,14,0)",
+            "format A1:B1 date",
+        ] {
+            app.execute(cmd).unwrap();
+        }
+        let text = |app: &Spreadsheet, cell: usize| app.sheet.borrow().cell_text(cell, false);
+        assert_eq!(value_of(&app, 0), 19753);
+        assert_eq!(text(&app, 0), "2024-01-31");
+        assert_eq!(text(&app, 1), "2024-03-01");
+        assert_eq!(text(&app, 2), "30");
+        assert_eq!(value_of(&app, 3), date::today() as i32);
+        assert_eq!(value_of(&app, 4), date::serial(2025, 1, 31) as i32);
+        assert_eq!(app.sheet.borrow().cell_text(0, true), "DATE(2024,1,31)");
+        assert_eq!(app.sheet.borrow().cell_text(3, true), "TODAY()");
+        assert!(app.graph.volatile.contains(&3));
+
+        // Numbers outside the calendar keep their usual text
+        app.execute("B1=-800000").unwrap();
+        assert_eq!(text(&app, 1), "-800000");
+        app.execute("format B1 general").unwrap();
+        app.execute("A2=DATE(2024,3/2,1)").unwrap();
+        assert!(app.sheet.borrow().get(get_cell(1, 0)).info.invalid());
+        assert_eq!(
+            app.execute("format A1 calendar"),
+            Err(StatusCode::InvalidCmd)
+        );
+    }
+
+    #[test]
+    fn test_lookup_functions_read_tables() {
+        let mut app = create_test_spreadsheet();
+        // A price table: codes in A1:A3, prices in B1:B3
+        for cmd in [
+            "A1=10",
+            "A2=20",
+            "A3=30",
+            "B1=5",
+            "B2=7",
+            "B3=9",
+            "C1=INDEX(A1:B3,2,2)",
+            "C2=MATCH(30,A1:A3)",
+            "C3=VLOOKUP(20,A1:B3,2)*2",
+            "C4=INDEX(A1:B3,MATCH(10,A1:A3),2)",
+        ] {
+            app.execute(cmd).unwrap();
+        }
+        assert_eq!(
+            [2, 3, 4, 5].map(|row| value_of(&app, get_cell(row - 2, 2))),
+            [7, 3, 14, 5]
+        );
+        let text = |app: &Spreadsheet, cell| app.sheet.borrow().cell_text(cell, true);
+        assert_eq!(text(&app, get_cell(2, 2)), "VLOOKUP(20,A1:B3,2)*2");
+
+        // The tables are dependencies, so the lookups follow edits to them
+        app.execute("B2=8").unwrap();
+        app.execute("A1=40").unwrap();
+        assert_eq!(value_of(&app, 2), 8);
+        assert_eq!(value_of(&app, get_cell(2, 2)), 16);
+        assert!(app.sheet.borrow().get(get_cell(3, 2)).info.invalid());
+        assert_eq!(app.sheet.borrow().cell_text(get_cell(3, 2), false), "#N/A");
+
+        let shown = |app: &mut Spreadsheet, formula: &str| {
+            app.execute(&format!("D1={}", formula)).unwrap();
+            app.sheet.borrow().cell_text(get_cell(0, 3), false)
+        };
+        assert_eq!(shown(&mut app, "INDEX(A1:B3,4,1)"), "#REF");
+        assert_eq!(shown(&mut app, "VLOOKUP(20,A1:B3,3)"), "#REF");
+        assert_eq!(shown(&mut app, "MATCH(99,A1:A3)"), "#N/A");
+        assert_eq!(shown(&mut app, "MATCH(7,A1:B3)"), "#VALUE");
+        assert_eq!(app.execute("D1=MATCH(A1,B1)"), Err(StatusCode::InvalidCmd));
+        assert_eq!(
+            app.execute("D1=INDEX(A1:A3,1)"),
+            Err(StatusCode::InvalidCmd)
+        );
+    }
+
+    #[test]
+    fn test_array_formulas_spill_into_their_block() {
+        let mut app = create_test_spreadsheet();
+        for cmd in ["A1=1", "A2=2", "A3=3", "B1=10", "B2=20", "B3=30", "D3=7"] {
+            app.execute(cmd).unwrap();
+        }
+        assert_eq!(app.execute("C1=A1:A3+B1:B3"), Ok(Some(2)));
+        let column = |app: &Spreadsheet| {
+            (0..3)
+                .map(|r| value_of(app, get_cell(r, 2)))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(column(&app), [11, 22, 33]);
+        // Every cell of the block has its own edges
+        app.execute("B2=0").unwrap();
+        assert_eq!(column(&app), [11, 2, 33]);
+
+        // The block is one undo step, and overwrites what was there
+        app.execute("D1=A1:A3*2").unwrap();
+        assert_eq!(value_of(&app, get_cell(2, 3)), 6);
+        app.execute("undo").unwrap();
+        assert_eq!(value_of(&app, get_cell(2, 3)), 7);
+        assert!(app.sheet.borrow().get(get_cell(0, 3)).is_blank());
+
+        // A block reading itself is a cycle, and changes nothing
+        assert_eq!(app.execute("A1=A1:A3+1"), Err(StatusCode::CyclicDep));
+        assert_eq!(value_of(&app, get_cell(1, 0)), 2);
+        assert_eq!(app.execute("C1=A1:A3+B1:B2"), Err(StatusCode::InvalidCmd));
+    }
+
+    #[test]
+    fn test_subscribers_see_each_pass_that_changes_values() {
+        let mut app = create_test_spreadsheet();
+        let heard = Rc::new(RefCell::new(Vec::new()));
+        let log = heard.clone();
+        let sheet = app.sheet.clone();
+        app.subscribe(move |change: CellChange| {
+            let sheet = sheet.borrow();
+            let cells = change
+                .indices()
+                .iter()
+                .map(|&c| sheet.cell_name(c))
+                .collect();
+            log.borrow_mut().push(cells);
+        });
+        let mut changes = |app: &mut Spreadsheet, command: &str| -> Vec<Vec<String>> {
+            app.execute(command).unwrap();
+            heard.borrow_mut().drain(..).collect()
+        };
+
+        assert_eq!(changes(&mut app, "A1=2"), [["A1"]]);
+        assert_eq!(changes(&mut app, "B1=A1+1"), [["B1"]]);
+        // Same value, different formula
+        assert!(changes(&mut app, "A1=1+1").is_empty());
+        assert_eq!(changes(&mut app, "A1=5"), [["A1", "B1"]]);
+        assert_eq!(changes(&mut app, "undo"), [["A1", "B1"]]);
+        assert_eq!(value_of(&app, 1), 3);
+        assert_eq!(changes(&mut app, "C1=\"x\""), [["C1"]]);
+
+        app.execute("set_calc manual").unwrap();
+        assert_eq!(changes(&mut app, "A1=7"), [["A1"]]);
+        assert_eq!(changes(&mut app, "recalc"), [["B1"]]);
+        let change = CellChange::from_pass(&app.sheet.borrow(), &[(1, CellInfo::default())]);
+        assert_eq!(change.unwrap().cells[0].2.value, 8.0);
+    }
+
+    #[test]
+    fn test_manual_calc_defers_dependents_until_recalc() {
+        let mut app = create_test_spreadsheet();
+        for cmd in ["A1=1", "B1=A1+1", "C1=SUM(A1:B1)", "set_calc manual"] {
+            app.execute(cmd).unwrap();
+        }
+        let result = app.run_command("A1=10");
+        assert_eq!(result.status, StatusCode::Ok);
+        assert_eq!(result.message.as_deref(), Some("recalc pending"));
+        // The edited cells are computed, what reads them waits
+        app.execute("D1=A1*2").unwrap();
+        assert_eq!(value_of(&app, 0), 10);
+        assert_eq!(value_of(&app, 3), 20);
+        assert_eq!(value_of(&app, 1), 2);
+        assert_eq!(value_of(&app, 2), 3);
+
+        app.execute("recalc").unwrap();
+        assert_eq!(value_of(&app, 1), 11);
+        assert_eq!(value_of(&app, 2), 21);
+        assert_eq!(
+            app.run_command("E1=1").message.as_deref(),
+            Some("recalc pending")
+        );
+
+        // Cycles are still rejected, and going back to auto catches up
+        assert_eq!(app.execute("A1=C1"), Err(StatusCode::CyclicDep));
+        app.execute("A1=5").unwrap();
+        app.execute("set_calc auto").unwrap();
+        assert_eq!(value_of(&app, 2), 11);
+        assert_eq!(app.run_command("A1=6").message, None);
+        assert_eq!(value_of(&app, 2), 13);
+    }
+
+    #[test]
+    fn test_history_depth_limits_undo() {
+        let mut app = create_test_spreadsheet();
+        app.execute("set history_depth 2").unwrap();
+        for cmd in ["A1=1", "A1=2", "A1=3"] {
+            app.execute(cmd).unwrap();
+        }
+        app.execute("undo").unwrap();
+        app.execute("undo").unwrap();
+        assert_eq!(value_of(&app, 0), 1);
+        assert_eq!(app.execute("undo"), Err(StatusCode::NothingToUndo));
+        assert_eq!(app.execute("history"), Ok(None));
+    }
+}
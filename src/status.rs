@@ -1,9 +1,8 @@
 // status.rs
-//! This module provides status code tracking and time-based feedback for command execution.
-use lazy_static::lazy_static;
+//! This module describes the outcome of each command: its status code, how long it took
+//! and any detail worth showing, plus the one-line status printed before the prompt.
 use std::io::{self, Write};
-use std::sync::Mutex;
-use std::time::{Duration, SystemTime};
+use std::time::Duration;
 /// Represents various status codes that indicate different system states.
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum StatusCode {
@@ -27,18 +26,15 @@ pub enum StatusCode {
     OutOfBounds,
     /// The provided value is not valid.
     InvalidValue,
+    /// The cell is protected by `lock` and cannot be changed.
+    CellLocked,
+    /// The sheet was opened read-only (`--readonly`, `set readonly`), so it cannot be changed.
+    ReadOnly,
     /// An internal error has occurred.
     InternalError,
 }
-/// Global mutex to hold the current system status code
-lazy_static! {
-    /// tells the commands
-    pub static ref STATUS_CODE: Mutex<StatusCode> = Mutex::new(StatusCode::Ok);
-     /// Tracks the last command execution time.
-    static ref LAST_CMD_TIME: Mutex<SystemTime> = Mutex::new(SystemTime::now());
-}
 /// Status messages associated with each `StatusCode`.
-const STATUS_MSG: [&str; 10] = [
+const STATUS_MSG: [&str; 12] = [
     "ok",
     "invalid command",
     "overflow occurred",
@@ -47,64 +43,93 @@ const STATUS_MSG: [&str; 10] = [
     "cyclic dependency found",
     "Nothing to undo",
     "Nothing to redo",
-    "scrolling out of sheet",
+    "out of sheet bounds",
     "invalid value",
+    "cell is locked",
+    "sheet is read-only",
 ];
-/// Resets the start time to the current system time.
-///
-/// This is used to track the elapsed time since the last command execution.
-pub fn start_time() {
-    *LAST_CMD_TIME.lock().unwrap() = SystemTime::now();
-}
-
-/// Updates the global status code.
-///
-/// # Arguments
-/// - `status`: The new `StatusCode` to set.
+/// Returns the human-readable message for a status code, as shown in the prompt.
 ///
 /// # Examples
 /// ```
-/// set_status_code(StatusCode::InvalidCmd);
-/// assert_eq!(get_status_code(), StatusCode::InvalidCmd);
+/// assert_eq!(status_message(StatusCode::CyclicDep), "cyclic dependency found");
 /// ```
-pub fn set_status_code(status: StatusCode) {
-    *STATUS_CODE.lock().unwrap() = status;
+pub fn status_message(status: StatusCode) -> &'static str {
+    STATUS_MSG
+        .get(status as usize)
+        .copied()
+        .unwrap_or("internal error")
 }
-/// Retrieves the current system status code.
-///
-/// # Returns
-/// The current `StatusCode`.
-///
-/// # Examples
-/// ```
-/// assert_eq!(get_status_code(), StatusCode::Ok);
-/// ```
-pub fn get_status_code() -> StatusCode {
-    *STATUS_CODE.lock().unwrap()
+
+/// The outcome of one command, returned by `Spreadsheet::run_command`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandResult {
+    /// How the command ended.
+    pub status: StatusCode,
+    /// The cell the command assigned, if it was an assignment that succeeded.
+    pub cell: Option<usize>,
+    /// Time taken to run the command.
+    pub elapsed: Duration,
+    /// Detail about the status, e.g. `cycle: A1 -> B1 -> A1`.
+    pub message: Option<String>,
 }
 
-/// Prints the current status message along with the elapsed time since the last command.
-///
-/// The format is: `[<elapsed_seconds>] (<status_message>) >`
+impl Default for CommandResult {
+    fn default() -> Self {
+        Self::new(StatusCode::Ok)
+    }
+}
+
+impl CommandResult {
+    /// Creates a result with `status`, no cell, no message and no elapsed time.
+    pub fn new(status: StatusCode) -> Self {
+        Self {
+            status,
+            cell: None,
+            elapsed: Duration::ZERO,
+            message: None,
+        }
+    }
+    /// Converts the result into the `Result` returned by `Spreadsheet::execute`.
+    pub fn into_result(self) -> Result<Option<usize>, StatusCode> {
+        match self.status {
+            StatusCode::Ok => Ok(self.cell),
+            status => Err(status),
+        }
+    }
+    /// Formats the status line shown before the prompt.
+    ///
+    /// The format is: `[<elapsed_seconds>] (<status_message>) >`, or
+    /// `[<elapsed_seconds>] (<status_message>, <message>) >` when a message is set.
+    ///
+    /// # Examples
+    /// ```
+    /// let result = CommandResult::new(StatusCode::Overflow);
+    /// assert_eq!(result.status_line(), "[0.0] (overflow occurred) >");
+    /// ```
+    pub fn status_line(&self) -> String {
+        format!("{} >", self.summary())
+    }
+    /// Formats the status line without its prompt, e.g. `[0.0] (ok)`, as vim's status
+    /// bar shows it.
+    pub fn summary(&self) -> String {
+        let elapsed = self.elapsed.as_secs_f64();
+        let msg = STATUS_MSG[self.status as usize];
+        match &self.message {
+            Some(message) => format!("[{:.1}] ({}, {})", elapsed, msg, message),
+            None => format!("[{:.1}] ({})", elapsed, msg),
+        }
+    }
+}
+
+/// Prints the status line of the last command, without a newline.
 ///
 /// # Examples
 /// ```
-/// start_time();
-/// set_status_code(StatusCode::Overflow);
-/// print_status();
+/// print_status(&CommandResult::new(StatusCode::Overflow));
 /// ```
-pub fn print_status() {
-    let elapsed = LAST_CMD_TIME
-        .lock()
-        .unwrap()
-        .elapsed()
-        .unwrap_or(Duration::ZERO)
-        .as_secs_f64();
-
-    let status = *STATUS_CODE.lock().unwrap();
-    let msg = STATUS_MSG[status as usize];
-
-    print!("[{:.1}] ({}) >", elapsed, msg);
+pub fn print_status(result: &CommandResult) {
+    print!("{}", result.status_line());
     io::stdout().flush().unwrap();
 }
 
@@ -115,13 +140,46 @@ mod tests {
     use std::thread::sleep;
     use std::time::{Duration, SystemTime};
 
+    #[test]
+    fn test_status_message() {
+        assert_eq!(status_message(StatusCode::Ok), "ok");
+        assert_eq!(status_message(StatusCode::InvalidValue), "invalid value");
+        assert_eq!(status_message(StatusCode::CellLocked), "cell is locked");
+        assert_eq!(status_message(StatusCode::InternalError), "internal error");
+    }
+
     #[test]
     #[should_panic]
     fn test_print_status_internal_error() {
-        // The STATUS_MSG array is defined with 10 elements (indices 0..9)
-        // but StatusCode::InternalError, when cast as usize, equals 10.
+        // The STATUS_MSG array is defined with 12 elements (indices 0..11)
+        // but StatusCode::InternalError, when cast as usize, equals 12.
         // This should cause an out-of-bound panic when attempting to index STATUS_MSG.
-        set_status_code(StatusCode::InternalError);
-        print_status();
+        print_status(&CommandResult::new(StatusCode::InternalError));
+    }
+
+    #[test]
+    fn test_status_line_with_message() {
+        let result = CommandResult {
+            elapsed: Duration::from_millis(1250),
+            message: Some("cycle: A1 -> A1".to_string()),
+            ..CommandResult::new(StatusCode::CyclicDep)
+        };
+        assert_eq!(
+            result.status_line(),
+            "[1.2] (cyclic dependency found, cycle: A1 -> A1) >"
+        );
+        assert_eq!(
+            result.summary(),
+            "[1.2] (cyclic dependency found, cycle: A1 -> A1)"
+        );
+        assert_eq!(result.into_result(), Err(StatusCode::CyclicDep));
+        assert_eq!(
+            CommandResult {
+                cell: Some(3),
+                ..CommandResult::default()
+            }
+            .into_result(),
+            Ok(Some(3))
+        );
     }
 }
@@ -0,0 +1,202 @@
+// structure.rs
+//! This module implements structural commands, which move formulas around the sheet
+//! rather than computing new values, and rewrite the references that move with them.
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+use crate::graph::Graph;
+use crate::status::StatusCode;
+
+/// What a row of `sort` is ordered by: the cell of its key column.
+#[derive(Debug, PartialEq)]
+enum SortKey {
+    Number(f64),
+    Text(Arc<str>),
+    Error,
+}
+
+impl SortKey {
+    /// Orders numbers before text and text before errors; `descending` reverses the order
+    /// among numbers and among text only, so errors always come last.
+    fn compare(&self, other: &Self, descending: bool) -> Ordering {
+        let order = match (self, other) {
+            (SortKey::Number(a), SortKey::Number(b)) => a.total_cmp(b),
+            (SortKey::Text(a), SortKey::Text(b)) => a.cmp(b),
+            _ => return self.rank().cmp(&other.rank()),
+        };
+        if descending { order.reverse() } else { order }
+    }
+    /// The group a key sorts into.
+    fn rank(&self) -> u8 {
+        match self {
+            SortKey::Number(_) => 0,
+            SortKey::Text(_) => 1,
+            SortKey::Error => 2,
+        }
+    }
+}
+
+/// Reorders the rows of `start:end` by the values in column `key`, ascending unless
+/// `descending` is set. Rows with equal keys keep their order.
+///
+/// Formulas move with their rows. A reference to a cell of the range, or to a range
+/// within one of its rows, follows that row to its new place; references to anything
+/// else, including ranges spanning several rows of it, are left as they are. Formats,
+/// roles and notes stay where they are. All cells change at once, so the command is one
+/// undo step, and nothing changes if the moved formulas would form a cycle.
+///
+/// Returns `InvalidRange` if column `key` is outside the range.
+pub fn sort_rows(
+    graph: &mut Graph,
+    start: usize,
+    end: usize,
+    key: usize,
+    descending: bool,
+) -> Result<(), StatusCode> {
+    let writes = {
+        let sheet = graph.sheet.borrow();
+        let (r1, c1) = sheet.get_row_and_column(start);
+        let (r2, c2) = sheet.get_row_and_column(end);
+        if !(c1..=c2).contains(&key) {
+            return Err(StatusCode::InvalidRange);
+        }
+
+        let keys: Vec<SortKey> = (r1..=r2)
+            .map(|row| {
+                let data = sheet.get(sheet.get_cell(row, key));
+                match data.text() {
+                    Some(text) => SortKey::Text(text),
+                    None if data.info.invalid() => SortKey::Error,
+                    None => SortKey::Number(data.value),
+                }
+            })
+            .collect();
+        // `order[i]` is the row that ends up at row `r1 + i`
+        let mut order: Vec<usize> = (r1..=r2).collect();
+        order.sort_by(|&a, &b| keys[a - r1].compare(&keys[b - r1], descending));
+        let mut target = vec![0; order.len()];
+        for (i, &row) in order.iter().enumerate() {
+            target[row - r1] = r1 + i;
+        }
+
+        // Moves a range that lies within one row of the block along with that row
+        let follow = |start: usize, end: usize| {
+            let (row, first) = sheet.get_row_and_column(start);
+            let (other_row, last) = sheet.get_row_and_column(end);
+            let inside = (c1..=c2).contains(&first) && (c1..=c2).contains(&last);
+            if row == other_row && (r1..=r2).contains(&row) && inside {
+                let row = target[row - r1];
+                Some((sheet.get_cell(row, first), sheet.get_cell(row, last)))
+            } else {
+                Some((start, end))
+            }
+        };
+
+        let mut writes = Vec::new();
+        for (i, &row) in order.iter().enumerate() {
+            for c in c1..=c2 {
+                let original = sheet.get(sheet.get_cell(row, c)).info;
+                let info = original.map_ranges(follow).unwrap_or(original);
+                // A row that stays put only changes where it reads a moved row
+                if row != r1 + i || info.arg != original.arg {
+                    writes.push((sheet.get_cell(r1 + i, c), info));
+                }
+            }
+        }
+        writes
+    };
+    graph.update_expressions(&writes)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::ParserContext;
+    use crate::sheet::{Sheet, TEST_COLS, TEST_ROWS, get_cell, init_test_dimensions};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn create_test_graph() -> Graph {
+        init_test_dimensions();
+        let sheet = Rc::new(RefCell::new(Sheet::new(TEST_ROWS, TEST_COLS)));
+        Graph::new(TEST_ROWS, TEST_COLS, sheet)
+    }
+
+    fn set(graph: &mut Graph, cell: usize, expr: &str) {
+        let info = ParserContext::new().parse_expression(expr).unwrap();
+        graph.update_expression(cell, &info).unwrap();
+    }
+
+    fn column(graph: &Graph, col: usize, rows: usize) -> Vec<String> {
+        let sheet = graph.sheet.borrow();
+        (0..rows)
+            .map(|r| sheet.cell_text(get_cell(r, col), false))
+            .collect()
+    }
+
+    #[test]
+    fn test_sort_moves_formulas_with_their_rows() {
+        let mut graph = create_test_graph();
+        // A holds the keys, B formulas reading their own row
+        for (row, key) in [(0, "3"), (1, "\"b\""), (2, "1"), (3, "1/0"), (4, "2")] {
+            set(&mut graph, get_cell(row, 0), key);
+            set(&mut graph, get_cell(row, 1), &format!("A{}*10", row + 1));
+        }
+        set(&mut graph, get_cell(0, 2), "SUM(B1:B3)");
+        set(&mut graph, get_cell(1, 2), "B1+1");
+
+        sort_rows(&mut graph, 0, get_cell(4, 1), 0, false).unwrap();
+        assert_eq!(column(&graph, 0, 5), ["1", "2", "3", "b", "#DIV/0"]);
+        assert_eq!(column(&graph, 1, 5), ["10", "20", "30", "#VALUE", "ERR"]);
+        {
+            let sheet = graph.sheet.borrow();
+            assert_eq!(sheet.cell_text(get_cell(2, 1), true), "A3*10");
+            // Formulas outside the range keep reading the same cells
+            assert_eq!(sheet.cell_text(get_cell(1, 2), true), "B1+1");
+            assert_eq!(sheet.get(get_cell(0, 2)).value, 60.0);
+        }
+
+        sort_rows(&mut graph, 0, get_cell(4, 1), 0, true).unwrap();
+        assert_eq!(column(&graph, 0, 5), ["3", "2", "1", "b", "#DIV/0"]);
+        assert_eq!(column(&graph, 1, 5), ["30", "20", "10", "#VALUE", "ERR"]);
+        assert_eq!(
+            sort_rows(&mut graph, 0, get_cell(4, 1), 2, true),
+            Err(StatusCode::InvalidRange)
+        );
+    }
+
+    #[test]
+    fn test_sort_swaps_rows_that_read_each_other() {
+        let mut graph = create_test_graph();
+        set(&mut graph, get_cell(0, 0), "2");
+        set(&mut graph, get_cell(0, 1), "7");
+        set(&mut graph, get_cell(1, 0), "1");
+        set(&mut graph, get_cell(1, 1), "B1+1");
+        // Written one at a time, B1=B2+1 would meet the old B2=B1+1 in a cycle
+        sort_rows(&mut graph, 0, get_cell(1, 1), 0, false).unwrap();
+        assert_eq!(column(&graph, 1, 2), ["8", "7"]);
+        assert_eq!(graph.sheet.borrow().cell_text(1, true), "B2+1");
+    }
+
+    #[test]
+    fn test_sort_rejects_cycles_through_other_cells() {
+        let mut graph = create_test_graph();
+        set(&mut graph, get_cell(0, 0), "2");
+        set(&mut graph, get_cell(1, 0), "1");
+        set(&mut graph, get_cell(1, 1), "5");
+        set(&mut graph, get_cell(0, 2), "B2");
+        set(&mut graph, get_cell(0, 1), "C1");
+        // B1=C1 would move to B2, which C1 reads
+        assert_eq!(
+            sort_rows(&mut graph, 0, get_cell(1, 1), 0, false),
+            Err(StatusCode::CyclicDep)
+        );
+        assert_eq!(column(&graph, 0, 2), ["2", "1"]);
+        assert_eq!(column(&graph, 1, 2), ["5", "5"]);
+        assert_eq!(graph.sheet.borrow().cell_text(1, true), "C1");
+        // The graph was restored too: C1 still follows B2
+        set(&mut graph, get_cell(1, 1), "6");
+        assert_eq!(column(&graph, 1, 2), ["6", "6"]);
+    }
+}
@@ -0,0 +1,59 @@
+// text.rs
+//! This module stores the strings of text cells such as `A1="Revenue"`.
+//!
+//! `Info` is a small `Copy` value, so a text cell holds an ID into a process-wide table
+//! of interned strings instead of the string itself. Strings are never removed: labels
+//! are few and short, and an ID stays valid for as long as any cell or history entry
+//! might hold it.
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Interned strings, with the ID of each one.
+#[derive(Default)]
+struct Interner {
+    /// Strings by ID.
+    texts: Vec<Arc<str>>,
+    /// ID of each string in `texts`.
+    ids: HashMap<Arc<str>, i32>,
+}
+
+lazy_static! {
+    /// The table shared by every sheet.
+    static ref TEXTS: Mutex<Interner> = Mutex::new(Interner::default());
+}
+
+/// Returns the ID of `text`, adding it to the table the first time it is seen.
+pub fn intern(text: &str) -> i32 {
+    let mut table = TEXTS.lock().unwrap();
+    if let Some(&id) = table.ids.get(text) {
+        return id;
+    }
+    let id = table.texts.len() as i32;
+    let text: Arc<str> = Arc::from(text);
+    table.texts.push(text.clone());
+    table.ids.insert(text, id);
+    id
+}
+/// Returns the string with the given ID, or `None` if no string has it.
+pub fn lookup(id: i32) -> Option<Arc<str>> {
+    let table = TEXTS.lock().unwrap();
+    usize::try_from(id)
+        .ok()
+        .and_then(|id| table.texts.get(id).cloned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intern_reuses_ids() {
+        let id = intern("Revenue");
+        assert_eq!(intern("Revenue"), id);
+        assert_ne!(intern("Costs"), id);
+        assert_eq!(lookup(id).as_deref(), Some("Revenue"));
+        assert_eq!(lookup(-1), None);
+        assert_eq!(lookup(i32::MAX), None);
+    }
+}
@@ -0,0 +1,123 @@
+// trace.rs
+//! This module reports every recomputation pass while `trace on` is active: the cells
+//! recomputed, in the order they were computed, with their values before and after. It
+//! also describes the changes a pass made for `Spreadsheet::subscribe`.
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::info::CellInfo;
+use crate::sheet::{Sheet, format_value};
+
+/// Writes one block per recomputation pass, e.g.
+///
+/// ```text
+/// pass 2: 2 cells
+///   B1: 4 -> 5
+///   C1: 12 -> 15
+/// ```
+pub struct Trace {
+    /// Destination of the trace, standard output or an appended file.
+    writer: Box<dyn Write>,
+    /// Number of passes written so far.
+    passes: usize,
+}
+
+impl Trace {
+    /// Creates a trace printing to standard output.
+    pub fn stdout() -> Self {
+        Self::new(Box::new(io::stdout()))
+    }
+    /// Opens `path` for appending, creating it if needed.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(Box::new(file)))
+    }
+    /// Creates a trace that writes to `writer`.
+    pub fn new(writer: Box<dyn Write>) -> Self {
+        Self { writer, passes: 0 }
+    }
+    /// Writes one pass: each recomputed cell with its state before and after, in order.
+    ///
+    /// The block is flushed immediately, and a trace that cannot be written is given up
+    /// on silently rather than failing the command being traced.
+    pub fn record(&mut self, sheet: &Sheet, cells: &[(usize, CellInfo)]) {
+        self.passes += 1;
+        let mut block = format!("pass {}: {} cells\n", self.passes, cells.len());
+        for &(cell, before) in cells {
+            block += &format!(
+                "  {}: {} -> {}\n",
+                sheet.cell_name(cell),
+                shown(&before),
+                shown(&sheet.data[cell])
+            );
+        }
+        let _ = self
+            .writer
+            .write_all(block.as_bytes())
+            .and_then(|_| self.writer.flush());
+    }
+}
+
+/// The cells whose values one recomputation pass changed, passed to the callbacks of
+/// `Spreadsheet::subscribe`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CellChange {
+    /// Each changed cell, in the order it was computed, with its state before and after.
+    pub cells: Vec<(usize, CellInfo, CellInfo)>,
+}
+
+impl CellChange {
+    /// Collects the cells of a pass, given as in `Trace::record`, whose value, error or
+    /// text differs from before. Returns `None` if none does.
+    pub fn from_pass(sheet: &Sheet, cells: &[(usize, CellInfo)]) -> Option<Self> {
+        let cells: Vec<(usize, CellInfo, CellInfo)> = cells
+            .iter()
+            .map(|&(cell, before)| (cell, before, sheet.data[cell]))
+            .filter(|(_, before, after)| shown(before) != shown(after))
+            .collect();
+        (!cells.is_empty()).then_some(Self { cells })
+    }
+    /// Returns the indices of the changed cells.
+    pub fn indices(&self) -> Vec<usize> {
+        self.cells.iter().map(|&(cell, _, _)| cell).collect()
+    }
+}
+/// Returns a cell's value as the trace shows it: a number, an error marker or quoted text.
+fn shown(cell: &CellInfo) -> String {
+    match (cell.text(), cell.info.error) {
+        (Some(text), _) => format!("\"{}\"", text),
+        (None, Some(kind)) => kind.marker().to_string(),
+        (None, None) => format_value(cell.value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sheet::{TEST_COLS, TEST_ROWS, init_test_dimensions};
+    use crate::spreadsheet::Spreadsheet;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_trace_lists_each_pass_in_order() {
+        init_test_dimensions();
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("trace.log");
+        let mut app = Spreadsheet::new(TEST_ROWS, TEST_COLS);
+        app.execute("A1=4").unwrap();
+        app.execute("B1=A1+1").unwrap();
+        app.execute("C1=B1*3").unwrap();
+        app.execute(&format!("trace on {}", path.display()))
+            .unwrap();
+        app.execute("A1=1/0").unwrap();
+        app.execute("A1=5").unwrap();
+        app.execute("trace off").unwrap();
+        app.execute("A1=6").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "pass 1: 3 cells\n  A1: 4 -> #DIV/0\n  B1: 5 -> ERR\n  C1: 15 -> ERR\n\
+             pass 2: 3 cells\n  A1: #DIV/0 -> 5\n  B1: ERR -> 6\n  C1: ERR -> 18\n"
+        );
+    }
+}
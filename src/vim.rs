@@ -6,129 +6,147 @@
 use crossterm::{
     cursor,
     event::{self, KeyCode, KeyEvent, KeyModifiers},
-    execute,
+    execute, queue,
     style::{Color, Print, PrintStyledContent, Stylize},
     terminal,
 };
+use lazy_static::lazy_static;
+use regex::Regex;
 use std::{
     cell::RefCell,
+    collections::HashMap,
     io::{self, Write, stdout},
+    path::Path,
     rc::Rc,
     time::{Duration, Instant},
 };
 
 // static const:usize ERROR_DURATION = 5;
 const ERROR_DURATION: u64 = 2;
-use crate::sheet::Sheet;
-use crate::status::{StatusCode, print_status, set_status_code, start_time};
-use std::collections::HashMap;
-#[derive(Clone)]
-struct CellChange {
-    cell_idx: usize,
-    previous_expr: Option<String>,
-    previous_value: i32,
-    previous_literal_mode: bool,
-    previous_invalid: bool,
+/// Default wait for input while the user is active, overridden by `--poll-ms`.
+pub const DEFAULT_POLL_MS: u64 = 100;
+/// How long after the last key press the editor still counts as active.
+const ACTIVE_WINDOW: Duration = Duration::from_secs(2);
+/// Wait for input once the editor has gone idle.
+const IDLE_POLL: Duration = Duration::from_millis(1000);
+/// Terminal lines around the visible rows: mode line, headers, blank lines, status bar
+/// and error line.
+const SCREEN_MARGIN_ROWS: usize = 7;
+/// How deeply macros may replay macros, so a macro calling itself ends.
+const MAX_MACRO_DEPTH: usize = 16;
+use crate::chart;
+use crate::info::{FIXED_COL, FIXED_ROW, Info};
+use crate::parser::{cell_parser, color_parser, number_format_parser, text_literal};
+use crate::sheet::{Align, FormatChange, LABEL_WIDTH, Sheet, pad_cell, scroll_position};
+use crate::spreadsheet::Spreadsheet;
+use crate::status::{CommandResult, StatusCode, status_message};
+
+lazy_static! {
+    /// A cell reference or range being typed, with the `(` that would make it a function
+    /// name such as `MAX2(` instead.
+    static ref REFERENCE: Regex =
+        Regex::new(r"(?i)\$?([A-Z]+)\$?(\d+)(?:\s*:\s*\$?([A-Z]+)\$?(\d+))?\s*(\()?").unwrap();
 }
 
-type Transaction = Vec<CellChange>;
+/// Cells copied by `y` in visual mode, pasted by `p`.
+struct Clipboard {
+    /// `(row, col)` of the top-left copied cell, which references are shifted from.
+    origin: (usize, usize),
+    /// Number of copied columns.
+    cols: usize,
+    /// Formula of each copied cell in row-major order, `None` for a blank one.
+    cells: Vec<Option<String>>,
+}
+
+/// A `q` or `@` waiting for the register it names.
+enum PendingRegister {
+    /// `q`: start recording into the register.
+    Record,
+    /// `@`, with the count typed before it: replay the register that many times.
+    Replay(usize),
+}
 
 pub enum VimMode {
     Normal,
     Insert,
     Command,
     Help, // Added Help mode
-}
-
-// Cell formatting options
-#[derive(Clone, Default)]
-pub struct CellFormat {
-    pub bold: bool,
-    pub italic: bool,
-    pub underline: bool,
-    pub color: Option<Color>,
+    Visual,
+    Search,
 }
 
 pub struct VimEditor {
-    undo_stack: Vec<Transaction>,
-    redo_stack: Vec<Transaction>,
-    current_transaction: Option<Transaction>,
+    app: Spreadsheet, // The engine every edit goes through, undo history included
     sheet: Rc<RefCell<Sheet>>,
     cursor_x: usize,
     cursor_y: usize,
     mode: VimMode,
     command_buffer: String,
     last_status: StatusCode,
+    last_elapsed: Duration, // Time the last engine command took, shown in the status bar
     error_message: Option<(String, Instant)>, // Error message and when it was shown
-    cell_formats: Vec<Vec<CellFormat>>,       // Store formatting for each cell
-    current_input: String,                    // Add this field
-    cell_expressions: HashMap<usize, String>, // Store expressions by cell index
+    current_input: String,  // Add this field
+    last_input: Option<String>, // Last committed input, replayed by `.`
+    visual_anchor: Option<(usize, usize)>, // (row, col) where the visual selection started
+    clipboard: Option<Clipboard>, // Last selection yanked with `y`
+    search: Option<String>, // Last `/` pattern, repeated by `n`/`N`
+    count: Option<usize>,   // Count typed before a motion, e.g. the 5 of `5j`
+    pending_g: bool,        // The first `g` of `gg` was typed
+    pending_register: Option<PendingRegister>, // `q` or `@` typed, its register not yet
+    recording: Option<(char, Vec<KeyEvent>)>, // Register being recorded and its keys so far
+    macros: HashMap<char, Vec<KeyEvent>>, // Keys recorded in each register
+    replay_depth: usize,    // Macros being replayed, innermost last
     // top_row : usize,
     start_row: usize,
     start_col: usize,
     display_rows: usize,
     display_cols: usize,
     col_width: usize,
+    col_gap: usize,      // Spaces between rendered columns (`:set colgap <n>`)
+    poll: Duration,      // Input wait while active, see `poll_interval`
+    show_formulas: bool, // Show expressions instead of values (Ctrl-`)
+    screen: Vec<String>, // Lines of the last frame drawn, which the next one is diffed against
+    terminal_size: Option<(u16, u16)>, // (columns, rows) the view was last fitted to
 }
 
 impl VimEditor {
-    pub fn new(sheet: Rc<RefCell<Sheet>>) -> Self {
-        let n = sheet.borrow().n;
-        let m = sheet.borrow().m;
-        let formats = vec![vec![CellFormat::default(); m]; n];
-
+    pub fn new(app: Spreadsheet) -> Self {
         Self {
-            undo_stack: Vec::new(),
-            redo_stack: Vec::new(),
-            current_transaction: None,
-            sheet,
+            sheet: app.sheet.clone(),
+            app,
             cursor_x: 0,
             cursor_y: 0,
             mode: VimMode::Normal,
             command_buffer: String::new(),
             last_status: StatusCode::Ok,
+            last_elapsed: Duration::ZERO,
             error_message: None,
-            cell_formats: formats,
             current_input: String::new(),
-            cell_expressions: HashMap::new(),
+            last_input: None,
+            visual_anchor: None,
+            clipboard: None,
+            search: None,
+            count: None,
+            pending_g: false,
+            pending_register: None,
+            recording: None,
+            macros: HashMap::new(),
+            replay_depth: 0,
             start_row: 0,
             start_col: 0,
             display_rows: 20,
             display_cols: 20,
             col_width: 10,
+            col_gap: 0,
+            poll: Duration::from_millis(DEFAULT_POLL_MS),
+            show_formulas: false,
+            screen: Vec::new(),
+            terminal_size: None,
         }
     }
-    /// Starts a new cell modification transaction for undo/redo tracking.
-    fn start_transaction(&mut self) {
-        self.current_transaction = Some(Vec::new());
-    }
-    /// Commits the current transaction to the undo stack and clears the redo stack.
-    fn commit_transaction(&mut self) {
-        if let Some(transaction) = self.current_transaction.take() {
-            self.undo_stack.push(transaction);
-            self.redo_stack.clear();
-        }
-    }
-    /// Records the previous state of a cell before a change, for undo/redo tracking.
-    ///
-    /// # Arguments
-    ///
-    /// * `cell_idx` - The index of the cell being modified.
-    fn record_cell_change(&mut self, cell_idx: usize) {
-        if let Some(transaction) = &mut self.current_transaction {
-            if !transaction.iter().any(|cc| cc.cell_idx == cell_idx) {
-                let sheet = self.sheet.borrow();
-                let cell_info = sheet.get(cell_idx);
-                let previous_expr = self.cell_expressions.get(&cell_idx).cloned();
-                transaction.push(CellChange {
-                    cell_idx,
-                    previous_expr,
-                    previous_value: cell_info.value,
-                    previous_literal_mode: cell_info.literal_mode,
-                    previous_invalid: cell_info.info.invalid,
-                });
-            }
-        }
+    /// Sets how long the main loop waits for input while the user is active.
+    pub fn set_poll_interval(&mut self, ms: u64) {
+        self.poll = Duration::from_millis(ms);
     }
     /// Launches the Vim editor and starts the main input loop.
     ///
@@ -140,7 +158,11 @@ impl VimEditor {
         execute!(stdout, terminal::EnterAlternateScreen)?;
         terminal::enable_raw_mode()?;
 
+        if let Ok((cols, rows)) = terminal::size() {
+            self.fit_to_terminal(cols, rows);
+        }
         self.redraw_screen()?;
+        let mut last_key = Instant::now();
 
         'main_loop: loop {
             // Check if we need to clear error message (after 5 seconds)
@@ -151,12 +173,32 @@ impl VimEditor {
                 }
             }
 
-            if let Ok(true) = event::poll(Duration::from_millis(100)) {
-                if let Ok(event::Event::Key(key_event)) = event::read() {
-                    if self.handle_key_event(key_event) {
-                        break 'main_loop;
+            let error_remaining = self.error_message.as_ref().map(|(_, timestamp)| {
+                Duration::from_secs(ERROR_DURATION).saturating_sub(timestamp.elapsed())
+            });
+            // A save by time is due even while no key is pressed
+            if let Err(e) = self.app.autosave_if_due() {
+                self.set_error_message(format!("Autosave not written: {}", e));
+                self.redraw_screen()?;
+            }
+            let timeout = poll_interval(self.poll, last_key.elapsed(), error_remaining);
+            if let Ok(true) = event::poll(timeout) {
+                last_key = Instant::now();
+                match event::read() {
+                    Ok(event::Event::Key(key_event)) => {
+                        if self.handle_key_event(key_event) {
+                            break 'main_loop;
+                        }
+                        self.redraw_screen()?;
                     }
-                    self.redraw_screen()?;
+                    Ok(event::Event::Resize(cols, rows)) => {
+                        // The terminal may have reflowed, so nothing on it can be trusted
+                        self.fit_to_terminal(cols, rows);
+                        execute!(stdout, terminal::Clear(terminal::ClearType::All))?;
+                        self.screen.clear();
+                        self.redraw_screen()?;
+                    }
+                    _ => {}
                 }
             }
         }
@@ -164,29 +206,107 @@ impl VimEditor {
         // Restore terminal
         terminal::disable_raw_mode()?;
         execute!(stdout, terminal::LeaveAlternateScreen)?;
+        self.app.discard_autosave();
 
         Ok(())
     }
     /// Handles key events depending on the current Vim mode.
     ///
+    /// While a macro is recorded, every key typed is also appended to it; keys a replay
+    /// feeds in are not, since the `@` that started the replay already was.
+    ///
     /// Returns `true` if the event signals to exit the application.
     fn handle_key_event(&mut self, event: KeyEvent) -> bool {
-        match self.mode {
+        if let (Some((_, keys)), 0) = (self.recording.as_mut(), self.replay_depth) {
+            keys.push(event);
+        }
+        if let Some(pending) = self.pending_register.take() {
+            let quit = self.use_register(pending, event);
+            self.follow_cursor();
+            return quit;
+        }
+        let quit = match self.mode {
             VimMode::Normal => self.handle_normal_mode(event),
             VimMode::Insert => self.handle_insert_mode(event),
             VimMode::Command => self.handle_command_mode(event),
             VimMode::Help => self.handle_help_mode(event),
+            VimMode::Visual => self.handle_visual_mode(event),
+            VimMode::Search => self.handle_search_mode(event),
+        };
+        self.follow_cursor();
+        quit
+    }
+    /// Scrolls the view as little as needed to keep the cursor on screen.
+    fn follow_cursor(&mut self) {
+        if self.cursor_y < self.start_row {
+            self.start_row = self.cursor_y;
+        } else if self.cursor_y >= self.start_row + self.display_rows {
+            self.start_row = self.cursor_y + 1 - self.display_rows;
+        }
+        if self.cursor_x < self.start_col {
+            self.start_col = self.cursor_x;
+        } else if self.cursor_x >= self.start_col + self.display_cols {
+            self.start_col = self.cursor_x + 1 - self.display_cols;
         }
     }
-
-    fn handle_normal_mode(&mut self, event: KeyEvent) -> bool {
+    /// Applies a motion of normal or visual mode, taking the count typed before it:
+    /// `h`/`j`/`k`/`l` and the arrows move that many cells, `gg` and `G` jump to the first
+    /// and last row (or to row `count`), `0` and `$` to the first and last column, and
+    /// Ctrl-d/Ctrl-u scroll half a page down or up.
+    ///
+    /// Returns `true` if the key was part of a motion, including a count digit or the
+    /// first `g` of `gg`. Any other key drops the count.
+    fn motion(&mut self, event: KeyEvent) -> bool {
+        let plain = event.modifiers == KeyModifiers::NONE;
+        if let KeyCode::Char(digit @ '0'..='9') = event.code
+            && plain
+            && (digit != '0' || self.count.is_some())
+        {
+            let digit = digit.to_digit(10).unwrap() as usize;
+            self.count = Some(
+                self.count
+                    .unwrap_or(0)
+                    .saturating_mul(10)
+                    .saturating_add(digit),
+            );
+            return true;
+        }
+        let count = self.count.take();
+        let after_g = std::mem::take(&mut self.pending_g);
+        let (n, m) = {
+            let sheet = self.sheet.borrow();
+            (sheet.n, sheet.m)
+        };
+        let row = |count: usize| count.clamp(1, n) - 1;
+        let half_page = (self.display_rows / 2).max(1) as isize;
+        let control = event.modifiers.contains(KeyModifiers::CONTROL);
         match event.code {
-            // Quit vim mode
-            KeyCode::Char('q') if event.modifiers == KeyModifiers::NONE => {
-                return true;
+            KeyCode::Char('d') if control => self.scroll_view((half_page, 0)),
+            KeyCode::Char('u') if control => self.scroll_view((-half_page, 0)),
+            KeyCode::Char('g') if plain && !after_g => {
+                self.pending_g = true;
+                self.count = count;
             }
-
-            // Movement keys
+            KeyCode::Char('g') if plain => self.cursor_y = count.map_or(0, row),
+            KeyCode::Char('G') => self.cursor_y = count.map_or(n - 1, row),
+            KeyCode::Char('0') if plain => self.cursor_x = 0,
+            KeyCode::Char('$') => self.cursor_x = m - 1,
+            code => {
+                // Moves stop at the edges, so more than the sheet's size changes nothing
+                for _ in 0..count.unwrap_or(1).min(n.max(m)) {
+                    if !self.move_cursor(code) {
+                        return false;
+                    }
+                }
+            }
+        }
+        true
+    }
+    /// Moves the cursor for `h`/`j`/`k`/`l` and the arrow keys.
+    ///
+    /// Returns `true` if the key was a movement key.
+    fn move_cursor(&mut self, code: KeyCode) -> bool {
+        match code {
             KeyCode::Char('h') | KeyCode::Left => {
                 if self.cursor_x > 0 {
                     self.cursor_x -= 1;
@@ -207,10 +327,89 @@ impl VimEditor {
                     self.cursor_x += 1;
                 }
             }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Starts recording into the register named by `event`, or replays it, for a `q` or
+    /// `@` typed before. A key that names no register (`a` to `z`) cancels the `q` or `@`.
+    ///
+    /// Replayed keys go through `handle_key_event` like typed ones, so their edits reach
+    /// the engine, and its undo history, one command at a time. Returns `true` if a
+    /// replayed key quit.
+    fn use_register(&mut self, pending: PendingRegister, event: KeyEvent) -> bool {
+        let register = match event.code {
+            KeyCode::Char(c @ 'a'..='z') if event.modifiers == KeyModifiers::NONE => c,
+            _ => return false,
+        };
+        let count = match pending {
+            PendingRegister::Record => {
+                self.recording = Some((register, Vec::new()));
+                return false;
+            }
+            PendingRegister::Replay(count) => count,
+        };
+        let Some(keys) = self.macros.get(&register).cloned() else {
+            self.set_error_message(format!("Register {} is empty", register));
+            return false;
+        };
+        if self.replay_depth >= MAX_MACRO_DEPTH {
+            self.set_error_message("Macros nested too deeply".to_string());
+            return false;
+        }
+        self.replay_depth += 1;
+        let quit = (0..count).any(|_| keys.iter().any(|&key| self.handle_key_event(key)));
+        self.replay_depth -= 1;
+        quit
+    }
+
+    fn handle_normal_mode(&mut self, event: KeyEvent) -> bool {
+        let plain = event.modifiers == KeyModifiers::NONE;
+        match event.code {
+            // Record a macro with `q<register>`, stopped by the next `q`
+            KeyCode::Char('q') if plain => match self.recording.take() {
+                Some((register, mut keys)) => {
+                    keys.pop(); // The `q` that stopped it
+                    self.macros.insert(register, keys);
+                }
+                None => self.pending_register = Some(PendingRegister::Record),
+            },
+
+            // Replay a macro with `@<register>`, as many times as the count before it
+            KeyCode::Char('@') if plain => {
+                let count = self.count.take().unwrap_or(1);
+                self.pending_register = Some(PendingRegister::Replay(count));
+            }
+
+            // Movement keys, with counts and jumps
+            _ if self.motion(event) => {}
+
+            // Toggle the formula view. Most terminals send Ctrl-` as NUL, which
+            // crossterm reports as Ctrl-Space.
+            KeyCode::Char('`') | KeyCode::Char(' ')
+                if event.modifiers.contains(KeyModifiers::CONTROL) =>
+            {
+                self.show_formulas = !self.show_formulas;
+            }
+
+            // Repeat the last committed edit at the cursor
+            KeyCode::Char('.') => {
+                if let Some(input) = self.last_input.clone() {
+                    self.commit_input(input);
+                }
+            }
 
             // Enter insert mode
-            KeyCode::Char('i') => {
-                self.mode = VimMode::Insert;
+            KeyCode::Char('i') => self.enter_insert_mode(),
+
+            // Paste the yanked cells with their top-left corner at the cursor
+            KeyCode::Char('p') => self.paste(self.cursor_y, self.cursor_x),
+
+            // Start a visual selection at the cursor
+            KeyCode::Char('v') => {
+                self.visual_anchor = Some((self.cursor_y, self.cursor_x));
+                self.mode = VimMode::Visual;
             }
 
             // Enter command mode
@@ -219,6 +418,57 @@ impl VimEditor {
                 self.command_buffer.clear();
             }
 
+            // Type a search pattern, then step through its matches
+            KeyCode::Char('/') => {
+                self.mode = VimMode::Search;
+                self.command_buffer.clear();
+            }
+            KeyCode::Char('n') => self.search_next(true),
+            KeyCode::Char('N') => self.search_next(false),
+
+            // Page through the sheet, clamped like the terminal sheet's `scroll`
+            KeyCode::PageDown => self.scroll_view((self.display_rows as isize, 0)),
+            KeyCode::PageUp => self.scroll_view((-(self.display_rows as isize), 0)),
+            KeyCode::Home => self.scroll_view((isize::MIN, isize::MIN)),
+            KeyCode::End => self.scroll_view((isize::MAX, isize::MAX)),
+
+            _ => {}
+        }
+        false
+    }
+
+    fn handle_visual_mode(&mut self, event: KeyEvent) -> bool {
+        match event.code {
+            _ if self.motion(event) => {}
+
+            KeyCode::Esc => {
+                self.visual_anchor = None;
+                self.mode = VimMode::Normal;
+            }
+
+            // Type one formula for the whole selection
+            KeyCode::Char('=') | KeyCode::Char('i') => self.enter_insert_mode(),
+
+            // Formatting commands such as `:b` apply to the whole selection
+            KeyCode::Char(':') => {
+                self.mode = VimMode::Command;
+                self.command_buffer.clear();
+            }
+
+            KeyCode::Char(c @ ('d' | 'y' | 'p')) => {
+                let (top, left, ..) = self.selection().unwrap();
+                match c {
+                    'd' => self.delete_selection(),
+                    'y' => self.yank_selection(),
+                    _ => self.paste(top, left),
+                }
+                // Like vim, the cursor goes back to the start of the selection
+                self.cursor_y = top;
+                self.cursor_x = left;
+                self.visual_anchor = None;
+                self.mode = VimMode::Normal;
+            }
+
             _ => {}
         }
         false
@@ -228,55 +478,29 @@ impl VimEditor {
         match event.code {
             KeyCode::Esc => {
                 self.mode = VimMode::Normal;
+                self.visual_anchor = None;
                 self.current_input.clear();
             }
 
             KeyCode::Enter => {
                 if !self.current_input.is_empty() {
-                    self.start_transaction();
-                    let cell_idx = self.sheet.borrow().get_cell(self.cursor_y, self.cursor_x);
-                    self.record_cell_change(cell_idx);
-
-                    match self.evaluate_expression(&self.current_input) {
-                        Ok(value) => {
-                            // Update cell value
-                            let mut sheet = self.sheet.borrow_mut();
-                            let mut cell_info = sheet.get(cell_idx);
-                            cell_info.value = value;
-                            cell_info.info.invalid = false;
-
-                            // Set literal_mode = false to indicate this is an expression
-                            cell_info.literal_mode = false;
-
-                            sheet.set(cell_idx, cell_info);
-
-                            // Store the expression
-                            self.cell_expressions
-                                .insert(cell_idx, self.current_input.clone());
-
-                            // Update dependencies after we're done with sheet
-                            drop(sheet);
-                            self.update_dependent_cells(cell_idx);
-                        }
-                        Err(err_msg) => {
-                            // self.set_error_message(format!("Invalid expression: {} ({})",                                                         // self.current_input, err_msg));
-                            self.error_message = Some((
-                                format!("Invalid expression: {}", self.current_input),
-                                Instant::now(),
-                            ));
-                        }
+                    let input = std::mem::take(&mut self.current_input);
+                    if self.visual_anchor.is_some() {
+                        self.fill_selection(&input);
+                        self.visual_anchor = None;
+                    } else {
+                        self.commit_input(input);
                     }
-                    self.update_dependent_cells(cell_idx);
-                    self.commit_transaction();
-                    self.current_input.clear();
                     self.mode = VimMode::Normal;
-                    // self.current_input.clear();
                 }
             }
 
             KeyCode::Char(c) => {
-                // Allow alphanumeric chars and operators
-                if c.is_alphanumeric() || "+-*/".contains(c) {
+                // Allow alphanumeric chars, operators and calls, and anything inside text
+                if c.is_alphanumeric()
+                    || "+-*/().:,$\"".contains(c)
+                    || self.current_input.starts_with('"')
+                {
                     self.current_input.push(c);
                 }
             }
@@ -289,280 +513,249 @@ impl VimEditor {
         }
         false
     }
-    /// Evaluates a string expression into an integer result.
-    ///
-    /// Supports numbers, cell references (e.g., A1), and basic arithmetic.
+    /// Assigns `input` to the cell under the cursor through the engine, as one undoable
+    /// edit.
     ///
-    /// Returns a `Result<i32, &str>` indicating either a value or an error message.
-    fn evaluate_expression(&self, expr: &str) -> Result<i32, &'static str> {
-        // Check if it's a simple number
-        if let Ok(num) = expr.parse::<i32>() {
-            return Ok(num);
-        }
-
-        // Check for cell references like A1, B2
-        if expr
-            .chars()
-            .next()
-            .map_or(false, |c| c.is_ascii_alphabetic())
-            && expr.chars().skip(1).all(|c| c.is_ascii_digit())
-        {
-            return self.get_cell_value(expr);
+    /// On success the input is remembered so that `.` in normal mode can replay it.
+    fn commit_input(&mut self, input: String) {
+        let name = {
+            let sheet = self.sheet.borrow();
+            sheet.cell_name(sheet.get_cell(self.cursor_y, self.cursor_x))
+        };
+        match self.run_command(&format!("{}={}", name, input)) {
+            Ok(_) => {
+                self.last_input = Some(input);
+                self.last_status = StatusCode::Ok;
+            }
+            Err(code) => {
+                // Point into what was typed rather than the `B1=` command
+                let typed = -(name.len() as isize + 1);
+                let error = self.app.parser_ctx.last_error.take();
+                self.app.parser_ctx.last_error = error.map(|error| error.moved(typed));
+                self.report_error(code, &input)
+            }
         }
-
-        // Look for basic arithmetic: val1 op val2
-        let operations = ['+', '-', '*', '/'];
-
-        for op in operations {
-            if let Some(pos) = expr.find(op) {
-                let left = &expr[0..pos];
-                let right = &expr[pos + 1..];
-
-                // Get values for left and right operands
-                let left_val = if left
-                    .chars()
-                    .next()
-                    .map_or(false, |c| c.is_ascii_alphabetic())
-                {
-                    self.get_cell_value(left)?
-                } else {
-                    left.parse::<i32>().map_err(|_| "Invalid left operand")?
-                };
-
-                let right_val = if right
-                    .chars()
-                    .next()
-                    .map_or(false, |c| c.is_ascii_alphabetic())
-                {
-                    self.get_cell_value(right)?
-                } else {
-                    right.parse::<i32>().map_err(|_| "Invalid right operand")?
-                };
-
-                // Perform operation
-                match op {
-                    '+' => return Ok(left_val + right_val),
-                    '-' => return Ok(left_val - right_val),
-                    '*' => return Ok(left_val * right_val),
-                    '/' => {
-                        if right_val == 0 {
-                            return Err("Division by zero");
-                        }
-                        return Ok(left_val / right_val);
-                    }
-                    _ => unreachable!(),
-                }
+    }
+    /// Runs one command through the engine, remembering how long it took.
+    fn run_command(&mut self, input: &str) -> Result<Option<usize>, StatusCode> {
+        let result = self.app.run_command(input);
+        self.last_elapsed = result.elapsed;
+        result.into_result()
+    }
+    /// Shows the error for a failed edit of `input` and records its status.
+    fn report_error(&mut self, code: StatusCode, input: &str) {
+        let message = match code {
+            StatusCode::CyclicDep => match self.app.cycle_text() {
+                Some(cycle) => format!("Cyclic dependency ({})", cycle),
+                None => "Cyclic dependency".to_string(),
+            },
+            StatusCode::InvalidCell | StatusCode::InvalidRange => {
+                "Reference out of bounds".to_string()
             }
+            StatusCode::CellLocked => "Cell is locked".to_string(),
+            StatusCode::ReadOnly => "Sheet is read-only (see :set noreadonly)".to_string(),
+            // The edit was kept; only its result is an error
+            StatusCode::Overflow => "Result too large (see :set overflow_policy)".to_string(),
+            _ => match self.app.last_parse_error() {
+                Some(error) => format!("Invalid expression ({})", error),
+                None => "Invalid expression".to_string(),
+            },
+        };
+        self.set_error_message(format!("{}: {}", message, input));
+        self.last_status = code;
+    }
+    /// Switches to insert mode, unless the sheet is read-only or the cursor cell, or any
+    /// cell of the visual selection, is locked.
+    fn enter_insert_mode(&mut self) {
+        if self.app.readonly() {
+            self.set_error_message("Sheet is read-only (see :set noreadonly)".to_string());
+            self.last_status = StatusCode::ReadOnly;
+            return;
+        }
+        let (top, left, bottom, right) = self.selection().unwrap_or((
+            self.cursor_y,
+            self.cursor_x,
+            self.cursor_y,
+            self.cursor_x,
+        ));
+        let locked = {
+            let sheet = self.sheet.borrow();
+            (top..=bottom)
+                .flat_map(|row| (left..=right).map(move |col| (row, col)))
+                .map(|(row, col)| sheet.get_cell(row, col))
+                .find(|&cell| sheet.is_locked(cell))
+                .map(|cell| sheet.cell_name(cell))
+        };
+        match locked {
+            Some(name) => {
+                self.set_error_message(format!("Cell is locked: {}", name));
+                self.last_status = StatusCode::CellLocked;
+            }
+            None => self.mode = VimMode::Insert,
         }
-
-        Err("Invalid expression format")
     }
-    /// Gets the value of a referenced cell by name (e.g., "A1").
+    /// Returns the visual selection as `(top, left, bottom, right)`, inclusive.
+    fn selection(&self) -> Option<(usize, usize, usize, usize)> {
+        let (row, col) = self.visual_anchor?;
+        Some((
+            row.min(self.cursor_y),
+            col.min(self.cursor_x),
+            row.max(self.cursor_y),
+            col.max(self.cursor_x),
+        ))
+    }
+    /// Writes `input` into every selected cell as one undoable edit.
     ///
-    /// Returns `Ok(value)` or an `Err` if the reference is invalid.
-    fn get_cell_value(&self, cell_ref: &str) -> Result<i32, &'static str> {
-        let col_end = cell_ref
-            .chars()
-            .position(|c| !c.is_ascii_alphabetic())
-            .unwrap_or(cell_ref.len());
-
-        let col_str = &cell_ref[0..col_end];
-        let row_str = &cell_ref[col_end..];
-
-        // Convert column letters to number (1-based)
-        let col = crate::convert::alpha_to_num(col_str).ok_or("Invalid column reference")?;
-
-        // Parse row (1-based)
-        let row = row_str
-            .parse::<usize>()
-            .map_err(|_| "Invalid row reference")?;
-
-        // Convert to 0-based indices
-        let row_idx = row - 1;
-        let col_idx = col - 1;
-
-        let sheet = self.sheet.borrow();
-        if !sheet.is_valid_cell(row_idx, col_idx) {
-            return Err("Cell reference out of bounds");
-        }
-
-        let cell_idx = sheet.get_cell(row_idx, col_idx);
-        let cell = sheet.get(cell_idx);
-
-        if cell.info.invalid {
-            return Err("Referenced cell contains an error");
+    /// `input` is written as-is into the top-left cell; every other cell gets a copy with
+    /// its cell references shifted by the cell's offset from the top-left, so `A2*2` typed
+    /// over `B2:B4` becomes `A3*2` in B3 and `A4*2` in B4. Nothing is changed if a shifted
+    /// reference leaves the sheet, a copy does not parse or the result would be cyclic.
+    fn fill_selection(&mut self, input: &str) {
+        let Some((top, left, bottom, right)) = self.selection() else {
+            return;
+        };
+        let (n, m) = {
+            let sheet = self.sheet.borrow();
+            (sheet.n, sheet.m)
+        };
+
+        let mut writes = Vec::new();
+        for row in top..=bottom {
+            for col in left..=right {
+                let offset = ((row - top) as isize, (col - left) as isize);
+                let Some(expr) = shift_references(input, offset, (n, m)) else {
+                    self.set_error_message(format!("Reference out of bounds: {}", input));
+                    return;
+                };
+                writes.push((self.sheet.borrow().get_cell(row, col), Some(expr)));
+            }
         }
-
-        Ok(cell.value)
+        self.write_cells(writes, input);
     }
-    /// Updates all cells that depend on a changed cell, recursively.
-    fn update_dependent_cells(&mut self, changed_cell_idx: usize) {
-        let (changed_row, changed_col) = self.sheet.borrow().get_row_and_column(changed_cell_idx);
-        let changed_cell_ref = format!(
-            "{}{}",
-            crate::convert::num_to_alpha((changed_col + 1) as u32),
-            changed_row + 1
-        );
-
-        // Find cells that depend on the changed cell
-        let cells_to_update: Vec<usize> = self
-            .cell_expressions
-            .iter()
-            .filter_map(|(&idx, expr)| {
-                if idx != changed_cell_idx && expr.contains(&changed_cell_ref) {
-                    Some(idx)
-                } else {
-                    None
-                }
+    /// Blanks every selected cell as one undoable edit, recomputing their dependents.
+    fn delete_selection(&mut self) {
+        let writes = self.selected_cells().into_iter().map(|cell| (cell, None));
+        self.write_cells(writes.collect(), "d");
+    }
+    /// Copies the formulas of the selected cells for `p` to paste.
+    fn yank_selection(&mut self) {
+        let Some((top, left, _, right)) = self.selection() else {
+            return;
+        };
+        let cells = self
+            .selected_cells()
+            .into_iter()
+            .map(|cell| {
+                let text = self.sheet.borrow().cell_text(cell, true);
+                (!text.is_empty()).then_some(text)
             })
             .collect();
+        self.clipboard = Some(Clipboard {
+            origin: (top, left),
+            cols: right - left + 1,
+            cells,
+        });
+    }
+    /// Pastes the yanked cells with their top-left corner at `(row, col)`, as one undoable
+    /// edit. Blank yanked cells blank their target.
+    ///
+    /// References are shifted by the distance from the yanked cells to the target, like a
+    /// fill. Nothing is changed if the cells or a shifted reference would leave the sheet,
+    /// or the result would be cyclic.
+    fn paste(&mut self, row: usize, col: usize) {
+        let Some(clipboard) = &self.clipboard else {
+            self.set_error_message("Nothing yanked, select cells and press y".to_string());
+            return;
+        };
+        let (n, m) = {
+            let sheet = self.sheet.borrow();
+            (sheet.n, sheet.m)
+        };
+        let rows = clipboard.cells.len() / clipboard.cols;
+        if row + rows > n || col + clipboard.cols > m {
+            self.set_error_message("Paste does not fit in the sheet".to_string());
+            return;
+        }
+        let offset = (
+            row as isize - clipboard.origin.0 as isize,
+            col as isize - clipboard.origin.1 as isize,
+        );
 
-        // Update each dependent cell
-        for idx in cells_to_update {
-            if let Some(expr) = self.cell_expressions.get(&idx).cloned() {
-                match self.evaluate_expression(&expr) {
-                    Ok(value) => {
-                        let mut sheet = self.sheet.borrow_mut();
-                        let mut cell_info = sheet.get(idx);
-                        cell_info.value = value;
-                        cell_info.info.invalid = false;
-                        sheet.set(idx, cell_info);
-                        drop(sheet);
-                        self.record_cell_change(idx);
-                        // Continue updating the dependency chain
-                        self.update_dependent_cells(idx);
-                    }
-                    Err(_) => {
-                        // Mark cell as invalid
-                        let mut sheet = self.sheet.borrow_mut();
-                        let mut cell_info = sheet.get(idx);
-                        cell_info.info.invalid = true;
-                        sheet.set(idx, cell_info);
+        let mut writes = Vec::new();
+        for (i, expr) in clipboard.cells.iter().enumerate() {
+            let (r, c) = (row + i / clipboard.cols, col + i % clipboard.cols);
+            let expr = match expr {
+                Some(expr) => match shift_references(expr, offset, (n, m)) {
+                    Some(shifted) => Some(shifted),
+                    None => {
+                        let message = format!("Reference out of bounds: {}", expr);
+                        self.set_error_message(message);
+                        return;
                     }
-                }
-            }
+                },
+                None => None,
+            };
+            writes.push((self.sheet.borrow().get_cell(r, c), expr));
         }
+        self.write_cells(writes, "p");
     }
-    /// Undoes the last cell modification transaction.
-    fn undo(&mut self) {
-        if let Some(transaction) = self.undo_stack.pop() {
-            let mut redo_transaction = Vec::new();
-
-            for change in transaction.iter().rev() {
-                let cell_idx = change.cell_idx;
-                let mut sheet = self.sheet.borrow_mut();
-                let mut cell_info = sheet.get(cell_idx);
-
-                // Capture current state for redo
-                redo_transaction.push(CellChange {
-                    cell_idx,
-                    previous_expr: self.cell_expressions.get(&cell_idx).cloned(),
-                    previous_value: cell_info.value,
-                    previous_literal_mode: cell_info.literal_mode,
-                    previous_invalid: cell_info.info.invalid,
-                });
-
-                // Revert to previous state
-                cell_info.value = change.previous_value;
-                cell_info.literal_mode = change.previous_literal_mode;
-                cell_info.info.invalid = change.previous_invalid;
-                sheet.set(cell_idx, cell_info);
-
-                // Update expressions map
-                if let Some(expr) = &change.previous_expr {
-                    self.cell_expressions.insert(cell_idx, expr.clone());
-                } else {
-                    self.cell_expressions.remove(&cell_idx);
-                }
-            }
-
-            self.redo_stack.push(redo_transaction);
-        }
+    /// Returns the cells of the visual selection in row-major order.
+    fn selected_cells(&self) -> Vec<usize> {
+        let Some((top, left, bottom, right)) = self.selection() else {
+            return Vec::new();
+        };
+        let sheet = self.sheet.borrow();
+        (top..=bottom)
+            .flat_map(|row| (left..=right).map(move |col| (row, col)))
+            .map(|(row, col)| sheet.get_cell(row, col))
+            .collect()
     }
-    /// Redoes the last cell modification transaction.
-    fn redo(&mut self) {
-        if let Some(transaction) = self.redo_stack.pop() {
-            let mut undo_transaction = Vec::new();
-
-            for change in transaction.iter().rev() {
-                let cell_idx = change.cell_idx;
-                let mut sheet = self.sheet.borrow_mut();
-                let mut cell_info = sheet.get(cell_idx);
-
-                // Capture current state for undo
-                undo_transaction.push(CellChange {
-                    cell_idx,
-                    previous_expr: self.cell_expressions.get(&cell_idx).cloned(),
-                    previous_value: cell_info.value,
-                    previous_literal_mode: cell_info.literal_mode,
-                    previous_invalid: cell_info.info.invalid,
-                });
-
-                // Apply redo change
-                cell_info.value = change.previous_value;
-                cell_info.literal_mode = change.previous_literal_mode;
-                cell_info.info.invalid = change.previous_invalid;
-                sheet.set(cell_idx, cell_info);
-
-                // Update expressions map
-                if let Some(expr) = &change.previous_expr {
-                    self.cell_expressions.insert(cell_idx, expr.clone());
-                } else {
-                    self.cell_expressions.remove(&cell_idx);
-                }
-            }
-
-            self.undo_stack.push(undo_transaction);
+    /// Parses the formula for each paired cell, or blanks it for `None`, and writes them all
+    /// through the engine as one undoable edit. Nothing changes if a formula does not parse
+    /// or the result would be cyclic; the error names `input`.
+    fn write_cells(&mut self, writes: Vec<(usize, Option<String>)>, input: &str) {
+        let mut parsed = Vec::with_capacity(writes.len());
+        for (cell_idx, expr) in writes {
+            let info = match expr {
+                Some(expr) => match self.app.parser_ctx.parse_expression(&expr) {
+                    Ok(info) => info,
+                    Err(e) => return self.report_error(e.status_code(), input),
+                },
+                None => Info::default(),
+            };
+            parsed.push((cell_idx, info));
+        }
+        let start = Instant::now();
+        let result = self.app.write_cells(&parsed, input);
+        self.last_elapsed = start.elapsed();
+        match result {
+            Ok(()) => self.last_status = StatusCode::Ok,
+            Err(code) => self.report_error(code, input),
         }
     }
-    /// Parses a token into a value, which may be a number or a cell reference.
-    ///
-    /// Returns `Ok(value)` or `Err` if the token is invalid.
-    fn parse_token(&self, token: &str) -> Result<i32, &'static str> {
-        // If token is a cell reference
-        if !token.is_empty() && token.chars().next().unwrap_or(' ').is_ascii_alphabetic() {
-            let col_end = token
-                .chars()
-                .take_while(|c| c.is_ascii_alphabetic())
-                .count();
-            let col_str = &token[0..col_end];
-            let row_str = &token[col_end..];
-
-            if let Some(col) = crate::convert::alpha_to_num(col_str) {
-                if let Ok(row) = row_str.parse::<usize>() {
-                    // Adjust for 0-based indexing
-                    let col_idx = col - 1;
-                    let row_idx = row - 1;
-
-                    if self.sheet.borrow().is_valid_cell(row_idx, col_idx) {
-                        let cell_idx = self.sheet.borrow().get_cell(row_idx, col_idx);
-                        let cell = self.sheet.borrow().get(cell_idx);
-
-                        if !cell.info.invalid {
-                            return Ok(cell.value);
-                        } else {
-                            return Err("Referenced cell contains an error");
-                        }
-                    } else {
-                        return Err("Invalid cell reference");
-                    }
-                }
+    /// Runs `undo` or `redo` against the engine's history.
+    fn step_history(&mut self, command: &str) {
+        self.last_status = match self.run_command(command) {
+            Ok(_) => StatusCode::Ok,
+            Err(code) => {
+                self.set_error_message(status_message(code).to_string());
+                code
             }
-            return Err("Invalid cell reference format");
-        }
-
-        // Otherwise treat as a number
-        token.trim().parse::<i32>().map_err(|_| "Invalid number")
+        };
     }
 
     fn handle_command_mode(&mut self, event: KeyEvent) -> bool {
         match event.code {
             KeyCode::Esc => {
                 self.mode = VimMode::Normal;
+                self.visual_anchor = None;
                 self.command_buffer.clear();
             }
 
             KeyCode::Enter => {
+                if matches!(self.command_buffer.trim(), "q" | "quit") {
+                    return true;
+                }
                 // Check for help command first - special case
                 if self.command_buffer.trim() == "h" || self.command_buffer.trim() == "help" {
                     self.mode = VimMode::Help;
@@ -570,6 +763,7 @@ impl VimEditor {
                     self.execute_command();
                     self.mode = VimMode::Normal;
                 }
+                self.visual_anchor = None;
                 self.command_buffer.clear();
             }
 
@@ -586,6 +780,93 @@ impl VimEditor {
         false
     }
 
+    fn handle_search_mode(&mut self, event: KeyEvent) -> bool {
+        match event.code {
+            KeyCode::Esc => {
+                self.mode = VimMode::Normal;
+                self.command_buffer.clear();
+            }
+
+            KeyCode::Enter => {
+                // An empty pattern repeats the last search, like vim
+                if !self.command_buffer.is_empty() {
+                    self.search = Some(std::mem::take(&mut self.command_buffer));
+                }
+                self.mode = VimMode::Normal;
+                self.search_next(true);
+            }
+
+            KeyCode::Char(c) => {
+                self.command_buffer.push(c);
+            }
+
+            KeyCode::Backspace => {
+                self.command_buffer.pop();
+            }
+
+            _ => {}
+        }
+        false
+    }
+    /// Returns whether the last search pattern occurs in a cell's shown value or label, or
+    /// in its formula.
+    ///
+    /// Cells without a formula are skipped, so searching for `0` does not match every blank
+    /// cell.
+    /// Returns the cells the formula being typed in insert mode refers to, as
+    /// `(top, left, bottom, right)` rectangles, which the grid highlights. Outside insert
+    /// mode there are none, so Esc and Enter clear them.
+    fn highlights(&self, sheet: &Sheet) -> Vec<(usize, usize, usize, usize)> {
+        match self.mode {
+            VimMode::Insert => referenced_cells(&self.current_input, (sheet.n, sheet.m)),
+            _ => Vec::new(),
+        }
+    }
+    fn is_match(&self, sheet: &Sheet, cell_index: usize) -> bool {
+        let Some(pattern) = self.search.as_deref() else {
+            return false;
+        };
+        let formula = sheet.cell_text(cell_index, true);
+        !formula.is_empty()
+            && (formula.contains(pattern) || sheet.cell_text(cell_index, false).contains(pattern))
+    }
+    /// Moves the cursor to the next match of the last search pattern in row-major order,
+    /// or the previous one if `forward` is false, wrapping around the sheet. The view
+    /// scrolls to the match if it is off screen.
+    fn search_next(&mut self, forward: bool) {
+        let Some(pattern) = self.search.clone() else {
+            self.set_error_message("No previous search, type / and a pattern".to_string());
+            return;
+        };
+        let found = {
+            let sheet = self.sheet.borrow();
+            let total = sheet.n * sheet.m;
+            let start = sheet.get_cell(self.cursor_y, self.cursor_x);
+            (1..=total)
+                .map(|step| {
+                    if forward {
+                        (start + step) % total
+                    } else {
+                        (start + total - step) % total
+                    }
+                })
+                .find(|&cell| self.is_match(&sheet, cell))
+                .map(|cell| (cell / sheet.m, cell % sheet.m))
+        };
+        let Some((row, col)) = found else {
+            self.set_error_message(format!("Pattern not found: {}", pattern));
+            return;
+        };
+        self.cursor_y = row;
+        self.cursor_x = col;
+        if !(self.start_row..self.start_row + self.display_rows).contains(&row) {
+            self.start_row = row;
+        }
+        if !(self.start_col..self.start_col + self.display_cols).contains(&col) {
+            self.start_col = col;
+        }
+    }
+
     fn handle_help_mode(&mut self, event: KeyEvent) -> bool {
         // Exit help mode on Escape key
         if event.code == KeyCode::Esc {
@@ -597,17 +878,74 @@ impl VimEditor {
     fn execute_command(&mut self) {
         let cmd = self.command_buffer.trim();
 
-        if cmd == "q" || cmd == "quit" {
-            std::process::exit(0);
-        } else if cmd == "undo" {
-            self.undo();
-            self.last_status = StatusCode::Ok;
-        } else if cmd == "redo" {
-            self.redo();
+        if cmd == "undo" || cmd == "redo" {
+            let cmd = cmd.to_string();
+            self.step_history(&cmd);
+        } else if let Some(view) = cmd
+            .strip_prefix("set view ")
+            .or_else(|| cmd.strip_prefix("set "))
+            .filter(|&view| view == "formulas" || view == "values")
+        {
+            self.show_formulas = view == "formulas";
             self.last_status = StatusCode::Ok;
+        } else if cmd == "chart" || cmd.starts_with("chart ") {
+            // A bare :chart draws the visual selection
+            let cmd = match (cmd, self.selection()) {
+                ("chart", Some((top, left, bottom, right))) => {
+                    let sheet = self.sheet.borrow();
+                    let corner = |row, col| sheet.cell_name(sheet.get_cell(row, col));
+                    format!("chart bar {}:{}", corner(top, left), corner(bottom, right))
+                }
+                _ => cmd.to_string(),
+            };
+            self.last_status = self.run_command(&cmd).err().unwrap_or(StatusCode::Ok);
+            if self.last_status != StatusCode::Ok {
+                self.set_error_message(
+                    "Use :chart on a selection, :chart bar A1:A10 or :chart off".to_string(),
+                );
+            }
+            if let Some((cols, rows)) = self.terminal_size {
+                self.fit_to_terminal(cols, rows);
+            }
+        } else if cmd == "set readonly" || cmd == "set noreadonly" {
+            let cmd = cmd.to_string();
+            self.last_status = self.run_command(&cmd).err().unwrap_or(StatusCode::Ok);
+        } else if cmd.starts_with("set overflow_policy ") || cmd.starts_with("set overflow ") {
+            let cmd = cmd.to_string();
+            self.last_status = self.run_command(&cmd).err().unwrap_or(StatusCode::Ok);
+        } else if let Some(note) = cmd.strip_prefix("note ") {
+            // Notes go on the cell under the cursor
+            let cell = self.sheet.borrow().get_cell(self.cursor_y, self.cursor_x);
+            let name = self.sheet.borrow().cell_name(cell);
+            let cmd = format!("note {} {}", name, note);
+            self.last_status = self.run_command(&cmd).err().unwrap_or(StatusCode::Ok);
+        } else if let Some(gap) = cmd.strip_prefix("set colgap ") {
+            self.last_status = match gap.trim().parse::<usize>() {
+                Ok(gap) => {
+                    self.col_gap = gap;
+                    if let Some((cols, rows)) = self.terminal_size {
+                        self.fit_to_terminal(cols, rows);
+                    }
+                    StatusCode::Ok
+                }
+                Err(_) => StatusCode::InvalidValue,
+            };
+        } else if let Some(path) = cmd
+            .strip_prefix("w ")
+            .or_else(|| cmd.strip_prefix("write "))
+        {
+            let path = path.trim().to_string();
+            let saved = self.sheet.borrow().save_csv(Path::new(&path));
+            self.last_status = match saved {
+                Ok(()) => StatusCode::Ok,
+                Err(e) => {
+                    self.set_error_message(format!("Cannot write {}: {}", path, e));
+                    StatusCode::InvalidValue
+                }
+            };
         } else if cmd == "w" || cmd == "write" {
-            // Save functionality could be implemented here
-            self.last_status = StatusCode::Ok;
+            self.set_error_message("No file name, use :w <file>".to_string());
+            self.last_status = StatusCode::InvalidCmd;
         } else if cmd.starts_with("maxcols ") {
             if let Some(max_str) = cmd.strip_prefix("setmaxcols ") {
                 if let Ok(max) = max_str.parse::<usize>() {
@@ -622,64 +960,55 @@ impl VimEditor {
         } else if cmd.starts_with("goto ") {
             // Parse cell reference and move cursor
             if let Some(cell_ref) = cmd.strip_prefix("goto ") {
-                // Simple A1 style reference parser
-                if let Some(col_index) = cell_ref.chars().next().filter(|c| c.is_ascii_uppercase())
-                {
-                    let col = (col_index as u8 - b'A') as usize;
-                    if let Ok(row) = cell_ref[1..].parse::<usize>().map(|r| r - 1) {
-                        if col < self.sheet.borrow().m && row < self.sheet.borrow().n {
-                            self.cursor_x = col;
-                            self.cursor_y = row;
-                            self.start_col = col;
-                            self.start_row = row;
-                            self.last_status = StatusCode::Ok;
-                            return;
-                        }
+                // Any A1 style reference, `AA12` and `zz3` included
+                if let Ok(cell) = cell_parser(cell_ref.trim()) {
+                    let (row, col) = self.sheet.borrow().get_row_and_column(cell);
+                    if col < self.sheet.borrow().m && row < self.sheet.borrow().n {
+                        self.jump_to(row, col);
+                        self.last_status = StatusCode::Ok;
+                        return;
                     }
                 }
 
                 self.last_status = StatusCode::InvalidCell;
             }
+        } else if cmd == "maxcell" || cmd == "mincell" {
+            let found = self.sheet.borrow().extreme_cell(cmd == "maxcell");
+            match found {
+                Some(cell) => {
+                    let (row, col) = self.sheet.borrow().get_row_and_column(cell);
+                    self.jump_to(row, col);
+                    self.last_status = StatusCode::Ok;
+                }
+                None => {
+                    self.set_error_message("No valid cells".to_string());
+                    self.last_status = StatusCode::InvalidValue;
+                }
+            }
         }
-        // Text formatting commands
-        else if cmd == "b" {
-            // Toggle bold for current cell
-            let format = &mut self.cell_formats[self.cursor_y][self.cursor_x];
-            format.bold = !format.bold;
-            self.last_status = StatusCode::Ok;
-        } else if cmd == "i" {
-            // Toggle italic for current cell
-            let format = &mut self.cell_formats[self.cursor_y][self.cursor_x];
-            format.italic = !format.italic;
-            self.last_status = StatusCode::Ok;
-        } else if cmd == "u" {
-            // Toggle underline for current cell
-            let format = &mut self.cell_formats[self.cursor_y][self.cursor_x];
-            format.underline = !format.underline;
-            self.last_status = StatusCode::Ok;
-        } else if cmd == "reset" {
-            // Reset formatting for current cell
-            self.cell_formats[self.cursor_y][self.cursor_x] = CellFormat::default();
-            self.last_status = StatusCode::Ok;
-        } else if cmd.starts_with("color ") {
+        // Text formatting commands, stored in the sheet so the standard display shows them too
+        else if let Some((change, style)) = match cmd {
+            "b" => Some((FormatChange::Bold, "bold")),
+            "i" => Some((FormatChange::Italic, "italic")),
+            "u" => Some((FormatChange::Underline, "underline")),
+            "reset" => Some((FormatChange::Reset, cmd)),
+            _ => {
+                let words: Vec<&str> = cmd.split_whitespace().collect();
+                number_format_parser(&words)
+                    .ok()
+                    .map(|number| (FormatChange::Number(number), cmd))
+            }
+        } {
+            let style = style.to_string();
+            self.change_format(change, &style);
+        } else if let Some(color_name) = cmd.strip_prefix("color ") {
             // Change text color
-            if let Some(color_name) = cmd.strip_prefix("color ") {
-                let color = match color_name.trim().to_lowercase().as_str() {
-                    "red" => Some(Color::Red),
-                    "green" => Some(Color::Green),
-                    "blue" => Some(Color::Blue),
-                    "yellow" => Some(Color::Yellow),
-                    "cyan" => Some(Color::Cyan),
-                    "magenta" => Some(Color::Magenta),
-                    "white" => Some(Color::White),
-                    "black" => Some(Color::Black),
-                    _ => None,
-                };
-
-                if let Some(c) = color {
-                    self.cell_formats[self.cursor_y][self.cursor_x].color = Some(c);
-                    self.last_status = StatusCode::Ok;
-                } else {
+            match color_parser(color_name.trim()) {
+                Ok(color) => {
+                    let style = format!("color {}", color_name.trim());
+                    self.change_format(FormatChange::Color(color), &style)
+                }
+                Err(_) => {
                     self.set_error_message(format!("Invalid color: {}", color_name));
                     self.last_status = StatusCode::InvalidCmd;
                 }
@@ -693,18 +1022,87 @@ impl VimEditor {
         }
     }
 
+    /// Applies a formatting change to the visual selection, or to the cell under the
+    /// cursor without one, as the `format` command with `style`, e.g. `bold`, so it is
+    /// journaled and autosaved like any other command.
+    ///
+    /// Bold, italic and underline are toggled: switched off if every cell already has the
+    /// style, and switched on for all of them otherwise.
+    fn change_format(&mut self, change: FormatChange, style: &str) {
+        if self.app.readonly() {
+            self.set_error_message("Sheet is read-only (see :set noreadonly)".to_string());
+            self.last_status = StatusCode::ReadOnly;
+            return;
+        }
+        let (top, left, bottom, right) = self.selection().unwrap_or((
+            self.cursor_y,
+            self.cursor_x,
+            self.cursor_y,
+            self.cursor_x,
+        ));
+        let command = {
+            let sheet = self.sheet.borrow();
+            let all_set = (top..=bottom)
+                .flat_map(|row| (left..=right).map(move |col| (row, col)))
+                .all(|(row, col)| {
+                    let format = sheet.format(sheet.get_cell(row, col));
+                    match change {
+                        FormatChange::Bold => format.bold,
+                        FormatChange::Italic => format.italic,
+                        FormatChange::Underline => format.underline,
+                        _ => false,
+                    }
+                });
+            let corner = |row, col| sheet.cell_name(sheet.get_cell(row, col));
+            let prefix = if all_set { "no" } else { "" };
+            format!(
+                "format {}:{} {}{}",
+                corner(top, left),
+                corner(bottom, right),
+                prefix,
+                style
+            )
+        };
+        self.last_status = self.run_command(&command).err().unwrap_or(StatusCode::Ok);
+    }
+    /// Scrolls the view by `delta` rows and columns, keeping it inside the sheet, and moves
+    /// the cursor by the same amount so it stays at the same place on screen.
+    fn scroll_view(&mut self, delta: (isize, isize)) {
+        let (n, m) = {
+            let sheet = self.sheet.borrow();
+            (sheet.n, sheet.m)
+        };
+        let (row, col) = scroll_position(
+            (self.start_row, self.start_col),
+            delta,
+            (self.display_rows, self.display_cols),
+            (n, m),
+        );
+        self.cursor_y = self
+            .cursor_y
+            .saturating_add_signed(row as isize - self.start_row as isize)
+            .min(n - 1);
+        self.cursor_x = self
+            .cursor_x
+            .saturating_add_signed(col as isize - self.start_col as isize)
+            .min(m - 1);
+        self.start_row = row;
+        self.start_col = col;
+    }
+    /// Moves the cursor to a cell and scrolls it to the top-left of the view.
+    fn jump_to(&mut self, row: usize, col: usize) {
+        self.cursor_x = col;
+        self.cursor_y = row;
+        self.start_col = col;
+        self.start_row = row;
+    }
+
     fn set_error_message(&mut self, message: String) {
         self.error_message = Some((message, Instant::now()));
     }
 
-    fn draw_help_menu(&self) -> io::Result<()> {
-        let mut stdout = io::stdout();
-        execute!(
-            stdout,
-            terminal::Clear(terminal::ClearType::All),
-            cursor::MoveTo(0, 0)
-        )?;
-
+    /// Returns the lines of the help menu.
+    fn help_lines(&self) -> Vec<String> {
         let help_text = [
             "📖 Spreadsheet Vim Mode Help Menu",
             "────────────────────────────────",
@@ -712,23 +1110,50 @@ impl VimEditor {
             "  h, ←        → Move left",
             "  l, →        → Move right",
             "  k, ↑        → Move up",
-            "  j, ↓        → Move down",
+            "  j, ↓        → Move down (a count repeats a move: 5j moves down five rows)",
+            "  gg, G       → Jump to the first or last row (5G: to row 5)",
+            "  0, $        → Jump to the first or last column",
+            "  Ctrl-d, Ctrl-u → Scroll half a page down or up",
+            "  PgUp, PgDn  → Scroll a page up or down (Home, End: first or last page)",
             "",
             "EDITING:",
             "  i           → Enter insert mode (for numeric input)",
             "  ESC         → Exit insert mode or command mode",
+            "  .           → Repeat the last edit at the cursor",
+            "  qa ... q    → Record the keys typed into register a (any of a-z)",
+            "  @a          → Replay register a (3@a replays it three times)",
+            "  Ctrl-`      → Toggle between values and formulas (also :set formulas|values)",
+            "  v           → Start a visual selection (move to extend, ESC to cancel)",
+            "  = (visual)  → Type one formula for the whole selection, shifted per cell",
+            "  d (visual)  → Blank the selection",
+            "  y (visual)  → Yank the selection, p pastes it at the cursor (or selection)",
+            "  : (visual)  → Apply a formatting command below to the whole selection",
+            "",
+            "SEARCH:",
+            "  /pattern    → Find cells whose value or formula contains pattern (highlighted)",
+            "  n, N        → Jump to the next or previous match",
             "",
             "COMMANDS (type : to enter command mode):",
             "  :h, :help   → Show this help menu",
-            "  :goto A1    → Jump to cell A1, also scrolls the sheet to that location.",
-            "  :q, :quit   → Quit the program",
-            "  :w, :write  → Save (placeholder)",
+            "  :goto A1    → Jump to cell A1 (or AA12), also scrolls the sheet to that location.",
+            "  :maxcell    → Jump to the cell with the largest value (:mincell for smallest)",
+            "  :q, :quit   → Quit vim mode",
+            "  :w file     → Save the sheet as CSV, keeping formulas",
+            "  :set colgap 2 → Put 2 spaces between columns (default 0)",
+            "  :set overflow_policy saturate → Clamp results too large to represent (default error)",
+            "  :set overflow * → Fill numbers too wide for their column with * (default #)",
+            "  :set readonly → Reject every edit until :set noreadonly (--readonly at startup)",
+            "  :set autosave 30s → Save the session every 30s (or every N edits: 20) for recovery",
+            "  :note \"text\" → Attach a note to the current cell, shown in the status line (\"\" removes it)",
+            "  :chart      → Draw the selection as a bar chart under the grid (:chart bar A1:A10, :chart off)",
             "",
             "TEXT FORMATTING:",
             "  :b          → Toggle bold for current cell",
             "  :i          → Toggle italic for current cell",
             "  :u          → Toggle underline for current cell",
             "  :color name → Change text color (red, green, blue, yellow, cyan, magenta)",
+            "  :date       → Show numbers as dates (:general to undo)",
+            "  :percent    → Show numbers as percentages (also :comma, :fixed 2)",
             "  :reset      → Remove all formatting",
             "",
             "CELL EDITING:",
@@ -740,178 +1165,1134 @@ impl VimEditor {
             "────────────────────────────────",
             "Press ESC to return to the spreadsheet.",
         ];
+        help_text.iter().map(|line| line.to_string()).collect()
+    }
 
-        for (i, line) in help_text.iter().enumerate() {
-            execute!(stdout, cursor::MoveTo(0, i as u16))?;
-            print!("{}", line);
-            execute!(stdout, cursor::MoveTo(0, (i + 1) as u16))?;
+    /// Returns the status bar: the cursor cell's name, formula and value, its note, the
+    /// aggregates of the visual selection and the status and time of the last command, e.g.
+    /// `C7: A1+B1 = 42  [0.0] (ok)`. A literal is shown once, as `C7: 5`, and a blank
+    /// cell by its name alone.
+    fn status_bar(&self, sheet: &Sheet) -> String {
+        let cell = sheet.get_cell(self.cursor_y, self.cursor_x);
+        let formula = sheet.cell_text(cell, true);
+        let value = sheet.cell_text(cell, false);
+        let mut bar = sheet.cell_name(cell);
+        if !sheet.get(cell).is_blank() {
+            bar += &format!(": {}", formula);
+            if formula != value {
+                bar += &format!(" = {}", value);
+            }
         }
-
-        stdout.flush()?;
-        Ok(())
+        if let Some(note) = sheet.note(cell) {
+            bar += &format!("  Note: {}", note);
+        }
+        if let Some((top, left, bottom, right)) = self.selection() {
+            let summary =
+                sheet.range_summary(sheet.get_cell(top, left), sheet.get_cell(bottom, right));
+            bar += &format!("  {}", summary);
+        }
+        let last = CommandResult {
+            elapsed: self.last_elapsed,
+            ..CommandResult::new(self.last_status)
+        };
+        format!("{}  {}", bar, last.summary())
     }
-
-    fn redraw_screen(&self) -> io::Result<()> {
-        // If we're in help mode, show the help menu and return
+    /// Returns the text shown for a cell and whether it is an error.
+    ///
+    /// In the formula view this is the cell's formula (blank if it has none), cut to fit
+    /// the column.
+    fn display_text(&self, sheet: &Sheet, cell_index: usize) -> (String, bool) {
+        let text = sheet.cell_text(cell_index, self.show_formulas);
+        if self.show_formulas {
+            let text = text.chars().take(self.col_width - 2).collect();
+            (sheet.with_note_marker(cell_index, text), false)
+        } else {
+            let data = sheet.get(cell_index);
+            let is_error = data.text().is_none() && data.info.invalid();
+            (sheet.with_note_marker(cell_index, text), is_error)
+        }
+    }
+    /// Fits the view to a terminal of `cols` x `rows` characters: as many rows and columns
+    /// as fit around the mode line, headers, status bar and error line.
+    fn fit_to_terminal(&mut self, cols: u16, rows: u16) {
+        self.terminal_size = Some((cols, rows));
+        let reserved = SCREEN_MARGIN_ROWS + self.app.parser_ctx.chart_lines();
+        self.display_rows = (rows as usize).saturating_sub(reserved).max(1);
+        self.display_cols =
+            (cols as usize).saturating_sub(LABEL_WIDTH + 1) / (self.col_width + self.col_gap);
+        self.display_cols = self.display_cols.max(1);
+        self.follow_cursor();
+    }
+    /// Returns the screen as lines of styled text, top to bottom: the mode line, the
+    /// column headers, the visible rows, the status bar and any error message. In help
+    /// mode it is the help menu.
+    fn frame(&self) -> Vec<String> {
         if let VimMode::Help = self.mode {
-            return self.draw_help_menu();
+            return self.help_lines();
         }
+        let mut lines = Vec::new();
 
-        let mut stdout = io::stdout();
-        execute!(
-            stdout,
-            terminal::Clear(terminal::ClearType::All),
-            cursor::MoveTo(0, 0)
-        )?;
-
-        // Display mode indicator
-
-        match self.mode {
-            VimMode::Normal => {
-                execute!(stdout, PrintStyledContent("-- NORMAL --".bold()))?;
-            }
-            VimMode::Insert => {
-                execute!(stdout, PrintStyledContent("-- INSERT --".bold().green()))?;
-                // Show current input in insert mode
-                if !self.current_input.is_empty() {
-                    print!(" Input: {}", self.current_input);
-                }
+        // Mode indicator
+        let mut mode_line = match self.mode {
+            VimMode::Normal => "-- NORMAL --".bold().to_string(),
+            VimMode::Insert if self.current_input.is_empty() => {
+                "-- INSERT --".bold().green().to_string()
             }
+            VimMode::Insert => format!(
+                "{} Input: {}",
+                "-- INSERT --".bold().green(),
+                self.current_input
+            ),
             VimMode::Command => {
-                execute!(stdout, PrintStyledContent("-- COMMAND --".bold().blue()))?;
-                print!(": {}", self.command_buffer);
+                format!("{}: {}", "-- COMMAND --".bold().blue(), self.command_buffer)
             }
-            VimMode::Help => {
-                return Ok(());
+            VimMode::Visual => "-- VISUAL --".bold().magenta().to_string(),
+            VimMode::Search => {
+                format!(
+                    "{} /{}",
+                    "-- SEARCH --".bold().yellow(),
+                    self.command_buffer
+                )
             }
+            VimMode::Help => String::new(),
+        };
+        if let Some((register, _)) = &self.recording {
+            mode_line += &format!("  recording @{}", register);
         }
+        if self.app.readonly() {
+            mode_line += "  [readonly]";
+        }
+        lines.push(mode_line);
+        lines.push(String::new());
 
-        // Move cursor to beginning of next line
-        execute!(stdout, cursor::MoveTo(0, 1))?;
-        println!();
-
-        // Display spreadsheet
         let sheet = self.sheet.borrow();
         let COL_WIDTH: usize = self.col_width; // Fixed column width for all cells
-        // const COL_WIDTH: usize = 10; // Fixed column width for all cells
-
-        // let display_rows = self.display_rows; // Number of rows to display
-        // let display_cols = self.display_cols; // Number of columns to display
-
-        // Column headers
-        execute!(stdout, cursor::MoveTo(0, 2))?;
-        print!("    "); // Row number column space
-        // for j in 0..sheet.m.min(20) {
-        //     let col_heading = crate::convert::num_to_alpha((j + 1) as u32);
-        //     print!("{:^10}", col_heading); // Centered in COL_WIDTH spaces
-        // }
-
-        // Column headers (starting from custom column)
+        let overflow = self.app.parser_ctx.overflow_marker; // `:set overflow` fill character
         let start_col = self.start_col;
         let start_row = self.start_row;
-        for j in start_col..(start_col + self.display_cols).min(sheet.m) {
+        let selection = self.selection();
+        let highlights = self.highlights(&sheet);
+        let cols = start_col..(start_col + self.display_cols).min(sheet.m);
+
+        // Column headers (starting from custom column)
+        let mut header = " ".repeat(LABEL_WIDTH + 1); // Row number column space
+        for j in cols.clone() {
             let col_heading = crate::convert::num_to_alpha((j + 1) as u32); // +1 if you want 1-based
-            print!("{:^10}", col_heading);
+            header += &pad_cell(
+                &col_heading,
+                COL_WIDTH,
+                self.col_gap,
+                Align::Center,
+                overflow,
+            );
         }
-
-        // Print each row
-        for i in start_row..(start_row + self.display_rows).min(sheet.n) {
-            execute!(stdout, cursor::MoveTo(0, (i - start_row + 4) as u16))?; // Adjust Y position
-            print!("{:3} ", i + 1); // Row number (1-based)
-
-            // Print cells for this row (starting from custom column)
-            for j in start_col..(start_col + self.display_cols).min(sheet.m) {
+        lines.push(header);
+        lines.push(String::new());
+
+        // Each visible row
+        let rows = start_row..(start_row + self.display_rows).min(sheet.n);
+        for i in rows.clone() {
+            let mut line = format!("{:w$} ", i + 1, w = LABEL_WIDTH); // Row number (1-based)
+            for j in cols.clone() {
                 let cell_index = sheet.get_cell(i, j);
-                let cell = &sheet.data[cell_index];
-                let format = &self.cell_formats[i][j];
+                let format = sheet.format(cell_index);
 
-                // Create cell content with fixed width
-                let (content, is_error) = if cell.info.invalid {
-                    ("ERR".to_string(), true)
+                let (content, _) = self.display_text(&sheet, cell_index);
+                // Text reads from the left, numbers stay centered
+                let align = if !self.show_formulas && sheet.get(cell_index).text().is_some() {
+                    Align::Left
                 } else {
-                    (format!("{}", cell.value), false)
+                    Align::Center
                 };
 
-                // Handle cursor cell with consistent width
-                // if i == self.cursor_y && j == self.cursor_x {
-                //     let cursor_content = if is_error {
-                //         format!("[{:^(COL_WIDTH-2)}]", "ERR") // 8 characters between brackets
-                //     } else {
-                //         format!("[{:^(COL_WIDTH-2)}]", content) // 8 characters between brackets
-                //     };
-                //     execute!(stdout, PrintStyledContent(cursor_content.red().bold()))?;
-                // } else {
-                //     // For normal cell - apply padding first, then style
-                //     let padded_content = format!("{:^COL_WIDTH}", content);
-
                 if i == self.cursor_y && j == self.cursor_x {
-                    let cursor_content = if is_error {
-                        format!("[{:^width$}]", "ERR", width = COL_WIDTH - 2)
-                    } else {
-                        format!("[{:^width$}]", content, width = COL_WIDTH - 2)
-                    };
-                    execute!(stdout, PrintStyledContent(cursor_content.red().bold()))?;
+                    let cursor_content = format!(
+                        "[{}]",
+                        pad_cell(&content, COL_WIDTH - 2, 0, align, overflow)
+                    );
+                    line += &cursor_content.red().bold().to_string();
                 } else {
-                    let padded_content = format!("{:^width$}", content, width = COL_WIDTH);
+                    let padded_content = pad_cell(&content, COL_WIDTH, 0, align, overflow);
 
                     // Apply formatting to the padded content
-                    let mut styled_content = padded_content.stylize();
-                    if let Some(color) = format.color {
-                        styled_content = styled_content.with(color);
+                    let mut styled_content = format.style(padded_content);
+                    if self.is_match(&sheet, cell_index) {
+                        styled_content = styled_content.black().on_yellow();
                     }
-                    if format.bold {
-                        styled_content = styled_content.bold();
+                    let referenced =
+                        |&(top, left, bottom, right): &(usize, usize, usize, usize)| {
+                            (top..=bottom).contains(&i) && (left..=right).contains(&j)
+                        };
+                    if highlights.iter().any(referenced) {
+                        styled_content = styled_content.black().on_cyan();
                     }
-                    if format.italic {
-                        styled_content = styled_content.italic();
-                    }
-                    if format.underline {
-                        styled_content = styled_content.underlined();
+                    if let Some((top, left, bottom, right)) = selection {
+                        if (top..=bottom).contains(&i) && (left..=right).contains(&j) {
+                            styled_content = styled_content.reverse();
+                        }
                     }
-
-                    // Print the styled content
-                    execute!(stdout, PrintStyledContent(styled_content))?;
+                    line += &styled_content.to_string();
                 }
+                line += &" ".repeat(self.col_gap);
             }
+            lines.push(line);
         }
 
-        // Status line - show expression for current cell if applicable
-        let status_line_y = (sheet.n.min(20) + 5) as u16;
-        execute!(stdout, cursor::MoveTo(0, status_line_y))?;
-
-        if let VimMode::Normal = self.mode {
-            let current_cell_idx = sheet.get_cell(self.cursor_y, self.cursor_x);
-            if let Some(expr) = self.cell_expressions.get(&current_cell_idx) {
-                print!(
-                    "Cell: {}{} = {}",
-                    crate::convert::num_to_alpha((self.cursor_x + 1) as u32),
-                    self.cursor_y + 1,
-                    expr
-                );
-            } else {
-                print!("Press 'i' for insert mode, ':' for commands, ':h' for help, 'q' to quit");
-            }
-        } else if let VimMode::Command = self.mode {
-            print!(":{}", self.command_buffer);
+        if let Some((start, end)) = self.app.parser_ctx.chart {
+            let width = self
+                .terminal_size
+                .map_or(chart::DEFAULT_WIDTH, |(cols, _)| cols as usize);
+            lines.push(String::new());
+            lines.extend(chart::bar_chart(&sheet, start, end, width));
         }
 
-        // // Status line at bottom
-        // let status_line_y = (sheet.n.min(20) + 5) as u16;
-        // execute!(stdout, cursor::MoveTo(0, status_line_y))?;
-
-        // if let VimMode::Command = self.mode {
-        //     print!(":{}", self.command_buffer);
-        // } else {
-        //     print!("Press 'i' for insert mode, ':' for commands, ':h' for help, 'q' to quit");
-        // }
+        // Status bar: the cursor cell and the last command, after a blank line
+        lines.push(String::new());
+        lines.push(self.status_bar(&sheet));
 
-        // Display error message if any
+        // Error message, if any
         if let Some((error_msg, _)) = &self.error_message {
-            execute!(stdout, cursor::MoveTo(0, status_line_y + 1))?;
-            execute!(stdout, PrintStyledContent(error_msg.as_str().red().bold()))?;
+            lines.push(error_msg.as_str().red().bold().to_string());
         }
+        lines
+    }
 
+    /// Draws the current frame, rewriting only the lines that differ from the frame drawn
+    /// before, all in one write, so large terminals do not flicker.
+    fn redraw_screen(&mut self) -> io::Result<()> {
+        let frame = self.frame();
+        let mut stdout = io::stdout();
+        for y in changed_lines(&self.screen, &frame) {
+            queue!(stdout, cursor::MoveTo(0, y as u16))?;
+            match frame.get(y) {
+                Some(line) => queue!(
+                    stdout,
+                    Print(line),
+                    terminal::Clear(terminal::ClearType::UntilNewLine)
+                )?,
+                None => queue!(stdout, terminal::Clear(terminal::ClearType::CurrentLine))?,
+            }
+        }
         stdout.flush()?;
+        self.screen = frame;
         Ok(())
     }
 }
+
+/// Returns the indices of the lines that differ between two frames, including the lines
+/// only one of them has.
+fn changed_lines(previous: &[String], next: &[String]) -> Vec<usize> {
+    (0..previous.len().max(next.len()))
+        .filter(|&y| previous.get(y) != next.get(y))
+        .collect()
+}
+
+/// Chooses how long the main loop waits for input before checking its timers again.
+///
+/// Key presses end the wait immediately, so this only bounds how often an idle editor
+/// wakes up. While the user is active (a key within `ACTIVE_WINDOW`), `base` is used;
+/// after that the wait grows to `IDLE_POLL`. A pending error-message timeout always
+/// caps the wait so the message is still cleared on time.
+fn poll_interval(
+    base: Duration,
+    idle_for: Duration,
+    error_remaining: Option<Duration>,
+) -> Duration {
+    let interval = if idle_for < ACTIVE_WINDOW {
+        base
+    } else {
+        base.max(IDLE_POLL)
+    };
+    match error_remaining {
+        Some(remaining) => interval.min(remaining),
+        None => interval,
+    }
+}
+
+/// Shifts every cell reference in `expr` by `(rows, cols)`, as a copy of the formula
+/// that far away would read, keeping the `$` parts of a reference such as `$A1` in place.
+///
+/// Returns `None` if a shifted reference leaves a sheet of `(n, m)` cells.
+fn shift_references(
+    expr: &str,
+    (rows, cols): (isize, isize),
+    (n, m): (usize, usize),
+) -> Option<String> {
+    map_references(expr, |r, c, fixed| {
+        let r = if fixed & FIXED_ROW != 0 {
+            Some(r)
+        } else {
+            r.checked_add_signed(rows)
+        };
+        let c = if fixed & FIXED_COL != 0 {
+            Some(c)
+        } else {
+            c.checked_add_signed(cols)
+        };
+        match (r, c) {
+            (Some(r), Some(c)) if r < n && c < m => Some((r, c)),
+            _ => None,
+        }
+    })
+}
+/// Returns the cells and ranges `expr` refers to inside a sheet of `(n, m)` cells, as
+/// `(top, left, bottom, right)` rectangles in the order they appear. Function names such
+/// as `MAX2(`, text and references past the edge of the sheet are skipped, so a formula
+/// still being typed can be read.
+fn referenced_cells(expr: &str, (n, m): (usize, usize)) -> Vec<(usize, usize, usize, usize)> {
+    if expr.starts_with('"') {
+        return Vec::new();
+    }
+    let position = |letters: &str, digits: &str| {
+        let col = crate::convert::alpha_to_num(&letters.to_ascii_uppercase())?.checked_sub(1)?;
+        let row = digits.parse::<usize>().ok()?.checked_sub(1)?;
+        (row < n && col < m).then_some((row, col))
+    };
+    REFERENCE
+        .captures_iter(expr)
+        .filter(|caps| caps.get(5).is_none())
+        .filter_map(|caps| {
+            let (r1, c1) = position(&caps[1], &caps[2])?;
+            let (r2, c2) = match (caps.get(3), caps.get(4)) {
+                (Some(letters), Some(digits)) => position(letters.as_str(), digits.as_str())?,
+                _ => (r1, c1),
+            };
+            Some((r1.min(r2), c1.min(c2), r1.max(r2), c1.max(c2)))
+        })
+        .collect()
+}
+/// Rewrites every cell reference (e.g. `B12` or `$B$12`) in `expr` through `f`, which
+/// takes a 0-based `(row, col)` pair with the reference's `FIXED_COL`/`FIXED_ROW` flags
+/// and returns the new pair. The `$` signs are written back as they were.
+///
+/// Returns `None` as soon as `f` rejects a reference.
+fn map_references<F>(expr: &str, mut f: F) -> Option<String>
+where
+    F: FnMut(usize, usize, u8) -> Option<(usize, usize)>,
+{
+    // Text such as `"Q1"` holds no references
+    if text_literal(expr).is_some() {
+        return Some(expr.to_string());
+    }
+    let mut result = String::with_capacity(expr.len());
+    let chars: Vec<char> = expr.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let col_fixed = chars[i] == '$';
+        let letters_start = i + col_fixed as usize;
+        if !chars
+            .get(letters_start)
+            .is_some_and(char::is_ascii_alphabetic)
+        {
+            result.push(chars[i]);
+            i += 1;
+            continue;
+        }
+        let letters_end = (letters_start..chars.len())
+            .find(|&j| !chars[j].is_ascii_alphabetic())
+            .unwrap_or(chars.len());
+        let row_fixed = chars.get(letters_end) == Some(&'$');
+        let digits_start = letters_end + row_fixed as usize;
+        let digits_end = (digits_start..chars.len())
+            .find(|&j| !chars[j].is_ascii_digit())
+            .unwrap_or(chars.len());
+        let letters: String = chars[letters_start..letters_end].iter().collect();
+        let digits: String = chars[digits_start..digits_end].iter().collect();
+        match (
+            crate::convert::alpha_to_num(&letters.to_ascii_uppercase()),
+            digits.parse::<usize>(),
+        ) {
+            (Some(col), Ok(row)) if row > 0 => {
+                let fixed =
+                    if col_fixed { FIXED_COL } else { 0 } | if row_fixed { FIXED_ROW } else { 0 };
+                let (row, col) = f(row - 1, col - 1, fixed)?;
+                let sign = |fixed: bool| if fixed { "$" } else { "" };
+                result.push_str(sign(col_fixed));
+                result.push_str(&crate::convert::num_to_alpha((col + 1) as u32));
+                result.push_str(sign(row_fixed));
+                result.push_str(&(row + 1).to_string());
+            }
+            _ => {
+                result.extend(&chars[i..digits_end]);
+            }
+        }
+        i = digits_end;
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sheet::{TEST_COLS, TEST_ROWS, init_test_dimensions};
+
+    fn editor() -> VimEditor {
+        init_test_dimensions();
+        VimEditor::new(Spreadsheet::new(TEST_ROWS, TEST_COLS))
+    }
+
+    fn key(c: char) -> KeyEvent {
+        KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE)
+    }
+
+    fn type_input(editor: &mut VimEditor, text: &str) {
+        editor.handle_key_event(key('i'));
+        for c in text.chars() {
+            editor.handle_key_event(key(c));
+        }
+        editor.handle_key_event(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE));
+    }
+
+    fn value_at(editor: &VimEditor, row: usize, col: usize) -> i32 {
+        let sheet = editor.sheet.borrow();
+        sheet.get(sheet.get_cell(row, col)).value as i32
+    }
+
+    fn formula(editor: &VimEditor, cell: usize) -> String {
+        editor.sheet.borrow().cell_text(cell, true)
+    }
+
+    fn text(editor: &VimEditor, cell: usize) -> (String, bool) {
+        editor.display_text(&editor.sheet.borrow(), cell)
+    }
+
+    #[test]
+    fn test_dot_repeats_last_edit_at_cursor() {
+        let mut editor = editor();
+        type_input(&mut editor, "5");
+        assert_eq!(value_at(&editor, 0, 0), 5);
+
+        editor.handle_key_event(key('j'));
+        editor.handle_key_event(key('l'));
+        editor.handle_key_event(key('.'));
+        assert_eq!(value_at(&editor, 1, 1), 5);
+        assert_eq!(editor.app.history().undo_steps().len(), 2);
+    }
+
+    #[test]
+    fn test_macros_record_and_replay_keys() {
+        let mut editor = editor();
+        type_input(&mut editor, "1");
+        // Record: move down, write the cell above plus one
+        for c in "qaj".chars() {
+            editor.handle_key_event(key(c));
+        }
+        assert!(editor.frame()[0].contains("recording @a"));
+        type_input(&mut editor, "A1+1");
+        editor.handle_key_event(key('q'));
+        assert!(editor.recording.is_none());
+        assert_eq!(editor.macros[&'a'].len(), 7);
+        assert_eq!(value_at(&editor, 1, 0), 2);
+
+        for c in "3@a".chars() {
+            editor.handle_key_event(key(c));
+        }
+        assert_eq!(editor.cursor_y, 4);
+        // Each replayed edit went through the engine as its own undo step
+        assert_eq!(formula(&editor, 4 * TEST_COLS), "A1+1");
+        assert_eq!(editor.app.history().undo_steps().len(), 5);
+        editor.app.execute("A1=10").unwrap();
+        assert_eq!(value_at(&editor, 4, 0), 11);
+        editor.step_history("undo");
+        editor.step_history("undo");
+        assert_eq!(value_at(&editor, 4, 0), 0);
+
+        // A macro replaying itself stops at the depth limit
+        for c in "qbj@bq@b".chars() {
+            editor.handle_key_event(key(c));
+        }
+        assert_eq!(editor.cursor_y, 5 + MAX_MACRO_DEPTH);
+        assert!(!editor.handle_key_event(key('@')));
+        assert!(!editor.handle_key_event(key('z')));
+        assert!(editor.error_message.is_some());
+    }
+
+    #[test]
+    fn test_dot_without_edit_does_nothing() {
+        let mut editor = editor();
+        editor.handle_key_event(key('.'));
+        assert!(editor.app.history().undo_steps().len() == 0);
+    }
+
+    #[test]
+    fn test_division_keeps_fractions() {
+        let mut editor = editor();
+        type_input(&mut editor, "7/2");
+        assert_eq!(editor.sheet.borrow().get(0).value, 3.5);
+        assert_eq!(text(&editor, 0), ("3.5".to_string(), false));
+    }
+
+    #[test]
+    fn test_text_cell_is_shown_but_not_computed() {
+        let mut editor = editor();
+        type_input(&mut editor, "\"Q1 total\"");
+        assert_eq!(text(&editor, 0), ("Q1 total".to_string(), false));
+
+        editor.handle_key_event(key('l'));
+        type_input(&mut editor, "A1+1");
+        // Arithmetic on text evaluates to an error, as in the standard frontend
+        assert_eq!(text(&editor, 1), ("#VALUE".to_string(), true));
+        assert_eq!(formula(&editor, 1), "A1+1");
+    }
+
+    fn select_and_fill(editor: &mut VimEditor, moves: &str, formula: &str) {
+        editor.handle_key_event(key('v'));
+        for c in moves.chars() {
+            editor.handle_key_event(key(c));
+        }
+        editor.handle_key_event(key('='));
+        for c in formula.chars() {
+            editor.handle_key_event(key(c));
+        }
+        editor.handle_key_event(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE));
+    }
+
+    #[test]
+    fn test_visual_fill_shifts_references_per_cell() {
+        let mut editor = editor();
+        for value in ["3", "4", "5"] {
+            type_input(&mut editor, value);
+            editor.handle_key_event(key('j'));
+        }
+        // Select B1:B3 and fill with A1*2
+        editor.handle_key_event(key('k'));
+        editor.handle_key_event(key('l'));
+        select_and_fill(&mut editor, "kk", "A1*2");
+
+        assert_eq!([0, 1, 2].map(|row| value_at(&editor, row, 1)), [6, 8, 10]);
+        assert_eq!(formula(&editor, 2 * TEST_COLS + 1), "A3*2");
+        assert_eq!(editor.app.history().undo_steps().len(), 4);
+
+        // The filled cells stay linked to their own row
+        editor.cursor_x = 0;
+        editor.cursor_y = 1;
+        type_input(&mut editor, "7");
+        assert_eq!(value_at(&editor, 1, 1), 14);
+    }
+
+    #[test]
+    fn test_visual_fill_rejects_cycles_and_bad_cells() {
+        let mut editor = editor();
+        // B2 = A1*1, then fill A1:A2 with B2+1, which makes A1 depend on itself
+        editor.cursor_x = 1;
+        editor.cursor_y = 1;
+        type_input(&mut editor, "A1*1");
+        editor.cursor_x = 0;
+        editor.cursor_y = 0;
+        select_and_fill(&mut editor, "j", "B2+1");
+        assert!(editor.error_message.is_some());
+        assert_eq!(formula(&editor, 0), "");
+        assert_eq!(editor.app.history().undo_steps().len(), 1);
+
+        // A division by zero only makes its own cell an error
+        editor.error_message = None;
+        editor.cursor_x = 2;
+        editor.cursor_y = 0;
+        type_input(&mut editor, "1");
+        editor.cursor_x = 3;
+        type_input(&mut editor, "9");
+        // D1:D2 = 10/C1, 10/C2 where C2 is empty
+        select_and_fill(&mut editor, "j", "10/C1");
+        assert!(editor.error_message.is_none());
+        assert_eq!(value_at(&editor, 0, 3), 10);
+        assert_eq!(text(&editor, TEST_COLS + 3), ("#DIV/0".to_string(), true));
+        assert_eq!(editor.app.history().undo_steps().len(), 4);
+
+        // A reference shifted off the sheet rejects the whole fill
+        editor.cursor_x = 0;
+        editor.cursor_y = TEST_ROWS - 2;
+        select_and_fill(&mut editor, "j", &format!("A{}+1", TEST_ROWS));
+        assert!(editor.error_message.is_some());
+        assert_eq!(formula(&editor, (TEST_ROWS - 2) * TEST_COLS), "");
+        assert_eq!(editor.app.history().undo_steps().len(), 4);
+        assert!(editor.app.history().redo_steps().is_empty());
+    }
+
+    #[test]
+    fn test_visual_delete_yank_and_paste() {
+        let mut editor = editor();
+        // A1=2, A2=A1*3, B1=A2+1
+        type_input(&mut editor, "2");
+        editor.handle_key_event(key('j'));
+        type_input(&mut editor, "A1*3");
+        editor.cursor_x = 1;
+        editor.cursor_y = 0;
+        type_input(&mut editor, "A2+1");
+
+        // Yank A1:A2 and paste it at C3: the copy refers to its own cells
+        editor.cursor_x = 0;
+        for c in ['v', 'j', 'y'] {
+            editor.handle_key_event(key(c));
+        }
+        assert!(editor.visual_anchor.is_none());
+        assert_eq!((editor.cursor_y, editor.cursor_x), (0, 0));
+        editor.cursor_x = 2;
+        editor.cursor_y = 2;
+        editor.handle_key_event(key('p'));
+        assert_eq!(formula(&editor, 3 * TEST_COLS + 2), "C3*3");
+        assert_eq!(value_at(&editor, 3, 2), 6);
+
+        // Deleting A1:B2 blanks them and recomputes B1's dependents in one step
+        editor.cursor_x = 0;
+        editor.cursor_y = 0;
+        for c in ['v', 'j', 'l', 'd'] {
+            editor.handle_key_event(key(c));
+        }
+        assert_eq!(formula(&editor, 0), "");
+        assert_eq!([value_at(&editor, 0, 0), value_at(&editor, 1, 0)], [0, 0]);
+        let undo_depth = editor.app.history().undo_steps().len();
+        editor.step_history("undo");
+        assert_eq!(editor.app.history().undo_steps().len(), undo_depth - 1);
+        assert_eq!(value_at(&editor, 0, 1), 7);
+
+        // A paste that would run off the sheet changes nothing
+        editor.cursor_x = TEST_COLS - 1;
+        editor.cursor_y = TEST_ROWS - 1;
+        editor.handle_key_event(key('p'));
+        assert!(editor.error_message.is_some());
+        assert_eq!(editor.app.history().undo_steps().len(), undo_depth - 1);
+    }
+
+    #[test]
+    fn test_format_command_applies_to_selection() {
+        let mut editor = editor();
+        let bold = |editor: &VimEditor| -> Vec<bool> {
+            (0..3)
+                .map(|c| editor.sheet.borrow().format(c).bold)
+                .collect()
+        };
+        editor.handle_key_event(key(':'));
+        editor.handle_key_event(key('b'));
+        editor.handle_key_event(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE));
+        assert_eq!(bold(&editor), [true, false, false]);
+
+        // A mixed selection is switched on first, then off
+        for _ in 0..2 {
+            for c in ['v', 'l', 'l', ':', 'b'] {
+                editor.handle_key_event(key(c));
+            }
+            editor.handle_key_event(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE));
+            editor.cursor_x = 0;
+        }
+        assert!(editor.visual_anchor.is_none());
+        assert_eq!(bold(&editor), [false, false, false]);
+
+        type_input(&mut editor, "DATE(2024,1,31)");
+        for c in ":date".chars() {
+            editor.handle_key_event(key(c));
+        }
+        editor.handle_key_event(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE));
+        assert_eq!(
+            editor.display_text(&editor.sheet.borrow(), 0).0,
+            "2024-01-31"
+        );
+    }
+
+    #[test]
+    fn test_format_changes_are_journaled() {
+        let mut editor = editor();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.txt");
+        editor
+            .app
+            .set_journal(crate::journal::Journal::open(&path).unwrap());
+        for command in ["b", "b", "color red", "fixed 2"] {
+            editor.handle_key_event(key(':'));
+            for c in command.chars() {
+                editor.handle_key_event(key(c));
+            }
+            editor.handle_key_event(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE));
+            assert_eq!(editor.last_status, StatusCode::Ok, "{}", command);
+        }
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "format A1:A1 bold\nformat A1:A1 nobold\nformat A1:A1 color red\nformat A1:A1 fixed 2\n"
+        );
+        let format = editor.sheet.borrow().format(0);
+        assert_eq!((format.bold, format.color), (false, Some(Color::Red)));
+    }
+
+    #[test]
+    fn test_invalid_input_error_says_where() {
+        let mut editor = editor();
+        editor.cursor_x = 2;
+        type_input(&mut editor, "A1+*2");
+        let (message, _) = editor.error_message.clone().unwrap();
+        // The column counts in what was typed, not in the `C1=` command
+        assert_eq!(
+            message,
+            "Invalid expression (unexpected token '*' at column 4): A1+*2"
+        );
+    }
+
+    #[test]
+    fn test_status_bar_sums_the_visual_selection() {
+        let mut editor = editor();
+        for (col, input) in [(0, "3"), (1, "5")] {
+            editor.cursor_x = col;
+            type_input(&mut editor, input);
+        }
+        editor.cursor_x = 0;
+        let bar = |editor: &VimEditor| editor.status_bar(&editor.sheet.borrow());
+        assert!(!bar(&editor).contains("SUM="));
+        editor.handle_key_event(key('v'));
+        editor.handle_key_event(key('l'));
+        assert!(bar(&editor).contains("SUM=8 AVG=4 COUNT=2 MIN=3 MAX=5"));
+        editor.handle_key_event(KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE));
+        assert!(!bar(&editor).contains("SUM="));
+    }
+
+    #[test]
+    fn test_readonly_refuses_insert_mode() {
+        let mut editor = editor();
+        let run = |editor: &mut VimEditor, command: &str| {
+            for c in format!(":{}", command).chars() {
+                editor.handle_key_event(key(c));
+            }
+            editor.handle_key_event(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE));
+        };
+        run(&mut editor, "set readonly");
+        assert!(editor.frame()[0].contains("[readonly]"));
+        type_input(&mut editor, "42");
+        assert_eq!(value_at(&editor, 0, 0), 0);
+        assert_eq!(editor.last_status, StatusCode::ReadOnly);
+        run(&mut editor, "b");
+        assert!(!editor.sheet.borrow().format(0).bold);
+
+        run(&mut editor, "set noreadonly");
+        editor.cursor_y = 0;
+        editor.cursor_x = 0;
+        type_input(&mut editor, "42");
+        assert_eq!(value_at(&editor, 0, 0), 42);
+    }
+
+    #[test]
+    fn test_chart_draws_the_selection_under_the_grid() {
+        let mut editor = editor();
+        editor.fit_to_terminal(80, 30);
+        let rows = editor.display_rows;
+        for (row, input) in [(0, "4"), (1, "2")] {
+            editor.cursor_y = row;
+            type_input(&mut editor, input);
+        }
+        editor.cursor_y = 0;
+        for c in "vj:chart".chars() {
+            editor.handle_key_event(key(c));
+        }
+        editor.handle_key_event(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE));
+        let a2 = editor.sheet.borrow().get_cell(1, 0);
+        assert_eq!(editor.app.parser_ctx.chart, Some((0, a2)));
+        // The grid gives up a blank line and a line per bar
+        assert_eq!(editor.display_rows, rows - 3);
+        let frame = editor.frame();
+        let bars: Vec<&String> = frame.iter().filter(|line| line.contains('│')).collect();
+        assert_eq!(bars.len(), 2);
+        assert!(bars[0].starts_with("A1 │█") && bars[0].ends_with(" 4"));
+
+        for c in ":chart off".chars() {
+            editor.handle_key_event(key(c));
+        }
+        editor.handle_key_event(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE));
+        assert_eq!(editor.app.parser_ctx.chart, None);
+        assert_eq!(editor.display_rows, rows);
+    }
+
+    #[test]
+    fn test_search_steps_through_values_and_formulas() {
+        let mut editor = editor();
+        // B7=42, C2=B7*2 and A9=B7+1 all mention B7; D1 shows 42 as a value only
+        for (row, col, input) in [(6, 1, "42"), (1, 2, "B7*2"), (8, 0, "B7+1"), (0, 3, "40+2")] {
+            editor.cursor_y = row;
+            editor.cursor_x = col;
+            type_input(&mut editor, input);
+        }
+        editor.cursor_y = 0;
+        editor.cursor_x = 0;
+        editor.handle_key_event(key('/'));
+        for c in "B7".chars() {
+            editor.handle_key_event(key(c));
+        }
+        editor.handle_key_event(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE));
+        assert_eq!((editor.cursor_y, editor.cursor_x), (1, 2));
+        editor.handle_key_event(key('n'));
+        assert_eq!((editor.cursor_y, editor.cursor_x), (8, 0));
+        // Wraps around the sheet
+        editor.handle_key_event(key('n'));
+        assert_eq!((editor.cursor_y, editor.cursor_x), (1, 2));
+        editor.handle_key_event(key('N'));
+        assert_eq!((editor.cursor_y, editor.cursor_x), (8, 0));
+
+        // Values match too, so 42 is found in B7 and in D1
+        editor.search = Some("42".to_string());
+        let matches: Vec<bool> = {
+            let sheet = editor.sheet.borrow();
+            [3, 6 * TEST_COLS + 1, 2 * TEST_COLS]
+                .map(|cell| editor.is_match(&sheet, cell))
+                .to_vec()
+        };
+        assert_eq!(matches, [true, true, false]);
+
+        editor.search = Some("nowhere".to_string());
+        editor.handle_key_event(key('n'));
+        assert!(editor.error_message.is_some());
+        assert_eq!((editor.cursor_y, editor.cursor_x), (8, 0));
+    }
+
+    #[test]
+    fn test_map_references() {
+        let shifted = map_references("A1+BC12", |r, c, _| Some((r + 1, c + 2)));
+        assert_eq!(shifted.as_deref(), Some("C2+BE13"));
+        assert_eq!(
+            map_references("A1*2", |r, c, _| (r > 0).then_some((r, c))),
+            None
+        );
+        // Absolute parts stay where they are
+        let shifted = shift_references("$A$1+$A1+A$1+SUM(A1:$B2)", (2, 1), (5, 5));
+        assert_eq!(shifted.as_deref(), Some("$A$1+$A3+B$1+SUM(B3:$B4)"));
+        assert_eq!(
+            shift_references("$E1", (0, 1), (5, 5)).as_deref(),
+            Some("$E1")
+        );
+        assert_eq!(shift_references("E1", (0, 1), (5, 5)), None);
+    }
+
+    #[test]
+    fn test_deep_dependency_chain_recomputes_through_engine() {
+        let mut editor = editor();
+        for row in 1..TEST_ROWS {
+            let input = format!("A{}=A{}+1", row + 1, row);
+            editor.app.execute(&input).unwrap();
+        }
+        type_input(&mut editor, "5");
+        assert_eq!(
+            value_at(&editor, TEST_ROWS - 1, 0),
+            5 + TEST_ROWS as i32 - 1
+        );
+
+        editor.step_history("undo");
+        assert_eq!(value_at(&editor, TEST_ROWS - 1, 0), TEST_ROWS as i32 - 1);
+    }
+
+    #[test]
+    fn test_cyclic_edit_is_rejected() {
+        let mut editor = editor();
+        editor.cursor_x = 1;
+        type_input(&mut editor, "A1+1");
+        editor.cursor_x = 0;
+        type_input(&mut editor, "B1+1");
+        assert_eq!(editor.last_status, StatusCode::CyclicDep);
+        assert_eq!(
+            editor
+                .error_message
+                .as_ref()
+                .map(|(message, _)| message.as_str()),
+            Some("Cyclic dependency (cycle: A1 -> B1 -> A1): B1+1")
+        );
+        assert_eq!(formula(&editor, 0), "");
+        assert_eq!(value_at(&editor, 0, 1), 1);
+    }
+
+    #[test]
+    fn test_poll_interval_adapts_to_activity() {
+        let base = Duration::from_millis(DEFAULT_POLL_MS);
+        let active = Duration::from_millis(500);
+        let idle = Duration::from_secs(30);
+        assert_eq!(poll_interval(base, active, None), base);
+        assert_eq!(poll_interval(base, idle, None), IDLE_POLL);
+        // A slower configured interval is never shortened when idle
+        let slow = Duration::from_millis(1500);
+        assert_eq!(poll_interval(slow, idle, None), slow);
+        // A pending error message still expires on time
+        let remaining = Some(Duration::from_millis(300));
+        assert_eq!(
+            poll_interval(base, idle, remaining),
+            Duration::from_millis(300)
+        );
+        assert_eq!(poll_interval(base, active, remaining), base);
+    }
+
+    #[test]
+    fn test_formula_view_toggle() {
+        let mut editor = editor();
+        type_input(&mut editor, "4");
+        editor.handle_key_event(key('l'));
+        type_input(&mut editor, "A1*2");
+        let text = |editor: &VimEditor, cell: usize| text(editor, cell).0;
+        assert_eq!(text(&editor, 1), "8");
+
+        let toggle = KeyEvent::new(KeyCode::Char('`'), KeyModifiers::CONTROL);
+        editor.handle_key_event(toggle);
+        assert_eq!(text(&editor, 1), "A1*2");
+        assert_eq!(text(&editor, 2), "");
+        editor.handle_key_event(toggle);
+        assert_eq!(text(&editor, 1), "8");
+
+        for (command, shown) in [("set formulas", "A1*2"), ("set values", "8")] {
+            editor.handle_key_event(key(':'));
+            for c in command.chars() {
+                editor.handle_key_event(key(c));
+            }
+            editor.handle_key_event(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE));
+            assert_eq!(text(&editor, 1), shown);
+        }
+    }
+
+    #[test]
+    fn test_maxcell_and_mincell_jump() {
+        let mut editor = editor();
+        let run = |editor: &mut VimEditor, command: &str| {
+            editor.handle_key_event(key(':'));
+            for c in command.chars() {
+                editor.handle_key_event(key(c));
+            }
+            editor.handle_key_event(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE));
+        };
+        editor.handle_key_event(key('j'));
+        type_input(&mut editor, "7");
+        editor.handle_key_event(key('l'));
+        type_input(&mut editor, "-3");
+
+        run(&mut editor, "maxcell");
+        assert_eq!((editor.cursor_y, editor.cursor_x), (1, 0));
+        assert_eq!((editor.start_row, editor.start_col), (1, 0));
+        run(&mut editor, "mincell");
+        assert_eq!((editor.cursor_y, editor.cursor_x), (1, 1));
+    }
+
+    #[test]
+    fn test_goto_reaches_multi_letter_columns() {
+        let mut editor = editor();
+        editor.fit_to_terminal(80, 27);
+        let run = |editor: &mut VimEditor, command: &str| {
+            editor.handle_key_event(key(':'));
+            for c in command.chars() {
+                editor.handle_key_event(key(c));
+            }
+            editor.handle_key_event(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE));
+        };
+        run(&mut editor, "goto AA12");
+        assert_eq!(editor.last_status, StatusCode::Ok);
+        assert_eq!((editor.cursor_y, editor.cursor_x), (11, 26));
+        run(&mut editor, "goto cv3");
+        assert_eq!((editor.cursor_y, editor.cursor_x), (2, 99));
+
+        // Two-letter headers stay centered over their columns, past the row labels
+        editor.handle_key_event(key('h'));
+        let header = &editor.frame()[2];
+        let width = editor.col_width + editor.col_gap;
+        assert_eq!(header.len(), LABEL_WIDTH + 1 + 2 * width);
+        assert_eq!(header[LABEL_WIDTH + 1 + width..].trim(), "CV");
+        assert_eq!(header[LABEL_WIDTH + 1..][..width].trim(), "CU");
+
+        for bad in ["goto CW1", "goto A0", "goto 12"] {
+            run(&mut editor, bad);
+            assert_eq!(editor.last_status, StatusCode::InvalidCell, "{}", bad);
+        }
+        assert_eq!((editor.cursor_y, editor.cursor_x), (2, 98));
+    }
+
+    #[test]
+    fn test_set_overflow_changes_the_marker() {
+        let mut editor = editor();
+        editor.app.execute("A2=999999*99999").unwrap();
+        let run = |editor: &mut VimEditor, command: &str| {
+            editor.handle_key_event(key(':'));
+            for c in command.chars() {
+                editor.handle_key_event(key(c));
+            }
+            editor.handle_key_event(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE));
+        };
+        let row = |editor: &VimEditor| editor.frame()[5].clone();
+        assert!(row(&editor).contains(&"#".repeat(editor.col_width)));
+
+        run(&mut editor, "set overflow *");
+        assert_eq!(editor.last_status, StatusCode::Ok);
+        assert!(row(&editor).contains(&"*".repeat(editor.col_width)));
+        assert!(!row(&editor).contains('#'));
+        run(&mut editor, "set overflow ab");
+        assert_ne!(editor.last_status, StatusCode::Ok);
+        assert_eq!(editor.app.parser_ctx.overflow_marker, '*');
+    }
+
+    #[test]
+    fn test_note_goes_on_cursor_cell() {
+        let mut editor = editor();
+        editor.handle_key_event(key(':'));
+        for c in "note \"check this\"".chars() {
+            editor.handle_key_event(key(c));
+        }
+        editor.handle_key_event(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE));
+        assert_eq!(editor.last_status, StatusCode::Ok);
+        assert_eq!(editor.sheet.borrow().note(0), Some("check this"));
+        assert_eq!(text(&editor, 0).0, "0*");
+    }
+
+    #[test]
+    fn test_locked_cells_stay_out_of_insert_mode() {
+        let mut editor = editor();
+        editor.app.execute("B2=1").unwrap();
+        editor.app.execute("lock B2").unwrap();
+        editor.handle_key_event(key('i'));
+        assert!(matches!(editor.mode, VimMode::Insert));
+        editor.handle_key_event(KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE));
+
+        // A selection reaching the locked cell cannot be typed into either
+        for c in ['v', 'j', 'l', 'i'] {
+            editor.handle_key_event(key(c));
+        }
+        assert!(matches!(editor.mode, VimMode::Visual));
+        assert_eq!(editor.last_status, StatusCode::CellLocked);
+        // Pasting over it is refused like any other edit
+        editor.handle_key_event(key('y'));
+        editor.handle_key_event(key('p'));
+        assert_eq!(editor.last_status, StatusCode::CellLocked);
+    }
+
+    #[test]
+    fn test_page_keys_scroll_the_view() {
+        let mut editor = editor();
+        let page = |editor: &mut VimEditor, code| {
+            editor.handle_key_event(KeyEvent::new(code, KeyModifiers::NONE));
+        };
+        editor.handle_key_event(key('j'));
+        page(&mut editor, KeyCode::PageDown);
+        assert_eq!((editor.start_row, editor.cursor_y), (20, 21));
+        page(&mut editor, KeyCode::End);
+        assert_eq!(
+            (editor.start_row, editor.start_col),
+            (
+                TEST_ROWS - editor.display_rows,
+                TEST_COLS - editor.display_cols
+            )
+        );
+        page(&mut editor, KeyCode::PageDown);
+        assert_eq!(editor.start_row, TEST_ROWS - editor.display_rows);
+        page(&mut editor, KeyCode::Home);
+        assert_eq!((editor.start_row, editor.start_col), (0, 0));
+        assert_eq!((editor.cursor_y, editor.cursor_x), (1, 0));
+    }
+
+    #[test]
+    fn test_counts_and_jumps_move_the_cursor() {
+        let mut editor = editor();
+        let keys = |editor: &mut VimEditor, keys: &str| {
+            for c in keys.chars() {
+                editor.handle_key_event(key(c));
+            }
+        };
+        keys(&mut editor, "5j12l");
+        assert_eq!((editor.cursor_y, editor.cursor_x), (5, 12));
+        keys(&mut editor, "0");
+        assert_eq!(editor.cursor_x, 0);
+        keys(&mut editor, "$");
+        assert_eq!(editor.cursor_x, TEST_COLS - 1);
+        assert_eq!(editor.start_col, TEST_COLS - editor.display_cols);
+
+        // The view follows the cursor down and back up
+        keys(&mut editor, "G");
+        assert_eq!(editor.cursor_y, TEST_ROWS - 1);
+        assert_eq!(editor.start_row, TEST_ROWS - editor.display_rows);
+        keys(&mut editor, "gg");
+        assert_eq!((editor.cursor_y, editor.start_row), (0, 0));
+        keys(&mut editor, "30G");
+        assert_eq!((editor.cursor_y, editor.start_row), (29, 10));
+        keys(&mut editor, "3gg");
+        assert_eq!(editor.cursor_y, 2);
+        keys(&mut editor, "500k");
+        assert_eq!(editor.cursor_y, 0);
+
+        let control = |c| KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL);
+        editor.handle_key_event(control('d'));
+        assert_eq!((editor.start_row, editor.cursor_y), (10, 10));
+        editor.handle_key_event(control('u'));
+        assert_eq!((editor.start_row, editor.cursor_y), (0, 0));
+
+        // A count is dropped by any key that is not a motion
+        keys(&mut editor, "4vj");
+        assert_eq!(editor.cursor_y, 1);
+    }
+
+    #[test]
+    fn test_status_bar_describes_cursor_cell_and_last_command() {
+        let mut editor = editor();
+        let bar = |editor: &VimEditor| editor.status_bar(&editor.sheet.borrow());
+        assert_eq!(bar(&editor), "A1  [0.0] (ok)");
+        type_input(&mut editor, "5");
+        assert_eq!(bar(&editor), "A1: 5  [0.0] (ok)");
+        editor.handle_key_event(key('l'));
+        type_input(&mut editor, "A1*2+1");
+        assert_eq!(bar(&editor), "B1: A1*2+1 = 11  [0.0] (ok)");
+        type_input(&mut editor, "\"x\"");
+        assert_eq!(bar(&editor), "B1: \"x\" = x  [0.0] (ok)");
+        for c in ":note \"check\"".chars() {
+            editor.handle_key_event(key(c));
+        }
+        editor.handle_key_event(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE));
+        type_input(&mut editor, "B1+");
+        assert_eq!(
+            bar(&editor),
+            "B1: \"x\" = x  Note: check  [0.0] (invalid command)"
+        );
+    }
+
+    #[test]
+    fn test_frames_fit_the_terminal_and_diff_by_line() {
+        let mut editor = editor();
+        editor.fit_to_terminal(80, 27);
+        assert_eq!((editor.display_rows, editor.display_cols), (20, 7));
+        let before = editor.frame();
+        // Mode line, blank, headers, blank, 20 rows, blank and status bar
+        assert_eq!(before.len(), 26);
+        assert!(before[2].trim_end().ends_with('G'));
+
+        editor.app.execute("B3=42").unwrap();
+        let after = editor.frame();
+        assert_eq!(changed_lines(&before, &after), [6]);
+        assert!(after[6].contains("42"));
+        editor.set_error_message("oops".to_string());
+        let with_error = editor.frame();
+        assert_eq!(changed_lines(&after, &with_error), [26]);
+        assert_eq!(changed_lines(&with_error, &after), [26]);
+
+        // A narrow terminal still shows one cell, and the view follows the cursor into it
+        editor.cursor_x = 5;
+        editor.fit_to_terminal(10, 3);
+        assert_eq!((editor.display_rows, editor.display_cols), (1, 1));
+        assert_eq!(editor.start_col, 5);
+    }
+
+    #[test]
+    fn test_insert_mode_highlights_referenced_cells() {
+        let mut editor = editor();
+        editor.handle_key_event(key('i'));
+        for c in "B2+MAX2(D2:C1,A1)+ZZZ999".chars() {
+            editor.handle_key_event(key(c));
+        }
+        let sheet = Rc::clone(&editor.app.sheet);
+        // MAX2 is a function, and ZZZ999 lies outside the sheet
+        assert_eq!(
+            editor.highlights(&sheet.borrow()),
+            [(1, 1, 1, 1), (0, 2, 1, 3), (0, 0, 0, 0)]
+        );
+        editor.handle_key_event(KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE));
+        assert!(editor.highlights(&sheet.borrow()).is_empty());
+        assert!(referenced_cells("\"B2 and C3\"", (10, 10)).is_empty());
+    }
+
+    #[test]
+    fn test_write_saves_expressions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vim.csv");
+        let mut editor = editor();
+        type_input(&mut editor, "4");
+        editor.handle_key_event(key('l'));
+        type_input(&mut editor, "A1*2");
+
+        editor.handle_key_event(key(':'));
+        for c in format!("w {}", path.display()).chars() {
+            editor.handle_key_event(key(c));
+        }
+        editor.handle_key_event(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "4,=A1*2\n");
+    }
+}